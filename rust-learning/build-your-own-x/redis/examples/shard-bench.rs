@@ -0,0 +1,103 @@
+//! Compare keyspace throughput with a single shard against a sharded one.
+//!
+//! Run with `cargo run --release --example shard-bench`.
+//!
+//! Two numbers are printed for each shard count:
+//!
+//! * `db`: OS threads hammering `Db` directly. This isolates lock contention
+//!   and is where sharding shows up most clearly, as long as the machine has
+//!   several cores to run the threads in parallel.
+//! * `tcp`: many concurrent clients talking to an in-process server over
+//!   loopback, which is closer to what a real client sees.
+
+use bytes::Bytes;
+use mini_redis::client;
+use redis::{Db, server};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+const THREADS: usize = 8;
+const OPS_PER_THREAD: usize = 200_000;
+
+const CLIENTS: usize = 64;
+const OPS_PER_CLIENT: usize = 2_000;
+
+#[tokio::main]
+async fn main() -> mini_redis::Result<()> {
+    for shards in [1, 16, 64] {
+        let db = direct(shards);
+        let tcp = over_tcp(shards).await?;
+
+        println!(
+            "shards={:<3} db={:>12.0} ops/s  tcp={:>10.0} ops/s",
+            shards, db, tcp
+        );
+    }
+
+    Ok(())
+}
+
+fn direct(shards: usize) -> f64 {
+    let db = Db::new(shards);
+    let start = Instant::now();
+
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let db = db.clone();
+            s.spawn(move || {
+                // Build the keys up front so the loop measures the keyspace
+                // rather than the allocator.
+                let keys: Vec<String> = (0..1024).map(|i| format!("key:{}:{}", t, i)).collect();
+                let value = Bytes::from_static(b"value");
+                for i in 0..OPS_PER_THREAD {
+                    let key = &keys[i % keys.len()];
+                    if i % 2 == 0 {
+                        db.set(key.clone(), value.clone(), None);
+                    } else {
+                        db.get(key);
+                    }
+                }
+            });
+        }
+    });
+
+    ops_per_sec(THREADS * OPS_PER_THREAD, start.elapsed())
+}
+
+async fn over_tcp(shards: usize) -> mini_redis::Result<f64> {
+    // Port 0 lets the OS pick a free port, so the runs never collide.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = tokio::spawn(server::run(listener, Db::new(shards)));
+
+    let start = Instant::now();
+    let mut clients = Vec::with_capacity(CLIENTS);
+
+    for c in 0..CLIENTS {
+        clients.push(tokio::spawn(async move {
+            let mut client = client::connect(addr).await?;
+            for i in 0..OPS_PER_CLIENT {
+                let key = format!("key:{}:{}", c, i % 1024);
+                if i % 2 == 0 {
+                    client.set(&key, Bytes::from_static(b"value")).await?;
+                } else {
+                    client.get(&key).await?;
+                }
+            }
+            Ok::<_, mini_redis::Error>(())
+        }));
+    }
+
+    for client in clients {
+        client.await??;
+    }
+    let elapsed = start.elapsed();
+
+    server.abort();
+    Ok(ops_per_sec(CLIENTS * OPS_PER_CLIENT, elapsed))
+}
+
+fn ops_per_sec(ops: usize, elapsed: Duration) -> f64 {
+    ops as f64 / elapsed.as_secs_f64()
+}
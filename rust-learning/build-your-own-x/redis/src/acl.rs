@@ -0,0 +1,546 @@
+//! Access control: users, their passwords, and what they may run.
+//!
+//! Every command runs as some user. A connection starts out as `default`,
+//! unless that user needs a password, in which case it can only `AUTH` (or
+//! `HELLO` with `AUTH`) until it gives one. Out of the box, `default` may
+//! run anything without a password; `requirepass` gives it one.
+//!
+//! Users are described with the rules of `ACL SETUSER`, applied in order:
+//!
+//! * `on` and `off` enable or disable logging in as the user;
+//! * `>password` and `<password` add or remove a password, `#digest` and
+//!   `!digest` do the same given its hex SHA-1 digest, `nopass` lets any
+//!   password in and `resetpass` forgets all of them, `nopass` included;
+//! * `~pattern` gives access to the keys matching a glob pattern, `allkeys`
+//!   is `~*`, and `resetkeys` takes every pattern away;
+//! * `+command` and `-command` allow or deny a command, `+@category` and
+//!   `-@category` every command in a category, `allcommands` and
+//!   `nocommands` are `+@all` and `-@all`;
+//! * `reset` starts over from a new user: off, no password, no keys and no
+//!   commands.
+//!
+//! Only password digests are kept. A command a user may not run, or naming
+//! a key outside its patterns, is refused with a `NOPERM` error. Changes to
+//! a user apply to the connections logged in as it from their next command.
+
+use crate::{Command, Config, glob};
+
+use bytes::Bytes;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Arc;
+
+/// Error replied to a connection that has yet to authenticate.
+pub const NOAUTH: &str = "NOAUTH Authentication required.";
+
+/// Error replied to a failed `AUTH`. It does not say which part was wrong.
+pub const WRONGPASS: &str = "WRONGPASS invalid username-password pair or user is disabled.";
+
+/// The user connections start as, and `AUTH password` logs into.
+pub const DEFAULT_USER: &str = "default";
+
+/// Command categories, as `ACL CAT` lists them. `@all` comes on top.
+pub const CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "string",
+    "list",
+    "hash",
+    "set",
+    "sortedset",
+    "stream",
+    "pubsub",
+    "admin",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+    "blocking",
+];
+
+/// Every command, with the categories it belongs to.
+const COMMANDS: &[(&str, &[&str])] = &[
+    ("ping", &["connection"]),
+    ("echo", &["connection"]),
+    ("hello", &["connection"]),
+    ("auth", &["connection"]),
+    ("asking", &["connection"]),
+    ("get", &["read", "string"]),
+    ("set", &["write", "string"]),
+    ("del", &["write", "keyspace"]),
+    ("exists", &["read", "keyspace"]),
+    ("type", &["read", "keyspace"]),
+    ("keys", &["read", "keyspace", "dangerous"]),
+    ("scan", &["read", "keyspace"]),
+    ("ttl", &["read", "keyspace"]),
+    ("pttl", &["read", "keyspace"]),
+    ("expire", &["write", "keyspace"]),
+    ("pexpire", &["write", "keyspace"]),
+    ("expireat", &["write", "keyspace"]),
+    ("pexpireat", &["write", "keyspace"]),
+    ("persist", &["write", "keyspace"]),
+    ("dump", &["read", "keyspace"]),
+    ("restore", &["write", "keyspace", "dangerous"]),
+    ("restore-asking", &["write", "keyspace", "dangerous"]),
+    ("migrate", &["write", "keyspace", "dangerous"]),
+    ("lpush", &["write", "list"]),
+    ("rpush", &["write", "list"]),
+    ("lpop", &["write", "list"]),
+    ("rpop", &["write", "list"]),
+    ("blpop", &["write", "list", "blocking"]),
+    ("brpop", &["write", "list", "blocking"]),
+    ("llen", &["read", "list"]),
+    ("lrange", &["read", "list"]),
+    ("hset", &["write", "hash"]),
+    ("hget", &["read", "hash"]),
+    ("hdel", &["write", "hash"]),
+    ("hgetall", &["read", "hash"]),
+    ("hincrby", &["write", "hash"]),
+    ("hscan", &["read", "hash"]),
+    ("sadd", &["write", "set"]),
+    ("smembers", &["read", "set"]),
+    ("sinter", &["read", "set"]),
+    ("sscan", &["read", "set"]),
+    ("zadd", &["write", "sortedset"]),
+    ("zrange", &["read", "sortedset"]),
+    ("zrangebyscore", &["read", "sortedset"]),
+    ("zscan", &["read", "sortedset"]),
+    ("xadd", &["write", "stream"]),
+    ("xsetid", &["write", "stream"]),
+    ("xlen", &["read", "stream"]),
+    ("xrange", &["read", "stream"]),
+    ("xrevrange", &["read", "stream"]),
+    ("xread", &["read", "stream", "blocking"]),
+    ("xgroup", &["write", "stream"]),
+    ("xreadgroup", &["write", "stream", "blocking"]),
+    ("xack", &["write", "stream"]),
+    ("xpending", &["read", "stream"]),
+    ("xclaim", &["write", "stream"]),
+    ("multi", &["transaction"]),
+    ("exec", &["transaction"]),
+    ("discard", &["transaction"]),
+    ("watch", &["transaction"]),
+    ("unwatch", &["transaction"]),
+    ("publish", &["pubsub"]),
+    ("subscribe", &["pubsub"]),
+    ("unsubscribe", &["pubsub"]),
+    ("eval", &["scripting"]),
+    ("evalsha", &["scripting"]),
+    ("script", &["scripting"]),
+    ("save", &["admin", "dangerous"]),
+    ("bgsave", &["admin", "dangerous"]),
+    ("lastsave", &["admin", "dangerous"]),
+    ("bgrewriteaof", &["admin", "dangerous"]),
+    ("shutdown", &["admin", "dangerous"]),
+    ("config", &["admin", "dangerous"]),
+    ("role", &["admin", "dangerous"]),
+    ("replconf", &["admin", "dangerous"]),
+    ("psync", &["admin", "dangerous"]),
+    ("acl", &["admin", "dangerous"]),
+    ("cluster", &["admin", "dangerous"]),
+];
+
+/// The users of a server.
+#[derive(Debug)]
+pub struct Acl {
+    users: BTreeMap<String, Arc<User>>,
+}
+
+/// A user: how to log in as it, and what it may then do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    name: String,
+
+    /// Whether one may log in as the user.
+    enabled: bool,
+
+    /// Whether any password will do.
+    nopass: bool,
+
+    /// Hex SHA-1 digests of the passwords.
+    passwords: BTreeSet<String>,
+
+    /// The command rules since the last `+@all` or `-@all`, which describe
+    /// `allowed` in the terms it was given in.
+    commands: Vec<String>,
+
+    /// The commands the user may run.
+    allowed: HashSet<&'static str>,
+
+    /// Glob patterns of the keys the user may access.
+    keys: Vec<Bytes>,
+}
+
+impl Default for Acl {
+    /// Only the `default` user, which needs no password and may do
+    /// anything.
+    fn default() -> Acl {
+        let mut acl = Acl {
+            users: BTreeMap::new(),
+        };
+        acl.set_user(DEFAULT_USER, &["on", "nopass", "allkeys", "allcommands"])
+            .expect("the default user's rules are valid");
+        acl
+    }
+}
+
+impl Acl {
+    /// The users `config` sets up: `default`, with `requirepass` as its
+    /// password if there is one, then those of the `user` lines.
+    pub fn from_config(config: &Config) -> Result<Acl, String> {
+        let mut acl = Acl::default();
+        acl.set_requirepass(config.requirepass.as_deref());
+
+        for line in &config.users {
+            let mut words = line.split_whitespace();
+            let name = words.next().ok_or("user without a name")?;
+            let rules: Vec<_> = words.collect();
+            acl.set_user(name, &rules)
+                .map_err(|err| format!("user '{}': {}", name, err))?;
+        }
+        Ok(acl)
+    }
+
+    /// The user called `name`, if there is one.
+    pub fn user(&self, name: &str) -> Option<Arc<User>> {
+        self.users.get(name).cloned()
+    }
+
+    /// Every user, by name.
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.users.values().map(|user| &**user)
+    }
+
+    /// The user new connections start as: `default`, unless logging in as
+    /// it takes a password.
+    pub fn initial_user(&self) -> Option<String> {
+        let user = self.users.get(DEFAULT_USER)?;
+        (user.enabled && user.nopass).then(|| DEFAULT_USER.to_string())
+    }
+
+    /// Check `password` against the user called `name`.
+    pub fn authenticate(&self, name: &str, password: &[u8]) -> crate::Result<()> {
+        match self.users.get(name) {
+            Some(user)
+                if user.enabled && (user.nopass || user.passwords.contains(&digest(password))) =>
+            {
+                Ok(())
+            }
+            _ => Err(WRONGPASS.into()),
+        }
+    }
+
+    /// Apply `rules` to the user called `name`, creating it if need be.
+    ///
+    /// The rules apply together or not at all: if one is invalid, the user
+    /// is left as it was.
+    pub fn set_user<S: AsRef<str>>(&mut self, name: &str, rules: &[S]) -> crate::Result<()> {
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '\0') {
+            return Err("ERR Usernames can't contain spaces or null characters".into());
+        }
+
+        let mut user = match self.users.get(name) {
+            Some(user) => User::clone(user),
+            None => User::new(name),
+        };
+        for rule in rules {
+            let rule = rule.as_ref();
+            user.apply(rule).map_err(|reason| {
+                format!("ERR Error in ACL SETUSER modifier '{}': {}", rule, reason)
+            })?;
+        }
+
+        self.users.insert(name.to_string(), Arc::new(user));
+        Ok(())
+    }
+
+    /// Remove the user called `name`. Returns `false` if there was none.
+    /// Connections logged in as it can't run anything more.
+    pub fn del_user(&mut self, name: &str) -> crate::Result<bool> {
+        if name == DEFAULT_USER {
+            return Err("ERR The 'default' user cannot be removed".into());
+        }
+        Ok(self.users.remove(name).is_some())
+    }
+
+    /// Make `password` the only one of the `default` user, or let it in
+    /// without one if `None`, as `requirepass` does.
+    pub fn set_requirepass(&mut self, password: Option<&str>) {
+        let rules = match password {
+            Some(password) => vec!["resetpass".to_string(), format!(">{}", password)],
+            None => vec!["nopass".to_string()],
+        };
+        self.set_user(DEFAULT_USER, &rules)
+            .expect("password rules are valid");
+    }
+}
+
+impl User {
+    /// A new user: disabled, with no password, keys or commands.
+    fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            commands: vec!["-@all".to_string()],
+            allowed: HashSet::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    /// The user's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether one may log in as the user.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether any password will do to log in as the user.
+    pub fn is_nopass(&self) -> bool {
+        self.nopass
+    }
+
+    /// Whether the user may run `cmd`, with the keys it names.
+    ///
+    /// `ACL WHOAMI` is always allowed, like `AUTH`: every user may know who
+    /// it is.
+    pub fn check(&self, cmd: &Command) -> crate::Result<()> {
+        let whoami = cmd.name() == "acl"
+            && cmd
+                .args()
+                .first()
+                .is_some_and(|sub| sub.eq_ignore_ascii_case(b"whoami"));
+        if !whoami && !self.allowed.contains(cmd.name()) {
+            return Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                self.name,
+                cmd.name()
+            )
+            .into());
+        }
+
+        for key in cmd.keys() {
+            if !self.keys.iter().any(|pattern| glob::matches(pattern, key)) {
+                return Err("NOPERM No permissions to access a key".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// `on` or `off`, then `nopass`, as `ACL GETUSER` lists them.
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    /// Hex SHA-1 digests of the passwords.
+    pub fn passwords(&self) -> impl Iterator<Item = &str> {
+        self.passwords.iter().map(String::as_str)
+    }
+
+    /// The command rules, as in `+@all -config`.
+    pub fn commands(&self) -> String {
+        self.commands.join(" ")
+    }
+
+    /// The key patterns, as in `~cache:* ~session:*`.
+    pub fn keys(&self) -> String {
+        let patterns = self
+            .keys
+            .iter()
+            .map(|p| format!("~{}", String::from_utf8_lossy(p)));
+        patterns.collect::<Vec<_>>().join(" ")
+    }
+
+    /// The rules making up the user, as `ACL LIST` shows them. Given to
+    /// `ACL SETUSER`, they create the same user again.
+    pub fn describe(&self) -> String {
+        let mut rules = vec![format!("user {}", self.name)];
+        rules.extend(self.flags().into_iter().map(String::from));
+        rules.extend(self.passwords.iter().map(|digest| format!("#{}", digest)));
+        rules.push(match self.keys.is_empty() {
+            true => "resetkeys".to_string(),
+            false => self.keys(),
+        });
+        rules.push(self.commands());
+        rules.join(" ")
+    }
+
+    /// Apply a single `ACL SETUSER` rule, or say what is wrong with it.
+    fn apply(&mut self, rule: &str) -> Result<(), &'static str> {
+        if let Some(password) = rule.strip_prefix('>') {
+            self.passwords.insert(digest(password.as_bytes()));
+            self.nopass = false;
+        } else if let Some(password) = rule.strip_prefix('<') {
+            if !self.passwords.remove(&digest(password.as_bytes())) {
+                return Err("no such password");
+            }
+        } else if let Some(hex) = rule.strip_prefix('#') {
+            if hex.len() != 40 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err("the password digest must be 40 hex characters");
+            }
+            self.passwords.insert(hex.to_ascii_lowercase());
+            self.nopass = false;
+        } else if let Some(hex) = rule.strip_prefix('!') {
+            if !self.passwords.remove(&hex.to_ascii_lowercase()) {
+                return Err("no such password");
+            }
+        } else if let Some(pattern) = rule.strip_prefix('~') {
+            self.keys.push(Bytes::copy_from_slice(pattern.as_bytes()));
+        } else if let Some(name) = rule.strip_prefix('+').or(rule.strip_prefix('-')) {
+            let commands = commands_named(&name.to_ascii_lowercase())
+                .ok_or("Unknown command or category name in ACL")?;
+            self.set_commands(rule.starts_with('+'), commands, &rule.to_ascii_lowercase());
+        } else {
+            match rule.to_ascii_lowercase().as_str() {
+                "on" => self.enabled = true,
+                "off" => self.enabled = false,
+                "nopass" => {
+                    self.nopass = true;
+                    self.passwords.clear();
+                }
+                "resetpass" => {
+                    self.nopass = false;
+                    self.passwords.clear();
+                }
+                "allkeys" => self.keys = vec![Bytes::from_static(b"*")],
+                "resetkeys" => self.keys.clear(),
+                "allcommands" => self.apply("+@all")?,
+                "nocommands" => self.apply("-@all")?,
+                "reset" => *self = User::new(&self.name),
+                _ => return Err("Syntax error"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Allow or deny `commands`, recording `rule` as the reason.
+    fn set_commands(&mut self, allow: bool, commands: Vec<&'static str>, rule: &str) {
+        for name in commands {
+            if allow {
+                self.allowed.insert(name);
+            } else {
+                self.allowed.remove(name);
+            }
+        }
+
+        // Everything before `+@all` or `-@all` no longer matters.
+        if rule == "+@all" || rule == "-@all" {
+            self.commands.clear();
+        }
+        self.commands.push(rule.to_string());
+    }
+}
+
+/// The commands `name` stands for in a `+` or `-` rule: a command, or every
+/// command of a category with `@category`. `None` if there is no such
+/// thing.
+fn commands_named(name: &str) -> Option<Vec<&'static str>> {
+    match name.strip_prefix('@') {
+        Some("all") => Some(COMMANDS.iter().map(|(name, _)| *name).collect()),
+        Some(category) => category_commands(category),
+        None => COMMANDS
+            .iter()
+            .find(|(command, _)| *command == name)
+            .map(|(command, _)| vec![*command]),
+    }
+}
+
+/// The commands in `category`, or `None` if there is no such category.
+pub fn category_commands(category: &str) -> Option<Vec<&'static str>> {
+    if !CATEGORIES.contains(&category) {
+        return None;
+    }
+    let commands = COMMANDS
+        .iter()
+        .filter(|(_, categories)| categories.contains(&category))
+        .map(|(name, _)| *name);
+    Some(commands.collect())
+}
+
+/// Hex SHA-1 digest of a password.
+fn digest(password: &[u8]) -> String {
+    sha1_smol::Sha1::from(password).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(parts: &[&str]) -> Command {
+        let args = parts[1..]
+            .iter()
+            .map(|p| Bytes::copy_from_slice(p.as_bytes()));
+        Command::new(parts[0], args.collect())
+    }
+
+    #[test]
+    fn rules_build_up_a_user() {
+        let mut acl = Acl::default();
+        acl.set_user(
+            "alice",
+            &["on", ">secret", "~cache:*", "+@read", "-keys", "+set"],
+        )
+        .unwrap();
+        let alice = acl.user("alice").unwrap();
+
+        assert!(acl.authenticate("alice", b"secret").is_ok());
+        assert!(acl.authenticate("alice", b"nope").is_err());
+        assert_eq!(alice.commands(), "-@all +@read -keys +set");
+
+        assert!(alice.check(&cmd(&["GET", "cache:1"])).is_ok());
+        assert!(alice.check(&cmd(&["SET", "cache:1", "v"])).is_ok());
+        assert!(alice.check(&cmd(&["KEYS", "*"])).is_err());
+        assert!(alice.check(&cmd(&["DEL", "cache:1"])).is_err());
+        assert!(alice.check(&cmd(&["GET", "other"])).is_err());
+        assert!(
+            alice
+                .check(&cmd(&[
+                    "XREAD", "COUNT", "1", "STREAMS", "cache:s", "other", "0", "0"
+                ]))
+                .is_err()
+        );
+        assert!(alice.check(&cmd(&["ACL", "WHOAMI"])).is_ok());
+
+        // The description gives the same user back.
+        let description = alice.describe();
+        let rules: Vec<_> = description.split(' ').skip(2).collect();
+        acl.set_user("copy", &rules).unwrap();
+        let copy = acl.user("copy").unwrap();
+        assert_eq!(
+            copy.describe(),
+            description.replace("user alice", "user copy")
+        );
+    }
+
+    #[test]
+    fn bad_rules_change_nothing() {
+        let mut acl = Acl::default();
+        acl.set_user("bob", &["on", "+get"]).unwrap();
+        let before = acl.user("bob").unwrap();
+
+        assert!(acl.set_user("bob", &["+set", "+nope"]).is_err());
+        assert!(acl.set_user("bob", &["+set", "sideways"]).is_err());
+        assert_eq!(acl.user("bob").unwrap(), before);
+    }
+
+    #[test]
+    fn requirepass_sets_the_default_password() {
+        let mut acl = Acl::default();
+        assert_eq!(acl.initial_user().as_deref(), Some(DEFAULT_USER));
+
+        acl.set_requirepass(Some("hunter2"));
+        assert_eq!(acl.initial_user(), None);
+        assert!(acl.authenticate(DEFAULT_USER, b"hunter2").is_ok());
+
+        acl.set_requirepass(None);
+        assert_eq!(acl.initial_user().as_deref(), Some(DEFAULT_USER));
+    }
+}
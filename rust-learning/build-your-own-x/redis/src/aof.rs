@@ -0,0 +1,707 @@
+//! Append-only file persistence.
+//!
+//! Every write command is appended to the file in the same RESP form clients
+//! send it in, so restoring the keyspace at startup is a matter of decoding
+//! the file and running each command again.
+//!
+//! Appending happens on a dedicated task that owns the file. Connections
+//! hand it encoded commands through a channel, in the order they were
+//! applied (see `Db::write`), and the task writes whatever has queued up in
+//! one go. How often the data is then forced to disk is the `Fsync` policy.
+//!
+//! Left alone the file only grows, so it can be rewritten from the current
+//! keyspace while the server keeps running; see `rewrite`.
+
+use crate::cmd;
+use crate::frame::{Decoder, Protocol};
+use crate::{Command, Db, Value};
+
+use bytes::{Bytes, BytesMut};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, MissedTickBehavior};
+
+/// When appended data is forced to disk with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fsync {
+    /// Before replying to the write. Nothing acknowledged is ever lost, at
+    /// the cost of a disk flush per batch of writes.
+    Always,
+
+    /// Once a second, in the background. A crash loses at most about a
+    /// second of writes.
+    #[default]
+    EverySec,
+
+    /// Never explicitly; the operating system flushes when it sees fit.
+    No,
+}
+
+/// Handle to the task appending to the file. Cloning it is cheap.
+#[derive(Debug, Clone)]
+pub struct Aof {
+    tx: mpsc::UnboundedSender<Request>,
+    fsync: Fsync,
+    path: PathBuf,
+
+    /// Set while a rewrite is running; there is at most one at a time.
+    rewriting: Arc<AtomicBool>,
+}
+
+/// A message for the writer task.
+#[derive(Debug)]
+enum Request {
+    /// Append encoded commands.
+    Append {
+        data: Bytes,
+
+        /// With `Fsync::Always`, notified once the data is on disk.
+        synced: Option<oneshot::Sender<Result<(), String>>>,
+    },
+
+    /// A rewrite took its copy of the keyspace: from now on, also keep
+    /// what is appended, for the rewritten file.
+    StartRewrite,
+
+    /// The rewritten file at the given path is ready. Append what was kept
+    /// to it and put it in place of the current file.
+    FinishRewrite(PathBuf, oneshot::Sender<io::Result<()>>),
+
+    /// The rewrite failed; stop keeping appended data.
+    AbortRewrite,
+
+    /// Sync what was appended so far to disk, and say when done.
+    Sync(oneshot::Sender<io::Result<()>>),
+}
+
+/// Resolves once appended commands are on disk; see `Aof::append`.
+#[derive(Debug)]
+pub struct Synced(oneshot::Receiver<Result<(), String>>);
+
+/// Collections are rewritten with at most this many elements per command,
+/// so replaying a huge one does not need one huge request.
+const ITEMS_PER_COMMAND: usize = 64;
+
+impl Aof {
+    /// Open the file at `path` for appending, creating it if needed, and
+    /// start the writer task.
+    ///
+    /// Must be called from within a Tokio runtime. The task syncs the file
+    /// one last time and exits once every handle is dropped.
+    pub fn open(path: &Path, fsync: Fsync) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let writer = Writer {
+            file: File::from_std(file),
+            path: path.to_path_buf(),
+            fsync,
+            dirty: false,
+            rewrite: None,
+        };
+        tokio::spawn(writer.run(rx));
+
+        Ok(Aof {
+            tx,
+            fsync,
+            path: path.to_path_buf(),
+            rewriting: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn fsync(&self) -> Fsync {
+        self.fsync
+    }
+
+    /// Queue `cmds` to be appended, in order.
+    ///
+    /// With `Fsync::Always`, returns a `Synced` the caller must wait on
+    /// before acknowledging the write to the client.
+    pub fn append(&self, cmds: &[Command]) -> Option<Synced> {
+        if cmds.is_empty() {
+            return None;
+        }
+
+        let mut data = BytesMut::new();
+        for cmd in cmds {
+            cmd.to_frame().encode(&mut data, Protocol::Resp2);
+        }
+
+        let (synced, rx) = match self.fsync {
+            Fsync::Always => {
+                let (tx, rx) = oneshot::channel();
+                (Some(tx), Some(Synced(rx)))
+            }
+            _ => (None, None),
+        };
+
+        // Should the task be gone, the sender is dropped along with the
+        // message and `Synced` reports it.
+        let _ = self.tx.send(Request::Append {
+            data: data.freeze(),
+            synced,
+        });
+        rx
+    }
+
+    /// Wait for everything appended so far to be on disk, whatever the
+    /// `Fsync` policy.
+    pub async fn sync(&self) -> crate::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Request::Sync(tx))
+            .map_err(|_| "the AOF writer stopped")?;
+        rx.await.map_err(|_| "the AOF writer stopped")??;
+        Ok(())
+    }
+
+    /// Whether a rewrite is running.
+    pub fn is_rewriting(&self) -> bool {
+        self.rewriting.load(Ordering::Acquire)
+    }
+}
+
+/// Rewrite the append-only file of `db` as the shortest sequence of commands
+/// rebuilding the current keyspace, then swap it in for the old file.
+///
+/// The keyspace is copied up front, between two writes. Writes made while
+/// the new file is being produced keep going to the old file, and are also
+/// kept aside and appended to the new one before the swap, so nothing is
+/// lost whichever file survives a crash. The swap is a rename, which is
+/// atomic.
+pub async fn rewrite(db: &Db) -> crate::Result<()> {
+    let aof = db.aof().ok_or("ERR Append only file is disabled")?;
+    if aof.rewriting.swap(true, Ordering::AcqRel) {
+        return Err("ERR Background append only file rewriting already in progress".into());
+    }
+
+    let res = rewrite_with(db, &aof).await;
+    if res.is_err() {
+        let _ = aof.tx.send(Request::AbortRewrite);
+    }
+    aof.rewriting.store(false, Ordering::Release);
+    res
+}
+
+async fn rewrite_with(db: &Db, aof: &Aof) -> crate::Result<()> {
+    // No write can slip in between the copy and the start of the buffering:
+    // both happen while writes are held off.
+    let (now, entries) = db.between_writes(|| {
+        let _ = aof.tx.send(Request::StartRewrite);
+        (SystemTime::now(), db.snapshot())
+    });
+
+    let tmp = aof
+        .path
+        .with_extension(format!("rewrite-{}", std::process::id()));
+    let path = tmp.clone();
+    tokio::task::spawn_blocking(move || -> io::Result<()> {
+        let mut data = BytesMut::new();
+        for cmd in rewrite_commands(&entries, now) {
+            cmd.to_frame().encode(&mut data, Protocol::Resp2);
+        }
+
+        let mut file = std::fs::File::create(&path)?;
+        file.write_all(&data)?;
+        file.sync_all()
+    })
+    .await??;
+
+    let (done, rx) = oneshot::channel();
+    aof.tx
+        .send(Request::FinishRewrite(tmp, done))
+        .map_err(|_| "ERR The AOF writer stopped")?;
+    rx.await.map_err(|_| "ERR The AOF writer stopped")??;
+    Ok(())
+}
+
+/// The commands rebuilding `entries`, as returned by `Db::snapshot` at
+/// `now`.
+pub fn rewrite_commands(
+    entries: &[(String, Value, Option<Duration>)],
+    now: SystemTime,
+) -> Vec<Command> {
+    let mut cmds = Vec::new();
+
+    for (key, value, ttl) in entries {
+        let key = Bytes::copy_from_slice(key.as_bytes());
+
+        // One command per chunk of `items`, each made of `width` arguments.
+        let mut chunked = |name: &str, items: Vec<Bytes>, width: usize| {
+            for chunk in items.chunks(ITEMS_PER_COMMAND * width) {
+                let args = std::iter::once(key.clone()).chain(chunk.iter().cloned());
+                cmds.push(Command::new(name, args.collect()));
+            }
+        };
+
+        match value {
+            Value::String(value) => chunked("set", vec![value.clone()], 1),
+            Value::List(list) => chunked("rpush", list.iter().cloned().collect(), 1),
+            Value::Hash(hash) => {
+                let items = hash.iter().flat_map(|(f, v)| [f.clone(), v.clone()]);
+                chunked("hset", items.collect(), 2)
+            }
+            Value::Set(set) => chunked("sadd", set.iter().cloned().collect(), 1),
+            Value::ZSet(zset) => {
+                // `Display` for `f64` gives the shortest string parsing back
+                // to the same value, infinities included.
+                let items = zset
+                    .range_by_rank(0, zset.len(), false)
+                    .flat_map(|(m, s)| [Bytes::from(s.to_string()), m.clone()]);
+                chunked("zadd", items.collect(), 2)
+            }
+            Value::Stream(stream) => cmds.extend(cmd::stream::rebuild(&key, stream)),
+        }
+
+        if let Some(ttl) = ttl {
+            let at = (now + *ttl)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            cmds.push(Command::new(
+                "pexpireat",
+                vec![key, Bytes::from(at.to_string())],
+            ));
+        }
+    }
+
+    cmds
+}
+
+impl Synced {
+    /// Wait for the data to reach the disk.
+    pub async fn wait(self) -> crate::Result<()> {
+        match self.0.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(format!("MISCONF Errors writing to the AOF file: {}", err).into()),
+            Err(_) => Err("MISCONF The AOF writer stopped".into()),
+        }
+    }
+}
+
+/// State of the writer task.
+struct Writer {
+    file: File,
+    path: PathBuf,
+    fsync: Fsync,
+
+    /// Written since the last sync.
+    dirty: bool,
+
+    /// While a rewrite runs, what was appended since it copied the
+    /// keyspace.
+    rewrite: Option<BytesMut>,
+}
+
+impl Writer {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Request>) {
+        let mut ticks = time::interval(Duration::from_secs(1));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                request = rx.recv() => {
+                    let Some(request) = request else {
+                        break;
+                    };
+                    self.handle(request, &mut rx).await;
+                }
+                _ = ticks.tick(), if self.fsync == Fsync::EverySec && self.dirty => {
+                    if let Err(err) = self.file.sync_data().await {
+                        eprintln!("aof: fsync failed: {}", err);
+                    }
+                    self.dirty = false;
+                }
+            }
+        }
+
+        if let Err(err) = self.file.sync_data().await {
+            eprintln!("aof: fsync failed: {}", err);
+        }
+    }
+
+    async fn handle(&mut self, request: Request, rx: &mut mpsc::UnboundedReceiver<Request>) {
+        match request {
+            Request::Append { data, synced } => {
+                // Write everything queued so far with a single call, and
+                // with `always`, a single sync: group commit.
+                let mut batch = BytesMut::from(&data[..]);
+                let mut waiting: Vec<_> = synced.into_iter().collect();
+                let mut next = None;
+                while let Ok(request) = rx.try_recv() {
+                    match request {
+                        Request::Append { data, synced } => {
+                            batch.extend_from_slice(&data);
+                            waiting.extend(synced);
+                        }
+                        request => {
+                            next = Some(request);
+                            break;
+                        }
+                    }
+                }
+
+                self.append(&batch, waiting).await;
+                if let Some(request) = next {
+                    Box::pin(self.handle(request, rx)).await;
+                }
+            }
+            Request::StartRewrite => self.rewrite = Some(BytesMut::new()),
+            Request::Sync(done) => {
+                let res = self.file.sync_data().await;
+                self.dirty = res.is_err();
+                let _ = done.send(res);
+            }
+            Request::AbortRewrite => self.rewrite = None,
+            Request::FinishRewrite(tmp, done) => {
+                let res = self.finish_rewrite(&tmp).await;
+                if let Err(err) = &res {
+                    eprintln!("aof: rewrite failed: {}", err);
+                    let _ = tokio::fs::remove_file(&tmp).await;
+                }
+                let _ = done.send(res);
+            }
+        }
+    }
+
+    async fn append(&mut self, data: &[u8], waiting: Vec<oneshot::Sender<Result<(), String>>>) {
+        if let Some(rewrite) = &mut self.rewrite {
+            rewrite.extend_from_slice(data);
+        }
+
+        let mut res = write(&mut self.file, data).await;
+        if res.is_ok() && self.fsync == Fsync::Always {
+            res = self.file.sync_data().await;
+        } else {
+            self.dirty = true;
+        }
+
+        let res = res.map_err(|err| err.to_string());
+        if let Err(err) = &res {
+            eprintln!("aof: write failed: {}", err);
+        }
+        for tx in waiting {
+            let _ = tx.send(res.clone());
+        }
+    }
+
+    /// Complete the rewritten file at `tmp` and make it the current one.
+    async fn finish_rewrite(&mut self, tmp: &Path) -> io::Result<()> {
+        let buffered = self.rewrite.take().unwrap_or_default();
+
+        let mut file = File::from_std(OpenOptions::new().append(true).open(tmp)?);
+        write(&mut file, &buffered).await?;
+        file.sync_all().await?;
+
+        // The handle keeps pointing at the new file once it is renamed; the
+        // old one goes away when its handle is dropped.
+        tokio::fs::rename(tmp, &self.path).await?;
+        self.file = file;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+async fn write(file: &mut File, data: &[u8]) -> io::Result<()> {
+    file.write_all(data).await?;
+    // A `tokio::fs::File` hands writes to a background thread; flushing
+    // waits for them to reach the operating system.
+    file.flush().await
+}
+
+/// Replay the append-only file at `path` into `db`, returning the number of
+/// commands run. A missing file is an empty one.
+///
+/// A crash in the middle of an append leaves a partial command at the end
+/// of the file, or part of a transaction. Either is dropped, and the file
+/// truncated to the last complete command, so later appends start on a
+/// clean boundary. Garbage anywhere else is an error: silently skipping data
+/// there would lose writes.
+///
+/// Must run before the file is attached to `db`, or the replayed commands
+/// would be appended again.
+pub fn load(path: &Path, db: &Db) -> crate::Result<usize> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut buf = BytesMut::from(&data[..]);
+    let mut decoder = Decoder::new();
+
+    // Length of the prefix made of complete commands.
+    let mut valid = 0;
+    let mut count = 0;
+
+    // Commands read since a `MULTI`. They only run once its `EXEC` is read.
+    let mut transaction: Option<Vec<Command>> = None;
+    let mut offset = 0;
+
+    loop {
+        let frame = match decoder.decode(&mut buf) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(err) => return Err(Corrupt { offset, err }.into()),
+        };
+        let cmd = Command::from_frame(frame).map_err(|err| Corrupt { offset, err })?;
+        offset = data.len() - buf.len();
+
+        match (cmd.name(), &mut transaction) {
+            ("multi", None) => transaction = Some(Vec::new()),
+            ("exec", Some(_)) => {
+                for cmd in transaction.take().unwrap_or_default() {
+                    cmd::apply(&cmd, db);
+                    count += 1;
+                }
+                valid = offset;
+            }
+            (_, Some(queued)) => queued.push(cmd),
+            (_, None) => {
+                cmd::apply(&cmd, db);
+                count += 1;
+                valid = offset;
+            }
+        }
+    }
+
+    if valid < data.len() {
+        eprintln!(
+            "aof: {} ends with a truncated command; dropping its last {} bytes",
+            path.display(),
+            data.len() - valid
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid as u64)?;
+    }
+
+    Ok(count)
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Fsync, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "no" => Ok(Fsync::No),
+            _ => Err(format!("invalid fsync policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Fsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Fsync::Always => "always",
+            Fsync::EverySec => "everysec",
+            Fsync::No => "no",
+        })
+    }
+}
+
+/// Error returned by `load` when the file holds something other than
+/// commands.
+#[derive(Debug)]
+struct Corrupt {
+    offset: usize,
+    err: crate::Error,
+}
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corrupt append-only file at byte {}: {}",
+            self.offset, self.err
+        )
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh path under the system temp directory.
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("redis-aof-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn cmd(parts: &[&str]) -> Command {
+        let args = parts[1..]
+            .iter()
+            .map(|p| Bytes::copy_from_slice(p.as_bytes()))
+            .collect();
+        Command::new(parts[0], args)
+    }
+
+    fn encode(cmds: &[Command]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        for cmd in cmds {
+            cmd.to_frame().encode(&mut buf, Protocol::Resp2);
+        }
+        buf.to_vec()
+    }
+
+    #[tokio::test]
+    async fn replays_commands() {
+        let path = temp_path("replay.aof");
+        std::fs::write(
+            &path,
+            encode(&[
+                cmd(&["SET", "a", "1"]),
+                cmd(&["RPUSH", "l", "x", "y"]),
+                cmd(&["LPOP", "l"]),
+                cmd(&["DEL", "a"]),
+            ]),
+        )
+        .unwrap();
+
+        let db = Db::new(4);
+        assert_eq!(load(&path, &db).unwrap(), 4);
+        assert_eq!(db.get("a"), None);
+        assert_eq!(db.get("l"), Some(Value::List(["y".into()].into())));
+
+        assert_eq!(load(&temp_path("missing.aof"), &Db::new(1)).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn repairs_a_truncated_tail() {
+        let path = temp_path("truncated.aof");
+        let complete = encode(&[cmd(&["SET", "a", "1"]), cmd(&["SET", "b", "2"])]);
+        let mut data = complete.clone();
+        data.extend_from_slice(&encode(&[cmd(&["SET", "c", "3"])])[..12]);
+        std::fs::write(&path, &data).unwrap();
+
+        let db = Db::new(4);
+        assert_eq!(load(&path, &db).unwrap(), 2);
+        assert_eq!(db.get("b"), Some(Value::String("2".into())));
+        assert_eq!(db.get("c"), None);
+
+        // The partial command is gone from the file.
+        assert_eq!(std::fs::read(&path).unwrap(), complete);
+    }
+
+    #[tokio::test]
+    async fn rejects_garbage_before_the_end() {
+        let path = temp_path("corrupt.aof");
+        let mut data = encode(&[cmd(&["SET", "a", "1"])]);
+        data.extend_from_slice(b"hello\r\n");
+        data.extend_from_slice(&encode(&[cmd(&["SET", "b", "2"])]));
+        std::fs::write(&path, &data).unwrap();
+
+        let err = load(&path, &Db::new(4)).unwrap_err();
+        assert!(err.to_string().contains("at byte 27"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn always_syncs_before_acknowledging() {
+        let path = temp_path("always.aof");
+        let aof = Aof::open(&path, Fsync::Always).unwrap();
+
+        let cmds = [cmd(&["SET", "a", "1"]), cmd(&["SET", "b", "2"])];
+        aof.append(&cmds).unwrap().wait().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), encode(&cmds));
+
+        // Other policies do not wait.
+        let aof = Aof::open(&temp_path("no.aof"), Fsync::No).unwrap();
+        assert!(aof.append(&cmds).is_none());
+    }
+
+    #[tokio::test]
+    async fn rewritten_commands_rebuild_the_keyspace() {
+        let db = Db::new(4);
+        let mut zset = crate::zset::ZSet::new();
+        zset.insert("low".into(), f64::NEG_INFINITY);
+        zset.insert("mid".into(), 0.1 + 0.2);
+        let hash = (0..100)
+            .map(|i| (Bytes::from(format!("f{}", i)), Bytes::from(i.to_string())))
+            .collect();
+        db.insert("s".into(), Value::String("v".into()), None);
+        db.insert("z".into(), Value::ZSet(zset), None);
+        db.insert(
+            "h".into(),
+            Value::Hash(hash),
+            Some(Duration::from_secs(100)),
+        );
+
+        let cmds = rewrite_commands(&db.snapshot(), SystemTime::now());
+        // Two `HSET`s for the hundred fields, and the deadline.
+        assert_eq!(cmds.len(), 5);
+
+        let path = temp_path("rewritten.aof");
+        std::fs::write(&path, encode(&cmds)).unwrap();
+        let restored = Db::new(4);
+        load(&path, &restored).unwrap();
+
+        assert_eq!(restored.snapshot().len(), 3);
+        for key in ["s", "z", "h"] {
+            assert_eq!(restored.get(key), db.get(key), "{}", key);
+        }
+        assert!(restored.ttl("h").unwrap().unwrap() > Duration::from_secs(95));
+    }
+
+    #[tokio::test]
+    async fn rewrite_keeps_writes_made_meanwhile() {
+        let path = temp_path("rewrite.aof");
+        let db = Db::new(4);
+        db.set_aof(Some(Aof::open(&path, Fsync::Always).unwrap()));
+
+        let set = |value: &str| {
+            let cmd = cmd(&["SET", "k", value]);
+            let db = db.clone();
+            async move { cmd::execute(&cmd, &db, None).await }
+        };
+        for i in 0..100 {
+            set(&i.to_string()).await;
+        }
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        // The write lands after the copy, while the file is being written.
+        let rewrite = tokio::spawn({
+            let db = db.clone();
+            async move { rewrite(&db).await }
+        });
+        tokio::task::yield_now().await;
+        set("last").await;
+        rewrite.await.unwrap().unwrap();
+
+        assert!(std::fs::metadata(&path).unwrap().len() < before);
+        let restored = Db::new(4);
+        load(&path, &restored).unwrap();
+        assert_eq!(restored.get("k"), Some(Value::String("last".into())));
+
+        // Appends go on to the new file.
+        set("after").await;
+        let restored = Db::new(4);
+        load(&path, &restored).unwrap();
+        assert_eq!(restored.get("k"), Some(Value::String("after".into())));
+    }
+
+    #[test]
+    fn parses_fsync_policies() {
+        assert_eq!("always".parse(), Ok(Fsync::Always));
+        assert_eq!("EVERYSEC".parse(), Ok(Fsync::EverySec));
+        assert_eq!("no".parse(), Ok(Fsync::No));
+        assert!("sometimes".parse::<Fsync>().is_err());
+        assert_eq!(Fsync::EverySec.to_string(), "everysec");
+    }
+}
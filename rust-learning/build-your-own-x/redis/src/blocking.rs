@@ -0,0 +1,78 @@
+//! Support for commands that park a client until a key can serve it.
+//!
+//! A blocked client registers the same `Waiter` under every key it watches,
+//! in the shard holding that key. Whoever next adds data to one of those keys
+//! takes the oldest waiter registered there and hands the data over
+//! directly, so clients are served in the order they blocked and no other
+//! connection can grab the element in between.
+//!
+//! Stream readers are simpler: reading does not consume entries, so one new
+//! entry may serve them all. They register a plain `Notify` on their keys
+//! instead (see `ShardGuard::block_reader`), which every new entry wakes up
+//! to read again.
+
+use bytes::Bytes;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Which end of a list a command works on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+/// A client blocked on one or more keys.
+#[derive(Debug)]
+pub struct Waiter {
+    /// The end of the list the client pops from.
+    end: End,
+
+    /// Taken by whoever serves the client, or by the client itself when it
+    /// gives up. Only one of them can succeed.
+    tx: Mutex<Option<oneshot::Sender<(String, Bytes)>>>,
+}
+
+impl Waiter {
+    /// Create a waiter popping from `end`, along with the receiving half the
+    /// blocked client waits on.
+    pub fn new(end: End) -> (Arc<Waiter>, oneshot::Receiver<(String, Bytes)>) {
+        let (tx, rx) = oneshot::channel();
+        let waiter = Waiter {
+            end,
+            tx: Mutex::new(Some(tx)),
+        };
+        (Arc::new(waiter), rx)
+    }
+
+    pub fn end(&self) -> End {
+        self.end
+    }
+
+    /// Returns `true` once the waiter has been served or has given up.
+    pub fn is_done(&self) -> bool {
+        self.tx.lock().unwrap().is_none()
+    }
+
+    /// Hand `value`, popped from `key`, to the blocked client.
+    ///
+    /// Gives `value` back if the client already got something else, gave up
+    /// or disconnected; the caller should put it back where it came from.
+    pub fn serve(&self, key: &str, value: Bytes) -> Result<(), Bytes> {
+        // Sending while holding the lock means a client that fails to
+        // `claim` its waiter is guaranteed to find the value in the channel.
+        let mut tx = self.tx.lock().unwrap();
+        match tx.take() {
+            Some(sender) => sender
+                .send((key.to_string(), value))
+                .map_err(|(_, value)| value),
+            None => Err(value),
+        }
+    }
+
+    /// Claim the waiter for the blocked client itself, so nobody else can
+    /// serve it. Returns `false` if it was already served.
+    pub fn claim(&self) -> bool {
+        self.tx.lock().unwrap().take().is_some()
+    }
+}
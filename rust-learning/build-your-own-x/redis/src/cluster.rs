@@ -0,0 +1,659 @@
+//! Cluster mode: several servers sharing one keyspace.
+//!
+//! Keys are spread over `SLOTS` hash slots. A key's slot is the CRC16 of its
+//! name, or of the part between its first `{` and the next `}` when that is
+//! not empty, so `{user:1}:name` and `{user:1}:email` land together. Each
+//! slot is owned by one node, which alone serves its keys: another node asked
+//! about one replies `-MOVED slot host:port`, pointing the client at the
+//! owner, and commands whose keys span several slots are refused.
+//!
+//! Nodes learn about each other by gossip, over the port clients use. They
+//! are introduced with `CLUSTER MEET`; from then on each node regularly sends
+//! every node it knows its view of the cluster, as `CLUSTER NODES` prints it,
+//! with `CLUSTER GOSSIP`, and gets that node's view back. Views spread the
+//! nodes each one met and the slots each one claims. Rival claims on a slot
+//! are settled by config epoch: a node taking a slot over bumps its own past
+//! every epoch it has seen, so its claim wins everywhere.
+//!
+//! A slot moves the way it does in Redis: mark it `IMPORTING` on the target
+//! and `MIGRATING` on the source, `MIGRATE` its keys, then assign it with
+//! `CLUSTER SETSLOT slot NODE target` on the target, then on the source.
+//! Meanwhile the source serves the keys it still has and answers
+//! `-ASK slot host:port` for the others, which the target serves to clients
+//! that sent `ASKING` just before.
+//!
+//! There is no failover: a node that goes away takes its slots with it. Nor
+//! is the state saved; a restarted node starts out alone, with a new id.
+
+use crate::{Command, Connection, Db, Frame, replication};
+
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// Number of hash slots.
+pub const SLOTS: usize = 16384;
+
+/// How often a node gossips with every other node.
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a node gets to answer before it is deemed unreachable, until
+/// the next round.
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The state of the cluster as one node sees it.
+#[derive(Debug)]
+pub struct Cluster {
+    /// The id of this node.
+    myself: String,
+
+    /// Every node known, this one included, by id.
+    nodes: BTreeMap<String, Node>,
+
+    /// The id of the owner of each slot, if any.
+    slots: Vec<Option<String>>,
+
+    /// Slots being moved away, to the id of the node they go to.
+    migrating: BTreeMap<u16, String>,
+
+    /// Slots being moved here, from the id of the node they come from.
+    importing: BTreeMap<u16, String>,
+
+    /// The largest config epoch seen.
+    current_epoch: u64,
+}
+
+/// A node of the cluster.
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// Where clients reach it.
+    pub host: String,
+    pub port: u16,
+
+    /// Orders its claims on slots against other nodes'.
+    pub epoch: u64,
+
+    /// Whether the last gossip with it went through.
+    connected: bool,
+}
+
+/// A node as another node's view describes it.
+#[derive(Debug, PartialEq)]
+struct Gossip {
+    id: String,
+    host: String,
+    port: u16,
+
+    /// The node the view is from.
+    myself: bool,
+    epoch: u64,
+
+    /// Ranges of the slots it owns, both ends included.
+    slots: Vec<(u16, u16)>,
+}
+
+impl Cluster {
+    /// A cluster of one node, reached at `host` and `port`, owning no slots
+    /// yet.
+    pub fn new(host: &str, port: u16) -> Cluster {
+        let myself = new_node_id(port);
+        let node = Node {
+            host: host.to_string(),
+            port,
+            epoch: 0,
+            connected: true,
+        };
+        Cluster {
+            nodes: BTreeMap::from([(myself.clone(), node)]),
+            myself,
+            slots: vec![None; SLOTS],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            current_epoch: 0,
+        }
+    }
+
+    /// The id of this node.
+    pub fn myself(&self) -> &str {
+        &self.myself
+    }
+
+    /// A known node, by id.
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    /// The id of the node owning `slot`, if any.
+    pub fn owner(&self, slot: u16) -> Option<&str> {
+        self.slots[slot as usize].as_deref()
+    }
+
+    /// Whether every slot has an owner, so every key can be served.
+    pub fn is_ok(&self) -> bool {
+        self.slots.iter().all(Option::is_some)
+    }
+
+    /// `cluster_*` fields, as `CLUSTER INFO` lists them.
+    pub fn info(&self) -> String {
+        let assigned = self.slots.iter().filter(|owner| owner.is_some()).count();
+        let owners = self
+            .nodes
+            .keys()
+            .filter(|id| self.slots.iter().any(|owner| owner.as_ref() == Some(id)))
+            .count();
+        let state = if self.is_ok() { "ok" } else { "fail" };
+
+        let mut info = String::new();
+        let _ = write!(
+            info,
+            "cluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_slots_ok:{}\r\n\
+             cluster_slots_pfail:0\r\ncluster_slots_fail:0\r\ncluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\ncluster_current_epoch:{}\r\ncluster_my_epoch:{}\r\n",
+            state,
+            assigned,
+            assigned,
+            self.nodes.len(),
+            owners,
+            self.current_epoch,
+            self.nodes[&self.myself].epoch,
+        );
+        info
+    }
+
+    /// Runs of consecutive slots with the same owner, in order, with the id
+    /// of that owner.
+    pub fn ranges(&self) -> Vec<(u16, u16, &str)> {
+        let mut ranges: Vec<(u16, u16, &str)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let Some(owner) = owner.as_deref() else {
+                continue;
+            };
+            match ranges.last_mut() {
+                Some((_, end, id)) if *id == owner && *end as usize + 1 == slot => {
+                    *end = slot as u16;
+                }
+                _ => ranges.push((slot as u16, slot as u16, owner)),
+            }
+        }
+        ranges
+    }
+
+    /// Make this node the owner of `slot`, which must have none.
+    pub fn add_slot(&mut self, slot: u16) -> crate::Result<()> {
+        if self.slots[slot as usize].is_some() {
+            return Err(format!("ERR Slot {} is already busy", slot).into());
+        }
+        self.slots[slot as usize] = Some(self.myself.clone());
+        Ok(())
+    }
+
+    /// Forget who owns `slot`, which must have an owner.
+    pub fn del_slot(&mut self, slot: u16) -> crate::Result<()> {
+        if self.slots[slot as usize].take().is_none() {
+            return Err(format!("ERR Slot {} is already unassigned", slot).into());
+        }
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+        Ok(())
+    }
+
+    /// Mark `slot`, owned here, as moving to the node `id`.
+    pub fn set_migrating(&mut self, slot: u16, id: &str) -> crate::Result<()> {
+        if self.owner(slot) != Some(self.myself.as_str()) {
+            return Err(format!("ERR I'm not the owner of hash slot {}", slot).into());
+        }
+        self.known_other(id)?;
+        self.migrating.insert(slot, id.to_string());
+        Ok(())
+    }
+
+    /// Mark `slot` as moving here from the node `id`.
+    pub fn set_importing(&mut self, slot: u16, id: &str) -> crate::Result<()> {
+        if self.owner(slot) == Some(self.myself.as_str()) {
+            return Err(format!("ERR I'm already the owner of hash slot {}", slot).into());
+        }
+        self.known_other(id)?;
+        self.importing.insert(slot, id.to_string());
+        Ok(())
+    }
+
+    /// Stop moving `slot`, either way.
+    pub fn set_stable(&mut self, slot: u16) {
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+
+    /// Assign `slot` to the node `id`, ending its move.
+    ///
+    /// A node taking a slot over bumps its epoch, so its claim wins over
+    /// that of the previous owner across the cluster. One giving a slot
+    /// away must not hold any of its keys anymore, which the caller checks.
+    pub fn set_node(&mut self, slot: u16, id: &str) -> crate::Result<()> {
+        if !self.nodes.contains_key(id) {
+            return Err(format!("ERR Unknown node {}", id).into());
+        }
+
+        let previous = self.slots[slot as usize].replace(id.to_string());
+        self.set_stable(slot);
+        if id == self.myself && previous.as_deref() != Some(id) {
+            self.current_epoch += 1;
+            let epoch = self.current_epoch;
+            self.nodes.get_mut(&self.myself).unwrap().epoch = epoch;
+        }
+        Ok(())
+    }
+
+    fn known_other(&self, id: &str) -> crate::Result<()> {
+        if id == self.myself {
+            return Err("ERR Can't move a slot to or from myself".into());
+        }
+        if !self.nodes.contains_key(id) {
+            return Err(format!("ERR I don't know about node {}", id).into());
+        }
+        Ok(())
+    }
+
+    /// The other nodes, with where to reach them.
+    pub fn peers(&self) -> Vec<(String, String, u16)> {
+        self.nodes
+            .iter()
+            .filter(|(id, _)| **id != self.myself)
+            .map(|(id, node)| (id.clone(), node.host.clone(), node.port))
+            .collect()
+    }
+
+    /// Record whether the last gossip with the node `id` went through.
+    pub fn set_connected(&mut self, id: &str, connected: bool) {
+        if let Some(node) = self.nodes.get_mut(id) {
+            node.connected = connected;
+        }
+    }
+
+    /// The view of this node, in the `CLUSTER NODES` format: a line per
+    /// node, with its id, address, flags, primary, last ping and pong, epoch,
+    /// link state and slots.
+    ///
+    /// There is no separate cluster bus, so the bus port is the client port.
+    /// Slots moving in or out of this node follow its own slots, as
+    /// `[slot->-id]` and `[slot-<-id]`.
+    pub fn nodes(&self) -> String {
+        let mut slots: HashMap<&str, String> = HashMap::new();
+        for (start, end, id) in self.ranges() {
+            let list = slots.entry(id).or_default();
+            let _ = if start == end {
+                write!(list, " {}", start)
+            } else {
+                write!(list, " {}-{}", start, end)
+            };
+        }
+
+        let mut out = String::new();
+        for (id, node) in &self.nodes {
+            let myself = *id == self.myself;
+            let _ = write!(
+                out,
+                "{} {}:{}@{} {} - 0 0 {} {}{}",
+                id,
+                node.host,
+                node.port,
+                node.port,
+                if myself { "myself,master" } else { "master" },
+                node.epoch,
+                if node.connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                },
+                slots.get(id.as_str()).map_or("", String::as_str),
+            );
+            if myself {
+                for (slot, to) in &self.migrating {
+                    let _ = write!(out, " [{}->-{}]", slot, to);
+                }
+                for (slot, from) in &self.importing {
+                    let _ = write!(out, " [{}-<-{}]", slot, from);
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Take in the view of another node, as its `nodes` printed it.
+    ///
+    /// Nodes it knows are added, and the slots it claims taken over from
+    /// owners with a lower epoch. Other nodes' claims are left for them to
+    /// make: this node will hear from them too.
+    pub fn merge(&mut self, view: &str) -> crate::Result<()> {
+        let gossip = parse_view(view)?;
+        let sender = gossip
+            .iter()
+            .find(|node| node.myself)
+            .ok_or("ERR no node speaks for this view")?;
+        if sender.id == self.myself {
+            return Ok(());
+        }
+
+        for node in &gossip {
+            if node.id != self.myself && !self.nodes.contains_key(&node.id) {
+                let new = Node {
+                    host: node.host.clone(),
+                    port: node.port,
+                    epoch: node.epoch,
+                    connected: false,
+                };
+                self.nodes.insert(node.id.clone(), new);
+            }
+        }
+
+        let node = self.nodes.get_mut(&sender.id).unwrap();
+        node.host.clone_from(&sender.host);
+        node.port = sender.port;
+        node.epoch = node.epoch.max(sender.epoch);
+        node.connected = true;
+        self.current_epoch = self.current_epoch.max(sender.epoch);
+
+        for &(start, end) in &sender.slots {
+            for slot in start..=end {
+                let owner = self.slots[slot as usize].clone();
+                if owner.as_ref() == Some(&sender.id) {
+                    continue;
+                }
+                let owner_epoch = owner.as_ref().and_then(|id| self.nodes.get(id));
+                if owner_epoch.is_none_or(|owner| owner.epoch < sender.epoch) {
+                    if owner.as_ref() == Some(&self.myself) {
+                        self.migrating.remove(&slot);
+                    }
+                    self.slots[slot as usize] = Some(sender.id.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The hash slot of `key`.
+pub fn key_slot(key: &[u8]) -> u16 {
+    // Only the hash tag counts, if there is a non-empty one.
+    let tag = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        let close = rest.iter().position(|&b| b == b'}')?;
+        (close > 0).then(|| &rest[..close])
+    });
+    crc16(tag.unwrap_or(key)) % SLOTS as u16
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, no reflection, starting from zero.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Check that `cmd` may run on this node, `asking` if the client sent
+/// `ASKING` just before. Otherwise, returns the redirection to reply with.
+///
+/// Commands naming no key run anywhere, as does everything outside cluster
+/// mode.
+pub(crate) fn route(db: &Db, cmd: &Command, asking: bool) -> crate::Result<()> {
+    let cluster = db.cluster();
+    let Some(cluster) = cluster.as_ref() else {
+        return Ok(());
+    };
+    let keys = cmd.keys();
+    let Some(first) = keys.first() else {
+        return Ok(());
+    };
+    let slot = key_slot(first);
+    if keys.iter().any(|key| key_slot(key) != slot) {
+        return Err("CROSSSLOT Keys in request don't hash to the same slot".into());
+    }
+
+    let redirect = |kind: &str, id: &str| -> crate::Error {
+        let node = &cluster.nodes[id];
+        format!("{} {} {}:{}", kind, slot, node.host, node.port).into()
+    };
+    match cluster.owner(slot) {
+        Some(owner) if owner == cluster.myself => {
+            // Keys already moved are to be found where the slot is going,
+            // except by `MIGRATE`, which only has to find nothing left.
+            let Some(target) = cluster.migrating.get(&slot) else {
+                return Ok(());
+            };
+            if cmd.name() == "migrate" {
+                return Ok(());
+            }
+            let missing = keys
+                .iter()
+                .map(|key| String::from_utf8_lossy(key))
+                .filter(|key| db.lock(key).get(key).is_none())
+                .count();
+            match missing {
+                0 => Ok(()),
+                n if n == keys.len() => Err(redirect("ASK", target)),
+                _ => Err("TRYAGAIN Multiple keys request during rehashing of slot".into()),
+            }
+        }
+        _ if (asking || cmd.name() == "restore-asking")
+            && cluster.importing.contains_key(&slot) =>
+        {
+            Ok(())
+        }
+        Some(owner) => Err(redirect("MOVED", owner)),
+        None => Err("CLUSTERDOWN Hash slot not served".into()),
+    }
+}
+
+/// Introduce the node at `host` and `port` to this one: exchange views with
+/// it once, after which both gossip with each other.
+pub async fn meet(db: Db, host: String, port: u16) {
+    let res = time::timeout(GOSSIP_TIMEOUT, async {
+        let mut conn = connect(&db, &host, port).await?;
+        exchange(&db, &mut conn).await
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => eprintln!("cluster: can't meet {}:{}: {}", host, port, err),
+        Err(_) => eprintln!("cluster: can't meet {}:{}: timed out", host, port),
+    }
+}
+
+/// Gossip with every known node, every `GOSSIP_INTERVAL`, for as long as
+/// the server runs.
+pub async fn gossip(db: Db) {
+    let mut links: HashMap<String, Connection> = HashMap::new();
+    let mut ticks = time::interval(GOSSIP_INTERVAL);
+
+    loop {
+        ticks.tick().await;
+        let peers = match db.cluster().as_ref() {
+            Some(cluster) => cluster.peers(),
+            None => return,
+        };
+
+        for (id, host, port) in peers {
+            let res = time::timeout(GOSSIP_TIMEOUT, async {
+                let mut conn = match links.remove(&id) {
+                    Some(conn) => conn,
+                    None => connect(&db, &host, port).await?,
+                };
+                exchange(&db, &mut conn).await?;
+                links.insert(id.clone(), conn);
+                Ok::<_, crate::Error>(())
+            })
+            .await;
+
+            if !matches!(res, Ok(Ok(()))) {
+                links.remove(&id);
+                if let Some(cluster) = db.cluster_mut().as_mut() {
+                    cluster.set_connected(&id, false);
+                }
+            }
+        }
+    }
+}
+
+/// Connect to another node, logged in like a replica would.
+async fn connect(db: &Db, host: &str, port: u16) -> crate::Result<Connection> {
+    let socket = TcpStream::connect((host, port)).await?;
+    let mut conn = Connection::new(socket);
+    replication::login(db, &mut conn).await?;
+    Ok(conn)
+}
+
+/// Send this node's view over `conn` and take in the one that comes back.
+async fn exchange(db: &Db, conn: &mut Connection) -> crate::Result<()> {
+    let view = match db.cluster().as_ref() {
+        Some(cluster) => cluster.nodes(),
+        None => return Ok(()),
+    };
+    let request = Command::new(
+        "cluster",
+        vec![Bytes::from_static(b"GOSSIP"), Bytes::from(view)],
+    );
+    conn.write_frame(&request.to_frame()).await?;
+    conn.flush().await?;
+
+    let view = match conn.read_frame().await? {
+        Some(Frame::Bulk(view)) => view,
+        Some(Frame::Error(err)) => return Err(err.into()),
+        frame => return Err(format!("unexpected reply {:?}", frame).into()),
+    };
+    let view = std::str::from_utf8(&view).map_err(|_| "gossip is not UTF-8")?;
+    if let Some(cluster) = db.cluster_mut().as_mut() {
+        cluster.merge(view)?;
+    }
+    Ok(())
+}
+
+/// Parse a view in the `CLUSTER NODES` format.
+fn parse_view(view: &str) -> crate::Result<Vec<Gossip>> {
+    let mut nodes = Vec::new();
+    for line in view.lines().filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let invalid = || format!("ERR invalid node line '{}'", line);
+        if fields.len() < 8 {
+            return Err(invalid().into());
+        }
+
+        let addr = fields[1].split('@').next().unwrap_or_default();
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+        let mut slots = Vec::new();
+        // Slots on the move, in brackets, are only for people to read.
+        for range in fields[8..].iter().filter(|s| !s.starts_with('[')) {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let start: u16 = start.parse().map_err(|_| invalid())?;
+            let end: u16 = end.parse().map_err(|_| invalid())?;
+            if start > end || end as usize >= SLOTS {
+                return Err(invalid().into());
+            }
+            slots.push((start, end));
+        }
+
+        nodes.push(Gossip {
+            id: fields[0].to_string(),
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+            myself: fields[2].split(',').any(|flag| flag == "myself"),
+            epoch: fields[6].parse().map_err(|_| invalid())?,
+            slots,
+        });
+    }
+    Ok(nodes)
+}
+
+/// A fresh node id: 40 hex characters, unique to this process, port and
+/// moment.
+fn new_node_id(port: u16) -> String {
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seed = format!("{}-{}-{}", std::process::id(), port, now.as_nanos());
+    sha1_smol::Sha1::from(seed).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_match_redis() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+
+        // Only a non-empty tag counts.
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % 16384);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+    }
+
+    #[test]
+    fn views_spread_nodes_and_slots() {
+        let mut a = Cluster::new("127.0.0.1", 7000);
+        let mut b = Cluster::new("127.0.0.1", 7001);
+        for slot in 0..100 {
+            a.add_slot(slot).unwrap();
+        }
+        b.add_slot(100).unwrap();
+        b.set_importing(5, a.myself()).unwrap_err();
+
+        a.merge(&b.nodes()).unwrap();
+        b.merge(&a.nodes()).unwrap();
+        assert_eq!(b.owner(42), Some(a.myself()));
+        assert_eq!(a.owner(100), Some(b.myself()));
+        assert_eq!(a.ranges(), [(0, 99, a.myself()), (100, 100, b.myself())]);
+
+        // Taking slot 5 over: the new owner's epoch wins at the old one.
+        a.set_migrating(5, b.myself()).unwrap();
+        b.set_importing(5, a.myself()).unwrap();
+        assert!(a.nodes().contains(&format!("[5->-{}]", b.myself())));
+        let id = b.myself().to_string();
+        b.set_node(5, &id).unwrap();
+        a.merge(&b.nodes()).unwrap();
+        assert_eq!(a.owner(5), Some(b.myself()));
+        assert!(a.migrating.is_empty());
+        assert_eq!(a.owner(4), Some(a.myself()));
+
+        // A stale claim from the old owner doesn't take it back.
+        let mut stale = Cluster::new("127.0.0.1", 7002);
+        stale.merge(&b.nodes()).unwrap();
+        stale.merge(&a.nodes()).unwrap();
+        assert_eq!(stale.owner(5), Some(b.myself()));
+        assert_eq!(stale.peers().len(), 2);
+    }
+
+    #[test]
+    fn bad_views_are_refused() {
+        let mut cluster = Cluster::new("127.0.0.1", 7000);
+        assert!(cluster.merge("garbage\n").is_err());
+        let id = "a".repeat(40);
+        let line = format!("{} 127.0.0.1:7001@7001 master - 0 0 0 connected 0-99\n", id);
+        // Nobody in it says "myself".
+        assert!(cluster.merge(&line).is_err());
+        assert!(
+            cluster
+                .merge(
+                    &line
+                        .replace("master", "myself,master")
+                        .replace("0-99", "99-0")
+                )
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! `AUTH` and `ACL`: logging in, and managing the users one logs in as.
+
+use crate::acl::{self, CATEGORIES, DEFAULT_USER};
+use crate::parse::Parse;
+use crate::{Db, Frame};
+
+use bytes::Bytes;
+
+/// `AUTH [username] password`: log the connection in as `username`, or as
+/// `default` if not given.
+pub fn auth(db: &Db, user: &mut Option<String>, parse: &mut Parse) -> crate::Result<Frame> {
+    let first = parse.next_bytes()?;
+    let (name, password) = match parse.remaining() {
+        0 => {
+            if db.acl().user(DEFAULT_USER).is_some_and(|u| u.is_nopass()) {
+                return Err("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".into());
+            }
+            (DEFAULT_USER.to_string(), first)
+        }
+        _ => (
+            String::from_utf8_lossy(&first).into_owned(),
+            parse.next_bytes()?,
+        ),
+    };
+    parse.finish()?;
+
+    login(db, user, &name, &password)?;
+    Ok(Frame::ok())
+}
+
+/// Log the connection in as `name`, if `password` is right; `user` is left
+/// alone otherwise.
+pub(crate) fn login(
+    db: &Db,
+    user: &mut Option<String>,
+    name: &str,
+    password: &[u8],
+) -> crate::Result<()> {
+    db.acl().authenticate(name, password)?;
+    *user = Some(name.to_string());
+    Ok(())
+}
+
+/// `ACL SETUSER username [rule ...]`, `ACL GETUSER username`,
+/// `ACL DELUSER username [username ...]`, `ACL LIST`, `ACL WHOAMI` and
+/// `ACL CAT [category]`.
+///
+/// `user` is who the connection is logged in as.
+pub fn acl(db: &Db, user: Option<&str>, parse: &mut Parse) -> crate::Result<Frame> {
+    let subcommand = parse.next_string()?.to_ascii_lowercase();
+
+    match subcommand.as_str() {
+        "setuser" => {
+            let name = parse.next_string()?;
+            let mut rules = Vec::new();
+            while parse.remaining() > 0 {
+                rules.push(parse.next_string()?);
+            }
+
+            db.acl_mut().set_user(&name, &rules)?;
+            Ok(Frame::ok())
+        }
+        "getuser" => {
+            let name = parse.next_string()?;
+            parse.finish()?;
+
+            let Some(user) = db.acl().user(&name) else {
+                return Ok(Frame::Null);
+            };
+            let flags = user.flags().into_iter().map(bulk).collect();
+            let passwords = user.passwords().map(bulk).collect();
+            Ok(Frame::Map(vec![
+                (bulk("flags"), Frame::Array(flags)),
+                (bulk("passwords"), Frame::Array(passwords)),
+                (bulk("commands"), bulk(&user.commands())),
+                (bulk("keys"), bulk(&user.keys())),
+            ]))
+        }
+        "deluser" => {
+            let mut names = vec![parse.next_string()?];
+            while parse.remaining() > 0 {
+                names.push(parse.next_string()?);
+            }
+
+            let mut acl = db.acl_mut();
+            let mut removed = 0;
+            for name in names {
+                removed += acl.del_user(&name)? as i64;
+            }
+            Ok(Frame::Integer(removed))
+        }
+        "list" => {
+            parse.finish()?;
+            let users = db.acl().users().map(|u| bulk(&u.describe())).collect();
+            Ok(Frame::Array(users))
+        }
+        "whoami" => {
+            parse.finish()?;
+            Ok(bulk(user.ok_or(acl::NOAUTH)?))
+        }
+        "cat" => {
+            let names = match parse.remaining() {
+                0 => CATEGORIES.to_vec(),
+                _ => {
+                    let category = parse.next_string()?.to_ascii_lowercase();
+                    acl::category_commands(&category)
+                        .ok_or_else(|| format!("ERR Unknown category '{}'", category))?
+                }
+            };
+            parse.finish()?;
+            Ok(Frame::Array(names.into_iter().map(bulk).collect()))
+        }
+        _ => Err(format!(
+            "ERR unknown subcommand '{}'. Try ACL SETUSER, GETUSER, DELUSER, LIST, WHOAMI or CAT.",
+            subcommand
+        )
+        .into()),
+    }
+}
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+}
@@ -0,0 +1,350 @@
+//! `CLUSTER`, `ASKING` and `MIGRATE`: running and resharding a cluster.
+
+use crate::cluster::{self, Cluster, SLOTS, key_slot};
+use crate::parse::Parse;
+use crate::{Command, Connection, Db, Frame, cmd};
+
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// Error replied to cluster commands outside cluster mode.
+const DISABLED: &str = "ERR This instance has cluster support disabled";
+
+/// `CLUSTER subcommand [arg ...]`:
+///
+/// * `MYID`, `INFO`, `NODES` and `SLOTS` describe the cluster;
+/// * `KEYSLOT key`, `COUNTKEYSINSLOT slot` and `GETKEYSINSLOT slot count`
+///   relate keys and slots;
+/// * `MEET host port` introduces another node;
+/// * `ADDSLOTS slot [slot ...]`, `ADDSLOTSRANGE start end [start end ...]`
+///   and `DELSLOTS slot [slot ...]` assign slots to this node or unassign
+///   them;
+/// * `SETSLOT slot IMPORTING|MIGRATING|NODE id` and `SETSLOT slot STABLE`
+///   move a slot between nodes;
+/// * `GOSSIP view` is how nodes exchange views, see `cluster`.
+pub fn cluster(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let subcommand = parse.next_string()?.to_ascii_lowercase();
+    if db.cluster().is_none() {
+        return Err(DISABLED.into());
+    }
+
+    match subcommand.as_str() {
+        "myid" => {
+            parse.finish()?;
+            Ok(bulk(read(db, |c| c.myself().to_string())))
+        }
+        "info" => {
+            parse.finish()?;
+            Ok(bulk(read(db, Cluster::info)))
+        }
+        "nodes" => {
+            parse.finish()?;
+            Ok(bulk(read(db, Cluster::nodes)))
+        }
+        "slots" => {
+            parse.finish()?;
+            Ok(Frame::Array(read(db, slots)))
+        }
+        "keyslot" => {
+            let key = parse.next_bytes()?;
+            parse.finish()?;
+            Ok(Frame::Integer(key_slot(&key) as i64))
+        }
+        "countkeysinslot" => {
+            let slot = next_slot(parse)?;
+            parse.finish()?;
+            Ok(Frame::Integer(
+                keys_in_slot(db, slot, usize::MAX).len() as i64
+            ))
+        }
+        "getkeysinslot" => {
+            let slot = next_slot(parse)?;
+            let count =
+                usize::try_from(parse.next_int()?).map_err(|_| "ERR Invalid number of keys")?;
+            parse.finish()?;
+            let keys = keys_in_slot(db, slot, count);
+            Ok(Frame::Array(keys.into_iter().map(bulk).collect()))
+        }
+        "meet" => {
+            let host = parse.next_string()?;
+            let port = parse
+                .next_string()?
+                .parse()
+                .map_err(|_| "ERR Invalid node address specified")?;
+            parse.finish()?;
+            // The other node answers like any client would, so not from
+            // within a command.
+            tokio::spawn(cluster::meet(db.clone(), host, port));
+            Ok(Frame::ok())
+        }
+        "addslots" | "delslots" => {
+            let mut slots = vec![next_slot(parse)?];
+            while parse.remaining() > 0 {
+                slots.push(next_slot(parse)?);
+            }
+            update_slots(db, &subcommand, &slots)
+        }
+        "addslotsrange" => {
+            let mut slots = Vec::new();
+            loop {
+                let (start, end) = (next_slot(parse)?, next_slot(parse)?);
+                if start > end {
+                    return Err("ERR start slot number is greater than end slot number".into());
+                }
+                slots.extend(start..=end);
+                if parse.remaining() == 0 {
+                    break;
+                }
+            }
+            update_slots(db, "addslots", &slots)
+        }
+        "setslot" => {
+            let slot = next_slot(parse)?;
+            let state = parse.next_string()?.to_ascii_lowercase();
+            let id = match state.as_str() {
+                "stable" => None,
+                _ => Some(parse.next_string()?),
+            };
+            parse.finish()?;
+            set_slot(db, slot, &state, id.as_deref())
+        }
+        "gossip" => {
+            let view = parse.next_string()?;
+            parse.finish()?;
+            let mut cluster = db.cluster_mut();
+            let cluster = cluster.as_mut().ok_or(DISABLED)?;
+            cluster.merge(&view)?;
+            Ok(bulk(cluster.nodes()))
+        }
+        _ => Err(format!("ERR unknown subcommand '{}'. Try CLUSTER HELP.", subcommand).into()),
+    }
+}
+
+/// `ASKING`: let the next command touch a slot being imported here.
+pub fn asking(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    parse.finish()?;
+    if db.cluster().is_none() {
+        return Err(DISABLED.into());
+    }
+    Ok(Frame::ok())
+}
+
+/// `MIGRATE host port key|"" db timeout [COPY] [REPLACE] [AUTH password |
+/// AUTH2 username password] [KEYS key ...]`: move keys to another server.
+///
+/// Each key is `DUMP`ed and sent with `RESTORE-ASKING`, which the target
+/// takes even for a slot it is only importing, then deleted here unless
+/// `COPY` is given. Replies `NOKEY` if none of the keys exist. `timeout` is
+/// in milliseconds, for the whole transfer.
+pub async fn migrate(db: &Db, cmd: &Command) -> crate::Result<Frame> {
+    let mut parse = cmd.parse();
+    let host = parse.next_string()?;
+    let port: u16 = parse
+        .next_string()?
+        .parse()
+        .map_err(|_| "ERR Invalid port")?;
+    let key = parse.next_string()?;
+    if parse.next_int()? != 0 {
+        return Err("ERR only database 0 exists".into());
+    }
+    let timeout = parse.next_int()?.max(1) as u64;
+
+    let (mut copy, mut replace, mut auth) = (false, false, Vec::new());
+    let mut keys = vec![key.clone()];
+    while parse.remaining() > 0 {
+        match parse.next_string()?.to_ascii_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "AUTH" => auth = vec![parse.next_string()?],
+            "AUTH2" => auth = vec![parse.next_string()?, parse.next_string()?],
+            "KEYS" if key.is_empty() => {
+                keys.clear();
+                while parse.remaining() > 0 {
+                    keys.push(parse.next_string()?);
+                }
+            }
+            _ => return Err("ERR syntax error".into()),
+        }
+    }
+
+    // What there is to send, taken now: whatever happens to the keys later
+    // is for the target to hear about from clients.
+    let mut restores = Vec::new();
+    for key in &keys {
+        let Frame::Bulk(payload) = cmd::apply(&command("dump", &[key]), db) else {
+            continue;
+        };
+        let ttl = match db.ttl(key) {
+            Some(Some(ttl)) => ttl.as_millis().max(1),
+            _ => 0,
+        };
+        let mut args = vec![
+            Bytes::from(key.clone()),
+            Bytes::from(ttl.to_string()),
+            payload,
+        ];
+        if replace {
+            args.push(Bytes::from_static(b"REPLACE"));
+        }
+        restores.push(Command::new("restore-asking", args));
+    }
+    if restores.is_empty() {
+        return Ok(Frame::Simple("NOKEY".to_string()));
+    }
+
+    let sent = time::timeout(Duration::from_millis(timeout), async {
+        let socket = TcpStream::connect((host.as_str(), port)).await?;
+        let mut conn = Connection::new(socket);
+        if !auth.is_empty() {
+            let auth: Vec<&str> = auth.iter().map(String::as_str).collect();
+            send(&mut conn, &[command("auth", &auth)]).await?;
+        }
+        send(&mut conn, &restores).await
+    })
+    .await;
+    match sent {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) if err.to_string().starts_with("ERR Target") => return Err(err),
+        _ => return Err("IOERR error or timeout writing to target instance".into()),
+    }
+
+    if !copy {
+        let moved: Vec<&str> = restores
+            .iter()
+            .filter_map(|restore| std::str::from_utf8(&restore.args()[0]).ok())
+            .collect();
+        if let Frame::Error(err) = cmd::execute(&command("del", &moved), db, None).await {
+            return Err(err.into());
+        }
+    }
+    Ok(Frame::ok())
+}
+
+/// Send `cmds` over `conn`, all at once, and check every reply is `+OK`.
+async fn send(conn: &mut Connection, cmds: &[Command]) -> crate::Result<()> {
+    for cmd in cmds {
+        conn.write_frame(&cmd.to_frame()).await?;
+    }
+    conn.flush().await?;
+
+    for _ in cmds {
+        match conn.read_frame().await? {
+            Some(Frame::Simple(_)) => {}
+            Some(Frame::Error(err)) => {
+                return Err(format!("ERR Target instance replied with error: {}", err).into());
+            }
+            _ => return Err("connection closed".into()),
+        }
+    }
+    Ok(())
+}
+
+/// `ADDSLOTS` or `DELSLOTS` on `slots`: either all of them change or none.
+fn update_slots(db: &Db, subcommand: &str, slots: &[u16]) -> crate::Result<Frame> {
+    let mut cluster = db.cluster_mut();
+    let cluster = cluster.as_mut().ok_or(DISABLED)?;
+
+    let mut changed = Vec::new();
+    for &slot in slots {
+        let res = match subcommand {
+            "addslots" => cluster.add_slot(slot),
+            _ => cluster.del_slot(slot),
+        };
+        if let Err(err) = res {
+            // Put back the ones already done.
+            for &slot in &changed {
+                let _ = match subcommand {
+                    "addslots" => cluster.del_slot(slot),
+                    _ => cluster.add_slot(slot),
+                };
+            }
+            return Err(err);
+        }
+        changed.push(slot);
+    }
+    Ok(Frame::ok())
+}
+
+/// `SETSLOT slot state [id]`.
+fn set_slot(db: &Db, slot: u16, state: &str, id: Option<&str>) -> crate::Result<Frame> {
+    // Counted before locking the cluster, which is never held while
+    // locking a shard.
+    let holds_keys = state == "node" && !keys_in_slot(db, slot, 1).is_empty();
+
+    let mut cluster = db.cluster_mut();
+    let cluster = cluster.as_mut().ok_or(DISABLED)?;
+    match (state, id) {
+        ("importing", Some(id)) => cluster.set_importing(slot, id)?,
+        ("migrating", Some(id)) => cluster.set_migrating(slot, id)?,
+        ("stable", None) => cluster.set_stable(slot),
+        ("node", Some(id)) => {
+            let mine = cluster.owner(slot) == Some(cluster.myself());
+            if mine && id != cluster.myself() && holds_keys {
+                return Err(format!(
+                    "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                    slot
+                )
+                .into());
+            }
+            cluster.set_node(slot, id)?;
+        }
+        _ => return Err("ERR Invalid CLUSTER SETSLOT action or number of arguments.".into()),
+    }
+    Ok(Frame::ok())
+}
+
+/// The `CLUSTER SLOTS` reply: each range of slots with the address and id
+/// of its owner.
+fn slots(cluster: &Cluster) -> Vec<Frame> {
+    cluster
+        .ranges()
+        .into_iter()
+        .map(|(start, end, id)| {
+            let node = cluster.node(id).expect("owners are known nodes");
+            Frame::Array(vec![
+                Frame::Integer(start as i64),
+                Frame::Integer(end as i64),
+                Frame::Array(vec![
+                    bulk(node.host.clone()),
+                    Frame::Integer(node.port as i64),
+                    bulk(id.to_string()),
+                ]),
+            ])
+        })
+        .collect()
+}
+
+/// Up to `count` keys in `slot`.
+///
+/// Slots are not indexed, so this walks the whole keyspace, like `KEYS`.
+fn keys_in_slot(db: &Db, slot: u16, count: usize) -> Vec<String> {
+    let (_, mut keys) = db.scan(0, usize::MAX, |key, _| key_slot(key.as_bytes()) == slot);
+    keys.truncate(count);
+    keys
+}
+
+fn next_slot(parse: &mut Parse) -> crate::Result<u16> {
+    match parse.next_int() {
+        Ok(slot) if (0..SLOTS as i64).contains(&slot) => Ok(slot as u16),
+        _ => Err("ERR Invalid or out of range slot".into()),
+    }
+}
+
+/// Read from the cluster, which the caller checked is there.
+fn read<T>(db: &Db, f: impl FnOnce(&Cluster) -> T) -> T {
+    f(db.cluster().as_ref().expect("cluster mode"))
+}
+
+fn command(name: &str, args: &[&str]) -> Command {
+    let args = args
+        .iter()
+        .map(|arg| Bytes::copy_from_slice(arg.as_bytes()))
+        .collect();
+    Command::new(name, args)
+}
+
+fn bulk(s: String) -> Frame {
+    Frame::Bulk(Bytes::from(s))
+}
@@ -0,0 +1,116 @@
+//! `CONFIG`: reading and changing settings at runtime.
+
+use crate::aof::{self, Aof};
+use crate::config::{MUTABLE, NAMES};
+use crate::parse::Parse;
+use crate::{Db, Frame, glob};
+
+use bytes::Bytes;
+
+pub fn config(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let subcommand = parse.next_string()?.to_ascii_lowercase();
+
+    match subcommand.as_str() {
+        "get" => {
+            let mut patterns = vec![parse.next_string()?.to_ascii_lowercase()];
+            while parse.remaining() > 0 {
+                patterns.push(parse.next_string()?.to_ascii_lowercase());
+            }
+
+            let config = db.config();
+            let pairs = NAMES
+                .iter()
+                .filter(|name| {
+                    patterns
+                        .iter()
+                        .any(|pattern| glob::matches(pattern.as_bytes(), name.as_bytes()))
+                })
+                .map(|name| {
+                    let value = config.get(name).unwrap_or_default();
+                    (
+                        Frame::Bulk(Bytes::from(*name)),
+                        Frame::Bulk(Bytes::from(value)),
+                    )
+                })
+                .collect();
+            Ok(Frame::Map(pairs))
+        }
+        "set" => {
+            let mut pairs = vec![(parse.next_string()?, parse.next_string()?)];
+            while parse.remaining() > 0 {
+                pairs.push((parse.next_string()?, parse.next_string()?));
+            }
+            set(db, &pairs)?;
+            Ok(Frame::ok())
+        }
+        _ => Err(format!(
+            "ERR unknown subcommand '{}'. Try CONFIG GET or CONFIG SET.",
+            subcommand
+        )
+        .into()),
+    }
+}
+
+/// Apply every pair, or none of them if one is rejected.
+fn set(db: &Db, pairs: &[(String, String)]) -> crate::Result<()> {
+    let failed = |name: &str, reason: &str| {
+        format!(
+            "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+            name, reason
+        )
+    };
+
+    // Hold the lock throughout, so two `CONFIG SET` don't each apply their
+    // change to the same old settings.
+    let mut current = db.config_mut();
+    let mut config = current.clone();
+
+    for (name, value) in pairs {
+        let name = name.to_ascii_lowercase();
+        if !NAMES.contains(&name.as_str()) {
+            return Err(format!(
+                "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            )
+            .into());
+        }
+        if !MUTABLE.contains(&name.as_str()) {
+            return Err(failed(&name, "can't set immutable config").into());
+        }
+        config
+            .set(&name, value)
+            .map_err(|err| failed(&name, &err))?;
+    }
+
+    if config.dir != current.dir && !config.dir.is_dir() {
+        return Err(failed("dir", "No such directory").into());
+    }
+
+    // Turning the append-only file on starts it from a rewrite of the
+    // keyspace, as it may hold nothing or something stale. Turning it off
+    // closes it once pending appends are written.
+    let appendonly = pairs
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("appendonly"));
+    if appendonly && config.appendonly && db.aof().is_none() {
+        let aof = Aof::open(&config.aof_path(), config.appendfsync)
+            .map_err(|err| failed("appendonly", &err.to_string()))?;
+        db.set_aof(Some(aof));
+
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(err) = aof::rewrite(&db).await {
+                eprintln!("config: append only file rewrite failed: {}", err);
+            }
+        });
+    } else if appendonly && !config.appendonly {
+        db.set_aof(None);
+    }
+
+    if config.requirepass != current.requirepass {
+        db.acl_mut().set_requirepass(config.requirepass.as_deref());
+    }
+
+    *current = config;
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Commands changing the state of the client's own connection.
+
+use crate::cmd::acl::login;
+use crate::frame::Protocol;
+use crate::parse::Parse;
+use crate::{Connection, Db, Frame};
+
+use bytes::Bytes;
+
+/// `PING [message]`
+pub fn ping(parse: &mut Parse) -> crate::Result<Frame> {
+    let reply = match parse.remaining() {
+        0 => Frame::Simple("PONG".to_string()),
+        _ => Frame::Bulk(parse.next_bytes()?),
+    };
+    parse.finish()?;
+
+    Ok(reply)
+}
+
+/// `ECHO message`
+pub fn echo(parse: &mut Parse) -> crate::Result<Frame> {
+    let message = parse.next_bytes()?;
+    parse.finish()?;
+
+    Ok(Frame::Bulk(message))
+}
+
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+///
+/// Switches the connection to the requested protocol version and replies
+/// with a map describing the server, encoded with the new version. With
+/// `AUTH`, logs in as `username` first, like `AUTH` does; a connection not
+/// logged in yet has to.
+pub fn hello(
+    db: &Db,
+    connection: &mut Connection,
+    id: u64,
+    user: &mut Option<String>,
+    parse: &mut Parse,
+) -> crate::Result<Frame> {
+    let mut protocol = connection.protocol();
+
+    if parse.remaining() > 0 {
+        protocol = match parse.next_int() {
+            Ok(2) => Protocol::Resp2,
+            Ok(3) => Protocol::Resp3,
+            _ => return Err("NOPROTO unsupported protocol version".into()),
+        };
+    }
+
+    while parse.remaining() > 0 {
+        let option = parse.next_string()?.to_ascii_uppercase();
+        match option.as_str() {
+            "AUTH" => {
+                let name = parse.next_string()?;
+                let password = parse.next_bytes()?;
+                login(db, user, &name, &password)?;
+            }
+            // Client names are not tracked yet; accept and drop it so
+            // clients that always send one can still connect.
+            "SETNAME" => {
+                parse.next_bytes()?;
+            }
+            _ => return Err(format!("ERR Syntax error in HELLO option '{}'", option).into()),
+        }
+    }
+
+    if user.is_none() {
+        return Err("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".into());
+    }
+    connection.set_protocol(protocol);
+
+    let proto = match protocol {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+
+    Ok(Frame::Map(vec![
+        (bulk("server"), bulk("redis")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), Frame::Integer(proto)),
+        (bulk("id"), Frame::Integer(id as i64)),
+        (bulk("mode"), bulk("standalone")),
+        (
+            bulk("role"),
+            bulk(if db.is_replica() { "replica" } else { "master" }),
+        ),
+        (bulk("modules"), Frame::Array(vec![])),
+    ]))
+}
+
+fn bulk(s: &'static str) -> Frame {
+    Frame::Bulk(Bytes::from_static(s.as_bytes()))
+}
@@ -0,0 +1,149 @@
+//! Commands operating on hash values.
+//!
+//! A hash maps binary-safe field names to values. Like lists, a hash whose
+//! last field is deleted is removed from the keyspace.
+
+use crate::cmd::WRONGTYPE;
+use crate::db::ShardGuard;
+use crate::parse::Parse;
+use crate::{Db, Frame, Value};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// `HSET key field value [field value ...]`: replies with the number of
+/// fields that were added, not counting the ones that were updated.
+pub fn hset(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let mut pairs = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+    while parse.remaining() > 0 {
+        pairs.push((parse.next_bytes()?, parse.next_bytes()?));
+    }
+
+    let mut shard = db.lock(&key);
+    let hash = hash_or_create(&mut shard, key)?;
+
+    let added = pairs
+        .into_iter()
+        .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+        .count();
+    Ok(Frame::Integer(added as i64))
+}
+
+/// `HGET key field`
+pub fn hget(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let field = parse.next_bytes()?;
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let value = hash(&mut shard, &key)?.and_then(|hash| hash.get(&field));
+    Ok(value.map_or(Frame::Null, |value| Frame::Bulk(value.clone())))
+}
+
+/// `HDEL key field [field ...]`: replies with the number of fields removed.
+pub fn hdel(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let mut fields = vec![parse.next_bytes()?];
+    while parse.remaining() > 0 {
+        fields.push(parse.next_bytes()?);
+    }
+
+    let mut shard = db.lock(&key);
+    let Some(hash) = hash_mut(&mut shard, &key)? else {
+        return Ok(Frame::Integer(0));
+    };
+
+    let removed = fields
+        .iter()
+        .filter(|field| hash.remove(*field).is_some())
+        .count();
+    if hash.is_empty() {
+        shard.remove(&key);
+    }
+    Ok(Frame::Integer(removed as i64))
+}
+
+/// `HGETALL key`
+///
+/// RESP3 clients get a map; RESP2 clients see it as a flat array of fields
+/// and values.
+pub fn hgetall(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let pairs = match hash(&mut shard, &key)? {
+        Some(hash) => hash
+            .iter()
+            .map(|(field, value)| (Frame::Bulk(field.clone()), Frame::Bulk(value.clone())))
+            .collect(),
+        None => vec![],
+    };
+    Ok(Frame::Map(pairs))
+}
+
+/// `HINCRBY key field increment`
+///
+/// A missing field counts as `0`. Replies with the value after the
+/// increment.
+pub fn hincrby(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let field = parse.next_bytes()?;
+    let increment = parse.next_int()?;
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let hash = hash_or_create(&mut shard, key)?;
+
+    let current = match hash.get(&field) {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or("ERR hash value is not an integer")?,
+        None => 0,
+    };
+    let value = current
+        .checked_add(increment)
+        .ok_or("ERR increment or decrement would overflow")?;
+
+    hash.insert(field, Bytes::from(value.to_string()));
+    Ok(Frame::Integer(value))
+}
+
+/// The hash stored at `key`, `None` if there is none, or a `WRONGTYPE` error
+/// if the key holds something else.
+fn hash<'a>(
+    shard: &'a mut ShardGuard,
+    key: &str,
+) -> crate::Result<Option<&'a HashMap<Bytes, Bytes>>> {
+    match shard.get(key) {
+        Some(Value::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(WRONGTYPE.into()),
+        None => Ok(None),
+    }
+}
+
+/// Like `hash`, for a command about to change it.
+fn hash_mut<'a>(
+    shard: &'a mut ShardGuard,
+    key: &str,
+) -> crate::Result<Option<&'a mut HashMap<Bytes, Bytes>>> {
+    match shard.get_mut(key) {
+        Some(Value::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(WRONGTYPE.into()),
+        None => Ok(None),
+    }
+}
+
+/// Like `hash`, but creates an empty hash at `key` if there is none. The
+/// caller must leave at least one field in it.
+fn hash_or_create<'a>(
+    shard: &'a mut ShardGuard,
+    key: String,
+) -> crate::Result<&'a mut HashMap<Bytes, Bytes>> {
+    if shard.get(&key).is_none() {
+        shard.insert(key.clone(), Value::Hash(HashMap::new()));
+    }
+    Ok(hash_mut(shard, &key)?.expect("hash was just created"))
+}
@@ -0,0 +1,275 @@
+//! Commands that work on keys regardless of the value they hold.
+
+use crate::parse::Parse;
+use crate::{Command, Db, Frame, glob, rdb};
+
+use bytes::Bytes;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// `DEL key [key ...]`: replies with the number of keys that were removed.
+pub fn del(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let keys = key_args(parse)?;
+
+    let removed = keys
+        .iter()
+        .filter(|key| db.lock(key).remove(key).is_some())
+        .count();
+    Ok(Frame::Integer(removed as i64))
+}
+
+/// `EXISTS key [key ...]`: replies with how many of the keys exist. A key
+/// named twice is counted twice.
+pub fn exists(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let keys = key_args(parse)?;
+
+    let found = keys
+        .iter()
+        .filter(|key| db.lock(key).get(key).is_some())
+        .count();
+    Ok(Frame::Integer(found as i64))
+}
+
+/// `TYPE key`
+pub fn type_(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    let name = match db.lock(&key).get(&key) {
+        Some(value) => value.type_name(),
+        None => "none",
+    };
+    Ok(Frame::Simple(name.to_string()))
+}
+
+/// `KEYS pattern`: every key matching the glob `pattern`.
+///
+/// This walks the whole keyspace in one go, and builds a reply as large as
+/// it. Fine for debugging a small dataset; anything else should iterate
+/// with `SCAN` instead, which holds no lock for long.
+pub fn keys(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let pattern = parse.next_bytes()?;
+    parse.finish()?;
+
+    let (_, keys) = db.scan(0, usize::MAX, |key, _| {
+        glob::matches(&pattern, key.as_bytes())
+    });
+    let keys = keys.into_iter().map(|key| Frame::Bulk(Bytes::from(key)));
+    Ok(Frame::Array(keys.collect()))
+}
+
+/// `DUMP key`: the value at `key` serialized for `RESTORE`, or a null if
+/// there is none.
+///
+/// The payload is a snapshot holding only that key, checksum included, so
+/// a damaged one is refused. The expiration is not part of it.
+pub fn dump(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    let Some(value) = db.get(&key) else {
+        return Ok(Frame::Null);
+    };
+    let payload = rdb::encode(&[(key, value, None)], SystemTime::now());
+    Ok(Frame::Bulk(Bytes::from(payload)))
+}
+
+/// `RESTORE key ttl payload [REPLACE] [ABSTTL]`: recreate at `key` the value
+/// `DUMP` serialized, expiring after `ttl` milliseconds, or never if `0`.
+///
+/// With `ABSTTL`, `ttl` is a Unix time in milliseconds instead. An existing
+/// key is only overwritten with `REPLACE`.
+pub fn restore(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let ttl = parse.next_int()?;
+    let payload = parse.next_bytes()?;
+    let (mut replace, mut absolute) = (false, false);
+    while parse.remaining() > 0 {
+        match parse.next_string()?.to_ascii_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absolute = true,
+            _ => return Err("ERR syntax error".into()),
+        }
+    }
+    if ttl < 0 {
+        return Err("ERR Invalid TTL value, must be >= 0".into());
+    }
+
+    let value = match rdb::decode(&payload).map(|entries| entries.into_iter().next()) {
+        Ok(Some((_, value, _))) => value,
+        _ => return Err("ERR DUMP payload version or checksum are wrong".into()),
+    };
+
+    let ms = if absolute { ttl - unix_ms() } else { ttl };
+    // Worked out before locking. One too far away to represent is as good
+    // as none.
+    let expires_at = Instant::now().checked_add(Duration::from_millis(ms.max(0) as u64));
+
+    let mut shard = db.lock(&key);
+    if !replace && shard.get(&key).is_some() {
+        return Err("BUSYKEY Target key name already exists.".into());
+    }
+    if ttl != 0 && ms <= 0 {
+        // Restored already expired: all that is left is the replacing.
+        shard.remove(&key);
+        return Ok(Frame::ok());
+    }
+
+    shard.insert(key.clone(), value);
+    if ttl != 0 {
+        shard.expire_at(&key, expires_at);
+    }
+    Ok(Frame::ok())
+}
+
+/// The `RESTORE` a restore command is logged as: with an absolute TTL, so
+/// replaying it later keeps the same deadline.
+pub(crate) fn propagated_restore(args: &[Bytes]) -> Command {
+    let mut args = args.to_vec();
+    let absolute = args
+        .iter()
+        .skip(3)
+        .any(|arg| arg.eq_ignore_ascii_case(b"absttl"));
+    let ttl = args
+        .get(1)
+        .and_then(|ttl| std::str::from_utf8(ttl).ok())
+        .and_then(|ttl| ttl.parse::<i64>().ok());
+
+    if let Some(ttl) = ttl.filter(|&ttl| ttl > 0 && !absolute) {
+        args[1] = Bytes::from(unix_ms().saturating_add(ttl).to_string());
+        args.push(Bytes::from_static(b"ABSTTL"));
+    }
+    Command::new("restore", args)
+}
+
+/// `TTL key`: remaining time to live in seconds, `-1` if the key has no
+/// expiration and `-2` if it does not exist.
+pub fn ttl(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    // Redis rounds to the nearest second rather than truncating.
+    time_to_live(db, parse, |ttl| ((ttl.as_millis() + 500) / 1000) as i64)
+}
+
+/// `PTTL key`: like `TTL`, in milliseconds.
+pub fn pttl(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    time_to_live(db, parse, |ttl| ttl.as_millis() as i64)
+}
+
+fn time_to_live(db: &Db, parse: &mut Parse, unit: fn(Duration) -> i64) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    Ok(Frame::Integer(match db.ttl(&key) {
+        None => -2,
+        Some(None) => -1,
+        Some(Some(ttl)) => unit(ttl),
+    }))
+}
+
+/// `PERSIST key`: drop the expiration of `key`. Replies `1` if there was one
+/// to drop.
+pub fn persist(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    Ok(Frame::Integer(db.persist(&key) as i64))
+}
+
+/// `EXPIRE key seconds`, `PEXPIRE key milliseconds`, `EXPIREAT key
+/// timestamp` and `PEXPIREAT key timestamp-ms`, depending on `unit` (in
+/// milliseconds) and whether the time is `absolute`.
+///
+/// Replies `1` if the key exists and `0` otherwise. A deadline that is
+/// already past deletes the key.
+pub fn expire(db: &Db, parse: &mut Parse, unit: i64, absolute: bool) -> crate::Result<Frame> {
+    let (key, ms) = expire_args(parse, unit, absolute)?;
+
+    let found = if ms > 0 {
+        db.expire(&key, Duration::from_millis(ms as u64))
+    } else {
+        db.lock(&key).remove(&key).is_some()
+    };
+    Ok(Frame::Integer(found as i64))
+}
+
+/// Check the arguments of an expiration command without running it.
+pub(crate) fn check_expire(cmd: &Command) -> crate::Result<()> {
+    let unit = if cmd.name().starts_with('p') { 1 } else { 1000 };
+    let absolute = cmd.name().ends_with("at");
+    expire_args(&mut cmd.parse(), unit, absolute).map(|_| ())
+}
+
+/// The key of an expiration command, and the milliseconds it has left.
+fn expire_args(parse: &mut Parse, unit: i64, absolute: bool) -> crate::Result<(String, i64)> {
+    let key = parse.next_string()?;
+    let time = parse.next_int()?;
+    parse.finish()?;
+
+    let ms = time
+        .checked_mul(unit)
+        // The deadline is logged in milliseconds since the epoch, which has
+        // to fit.
+        .filter(|&ms| absolute || unix_ms().checked_add(ms).is_some())
+        .ok_or_else(|| {
+            format!(
+                "ERR invalid expire time in '{}' command",
+                parse_name(unit, absolute)
+            )
+        })?;
+    if absolute {
+        return Ok((key, ms.saturating_sub(unix_ms())));
+    }
+    Ok((key, ms))
+}
+
+/// The `PEXPIREAT` an expiration command is logged as, so replaying it later
+/// restores the same deadline.
+pub(crate) fn propagated(name: &str, args: &[Bytes]) -> Command {
+    let unit = if name.starts_with('p') { 1 } else { 1000 };
+    let time = args
+        .get(1)
+        .and_then(|time| std::str::from_utf8(time).ok())
+        .and_then(|time| time.parse::<i64>().ok())
+        .and_then(|time| time.checked_mul(unit));
+
+    match time {
+        Some(ms) => {
+            let at = if name.ends_with("at") {
+                ms
+            } else {
+                unix_ms().saturating_add(ms)
+            };
+            Command::new(
+                "pexpireat",
+                vec![args[0].clone(), Bytes::from(at.to_string())],
+            )
+        }
+        // Invalid commands are not logged anyway.
+        None => Command::new(name, args.to_vec()),
+    }
+}
+
+fn parse_name(unit: i64, absolute: bool) -> &'static str {
+    match (unit, absolute) {
+        (1, false) => "pexpire",
+        (1, true) => "pexpireat",
+        (_, false) => "expire",
+        (_, true) => "expireat",
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn unix_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Read one or more keys up to the end of the arguments.
+fn key_args(parse: &mut Parse) -> crate::Result<Vec<String>> {
+    let mut keys = vec![parse.next_string()?];
+    while parse.remaining() > 0 {
+        keys.push(parse.next_string()?);
+    }
+    Ok(keys)
+}
@@ -0,0 +1,401 @@
+//! Commands operating on list values.
+//!
+//! Lists are stored as a `VecDeque`, so pushing and popping at either end is
+//! cheap. A list that becomes empty is removed from the keyspace.
+//!
+//! `BLPOP` and `BRPOP` block the connection until an element shows up; see
+//! the `blocking` module for how waiting clients are served.
+
+use crate::blocking::{End, Waiter};
+use crate::cmd::WRONGTYPE;
+use crate::db::ShardGuard;
+use crate::parse::Parse;
+use crate::shutdown::Shutdown;
+use crate::{Command, Connection, Db, Frame, Value};
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+/// `LPUSH key element [element ...]`: replies with the new length.
+pub fn lpush(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    push(db, parse, End::Left)
+}
+
+/// `RPUSH key element [element ...]`: replies with the new length.
+pub fn rpush(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    push(db, parse, End::Right)
+}
+
+/// `LPOP key [count]`
+pub fn lpop(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    pop(db, parse, End::Left)
+}
+
+/// `RPOP key [count]`
+pub fn rpop(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    pop(db, parse, End::Right)
+}
+
+/// `LLEN key`
+pub fn llen(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let len = list(&mut shard, &key)?.map_or(0, |list| list.len());
+    Ok(Frame::Integer(len as i64))
+}
+
+/// `LRANGE key start stop`
+///
+/// Indexes are zero-based and inclusive; negative ones count from the end,
+/// `-1` being the last element. Out of range indexes are clamped.
+pub fn lrange(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let start = parse.next_int()?;
+    let stop = parse.next_int()?;
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let Some(list) = list(&mut shard, &key)? else {
+        return Ok(Frame::Array(vec![]));
+    };
+
+    let items = match range(start, stop, list.len()) {
+        Some((start, stop)) => list
+            .range(start..=stop)
+            .map(|item| Frame::Bulk(item.clone()))
+            .collect(),
+        None => vec![],
+    };
+    Ok(Frame::Array(items))
+}
+
+fn push(db: &Db, parse: &mut Parse, end: End) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let mut elements = vec![parse.next_bytes()?];
+    while parse.remaining() > 0 {
+        elements.push(parse.next_bytes()?);
+    }
+
+    let mut shard = db.lock(&key);
+    let len = push_elements(&mut shard, key.clone(), elements, end)?;
+    serve_blocked(&mut shard, &key);
+
+    // Like Redis, the reply is the length right after the push, before any
+    // blocked client took its element.
+    Ok(Frame::Integer(len as i64))
+}
+
+/// Hand elements of the list at `key` to the clients blocked on it, oldest
+/// first, for as long as there are both.
+pub(crate) fn serve_blocked(shard: &mut ShardGuard, key: &str) {
+    loop {
+        match shard.get(key) {
+            Some(Value::List(list)) if !list.is_empty() => {}
+            _ => return,
+        }
+        let Some(waiter) = shard.next_waiter(key) else {
+            return;
+        };
+
+        let end = waiter.end();
+        let value = pop_elements(shard, key, 1, end).remove(0);
+        match waiter.serve(key, value) {
+            Ok(()) => shard.propagate(pop_command(key, end)),
+            Err(value) => {
+                // The client is gone; put the element back where it was.
+                push_elements(shard, key.to_string(), vec![value], end)
+                    .expect("key held a list a moment ago");
+            }
+        }
+    }
+}
+
+/// `BLPOP key [key ...] timeout` and `BRPOP key [key ...] timeout`
+///
+/// Pops from the first non-empty list among `keys`. If they are all empty,
+/// the connection blocks until another client pushes to one of them or
+/// `timeout` seconds pass (`0` waits forever). Replies with the key and the
+/// element, or a null on timeout. A shutdown ends the wait like a timeout.
+pub async fn blocking_pop(
+    db: &Db,
+    connection: &mut Connection,
+    cmd: &Command,
+    shutdown: &mut Shutdown,
+) -> crate::Result<Frame> {
+    let (end, keys, timeout) = blocking_args(cmd)?;
+
+    let (waiter, mut rx) = Waiter::new(end);
+    let (mut served, synced) =
+        db.concurrently(|| db.write(|| (pop_or_block(db, &keys, &waiter), None)));
+    if let Some(synced) = synced {
+        synced.wait().await?;
+    }
+
+    if let Ok(None) = served {
+        // Replies to pipelined requests before this one must not wait
+        // behind the block.
+        connection.flush().await?;
+
+        let expired = async {
+            match timeout {
+                Some(timeout) => time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        served = tokio::select! {
+            res = &mut rx => Ok(res.ok()),
+            _ = expired => Ok(None),
+            _ = connection.closed() => Ok(None),
+            _ = shutdown.recv() => Ok(None),
+        };
+
+        // Timed out, the client left or the server is shutting down: claim
+        // the waiter so nobody serves it. If that fails, an element was
+        // handed over just now and is waiting in the channel.
+        if let Ok(None) = served
+            && !waiter.claim()
+        {
+            served = Ok(rx.try_recv().ok());
+        }
+    }
+
+    for key in &keys {
+        db.lock(key).unblock(key, &waiter);
+    }
+
+    Ok(popped(served?))
+}
+
+/// `BLPOP` or `BRPOP` run inside a transaction, which cannot wait for other
+/// clients: replies as if the timeout was over right away when every list is
+/// empty.
+pub(crate) fn pop_now(db: &Db, cmd: &Command) -> crate::Result<Frame> {
+    let (end, keys, _) = blocking_args(cmd)?;
+
+    for key in keys {
+        let mut shard = db.lock(&key);
+        if list(&mut shard, &key)?.is_some() {
+            let value = pop_elements(&mut shard, &key, 1, end).remove(0);
+            shard.propagate(pop_command(&key, end));
+            return Ok(popped(Some((key, value))));
+        }
+    }
+
+    Ok(popped(None))
+}
+
+/// Check the arguments of a blocking pop without running it.
+pub(crate) fn check_blocking(cmd: &Command) -> crate::Result<()> {
+    blocking_args(cmd).map(|_| ())
+}
+
+/// The end to pop from, the keys and the timeout of a blocking pop.
+fn blocking_args(cmd: &Command) -> crate::Result<(End, Vec<String>, Option<Duration>)> {
+    let end = match cmd.name() {
+        "blpop" => End::Left,
+        _ => End::Right,
+    };
+
+    // At least one key, then the timeout.
+    let mut parse = cmd.parse();
+    let mut keys = vec![parse.next_string()?];
+    let mut last = parse.next_bytes()?;
+    while parse.remaining() > 0 {
+        keys.push(String::from_utf8_lossy(&last).into_owned());
+        last = parse.next_bytes()?;
+    }
+
+    Ok((end, keys, timeout(&last)?))
+}
+
+/// The reply to a blocking pop: the key and the element, or a null.
+fn popped(served: Option<(String, Bytes)>) -> Frame {
+    match served {
+        Some((key, value)) => Frame::Array(vec![Frame::Bulk(Bytes::from(key)), Frame::Bulk(value)]),
+        None => Frame::Null,
+    }
+}
+
+/// Pop from the first non-empty list among `keys`, or register `waiter` on
+/// every key if they are all empty.
+fn pop_or_block(
+    db: &Db,
+    keys: &[String],
+    waiter: &Arc<Waiter>,
+) -> crate::Result<Option<(String, Bytes)>> {
+    for key in keys {
+        let mut shard = db.lock(key);
+
+        if list(&mut shard, key)?.is_some() {
+            // Registered on an earlier key and already served through it.
+            if !waiter.claim() {
+                return Ok(None);
+            }
+            let value = pop_elements(&mut shard, key, 1, waiter.end()).remove(0);
+            shard.propagate(pop_command(key, waiter.end()));
+            return Ok(Some((key.clone(), value)));
+        }
+
+        shard.block(key, waiter.clone());
+    }
+
+    Ok(None)
+}
+
+/// The plain pop a blocking pop is logged as.
+fn pop_command(key: &str, end: End) -> Command {
+    let name = match end {
+        End::Left => "lpop",
+        End::Right => "rpop",
+    };
+    Command::new(name, vec![Bytes::copy_from_slice(key.as_bytes())])
+}
+
+/// Parse a blocking timeout given in (possibly fractional) seconds.
+fn timeout(arg: &Bytes) -> crate::Result<Option<Duration>> {
+    let secs: f64 = std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|s: &f64| s.is_finite())
+        .ok_or("ERR timeout is not a float or out of range")?;
+
+    if secs < 0.0 {
+        return Err("ERR timeout is negative".into());
+    }
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    match Duration::try_from_secs_f64(secs) {
+        Ok(timeout) => Ok(Some(timeout)),
+        Err(_) => Err("ERR timeout is out of range".into()),
+    }
+}
+
+/// Push `elements` one by one at `end` of the list at `key`, creating it if
+/// needed. Returns the new length.
+pub(crate) fn push_elements(
+    shard: &mut ShardGuard,
+    key: String,
+    elements: Vec<Bytes>,
+    end: End,
+) -> crate::Result<usize> {
+    let list = match shard.get_mut(&key) {
+        Some(Value::List(list)) => list,
+        Some(_) => return Err(WRONGTYPE.into()),
+        None => {
+            shard.insert(key.clone(), Value::List(VecDeque::new()));
+            match shard.get_mut(&key) {
+                Some(Value::List(list)) => list,
+                _ => unreachable!(),
+            }
+        }
+    };
+
+    for element in elements {
+        match end {
+            End::Left => list.push_front(element),
+            End::Right => list.push_back(element),
+        }
+    }
+    Ok(list.len())
+}
+
+fn pop(db: &Db, parse: &mut Parse, end: End) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let count = match parse.remaining() {
+        0 => None,
+        _ => match parse.next_int()? {
+            n if n >= 0 => Some(n as usize),
+            _ => return Err("ERR value is out of range, must be positive".into()),
+        },
+    };
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    if list(&mut shard, &key)?.is_none() {
+        return Ok(Frame::Null);
+    }
+
+    let popped = pop_elements(&mut shard, &key, count.unwrap_or(1), end);
+    Ok(match count {
+        None => popped.into_iter().next().map_or(Frame::Null, Frame::Bulk),
+        Some(_) => Frame::Array(popped.into_iter().map(Frame::Bulk).collect()),
+    })
+}
+
+/// Pop up to `count` elements from `end` of the list at `key`, removing the
+/// key if that empties it. The key must hold a list or not exist.
+pub(crate) fn pop_elements(
+    shard: &mut ShardGuard,
+    key: &str,
+    count: usize,
+    end: End,
+) -> Vec<Bytes> {
+    let Some(Value::List(list)) = shard.get_mut(key) else {
+        return vec![];
+    };
+
+    let count = count.min(list.len());
+    let popped = match end {
+        End::Left => list.drain(..count).collect(),
+        End::Right => list.drain(list.len() - count..).rev().collect(),
+    };
+
+    if list.is_empty() {
+        shard.remove(key);
+    }
+    popped
+}
+
+/// The list stored at `key`, `None` if there is none, or a `WRONGTYPE`
+/// error if the key holds something else.
+fn list<'a>(shard: &'a mut ShardGuard, key: &str) -> crate::Result<Option<&'a VecDeque<Bytes>>> {
+    match shard.get(key) {
+        Some(Value::List(list)) => Ok(Some(list)),
+        Some(_) => Err(WRONGTYPE.into()),
+        None => Ok(None),
+    }
+}
+
+/// Turn Redis-style inclusive `start`/`stop` indexes into valid bounds for a
+/// sequence of `len` elements, or `None` if the range is empty.
+pub(crate) fn range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_normalizes_indexes() {
+        assert_eq!(range(0, -1, 5), Some((0, 4)));
+        assert_eq!(range(-2, 100, 5), Some((3, 4)));
+        assert_eq!(range(-100, 1, 5), Some((0, 1)));
+        assert_eq!(range(3, 1, 5), None);
+        assert_eq!(range(5, 10, 5), None);
+        assert_eq!(range(0, -1, 0), None);
+    }
+}
@@ -0,0 +1,857 @@
+//! Command parsing and dispatch.
+//!
+//! A `Command` is just the lower-cased name and the raw arguments of a
+//! request. Each command family lives in its own module and exposes one
+//! function per command; `apply` routes a `Command` to the right one.
+
+pub(crate) mod acl;
+pub(crate) mod cluster;
+mod config;
+pub(crate) mod connection;
+mod hash;
+mod keys;
+pub(crate) mod list;
+pub(crate) mod persistence;
+pub(crate) mod pubsub;
+mod scan;
+pub(crate) mod scripting;
+mod set;
+pub(crate) mod stream;
+mod string;
+pub(crate) mod transaction;
+mod zset;
+
+use crate::acl::User;
+use crate::parse::Parse;
+use crate::{Db, Frame, replication};
+
+use bytes::Bytes;
+use std::sync::Arc;
+
+/// Error replied when a command meets a key holding another type of value.
+pub(crate) const WRONGTYPE: &str =
+    "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Commands that may modify the keyspace.
+const WRITE_COMMANDS: &[&str] = &[
+    "set",
+    "del",
+    "expire",
+    "pexpire",
+    "expireat",
+    "pexpireat",
+    "persist",
+    "restore",
+    "restore-asking",
+    "lpush",
+    "rpush",
+    "lpop",
+    "rpop",
+    "blpop",
+    "brpop",
+    "hset",
+    "hdel",
+    "hincrby",
+    "sadd",
+    "zadd",
+    "xadd",
+    "xsetid",
+    "xgroup",
+    "xreadgroup",
+    "xack",
+    "xclaim",
+];
+
+/// Writes that may need more memory, refused while the keyspace is full.
+const MEMORY_COMMANDS: &[&str] = &[
+    "set",
+    "restore",
+    "restore-asking",
+    "lpush",
+    "rpush",
+    "hset",
+    "hincrby",
+    "sadd",
+    "zadd",
+    "xadd",
+];
+
+/// Error replied to those when eviction can't make room.
+pub(crate) const OOM: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// A request read from a client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    name: String,
+    args: Vec<Bytes>,
+}
+
+impl Command {
+    /// Build a command from a name and its arguments.
+    pub fn new(name: &str, args: Vec<Bytes>) -> Command {
+        Command {
+            name: name.to_ascii_lowercase(),
+            args,
+        }
+    }
+
+    /// Parse a command from a received frame.
+    ///
+    /// Clients send commands as an array of bulk strings, the first one
+    /// being the command name.
+    pub fn from_frame(frame: Frame) -> crate::Result<Command> {
+        let parts = match frame {
+            Frame::Array(parts) => parts,
+            frame => return Err(format!("protocol error; expected array, got {}", frame).into()),
+        };
+
+        let mut args = Vec::with_capacity(parts.len());
+        for part in parts {
+            match part {
+                Frame::Bulk(bytes) => args.push(bytes),
+                Frame::Simple(s) => args.push(Bytes::from(s)),
+                frame => {
+                    return Err(
+                        format!("protocol error; expected bulk string, got {}", frame).into(),
+                    );
+                }
+            }
+        }
+
+        if args.is_empty() {
+            return Err("protocol error; empty command".into());
+        }
+        let name = String::from_utf8_lossy(&args.remove(0)).to_ascii_lowercase();
+
+        Ok(Command { name, args })
+    }
+
+    /// The lower-cased command name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The arguments following the command name.
+    pub fn args(&self) -> &[Bytes] {
+        &self.args
+    }
+
+    /// The request as clients send it: an array of bulk strings.
+    pub fn to_frame(&self) -> Frame {
+        let name = Frame::Bulk(Bytes::copy_from_slice(self.name.as_bytes()));
+        let args = self.args.iter().cloned().map(Frame::Bulk);
+        Frame::Array(std::iter::once(name).chain(args).collect())
+    }
+
+    /// The keys the command names, which it may read or write: those a
+    /// user running it must have access to, and that pick the slot it runs
+    /// on in a cluster.
+    pub fn keys(&self) -> Vec<&[u8]> {
+        let args = &self.args[..];
+        let keys: &[Bytes] = match self.name() {
+            "del" | "exists" | "sinter" | "watch" => args,
+            // The last argument is the timeout.
+            "blpop" | "brpop" => &args[..args.len().saturating_sub(1)],
+            // After the subcommand.
+            "xgroup" => args.get(1..2).unwrap_or_default(),
+            // The first half of what follows `STREAMS`; the IDs come next.
+            "xread" | "xreadgroup" => {
+                let streams = args
+                    .iter()
+                    .position(|arg| arg.eq_ignore_ascii_case(b"streams"));
+                let rest = streams.map_or(&[][..], |i| &args[i + 1..]);
+                &rest[..rest.len() / 2]
+            }
+            // As many as the script says, after the script itself.
+            "eval" | "evalsha" => {
+                let numkeys = args
+                    .get(1)
+                    .and_then(|n| std::str::from_utf8(n).ok())
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .unwrap_or(0);
+                args.get(2..)
+                    .map_or(&[][..], |rest| &rest[..numkeys.min(rest.len())])
+            }
+            // The one after host and port, or those after `KEYS` if it is
+            // empty.
+            "migrate" => match args.get(2) {
+                Some(key) if key.is_empty() => {
+                    let keys = args
+                        .iter()
+                        .position(|arg| arg.eq_ignore_ascii_case(b"keys"));
+                    keys.map_or(&[][..], |i| &args[i + 1..])
+                }
+                _ => args.get(2..3).unwrap_or_default(),
+            },
+            "get" | "set" | "type" | "ttl" | "pttl" | "expire" | "pexpire" | "expireat"
+            | "pexpireat" | "persist" | "dump" | "restore" | "restore-asking" | "lpush"
+            | "rpush" | "lpop" | "rpop" | "llen" | "lrange" | "hset" | "hget" | "hdel"
+            | "hgetall" | "hincrby" | "hscan" | "sadd" | "smembers" | "sscan" | "zadd"
+            | "zrange" | "zrangebyscore" | "zscan" | "xadd" | "xsetid" | "xlen" | "xrange"
+            | "xrevrange" | "xack" | "xpending" | "xclaim" => args.get(..1).unwrap_or_default(),
+            _ => &[],
+        };
+        keys.iter().map(|key| &key[..]).collect()
+    }
+
+    /// Whether the command may modify the keyspace. Those are the commands
+    /// written to the append-only file.
+    pub fn is_write(&self) -> bool {
+        WRITE_COMMANDS.contains(&self.name.as_str())
+    }
+
+    /// Whether the command may need more memory to run.
+    pub fn needs_memory(&self) -> bool {
+        MEMORY_COMMANDS.contains(&self.name.as_str())
+    }
+
+    /// The form of this write command to log. It has the same effect when
+    /// replayed later, which for relative expirations means turning them
+    /// into absolute ones.
+    pub(crate) fn propagated(&self) -> Command {
+        match self.name() {
+            "set" => Command {
+                name: self.name.clone(),
+                args: string::propagated(&self.args),
+            },
+            "expire" | "pexpire" | "expireat" => keys::propagated(&self.name, &self.args),
+            "restore" | "restore-asking" => keys::propagated_restore(&self.args),
+            _ => self.clone(),
+        }
+    }
+
+    pub(crate) fn parse(&self) -> Parse<'_> {
+        Parse::new(&self.name, &self.args)
+    }
+}
+
+/// Run `cmd` like `apply`, logging it to the append-only file if it is a
+/// write and persistence is enabled.
+///
+/// With `appendfsync always`, this waits for the write to reach the disk,
+/// so the reply never acknowledges data that could still be lost.
+///
+/// `user` is who runs the command, which the caller already checked may.
+/// A script it runs may only run what `user` may; with no user, anything.
+pub async fn execute(cmd: &Command, db: &Db, user: Option<&Arc<User>>) -> Frame {
+    // Make room first, so the command isn't refused for want of memory
+    // eviction could free. Scripts may run any write.
+    if cmd.needs_memory() || matches!(cmd.name(), "eval" | "evalsha") {
+        db.evict();
+    }
+    if let Err(err) = check_writable(db, cmd) {
+        return Frame::Error(err.to_string());
+    }
+    // Scripts log the commands they run themselves.
+    if let "eval" | "evalsha" = cmd.name() {
+        return scripting::eval(db, cmd, user).await;
+    }
+    if !cmd.is_write() {
+        return db.concurrently(|| apply(cmd, db));
+    }
+
+    let (response, synced) = db.concurrently(|| {
+        db.write(|| {
+            let response = apply(cmd, db);
+            let logged = logged(cmd, &response);
+            (response, logged)
+        })
+    });
+
+    match synced {
+        Some(synced) => match synced.wait().await {
+            Ok(()) => response,
+            Err(err) => Frame::Error(err.to_string()),
+        },
+        None => response,
+    }
+}
+
+/// Refuse `cmd` if it is a write this server can't take: one sent by a
+/// client to a replica, or one needing memory while the keyspace is full.
+pub(crate) fn check_writable(db: &Db, cmd: &Command) -> crate::Result<()> {
+    if cmd.is_write() && db.is_replica() {
+        return Err(replication::READONLY.into());
+    }
+    if cmd.needs_memory() && db.is_full() {
+        return Err(OOM.into());
+    }
+    Ok(())
+}
+
+/// What to log for `cmd`, which replied `response`.
+///
+/// Failed commands changed nothing. Blocking pops are logged through the
+/// plain pops they turn into, and stream group reads and claims through the
+/// PEL changes they made, as effects.
+pub(crate) fn logged(cmd: &Command, response: &Frame) -> Option<Command> {
+    match (cmd.name(), response) {
+        (_, Frame::Error(_)) | ("blpop" | "brpop" | "xreadgroup" | "xclaim", _) => None,
+        // `XADD` picks the ID, which the log must repeat. A null reply means
+        // `NOMKSTREAM` found no stream.
+        ("xadd", Frame::Bulk(id)) => Some(stream::propagated(cmd, id)),
+        ("xadd", _) => None,
+        _ if cmd.is_write() => Some(cmd.propagated()),
+        _ => None,
+    }
+}
+
+/// Check the arguments of `cmd` that can be checked without running it, so
+/// that `EXEC` finds the bad ones before holding every other command off.
+pub(crate) fn check_args(cmd: &Command) -> crate::Result<()> {
+    match cmd.name() {
+        "set" => string::check_set(cmd),
+        "expire" | "pexpire" | "expireat" | "pexpireat" => keys::check_expire(cmd),
+        "blpop" | "brpop" => list::check_blocking(cmd),
+        _ => Ok(()),
+    }
+}
+
+/// Run `cmd` against the keyspace and build its reply.
+pub fn apply(cmd: &Command, db: &Db) -> Frame {
+    let mut parse = cmd.parse();
+
+    let result = match cmd.name() {
+        "ping" => connection::ping(&mut parse),
+        "echo" => connection::echo(&mut parse),
+        "get" => string::get(db, &mut parse),
+        "set" => string::set(db, &mut parse),
+        "del" => keys::del(db, &mut parse),
+        "exists" => keys::exists(db, &mut parse),
+        "type" => keys::type_(db, &mut parse),
+        "keys" => keys::keys(db, &mut parse),
+        "scan" => scan::scan(db, &mut parse),
+        "ttl" => keys::ttl(db, &mut parse),
+        "pttl" => keys::pttl(db, &mut parse),
+        "expire" => keys::expire(db, &mut parse, 1000, false),
+        "pexpire" => keys::expire(db, &mut parse, 1, false),
+        "expireat" => keys::expire(db, &mut parse, 1000, true),
+        "pexpireat" => keys::expire(db, &mut parse, 1, true),
+        "persist" => keys::persist(db, &mut parse),
+        "dump" => keys::dump(db, &mut parse),
+        // `ASKING` only matters to where the command may run.
+        "restore" | "restore-asking" => keys::restore(db, &mut parse),
+        "lpush" => list::lpush(db, &mut parse),
+        "rpush" => list::rpush(db, &mut parse),
+        "lpop" => list::lpop(db, &mut parse),
+        "rpop" => list::rpop(db, &mut parse),
+        "llen" => list::llen(db, &mut parse),
+        "lrange" => list::lrange(db, &mut parse),
+        "hset" => hash::hset(db, &mut parse),
+        "hget" => hash::hget(db, &mut parse),
+        "hdel" => hash::hdel(db, &mut parse),
+        "hgetall" => hash::hgetall(db, &mut parse),
+        "hincrby" => hash::hincrby(db, &mut parse),
+        "hscan" => scan::hscan(db, &mut parse),
+        "sadd" => set::sadd(db, &mut parse),
+        "smembers" => set::smembers(db, &mut parse),
+        "sinter" => set::sinter(db, &mut parse),
+        "sscan" => scan::sscan(db, &mut parse),
+        "zadd" => zset::zadd(db, &mut parse),
+        "zrange" => zset::zrange(db, &mut parse),
+        "zrangebyscore" => zset::zrangebyscore(db, &mut parse),
+        "zscan" => scan::zscan(db, &mut parse),
+        "xadd" => stream::xadd(db, &mut parse),
+        "xsetid" => stream::xsetid(db, &mut parse),
+        "xlen" => stream::xlen(db, &mut parse),
+        "xrange" => stream::xrange(db, &mut parse),
+        "xrevrange" => stream::xrevrange(db, &mut parse),
+        "xread" => stream::xread(db, &mut parse),
+        "xgroup" => stream::xgroup(db, &mut parse),
+        "xreadgroup" => stream::xreadgroup(db, &mut parse),
+        "xack" => stream::xack(db, &mut parse),
+        "xpending" => stream::xpending(db, &mut parse),
+        "xclaim" => stream::xclaim(db, &mut parse),
+        "save" => persistence::save(db, &mut parse),
+        "bgsave" => persistence::bgsave(db, &mut parse),
+        "lastsave" => persistence::lastsave(db, &mut parse),
+        "bgrewriteaof" => persistence::bgrewriteaof(db, &mut parse),
+        "publish" => pubsub::publish(db, &mut parse),
+        "script" => scripting::script(db, &mut parse),
+        "role" => replication::role(db, &mut parse),
+        "config" => config::config(db, &mut parse),
+        "cluster" => cluster::cluster(db, &mut parse),
+        name => Err(format!("ERR unknown command '{}'", name).into()),
+    };
+
+    result.unwrap_or_else(|err| Frame::Error(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(parts: &[&str]) -> Command {
+        let frame = Frame::Array(
+            parts
+                .iter()
+                .map(|p| Frame::Bulk(Bytes::copy_from_slice(p.as_bytes())))
+                .collect(),
+        );
+        Command::from_frame(frame).unwrap()
+    }
+
+    #[tokio::test]
+    async fn set_get_roundtrip() {
+        let db = Db::new(4);
+
+        assert_eq!(apply(&cmd(&["SET", "k", "v"]), &db), Frame::ok());
+        assert_eq!(apply(&cmd(&["get", "k"]), &db), Frame::Bulk("v".into()));
+        assert_eq!(apply(&cmd(&["GET", "nope"]), &db), Frame::Null);
+    }
+
+    #[tokio::test]
+    async fn ttl_and_persist() {
+        let db = Db::new(4);
+
+        assert_eq!(apply(&cmd(&["TTL", "k"]), &db), Frame::Integer(-2));
+        apply(&cmd(&["SET", "k", "v"]), &db);
+        assert_eq!(apply(&cmd(&["TTL", "k"]), &db), Frame::Integer(-1));
+
+        apply(&cmd(&["SET", "k", "v", "EX", "100"]), &db);
+        assert_eq!(apply(&cmd(&["TTL", "k"]), &db), Frame::Integer(100));
+        assert_eq!(apply(&cmd(&["PERSIST", "k"]), &db), Frame::Integer(1));
+        assert_eq!(apply(&cmd(&["PERSIST", "k"]), &db), Frame::Integer(0));
+        assert_eq!(apply(&cmd(&["TTL", "k"]), &db), Frame::Integer(-1));
+    }
+
+    #[tokio::test]
+    async fn set_with_absolute_deadlines() {
+        let db = Db::new(4);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+
+        let at = (now.as_secs() + 100).to_string();
+        apply(&cmd(&["SET", "k", "v", "EXAT", &at]), &db);
+        assert!(matches!(
+            apply(&cmd(&["TTL", "k"]), &db),
+            Frame::Integer(99..=100)
+        ));
+
+        // A deadline in the past deletes the key.
+        let at = (now.as_millis() - 1000).to_string();
+        assert_eq!(
+            apply(&cmd(&["SET", "k", "v", "PXAT", &at]), &db),
+            Frame::ok()
+        );
+        assert_eq!(apply(&cmd(&["EXISTS", "k"]), &db), Frame::Integer(0));
+
+        // Relative expirations are logged as absolute ones.
+        let logged = cmd(&["SET", "k", "v", "ex", "10"]).propagated();
+        assert_eq!(logged.args()[2], "PXAT");
+        let at: u128 = std::str::from_utf8(&logged.args()[3])
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(at >= now.as_millis() + 10_000 && at < now.as_millis() + 11_000);
+    }
+
+    #[tokio::test]
+    async fn dump_and_restore() {
+        let db = Db::new(4);
+        apply(&cmd(&["RPUSH", "list", "a", "b"]), &db);
+        let Frame::Bulk(payload) = apply(&cmd(&["DUMP", "list"]), &db) else {
+            panic!("DUMP replies with a bulk string");
+        };
+        assert_eq!(apply(&cmd(&["DUMP", "nope"]), &db), Frame::Null);
+
+        let restore = |parts: &[&str]| {
+            let mut args: Vec<Bytes> = parts
+                .iter()
+                .map(|p| Bytes::copy_from_slice(p.as_bytes()))
+                .collect();
+            args.insert(2, payload.clone());
+            Command::new("restore", args)
+        };
+        assert_eq!(
+            apply(&restore(&["list", "0"]), &db),
+            Frame::Error("BUSYKEY Target key name already exists.".into())
+        );
+        assert_eq!(apply(&restore(&["copy", "5000"]), &db), Frame::ok());
+        assert_eq!(
+            apply(&cmd(&["LRANGE", "copy", "0", "-1"]), &db),
+            Frame::Array(vec![Frame::Bulk("a".into()), Frame::Bulk("b".into())])
+        );
+        assert!(matches!(
+            apply(&cmd(&["PTTL", "copy"]), &db),
+            Frame::Integer(4900..=5000)
+        ));
+
+        // Logged with an absolute deadline.
+        let logged = restore(&["copy", "5000", "REPLACE"]).propagated();
+        assert_eq!(logged.args().last().unwrap(), "ABSTTL");
+    }
+
+    #[tokio::test]
+    async fn expirations_out_of_range_are_refused() {
+        let db = Db::new(1);
+        let max = i64::MAX.to_string();
+
+        for option in ["EX", "PX"] {
+            assert_eq!(
+                apply(&cmd(&["SET", "k", "v", option, &max]), &db),
+                Frame::Error("ERR invalid expire time in 'set' command".into())
+            );
+        }
+        apply(&cmd(&["SET", "k", "v"]), &db);
+        for (name, error) in [
+            ("EXPIRE", "ERR invalid expire time in 'expire' command"),
+            ("PEXPIRE", "ERR invalid expire time in 'pexpire' command"),
+            ("EXPIREAT", "ERR invalid expire time in 'expireat' command"),
+        ] {
+            assert_eq!(
+                apply(&cmd(&[name, "k", &max]), &db),
+                Frame::Error(error.into())
+            );
+        }
+
+        // The furthest deadlines that are allowed work, and the key is
+        // still there to use.
+        assert_eq!(
+            apply(&cmd(&["SET", "k", "v", "PXAT", &max]), &db),
+            Frame::ok()
+        );
+        assert_eq!(
+            apply(&cmd(&["PEXPIREAT", "k", &max]), &db),
+            Frame::Integer(1)
+        );
+        assert_eq!(apply(&cmd(&["GET", "k"]), &db), Frame::Bulk("v".into()));
+    }
+
+    #[tokio::test]
+    async fn errors_become_error_frames() {
+        let db = Db::new(1);
+
+        assert_eq!(
+            apply(&cmd(&["GET"]), &db),
+            Frame::Error("ERR wrong number of arguments for 'get' command".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["SET", "k", "v", "EX", "0"]), &db),
+            Frame::Error("ERR invalid expire time in 'set' command".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["FLY"]), &db),
+            Frame::Error("ERR unknown command 'fly'".into())
+        );
+    }
+
+    fn bulks(items: &[&str]) -> Frame {
+        Frame::Array(
+            items
+                .iter()
+                .map(|i| Frame::Bulk(Bytes::copy_from_slice(i.as_bytes())))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn list_push_pop_range() {
+        let db = Db::new(4);
+
+        assert_eq!(
+            apply(&cmd(&["RPUSH", "l", "b", "c"]), &db),
+            Frame::Integer(2)
+        );
+        assert_eq!(
+            apply(&cmd(&["LPUSH", "l", "a", "z"]), &db),
+            Frame::Integer(4)
+        );
+        assert_eq!(
+            apply(&cmd(&["LRANGE", "l", "0", "-1"]), &db),
+            bulks(&["z", "a", "b", "c"])
+        );
+        assert_eq!(
+            apply(&cmd(&["LRANGE", "l", "-2", "10"]), &db),
+            bulks(&["b", "c"])
+        );
+
+        assert_eq!(apply(&cmd(&["LPOP", "l"]), &db), Frame::Bulk("z".into()));
+        assert_eq!(apply(&cmd(&["RPOP", "l", "2"]), &db), bulks(&["c", "b"]));
+        assert_eq!(apply(&cmd(&["LLEN", "l"]), &db), Frame::Integer(1));
+
+        // Popping the last element removes the key.
+        assert_eq!(apply(&cmd(&["RPOP", "l", "5"]), &db), bulks(&["a"]));
+        assert_eq!(apply(&cmd(&["EXISTS", "l"]), &db), Frame::Integer(0));
+        assert_eq!(apply(&cmd(&["LPOP", "l"]), &db), Frame::Null);
+    }
+
+    #[tokio::test]
+    async fn wrong_type_is_rejected() {
+        let db = Db::new(4);
+        let wrongtype = Frame::Error(WRONGTYPE.to_string());
+
+        apply(&cmd(&["SET", "s", "v"]), &db);
+        apply(&cmd(&["RPUSH", "l", "v"]), &db);
+
+        assert_eq!(apply(&cmd(&["LPUSH", "s", "x"]), &db), wrongtype);
+        assert_eq!(apply(&cmd(&["LRANGE", "s", "0", "-1"]), &db), wrongtype);
+        assert_eq!(apply(&cmd(&["GET", "l"]), &db), wrongtype);
+
+        assert_eq!(
+            apply(&cmd(&["TYPE", "s"]), &db),
+            Frame::Simple("string".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["TYPE", "l"]), &db),
+            Frame::Simple("list".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["TYPE", "x"]), &db),
+            Frame::Simple("none".into())
+        );
+
+        // SET replaces a value of any type.
+        assert_eq!(apply(&cmd(&["SET", "l", "v"]), &db), Frame::ok());
+        assert_eq!(apply(&cmd(&["DEL", "l", "s", "x"]), &db), Frame::Integer(2));
+    }
+
+    #[tokio::test]
+    async fn hash_fields() {
+        let db = Db::new(4);
+
+        assert_eq!(
+            apply(&cmd(&["HSET", "h", "name", "ann", "age", "41"]), &db),
+            Frame::Integer(2)
+        );
+        // Updating a field does not count as adding one.
+        assert_eq!(
+            apply(&cmd(&["HSET", "h", "age", "42", "city", "oslo"]), &db),
+            Frame::Integer(1)
+        );
+        assert_eq!(
+            apply(&cmd(&["HGET", "h", "age"]), &db),
+            Frame::Bulk("42".into())
+        );
+        assert_eq!(apply(&cmd(&["HGET", "h", "nope"]), &db), Frame::Null);
+        assert_eq!(
+            apply(&cmd(&["TYPE", "h"]), &db),
+            Frame::Simple("hash".into())
+        );
+
+        let Frame::Map(mut pairs) = apply(&cmd(&["HGETALL", "h"]), &db) else {
+            panic!("HGETALL replies with a map");
+        };
+        pairs.sort_by_key(|(field, _)| field.to_string());
+        assert_eq!(
+            pairs,
+            [("age", "42"), ("city", "oslo"), ("name", "ann")]
+                .map(|(f, v)| (Frame::Bulk(f.into()), Frame::Bulk(v.into())))
+        );
+
+        assert_eq!(
+            apply(&cmd(&["HDEL", "h", "age", "nope", "city"]), &db),
+            Frame::Integer(2)
+        );
+        // Deleting the last field removes the key.
+        assert_eq!(apply(&cmd(&["HDEL", "h", "name"]), &db), Frame::Integer(1));
+        assert_eq!(apply(&cmd(&["EXISTS", "h"]), &db), Frame::Integer(0));
+        assert_eq!(apply(&cmd(&["HGETALL", "h"]), &db), Frame::Map(vec![]));
+    }
+
+    #[tokio::test]
+    async fn hincrby_checks_types_and_overflow() {
+        let db = Db::new(4);
+
+        assert_eq!(
+            apply(&cmd(&["HINCRBY", "h", "n", "5"]), &db),
+            Frame::Integer(5)
+        );
+        assert_eq!(
+            apply(&cmd(&["HINCRBY", "h", "n", "-7"]), &db),
+            Frame::Integer(-2)
+        );
+
+        apply(
+            &cmd(&["HSET", "h", "s", "abc", "max", &i64::MAX.to_string()]),
+            &db,
+        );
+        assert_eq!(
+            apply(&cmd(&["HINCRBY", "h", "s", "1"]), &db),
+            Frame::Error("ERR hash value is not an integer".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["HINCRBY", "h", "max", "1"]), &db),
+            Frame::Error("ERR increment or decrement would overflow".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["HINCRBY", "h", "n", "x"]), &db),
+            Frame::Error("ERR value is not an integer or out of range".into())
+        );
+        // A failed increment leaves the field alone.
+        assert_eq!(
+            apply(&cmd(&["HGET", "h", "max"]), &db),
+            Frame::Bulk(i64::MAX.to_string().into())
+        );
+
+        let wrongtype = Frame::Error(WRONGTYPE.to_string());
+        apply(&cmd(&["SET", "s", "v"]), &db);
+        assert_eq!(apply(&cmd(&["HSET", "s", "f", "v"]), &db), wrongtype);
+        assert_eq!(apply(&cmd(&["HINCRBY", "s", "f", "1"]), &db), wrongtype);
+        assert_eq!(
+            apply(&cmd(&["HGET", "h", "n"]), &db),
+            Frame::Bulk("-2".into())
+        );
+        assert_eq!(apply(&cmd(&["HGETALL", "s"]), &db), wrongtype);
+        assert_eq!(apply(&cmd(&["GET", "h"]), &db), wrongtype);
+    }
+
+    fn sorted(frame: Frame) -> Vec<String> {
+        let (Frame::Set(items) | Frame::Array(items)) = frame else {
+            panic!("expected an aggregate, got {:?}", frame);
+        };
+        let mut items: Vec<String> = items.iter().map(|i| i.to_string()).collect();
+        items.sort();
+        items
+    }
+
+    #[tokio::test]
+    async fn sets() {
+        let db = Db::new(4);
+
+        assert_eq!(
+            apply(&cmd(&["SADD", "a", "x", "y", "z", "x"]), &db),
+            Frame::Integer(3)
+        );
+        assert_eq!(
+            apply(&cmd(&["SADD", "a", "y", "w"]), &db),
+            Frame::Integer(1)
+        );
+        apply(&cmd(&["SADD", "b", "y", "z", "q"]), &db);
+        apply(&cmd(&["SADD", "c", "z", "y"]), &db);
+
+        assert_eq!(
+            sorted(apply(&cmd(&["SMEMBERS", "a"]), &db)),
+            ["w", "x", "y", "z"]
+        );
+        assert_eq!(
+            sorted(apply(&cmd(&["SINTER", "a", "b", "c"]), &db)),
+            ["y", "z"]
+        );
+        assert_eq!(
+            apply(&cmd(&["SINTER", "a", "nope"]), &db),
+            Frame::Set(vec![])
+        );
+        assert_eq!(apply(&cmd(&["SMEMBERS", "nope"]), &db), Frame::Set(vec![]));
+        assert_eq!(
+            apply(&cmd(&["TYPE", "a"]), &db),
+            Frame::Simple("set".into())
+        );
+
+        let wrongtype = Frame::Error(WRONGTYPE.to_string());
+        apply(&cmd(&["SET", "s", "v"]), &db);
+        assert_eq!(apply(&cmd(&["SADD", "s", "x"]), &db), wrongtype);
+        assert_eq!(apply(&cmd(&["SINTER", "a", "s"]), &db), wrongtype);
+        assert_eq!(apply(&cmd(&["ZADD", "a", "1", "x"]), &db), wrongtype);
+    }
+
+    fn scored(items: &[(&str, f64)]) -> Frame {
+        Frame::Array(
+            items
+                .iter()
+                .flat_map(|(m, s)| {
+                    [
+                        Frame::Bulk(Bytes::copy_from_slice(m.as_bytes())),
+                        Frame::Double(*s),
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn sorted_set_ranges() {
+        let db = Db::new(4);
+
+        assert_eq!(
+            apply(
+                &cmd(&["ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d"]),
+                &db
+            ),
+            Frame::Integer(4)
+        );
+        // Updates are only counted with CH.
+        assert_eq!(
+            apply(&cmd(&["ZADD", "z", "5", "e", "0", "a"]), &db),
+            Frame::Integer(1)
+        );
+        assert_eq!(
+            apply(&cmd(&["ZADD", "z", "CH", "1", "a", "9", "b"]), &db),
+            Frame::Integer(2)
+        );
+        assert_eq!(
+            apply(
+                &cmd(&["ZADD", "z", "XX", "GT", "CH", "0", "a", "2", "b", "7", "x"]),
+                &db
+            ),
+            Frame::Integer(0)
+        );
+        assert_eq!(
+            apply(&cmd(&["TYPE", "z"]), &db),
+            Frame::Simple("zset".into())
+        );
+
+        assert_eq!(
+            apply(&cmd(&["ZRANGE", "z", "0", "-1", "WITHSCORES"]), &db),
+            scored(&[("a", 1.0), ("c", 3.0), ("d", 4.0), ("e", 5.0), ("b", 9.0)])
+        );
+        assert_eq!(
+            apply(&cmd(&["ZRANGE", "z", "0", "1", "REV"]), &db),
+            bulks(&["b", "e"])
+        );
+        assert_eq!(
+            apply(&cmd(&["ZRANGEBYSCORE", "z", "(1", "5", "WITHSCORES"]), &db),
+            scored(&[("c", 3.0), ("d", 4.0), ("e", 5.0)])
+        );
+        assert_eq!(
+            apply(
+                &cmd(&["ZRANGEBYSCORE", "z", "-inf", "+inf", "LIMIT", "1", "2"]),
+                &db
+            ),
+            bulks(&["c", "d"])
+        );
+        assert_eq!(
+            apply(
+                &cmd(&["ZRANGEBYSCORE", "z", "3", "inf", "LIMIT", "2", "-1"]),
+                &db
+            ),
+            bulks(&["e", "b"])
+        );
+        assert_eq!(
+            apply(
+                &cmd(&[
+                    "ZRANGE", "z", "(9", "2", "BYSCORE", "REV", "LIMIT", "1", "5"
+                ]),
+                &db
+            ),
+            bulks(&["d", "c"])
+        );
+        assert_eq!(
+            apply(&cmd(&["ZRANGEBYSCORE", "z", "6", "8"]), &db),
+            bulks(&[])
+        );
+        assert_eq!(apply(&cmd(&["ZRANGE", "nope", "0", "-1"]), &db), bulks(&[]));
+
+        assert_eq!(
+            apply(&cmd(&["ZADD", "z", "nan", "a"]), &db),
+            Frame::Error("ERR value is not a valid float".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["ZADD", "z", "1", "a", "2"]), &db),
+            Frame::Error("ERR syntax error".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["ZADD", "z", "NX", "XX", "1", "a"]), &db),
+            Frame::Error("ERR XX and NX options at the same time are not compatible".into())
+        );
+        assert_eq!(
+            apply(&cmd(&["ZRANGEBYSCORE", "z", "x", "1"]), &db),
+            Frame::Error("ERR min or max is not a float".into())
+        );
+        assert!(matches!(
+            apply(&cmd(&["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"]), &db),
+            Frame::Error(_)
+        ));
+    }
+}
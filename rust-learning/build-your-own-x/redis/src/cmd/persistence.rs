@@ -0,0 +1,84 @@
+//! Commands managing snapshots of the keyspace and the append-only file.
+
+use crate::parse::Parse;
+use crate::shutdown::SaveMode;
+use crate::{Db, Frame, aof, rdb};
+
+/// `SAVE`: write a snapshot, replying once it is on disk.
+pub fn save(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    parse.finish()?;
+
+    let res = rdb::save(db, &db.snapshot_path());
+    db.finish_save(false, res.is_ok());
+    res.map_err(|err| format!("ERR {}", err))?;
+    Ok(Frame::ok())
+}
+
+/// `BGSAVE`: copy the keyspace and write the snapshot in the background.
+pub fn bgsave(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    parse.finish()?;
+
+    if !db.start_background_save() {
+        return Err("ERR Background save already in progress".into());
+    }
+
+    let handle = db.clone();
+    rdb::save_in_background(db, db.snapshot_path(), move |res| {
+        if let Err(err) = &res {
+            eprintln!("bgsave: {}", err);
+        }
+        handle.finish_save(true, res.is_ok());
+    });
+    Ok(Frame::Simple("Background saving started".to_string()))
+}
+
+/// `LASTSAVE`: Unix time of the last successful snapshot.
+pub fn lastsave(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    parse.finish()?;
+    Ok(Frame::Integer(db.last_save() as i64))
+}
+
+/// `BGREWRITEAOF`: rewrite the append-only file in the background.
+pub fn bgrewriteaof(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    parse.finish()?;
+
+    match db.aof() {
+        None => return Err("ERR Append only file is disabled".into()),
+        Some(aof) if aof.is_rewriting() => {
+            return Err("ERR Background append only file rewriting already in progress".into());
+        }
+        Some(_) => {}
+    }
+
+    let db = db.clone();
+    tokio::spawn(async move {
+        if let Err(err) = aof::rewrite(&db).await {
+            eprintln!("bgrewriteaof: {}", err);
+        }
+    });
+    Ok(Frame::Simple(
+        "Background append only file rewriting started".to_string(),
+    ))
+}
+
+/// `SHUTDOWN [NOSAVE|SAVE] [NOW] [FORCE]`: stop the server, once every
+/// connection is done; see `server::run`.
+///
+/// The connection closes right after, without a reply unless the arguments
+/// are wrong. `NOW` and `FORCE` are accepted for compatibility: no replica
+/// is waited for beyond the drain, and a failed save is reported rather
+/// than keeping the server up.
+pub fn shutdown(db: &Db, parse: &mut Parse) -> crate::Result<()> {
+    let mut save = SaveMode::Default;
+    while parse.remaining() > 0 {
+        let arg = parse.next_string()?;
+        match arg.to_ascii_lowercase().as_str() {
+            "now" | "force" => {}
+            _ if save != SaveMode::Default => return Err("ERR syntax error".into()),
+            _ => save = arg.parse().map_err(|_| "ERR syntax error")?,
+        }
+    }
+
+    db.shutdown(save);
+    Ok(())
+}
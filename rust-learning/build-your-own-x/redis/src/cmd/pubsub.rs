@@ -0,0 +1,339 @@
+//! Publish/subscribe.
+//!
+//! `PUBLISH` is an ordinary command. `SUBSCRIBE` is not: it switches the
+//! connection into subscriber mode, where messages are pushed to the client
+//! as they arrive, until the last channel is dropped. RESP2 clients can only
+//! send pub/sub commands in that mode; RESP3 clients can tell pushes apart
+//! from replies, so they may keep running any command.
+
+use crate::acl::User;
+use crate::cmd;
+use crate::frame::Protocol;
+use crate::parse::Parse;
+use crate::shutdown::Shutdown;
+use crate::{Command, Connection, Db, Frame};
+
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{StreamExt, StreamMap};
+
+/// Channel name to the stream of messages published on it.
+type Subscriptions = StreamMap<String, BroadcastStream<Bytes>>;
+
+/// `PUBLISH channel message`: replies with the number of subscribers the
+/// message was delivered to.
+pub fn publish(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let channel = parse.next_string()?;
+    let message = parse.next_bytes()?;
+    parse.finish()?;
+
+    Ok(Frame::Integer(db.publish(&channel, message) as i64))
+}
+
+/// Run `cmd`, a `SUBSCRIBE` or `UNSUBSCRIBE`, and stay in subscriber mode
+/// for as long as the connection is subscribed to at least one channel.
+///
+/// Commands received meanwhile are refused unless `user` may run them.
+///
+/// Returns `Ok(false)` once the client has unsubscribed from everything and
+/// `Ok(true)` if it disconnected while still subscribed, or the server is
+/// shutting down.
+pub async fn subscriber_mode(
+    db: &Db,
+    connection: &mut Connection,
+    cmd: Command,
+    user: &Arc<User>,
+    shutdown: &mut Shutdown,
+) -> crate::Result<bool> {
+    // Polling the `StreamMap` yields the next message from whichever channel
+    // has one.
+    let mut subscriptions = Subscriptions::new();
+    let mut pending = Some(cmd);
+
+    loop {
+        if let Some(cmd) = pending.take() {
+            let replies = match user.check(&cmd) {
+                Ok(()) => handle(db, &mut subscriptions, &cmd, user, connection.protocol()).await,
+                Err(err) => Err(err),
+            }
+            .unwrap_or_else(|err| vec![Frame::Error(err.to_string())]);
+            for reply in &replies {
+                connection.write_frame(reply).await?;
+            }
+
+            if subscriptions.is_empty() {
+                return Ok(false);
+            }
+        }
+
+        // Nothing else to reply to right now; send what is queued before
+        // waiting for the next message or request.
+        connection.flush().await?;
+
+        tokio::select! {
+            Some((channel, msg)) = subscriptions.next() => {
+                // A lagging subscriber misses the messages that were dropped
+                // from the channel's buffer; carry on with the rest.
+                if let Ok(msg) = msg {
+                    let frame = Frame::Push(vec![
+                        Frame::Bulk(Bytes::from_static(b"message")),
+                        Frame::Bulk(Bytes::from(channel)),
+                        Frame::Bulk(msg),
+                    ]);
+                    connection.write_frame(&frame).await?;
+                }
+            }
+            res = connection.read_frame() => {
+                let Some(frame) = res? else {
+                    return Ok(true);
+                };
+
+                match Command::from_frame(frame) {
+                    Ok(cmd) => pending = Some(cmd),
+                    Err(err) => {
+                        let frame = Frame::Error(format!("ERR {}", err));
+                        connection.write_frame(&frame).await?;
+                    }
+                }
+            }
+            _ = shutdown.recv() => return Ok(true),
+        }
+    }
+}
+
+/// Run one command received in subscriber mode, returning its replies.
+async fn handle(
+    db: &Db,
+    subscriptions: &mut Subscriptions,
+    cmd: &Command,
+    user: &Arc<User>,
+    protocol: Protocol,
+) -> crate::Result<Vec<Frame>> {
+    let mut parse = Parse::new(cmd.name(), cmd.args());
+    let mut replies = Vec::new();
+
+    match cmd.name() {
+        "subscribe" => {
+            // At least one channel is required.
+            let mut channels = vec![parse.next_string()?];
+            while parse.remaining() > 0 {
+                channels.push(parse.next_string()?);
+            }
+
+            for channel in channels {
+                if !subscriptions.contains_key(&channel) {
+                    let rx = BroadcastStream::new(db.subscribe(channel.clone()));
+                    subscriptions.insert(channel.clone(), rx);
+                }
+                replies.push(confirmation(
+                    "subscribe",
+                    Some(channel),
+                    subscriptions.len(),
+                ));
+            }
+        }
+        "unsubscribe" => {
+            let mut channels = Vec::new();
+            while parse.remaining() > 0 {
+                channels.push(parse.next_string()?);
+            }
+
+            // No argument means every channel.
+            if channels.is_empty() {
+                channels = subscriptions.keys().cloned().collect();
+            }
+
+            if channels.is_empty() {
+                replies.push(confirmation("unsubscribe", None, 0));
+            }
+
+            for channel in channels {
+                subscriptions.remove(&channel);
+                replies.push(confirmation(
+                    "unsubscribe",
+                    Some(channel),
+                    subscriptions.len(),
+                ));
+            }
+        }
+        // RESP3 clients can tell replies from pushes, so they get the usual
+        // reply from the arm below.
+        "ping" if protocol == Protocol::Resp2 => {
+            let message = match parse.remaining() {
+                0 => Bytes::new(),
+                _ => parse.next_bytes()?,
+            };
+            parse.finish()?;
+
+            replies.push(Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"pong")),
+                Frame::Bulk(message),
+            ]));
+        }
+        _ if protocol == Protocol::Resp3 => replies.push(cmd::execute(cmd, db, Some(user)).await),
+        name => {
+            return Err(format!(
+                "ERR Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING are allowed in this context",
+                name
+            )
+            .into());
+        }
+    }
+
+    Ok(replies)
+}
+
+/// The `[kind, channel, count]` push confirming a (un)subscription.
+fn confirmation(kind: &'static str, channel: Option<String>, count: usize) -> Frame {
+    Frame::Push(vec![
+        Frame::Bulk(Bytes::from_static(kind.as_bytes())),
+        match channel {
+            Some(channel) => Frame::Bulk(Bytes::from(channel)),
+            None => Frame::Null,
+        },
+        Frame::Integer(count as i64),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn start() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server::run(listener, Db::new(4)));
+        addr
+    }
+
+    async fn connect(addr: std::net::SocketAddr) -> Connection {
+        Connection::new(TcpStream::connect(addr).await.unwrap())
+    }
+
+    async fn send(conn: &mut Connection, parts: &[&str]) {
+        let frame = Frame::Array(
+            parts
+                .iter()
+                .map(|p| Frame::Bulk(Bytes::copy_from_slice(p.as_bytes())))
+                .collect(),
+        );
+        conn.write_frame(&frame).await.unwrap();
+        conn.flush().await.unwrap();
+    }
+
+    fn strings(parts: &[&str]) -> Frame {
+        Frame::Array(
+            parts
+                .iter()
+                .map(|p| Frame::Bulk(Bytes::copy_from_slice(p.as_bytes())))
+                .collect(),
+        )
+    }
+
+    /// Pushes reach RESP2 clients as plain arrays.
+    fn as_array(frame: Frame) -> Frame {
+        match frame {
+            Frame::Push(items) => Frame::Array(items),
+            frame => frame,
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_reaches_subscribers() {
+        let addr = start().await;
+        let mut sub = connect(addr).await;
+        let mut publisher = connect(addr).await;
+
+        send(&mut sub, &["SUBSCRIBE", "news", "sport"]).await;
+        for (channel, count) in [("news", 1), ("sport", 2)] {
+            let reply = sub.read_frame().await.unwrap().unwrap();
+            assert_eq!(
+                reply,
+                as_array(confirmation("subscribe", Some(channel.into()), count))
+            );
+        }
+
+        send(&mut publisher, &["PUBLISH", "news", "hello"]).await;
+        assert_eq!(
+            publisher.read_frame().await.unwrap(),
+            Some(Frame::Integer(1))
+        );
+        assert_eq!(
+            sub.read_frame().await.unwrap(),
+            Some(strings(&["message", "news", "hello"]))
+        );
+
+        send(&mut publisher, &["PUBLISH", "nobody", "hello"]).await;
+        assert_eq!(
+            publisher.read_frame().await.unwrap(),
+            Some(Frame::Integer(0))
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriber_mode_restricts_commands_until_unsubscribed() {
+        let addr = start().await;
+        let mut conn = connect(addr).await;
+
+        send(&mut conn, &["SUBSCRIBE", "a"]).await;
+        conn.read_frame().await.unwrap();
+
+        send(&mut conn, &["GET", "k"]).await;
+        assert!(matches!(
+            conn.read_frame().await.unwrap(),
+            Some(Frame::Error(_))
+        ));
+
+        send(&mut conn, &["PING"]).await;
+        assert_eq!(
+            conn.read_frame().await.unwrap(),
+            Some(strings(&["pong", ""]))
+        );
+
+        send(&mut conn, &["UNSUBSCRIBE"]).await;
+        assert_eq!(
+            conn.read_frame().await.unwrap(),
+            Some(as_array(confirmation("unsubscribe", Some("a".into()), 0)))
+        );
+
+        // Back in normal mode.
+        send(&mut conn, &["GET", "k"]).await;
+        assert_eq!(conn.read_frame().await.unwrap(), Some(Frame::Null));
+    }
+
+    #[tokio::test]
+    async fn resp3_subscribers_get_pushes_and_can_run_commands() {
+        let addr = start().await;
+        let mut conn = connect(addr).await;
+
+        send(&mut conn, &["HELLO", "3"]).await;
+        assert!(matches!(
+            conn.read_frame().await.unwrap(),
+            Some(Frame::Map(_))
+        ));
+
+        send(&mut conn, &["SUBSCRIBE", "a"]).await;
+        assert_eq!(
+            conn.read_frame().await.unwrap(),
+            Some(confirmation("subscribe", Some("a".into()), 1))
+        );
+
+        send(&mut conn, &["GET", "k"]).await;
+        assert_eq!(conn.read_frame().await.unwrap(), Some(Frame::Null));
+
+        send(&mut conn, &["PING"]).await;
+        assert_eq!(
+            conn.read_frame().await.unwrap(),
+            Some(Frame::Simple("PONG".into()))
+        );
+        send(&mut conn, &["PING", "hi"]).await;
+        assert_eq!(
+            conn.read_frame().await.unwrap(),
+            Some(Frame::Bulk("hi".into()))
+        );
+    }
+}
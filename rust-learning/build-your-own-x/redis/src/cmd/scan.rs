@@ -0,0 +1,254 @@
+//! Cursor-based iteration: `SCAN` over the keyspace, and `HSCAN`, `SSCAN`
+//! and `ZSCAN` over the elements of a value.
+//!
+//! Each call returns a few elements along with a cursor to pass to the next
+//! one, until the cursor comes back as `0`. Elements are walked in the order
+//! of `db::scan_position`, a hash of each that never changes, so one present
+//! for the whole iteration is returned exactly once, however the collection
+//! grows, shrinks or gets rehashed in between.
+//!
+//! `COUNT` bounds how many elements a call looks at before `MATCH` filters
+//! them, so a call may return nothing while the cursor is not `0` yet.
+//!
+//! The keyspace keeps its keys in that order, so a `SCAN` only costs as much
+//! as its `COUNT`. Values don't: each call over one sorts the elements past
+//! the cursor, which costs as much as the value is large.
+
+use crate::cmd::WRONGTYPE;
+use crate::db::scan_position;
+use crate::parse::Parse;
+use crate::{Db, Frame, Value, glob};
+
+use bytes::Bytes;
+
+/// `COUNT` when none is given.
+const DEFAULT_COUNT: usize = 10;
+
+/// `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]`
+///
+/// `TYPE` only keeps keys holding that type of value, as named by `TYPE`.
+pub fn scan(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let cursor = cursor(parse)?;
+    let options = Options::parse(parse, "scan")?;
+
+    let (next, keys) = db.scan(cursor, options.count, |key, value| {
+        options.matches(key.as_bytes())
+            && options
+                .type_
+                .as_ref()
+                .is_none_or(|t| t == value.type_name())
+    });
+    let keys = keys.into_iter().map(|key| Frame::Bulk(Bytes::from(key)));
+    Ok(reply(next, keys.collect()))
+}
+
+/// `HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]`: fields and
+/// their values, or only the fields with `NOVALUES`.
+pub fn hscan(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let cursor = cursor(parse)?;
+    let options = Options::parse(parse, "hscan")?;
+
+    let mut shard = db.lock(&key);
+    let hash = match shard.get(&key) {
+        Some(Value::Hash(hash)) => hash,
+        Some(_) => return Err(WRONGTYPE.into()),
+        None => return Ok(reply(0, vec![])),
+    };
+
+    let (next, fields) = page(hash.iter(), cursor, &options);
+    let mut items = Vec::new();
+    for (field, value) in fields {
+        items.push(Frame::Bulk(field.clone()));
+        if !options.novalues {
+            items.push(Frame::Bulk(value.clone()));
+        }
+    }
+    Ok(reply(next, items))
+}
+
+/// `SSCAN key cursor [MATCH pattern] [COUNT count]`
+pub fn sscan(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let cursor = cursor(parse)?;
+    let options = Options::parse(parse, "sscan")?;
+
+    let mut shard = db.lock(&key);
+    let set = match shard.get(&key) {
+        Some(Value::Set(set)) => set,
+        Some(_) => return Err(WRONGTYPE.into()),
+        None => return Ok(reply(0, vec![])),
+    };
+
+    let (next, members) = page(set.iter().map(|m| (m, ())), cursor, &options);
+    let items = members.into_iter().map(|(m, _)| Frame::Bulk(m.clone()));
+    Ok(reply(next, items.collect()))
+}
+
+/// `ZSCAN key cursor [MATCH pattern] [COUNT count]`: members and their
+/// scores.
+pub fn zscan(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let cursor = cursor(parse)?;
+    let options = Options::parse(parse, "zscan")?;
+
+    let mut shard = db.lock(&key);
+    let zset = match shard.get(&key) {
+        Some(Value::ZSet(zset)) => zset,
+        Some(_) => return Err(WRONGTYPE.into()),
+        None => return Ok(reply(0, vec![])),
+    };
+
+    let members = zset.range_by_rank(0, zset.len(), false);
+    let (next, members) = page(members, cursor, &options);
+    let mut items = Vec::new();
+    for (member, score) in members {
+        items.push(Frame::Bulk(member.clone()));
+        items.push(Frame::Double(score));
+    }
+    Ok(reply(next, items))
+}
+
+/// What follows the cursor.
+struct Options {
+    pattern: Option<Bytes>,
+    count: usize,
+
+    /// `SCAN` only.
+    type_: Option<String>,
+
+    /// `HSCAN` only.
+    novalues: bool,
+}
+
+impl Options {
+    fn parse(parse: &mut Parse, command: &str) -> crate::Result<Options> {
+        let mut options = Options {
+            pattern: None,
+            count: DEFAULT_COUNT,
+            type_: None,
+            novalues: false,
+        };
+
+        while parse.remaining() > 0 {
+            match parse.next_string()?.to_ascii_uppercase().as_str() {
+                "MATCH" => options.pattern = Some(parse.next_bytes()?),
+                "COUNT" => {
+                    options.count = match parse.next_int()? {
+                        count if count > 0 => count as usize,
+                        _ => return Err("ERR syntax error".into()),
+                    }
+                }
+                "TYPE" if command == "scan" => {
+                    options.type_ = Some(parse.next_string()?.to_ascii_lowercase());
+                }
+                "NOVALUES" if command == "hscan" => options.novalues = true,
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(options)
+    }
+
+    fn matches(&self, element: &[u8]) -> bool {
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| glob::matches(pattern, element))
+    }
+}
+
+/// Parse a cursor: any unsigned 64-bit integer.
+fn cursor(parse: &mut Parse) -> crate::Result<u64> {
+    let arg = parse.next_bytes()?;
+    std::str::from_utf8(&arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| "ERR invalid cursor".into())
+}
+
+/// The elements of a value from `cursor` on, as many as `options.count`,
+/// that `options` lets through, with the cursor to continue from.
+fn page<'a, T>(
+    elements: impl Iterator<Item = (&'a Bytes, T)>,
+    cursor: u64,
+    options: &Options,
+) -> (u64, Vec<(&'a Bytes, T)>) {
+    let mut rest: Vec<_> = elements
+        .map(|(element, extra)| (scan_position(element), element, extra))
+        .filter(|(position, ..)| *position >= cursor)
+        .collect();
+    rest.sort_unstable_by_key(|(position, ..)| *position);
+
+    // Elements sharing a position are all taken together, as the cursor
+    // can't point between them.
+    let mut end = options.count.min(rest.len());
+    while end > 0 && end < rest.len() && rest[end].0 == rest[end - 1].0 {
+        end += 1;
+    }
+    let next = rest.get(end).map_or(0, |(position, ..)| *position);
+
+    rest.truncate(end);
+    let found = rest
+        .into_iter()
+        .filter(|(_, element, _)| options.matches(element))
+        .map(|(_, element, extra)| (element, extra))
+        .collect();
+    (next, found)
+}
+
+/// The reply of every scan: the next cursor, then the elements found.
+fn reply(next: u64, items: Vec<Frame>) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from(next.to_string())),
+        Frame::Array(items),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(count: usize, pattern: Option<&str>) -> Options {
+        Options {
+            pattern: pattern.map(|p| Bytes::copy_from_slice(p.as_bytes())),
+            count,
+            type_: None,
+            novalues: false,
+        }
+    }
+
+    #[test]
+    fn pages_cover_every_element_once() {
+        let elements: Vec<Bytes> = (0..100).map(|i| Bytes::from(format!("e{}", i))).collect();
+
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let items = elements.iter().map(|e| (e, ()));
+            let (next, found) = page(items, cursor, &options(7, None));
+            assert!(found.len() <= 7);
+            seen.extend(found.into_iter().map(|(e, _)| e.clone()));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        seen.sort();
+        let mut expected = elements.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn match_filters_after_counting() {
+        let elements: Vec<Bytes> = ["apple", "avocado", "banana"]
+            .map(Bytes::from)
+            .into_iter()
+            .collect();
+        let items = elements.iter().map(|e| (e, ()));
+
+        let (next, found) = page(items, 0, &options(10, Some("a*")));
+        assert_eq!(next, 0);
+        assert_eq!(found.len(), 2);
+    }
+}
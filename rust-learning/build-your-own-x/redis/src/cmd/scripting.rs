@@ -0,0 +1,497 @@
+//! Server-side scripting: `EVAL`, `EVALSHA` and `SCRIPT`.
+//!
+//! Scripts are written in [Rhai] rather than Lua. They see their key names
+//! in the `KEYS` array and their other arguments in `ARGV`, and run commands
+//! with `redis::cmd("SET", KEYS[0], ARGV[0])`, which raises the command's
+//! error, or `redis::pcmd(..)`, which returns it as `#{err: ".."}`. These
+//! are Lua's `redis.call` and `redis.pcall`, renamed since `call` is a
+//! keyword in Rhai.
+//!
+//! A script runs through `Db::atomically`, like a transaction: no other
+//! client sees it half done. The commands it ran are logged, not the script
+//! itself, so replaying the log does not depend on it. To keep a runaway
+//! script from wedging the server, each one gets a budget of operations and
+//! is killed once it is spent. Writes it made until then stay.
+//!
+//! [Rhai]: https://rhai.rs
+
+use crate::acl::User;
+use crate::cmd::{self, list, transaction};
+use crate::parse::Parse;
+use crate::{Command, Db, Frame};
+
+use bytes::Bytes;
+use rhai::packages::{Package, StandardPackage};
+use rhai::{AST, Array, Dynamic, Engine, EvalAltResult, ImmutableString, Map, Module, Scope};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// How many operations a script may run. An operation is roughly one
+/// expression or statement.
+pub const SCRIPT_BUDGET: u64 = 1_000_000;
+
+/// Commands a script cannot run: those taking over the connection, and
+/// those only making sense between a client and the server.
+const NOT_ALLOWED: &[&str] = &[
+    "config",
+    "eval",
+    "evalsha",
+    "multi",
+    "exec",
+    "discard",
+    "watch",
+    "unwatch",
+    "subscribe",
+    "unsubscribe",
+    "hello",
+    "auth",
+    "acl",
+    "shutdown",
+    "asking",
+    "migrate",
+];
+
+/// `EVAL script numkeys [key ...] [arg ...]` and
+/// `EVALSHA sha1 numkeys [key ...] [arg ...]`
+///
+/// Replies with what the script returned. The commands it runs are checked
+/// against the permissions of `user`, if given.
+pub async fn eval(db: &Db, cmd: &Command, user: Option<&Arc<User>>) -> Frame {
+    // Compiling can take a while; do it before holding everyone off.
+    let script = match Script::parse(db, cmd) {
+        Ok(script) => script,
+        Err(err) => return Frame::Error(err.to_string()),
+    };
+
+    let (reply, synced) = db.atomically(|| {
+        db.write(|| {
+            let mut logged = Vec::new();
+            let reply = script.run(db, user, &mut logged);
+            (reply, transaction::wrap(logged))
+        })
+    });
+
+    match synced {
+        Some(synced) => match synced.wait().await {
+            Ok(()) => reply,
+            Err(err) => Frame::Error(err.to_string()),
+        },
+        None => reply,
+    }
+}
+
+/// `EVAL` or `EVALSHA` run as part of a transaction, which already holds
+/// everyone off. The commands the script ran are added to `logged`.
+pub(crate) fn eval_now(
+    db: &Db,
+    cmd: &Command,
+    user: Option<&Arc<User>>,
+    logged: &mut Vec<Command>,
+) -> Frame {
+    match Script::parse(db, cmd) {
+        Ok(script) => script.run(db, user, logged),
+        Err(err) => Frame::Error(err.to_string()),
+    }
+}
+
+/// `SCRIPT LOAD script`, `SCRIPT EXISTS sha1 [sha1 ...]` and `SCRIPT FLUSH`
+pub fn script(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let subcommand = parse.next_string()?.to_ascii_lowercase();
+
+    match subcommand.as_str() {
+        "load" => {
+            let source = parse.next_string()?;
+            parse.finish()?;
+
+            let sha = sha1(&source);
+            load(db, &sha, &source)?;
+            Ok(Frame::Bulk(Bytes::from(sha)))
+        }
+        "exists" => {
+            let mut shas = vec![parse.next_string()?];
+            while parse.remaining() > 0 {
+                shas.push(parse.next_string()?);
+            }
+
+            let scripts = db.scripts();
+            let exists = shas
+                .iter()
+                .map(|sha| Frame::Integer(scripts.contains_key(&sha.to_ascii_lowercase()) as i64))
+                .collect();
+            Ok(Frame::Array(exists))
+        }
+        "flush" => {
+            // `ASYNC` and `SYNC` make no difference here.
+            if parse.remaining() > 0 {
+                parse.next_string()?;
+            }
+            parse.finish()?;
+
+            db.scripts().clear();
+            Ok(Frame::ok())
+        }
+        _ => Err(format!(
+            "ERR unknown subcommand '{}'. Try SCRIPT LOAD, SCRIPT EXISTS or SCRIPT FLUSH.",
+            subcommand
+        )
+        .into()),
+    }
+}
+
+/// A script along with its keys and arguments, ready to run.
+struct Script {
+    ast: Arc<AST>,
+    keys: Array,
+    argv: Array,
+}
+
+impl Script {
+    fn parse(db: &Db, cmd: &Command) -> crate::Result<Script> {
+        let mut parse = cmd.parse();
+
+        let ast = match cmd.name() {
+            "eval" => {
+                let source = parse.next_string()?;
+                load(db, &sha1(&source), &source)?
+            }
+            _ => {
+                let sha = parse.next_string()?.to_ascii_lowercase();
+                db.scripts()
+                    .get(&sha)
+                    .cloned()
+                    .ok_or("NOSCRIPT No matching script. Please use EVAL.")?
+            }
+        };
+
+        let numkeys = parse.next_int()?;
+        if numkeys < 0 {
+            return Err("ERR Number of keys can't be negative".into());
+        }
+        if numkeys as usize > parse.remaining() {
+            return Err("ERR Number of keys can't be greater than number of args".into());
+        }
+
+        let mut keys = Array::new();
+        for _ in 0..numkeys {
+            keys.push(to_dynamic(parse.next_bytes()?));
+        }
+        let mut argv = Array::new();
+        while parse.remaining() > 0 {
+            argv.push(to_dynamic(parse.next_bytes()?));
+        }
+
+        Ok(Script { ast, keys, argv })
+    }
+
+    /// Run the script as `user`, adding the commands it ran to `logged`.
+    fn run(&self, db: &Db, user: Option<&Arc<User>>, logged: &mut Vec<Command>) -> Frame {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let engine = engine(Some(Keyspace {
+            db: db.clone(),
+            user: user.cloned(),
+            ran: ran.clone(),
+        }));
+
+        let mut scope = Scope::new();
+        scope.push_constant("KEYS", self.keys.clone());
+        scope.push_constant("ARGV", self.argv.clone());
+
+        let result = engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast);
+        logged.append(&mut ran.lock().unwrap());
+
+        match result {
+            Ok(value) => to_frame(value),
+            Err(err) => match *err {
+                EvalAltResult::ErrorTooManyOperations(_) => Frame::Error(format!(
+                    "ERR Script killed after running out of its budget of {} operations",
+                    SCRIPT_BUDGET
+                )),
+                // Errors raised by `redis::cmd`, or thrown by the script.
+                EvalAltResult::ErrorRuntime(value, _) => Frame::Error(error_reply(value)),
+                err => Frame::Error(format!("ERR Error running script: {}", err)),
+            },
+        }
+    }
+}
+
+/// Compile `source` and cache it under `sha`, unless it already is.
+fn load(db: &Db, sha: &str, source: &str) -> crate::Result<Arc<AST>> {
+    if let Some(ast) = db.scripts().get(sha) {
+        return Ok(ast.clone());
+    }
+
+    let ast = engine(None)
+        .compile(source)
+        .map_err(|err| format!("ERR Error compiling script: {}", err))?;
+    let ast = Arc::new(ast);
+    db.scripts().insert(sha.to_string(), ast.clone());
+    Ok(ast)
+}
+
+/// Hex digest naming a script.
+fn sha1(source: &str) -> String {
+    sha1_smol::Sha1::from(source).digest().to_string()
+}
+
+/// What a running script's `redis` module works with.
+#[derive(Clone)]
+struct Keyspace {
+    db: Db,
+
+    /// Whose permissions the script's commands are checked against.
+    user: Option<Arc<User>>,
+
+    /// The commands the script ran, to log.
+    ran: Arc<Mutex<Vec<Command>>>,
+}
+
+/// An engine with the standard library and the limits every script runs
+/// under. Given a keyspace, it also has the `redis` module.
+///
+/// The engine is cheap to build: the standard library is only set up once.
+fn engine(keyspace: Option<Keyspace>) -> Engine {
+    static STD: OnceLock<rhai::Shared<Module>> = OnceLock::new();
+    let std = STD.get_or_init(|| StandardPackage::new().as_shared_module());
+
+    // A raw engine can neither print nor import modules from files.
+    let mut engine = Engine::new_raw();
+    engine.register_global_module(std.clone());
+    engine.disable_symbol("eval");
+    engine.set_max_operations(SCRIPT_BUDGET);
+    engine.set_max_call_levels(32);
+
+    if let Some(keyspace) = keyspace {
+        engine.register_static_module("redis", redis_module(keyspace).into());
+    }
+    engine
+}
+
+/// The `redis` module: `cmd` and `pcmd`, taking the command name and up
+/// to eight arguments, or a single array holding all of them. The commands
+/// run are recorded to `keyspace.ran`.
+fn redis_module(keyspace: Keyspace) -> Module {
+    let mut module = Module::new();
+
+    // Rhai has no variadic functions; register one overload per arity.
+    macro_rules! overloads {
+        ($($arg:ident)*) => {
+            for (name, raise) in [("cmd", true), ("pcmd", false)] {
+                let keyspace = keyspace.clone();
+                module.set_native_fn(
+                    name,
+                    move |name: ImmutableString, $($arg: Dynamic),*| {
+                        call(&keyspace, name.into(), vec![$($arg),*], raise)
+                    },
+                );
+            }
+        };
+    }
+    overloads!();
+    overloads!(a);
+    overloads!(a b);
+    overloads!(a b c);
+    overloads!(a b c d);
+    overloads!(a b c d e);
+    overloads!(a b c d e f);
+    overloads!(a b c d e f g);
+    overloads!(a b c d e f g h);
+
+    for (name, raise) in [("cmd", true), ("pcmd", false)] {
+        let keyspace = keyspace.clone();
+        module.set_native_fn(name, move |mut parts: Array| {
+            if parts.is_empty() {
+                return Err(
+                    "ERR Please specify at least one argument for this redis lib call".into(),
+                );
+            }
+            let name = parts.remove(0);
+            call(&keyspace, name, parts, raise)
+        });
+    }
+
+    module
+}
+
+/// Run a command on behalf of a script and convert its reply.
+///
+/// With `raise`, an error reply becomes a script error; otherwise it is
+/// returned as `#{err: ".."}`.
+fn call(
+    keyspace: &Keyspace,
+    name: Dynamic,
+    args: Vec<Dynamic>,
+    raise: bool,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let name = String::from_utf8_lossy(&to_bytes(name)?).to_ascii_lowercase();
+    let args = args.into_iter().map(to_bytes).collect::<Result<_, _>>()?;
+    let cmd = Command::new(&name, args);
+    let db = &keyspace.db;
+
+    let allowed = match &keyspace.user {
+        Some(user) => user.check(&cmd),
+        None => Ok(()),
+    };
+    let reply = if NOT_ALLOWED.contains(&cmd.name()) {
+        Frame::Error("ERR This Redis command is not allowed from script".into())
+    } else if let Err(err) = allowed.and_then(|()| cmd::check_writable(db, &cmd)) {
+        Frame::Error(err.to_string())
+    } else {
+        let reply = match cmd.name() {
+            // Nothing else can run while the script does, so there is no
+            // point in waiting.
+            "blpop" | "brpop" => {
+                list::pop_now(db, &cmd).unwrap_or_else(|err| Frame::Error(err.to_string()))
+            }
+            _ => cmd::apply(&cmd, db),
+        };
+
+        let mut logged = keyspace.ran.lock().unwrap();
+        logged.extend(cmd::logged(&cmd, &reply));
+        logged.extend(db.take_effects());
+        reply
+    };
+
+    match reply {
+        Frame::Error(err) if raise => Err(err.into()),
+        reply => Ok(to_dynamic_frame(reply)),
+    }
+}
+
+/// The bytes of a command argument given by a script.
+fn to_bytes(value: Dynamic) -> Result<Bytes, Box<EvalAltResult>> {
+    if value.is_string() || value.is_char() || value.is_int() || value.is_float() {
+        Ok(Bytes::from(value.to_string()))
+    } else if value.is_blob() {
+        Ok(Bytes::from(value.cast::<rhai::Blob>()))
+    } else {
+        Err(format!(
+            "ERR Command arguments must be strings or numbers, got {}",
+            value.type_name()
+        )
+        .into())
+    }
+}
+
+/// A string for the script, or a blob if the bytes are not valid UTF-8.
+fn to_dynamic(bytes: Bytes) -> Dynamic {
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(s) => s.into(),
+        Err(err) => Dynamic::from_blob(err.into_bytes()),
+    }
+}
+
+/// A command's reply, as seen by the script.
+fn to_dynamic_frame(frame: Frame) -> Dynamic {
+    match frame {
+        Frame::Simple(s) => s.into(),
+        Frame::Bulk(bytes) => to_dynamic(bytes),
+        Frame::Integer(n) => n.into(),
+        Frame::Double(n) => n.into(),
+        Frame::Boolean(b) => b.into(),
+        Frame::BigNumber(n) => n.into(),
+        Frame::Verbatim { text, .. } => to_dynamic(text),
+        Frame::Null => Dynamic::UNIT,
+        Frame::Error(err) => {
+            let mut map = Map::new();
+            map.insert("err".into(), err.into());
+            map.into()
+        }
+        Frame::Array(items) | Frame::Set(items) | Frame::Push(items) => items
+            .into_iter()
+            .map(to_dynamic_frame)
+            .collect::<Array>()
+            .into(),
+        Frame::Map(pairs) => {
+            let mut map = Map::new();
+            for (key, value) in pairs {
+                let key = match key {
+                    Frame::Bulk(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                    key => key.to_string(),
+                };
+                map.insert(key.into(), to_dynamic_frame(value));
+            }
+            map.into()
+        }
+    }
+}
+
+/// The reply to a script returning `value`.
+///
+/// As with Lua scripts in Redis, `true` becomes `1`, `false` a null, and a
+/// float is truncated to an integer. A map holding `err` or `ok` becomes an
+/// error or a status reply; any other map is replied as a map.
+fn to_frame(value: Dynamic) -> Frame {
+    if value.is_unit() {
+        return Frame::Null;
+    }
+    if let Some(b) = value.clone().try_cast::<bool>() {
+        return if b { Frame::Integer(1) } else { Frame::Null };
+    }
+    if let Some(n) = value.clone().try_cast::<i64>() {
+        return Frame::Integer(n);
+    }
+    if let Some(n) = value.clone().try_cast::<f64>() {
+        return Frame::Integer(n as i64);
+    }
+    if value.is_blob() {
+        return Frame::Bulk(Bytes::from(value.cast::<rhai::Blob>()));
+    }
+    if value.is_array() {
+        let items = value.cast::<Array>();
+        return Frame::Array(items.into_iter().map(to_frame).collect());
+    }
+    if value.is_map() {
+        let mut map = value.cast::<Map>();
+        if let Some(err) = map.remove("err") {
+            return Frame::Error(error_reply(err));
+        }
+        if let Some(ok) = map.remove("ok") {
+            return Frame::Simple(ok.to_string());
+        }
+        let pairs = map
+            .into_iter()
+            .map(|(key, value)| (Frame::Bulk(Bytes::from(key.to_string())), to_frame(value)))
+            .collect();
+        return Frame::Map(pairs);
+    }
+
+    Frame::Bulk(Bytes::from(value.to_string()))
+}
+
+/// An error reply carrying `value`. Messages not starting with an error
+/// code, like `WRONGTYPE`, get the generic `ERR`.
+fn error_reply(value: Dynamic) -> String {
+    let msg = value.to_string();
+    let code = msg.split(' ').next().unwrap_or_default();
+    if !code.is_empty() && code.bytes().all(|b| b.is_ascii_uppercase()) {
+        msg
+    } else {
+        format!("ERR {}", msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_script_values() {
+        assert_eq!(to_frame(Dynamic::UNIT), Frame::Null);
+        assert_eq!(to_frame(true.into()), Frame::Integer(1));
+        assert_eq!(to_frame(false.into()), Frame::Null);
+        assert_eq!(to_frame(3.9_f64.into()), Frame::Integer(3));
+        assert_eq!(to_frame("x".into()), Frame::Bulk("x".into()));
+
+        let mut map = Map::new();
+        map.insert("err".into(), "no good".into());
+        assert_eq!(to_frame(map.into()), Frame::Error("ERR no good".into()));
+
+        let mut map = Map::new();
+        map.insert("ok".into(), "FINE".into());
+        assert_eq!(to_frame(map.into()), Frame::Simple("FINE".into()));
+    }
+
+    #[test]
+    fn names_scripts_by_sha1() {
+        assert_eq!(sha1("return 1"), "e0e1f9fabfc9d4800c877a703b823ac0578ff8db");
+    }
+}
@@ -0,0 +1,88 @@
+//! Commands operating on set values.
+//!
+//! Sets are unordered, so replies list members in whatever order the
+//! underlying `HashSet` yields them. RESP3 clients receive them as a set.
+
+use crate::cmd::WRONGTYPE;
+use crate::parse::Parse;
+use crate::{Db, Frame, Value};
+
+use bytes::Bytes;
+use std::collections::HashSet;
+
+/// `SADD key member [member ...]`: replies with the number of members that
+/// were not in the set yet.
+pub fn sadd(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let mut members = vec![parse.next_bytes()?];
+    while parse.remaining() > 0 {
+        members.push(parse.next_bytes()?);
+    }
+
+    let mut shard = db.lock(&key);
+    let set = match shard.get_mut(&key) {
+        Some(Value::Set(set)) => set,
+        Some(_) => return Err(WRONGTYPE.into()),
+        None => {
+            shard.insert(key.clone(), Value::Set(HashSet::new()));
+            match shard.get_mut(&key) {
+                Some(Value::Set(set)) => set,
+                _ => unreachable!(),
+            }
+        }
+    };
+
+    let added = members
+        .into_iter()
+        .filter(|member| set.insert(member.clone()))
+        .count();
+    Ok(Frame::Integer(added as i64))
+}
+
+/// `SMEMBERS key`
+pub fn smembers(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    let members = match set(db, &key)? {
+        Some(set) => set.into_iter().map(Frame::Bulk).collect(),
+        None => vec![],
+    };
+    Ok(Frame::Set(members))
+}
+
+/// `SINTER key [key ...]`: the members present in every one of the sets. A
+/// missing key counts as an empty set.
+///
+/// Each key is read under its own shard lock, so the sets are not captured
+/// at a single instant when they live in different shards.
+pub fn sinter(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let mut keys = vec![parse.next_string()?];
+    while parse.remaining() > 0 {
+        keys.push(parse.next_string()?);
+    }
+
+    let mut result: Option<HashSet<Bytes>> = None;
+    for key in &keys {
+        // Keep checking types even once the result is known to be empty,
+        // like Redis does.
+        let set = set(db, key)?.unwrap_or_default();
+        result = Some(match result {
+            None => set,
+            Some(result) => result.intersection(&set).cloned().collect(),
+        });
+    }
+
+    let members = result.unwrap_or_default();
+    Ok(Frame::Set(members.into_iter().map(Frame::Bulk).collect()))
+}
+
+/// A copy of the set stored at `key`, `None` if there is none, or a
+/// `WRONGTYPE` error if the key holds something else.
+fn set(db: &Db, key: &str) -> crate::Result<Option<HashSet<Bytes>>> {
+    match db.lock(key).get(key) {
+        Some(Value::Set(set)) => Ok(Some(set.clone())),
+        Some(_) => Err(WRONGTYPE.into()),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,1055 @@
+//! Commands operating on streams.
+//!
+//! The data structure itself lives in `crate::stream`; this module parses
+//! requests and shapes replies. An entry is replied as a pair of its ID and
+//! its flattened field/value pairs.
+//!
+//! `XREAD` and `XREADGROUP` with `BLOCK` wait for new entries; see
+//! `blocking_read`.
+//!
+//! Group reads and claims are logged as their effects rather than as they
+//! were sent: an `XCLAIM ... FORCE JUSTID` setting each PEL entry they touched
+//! to what it became, and an `XGROUP SETID` for how far the group read. That
+//! way a replica, or a replayed log, ends up with the same delivery times
+//! and counts.
+
+use crate::cmd::WRONGTYPE;
+use crate::db::ShardGuard;
+use crate::parse::Parse;
+use crate::shutdown::Shutdown;
+use crate::stream::{Claim, Fields, Pending, Stream, StreamId};
+use crate::{Command, Connection, Db, Frame, Value};
+
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+const INVALID_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+
+/// `XADD key [NOMKSTREAM] [MAXLEN [= | ~] threshold] <* | id> field value
+/// [field value ...]`
+///
+/// Replies with the ID of the new entry, or a null if the key does not exist
+/// and `NOMKSTREAM` is given. The ID is taken from the clock with `*`, and
+/// given as `ms-*` only its sequence number is. `MAXLEN` then trims the
+/// oldest entries; `~` is accepted, but trimming is always exact.
+pub fn xadd(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+
+    let (mut nomkstream, mut maxlen) = (false, None);
+    let id = loop {
+        let arg = parse.next_bytes()?;
+        match arg.to_ascii_uppercase().as_slice() {
+            b"NOMKSTREAM" => nomkstream = true,
+            b"MAXLEN" => {
+                let mut threshold = parse.next_bytes()?;
+                if let b"=" | b"~" = threshold.as_ref() {
+                    threshold = parse.next_bytes()?;
+                }
+                maxlen = Some(number(&threshold)? as usize);
+            }
+            _ => break arg,
+        }
+    };
+
+    // Validate everything before touching the stream.
+    let id = match id.as_ref() {
+        b"*" => NewId::Auto,
+        arg => match arg.strip_suffix(b"-*") {
+            Some(ms) => NewId::Seq(number(ms).map_err(|_| INVALID_ID)?),
+            None => NewId::Id(id_arg(arg)?),
+        },
+    };
+    let mut fields = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+    while parse.remaining() > 0 {
+        fields.push((parse.next_bytes()?, parse.next_bytes()?));
+    }
+
+    let mut shard = db.lock(&key);
+    let added = match stream_mut(&mut shard, &key)? {
+        Some(stream) => add(stream, id, fields, maxlen)?,
+        None if nomkstream => return Ok(Frame::Null),
+        None => {
+            // Only create the key once the entry is known to fit.
+            let mut stream = Stream::new();
+            let added = add(&mut stream, id, fields, maxlen)?;
+            shard.insert(key.clone(), Value::Stream(stream));
+            added
+        }
+    };
+    shard.wake_readers(&key);
+
+    Ok(Frame::Bulk(Bytes::from(added.to_string())))
+}
+
+/// The ID asked for by `XADD`.
+#[derive(Debug, Clone, Copy)]
+enum NewId {
+    Auto,
+    Seq(u64),
+    Id(StreamId),
+}
+
+fn add(
+    stream: &mut Stream,
+    id: NewId,
+    fields: Fields,
+    maxlen: Option<usize>,
+) -> crate::Result<StreamId> {
+    let id = match id {
+        NewId::Auto => stream
+            .auto_id(now())
+            .ok_or("ERR The stream has exhausted the last possible ID, unable to add more items")?,
+        NewId::Seq(ms) => stream.auto_seq(ms).ok_or(
+            "ERR The ID specified in XADD is equal or smaller than the target stream top item",
+        )?,
+        NewId::Id(id) => id,
+    };
+    stream.add(id, fields)?;
+
+    if let Some(maxlen) = maxlen {
+        stream.trim(maxlen);
+    }
+    Ok(id)
+}
+
+/// The `XADD` to log for `cmd`, which added the entry `id`: the same
+/// command with the ID in full, so replaying it adds the same entry.
+pub(crate) fn propagated(cmd: &Command, id: &Bytes) -> Command {
+    let mut args = cmd.args().to_vec();
+
+    // Past the key and the options.
+    let mut i = 1;
+    while let Some(arg) = args.get(i) {
+        match arg.to_ascii_uppercase().as_slice() {
+            b"NOMKSTREAM" => i += 1,
+            b"MAXLEN" => match args.get(i + 1).map(|a| a.as_ref()) {
+                Some(b"=" | b"~") => i += 3,
+                _ => i += 2,
+            },
+            _ => break,
+        }
+    }
+
+    if let Some(arg) = args.get_mut(i) {
+        *arg = id.clone();
+    }
+    Command::new("xadd", args)
+}
+
+/// `XSETID key last-id`: makes `last-id` the greatest ID the stream ever
+/// had, so `XADD` only accepts IDs above it.
+pub fn xsetid(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let id = id_arg(&parse.next_bytes()?)?;
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let stream = stream_mut(&mut shard, &key)?.ok_or("ERR no such key")?;
+    stream.set_last_id(id)?;
+    Ok(Frame::ok())
+}
+
+/// `XLEN key`
+pub fn xlen(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let len = stream(&mut shard, &key)?.map_or(0, |stream| stream.len());
+    Ok(Frame::Integer(len as i64))
+}
+
+/// `XRANGE key start end [COUNT count]`
+///
+/// `-` and `+` are the smallest and greatest IDs, a bare time stands for
+/// all of its sequence numbers and `(` makes a bound exclusive.
+pub fn xrange(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    range(db, parse, false)
+}
+
+/// `XREVRANGE key end start [COUNT count]`: like `XRANGE`, newest first.
+pub fn xrevrange(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    range(db, parse, true)
+}
+
+fn range(db: &Db, parse: &mut Parse, rev: bool) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let (mut start, mut end) = (parse.next_bytes()?, parse.next_bytes()?);
+    if rev {
+        std::mem::swap(&mut start, &mut end);
+    }
+    let start = bound(&start, true)?;
+    let end = bound(&end, false)?;
+
+    let mut count = usize::MAX;
+    if parse.remaining() > 0 {
+        if !parse.next_string()?.eq_ignore_ascii_case("count") {
+            return Err("ERR syntax error".into());
+        }
+        count = parse.next_int()?.max(0) as usize;
+    }
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let (Some(stream), Some(start), Some(end)) = (stream(&mut shard, &key)?, start, end) else {
+        return Ok(Frame::Array(vec![]));
+    };
+
+    let entries = stream.range(start, end);
+    let items = match rev {
+        false => entries
+            .take(count)
+            .map(|(id, f)| entry(id, Some(f)))
+            .collect(),
+        true => entries
+            .rev()
+            .take(count)
+            .map(|(id, f)| entry(id, Some(f)))
+            .collect(),
+    };
+    Ok(Frame::Array(items))
+}
+
+/// `XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]`
+///
+/// Replies with the entries of each stream after the ID given for it, as
+/// pairs of the key and its entries, or a null if there are none. `$` is
+/// the last ID of the stream. Without a connection to block, `BLOCK` is
+/// ignored; see `blocking_read`.
+pub fn xread(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let read = read_args(parse, false)?;
+    read_streams(db, &read)
+}
+
+/// `XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds]
+/// [NOACK] STREAMS key [key ...] id [id ...]`
+///
+/// With `>` as the ID, delivers entries the group has not seen yet to
+/// `consumer`, adding them to the PEL unless `NOACK` is given. With any
+/// other ID, delivers again the entries pending for `consumer` after it.
+/// The reply is shaped like `XREAD`'s.
+pub fn xreadgroup(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let read = read_args(parse, true)?;
+    read_group(db, &read, now())
+}
+
+/// The arguments of `XREAD` and `XREADGROUP`.
+#[derive(Debug)]
+struct Read {
+    /// The group and consumer of `XREADGROUP`.
+    group: Option<(Bytes, Bytes)>,
+
+    count: usize,
+
+    /// How long to wait for entries, if at all; `Some(None)` waits forever.
+    block: Option<Option<Duration>>,
+
+    noack: bool,
+
+    keys: Vec<String>,
+
+    /// Where to read each of `keys` from.
+    starts: Vec<Start>,
+}
+
+/// Where to read a stream from.
+#[derive(Debug, Clone, Copy)]
+enum Start {
+    /// The entries after this ID.
+    After(StreamId),
+
+    /// `$`: entries added from now on.
+    Last,
+
+    /// `>`: entries the group has not seen yet.
+    New,
+}
+
+fn read_args(parse: &mut Parse, grouped: bool) -> crate::Result<Read> {
+    let name = if grouped { "xreadgroup" } else { "xread" };
+
+    let mut group = None;
+    if grouped {
+        if !parse.next_string()?.eq_ignore_ascii_case("group") {
+            return Err("ERR Missing GROUP option for XREADGROUP".into());
+        }
+        group = Some((parse.next_bytes()?, parse.next_bytes()?));
+    }
+
+    let (mut count, mut block, mut noack) = (usize::MAX, None, false);
+    loop {
+        match parse.next_string()?.to_ascii_lowercase().as_str() {
+            "count" => count = parse.next_int()?.max(0) as usize,
+            "block" => {
+                block = match parse.next_int()? {
+                    0 => Some(None),
+                    ms if ms > 0 => Some(Some(Duration::from_millis(ms as u64))),
+                    _ => return Err("ERR timeout is negative".into()),
+                }
+            }
+            "noack" if grouped => noack = true,
+            "streams" => break,
+            _ => return Err("ERR syntax error".into()),
+        }
+    }
+    // `COUNT 0` is no limit.
+    if count == 0 {
+        count = usize::MAX;
+    }
+
+    let mut args = Vec::new();
+    while parse.remaining() > 0 {
+        args.push(parse.next_bytes()?);
+    }
+    if args.is_empty() || args.len() % 2 != 0 {
+        return Err(format!(
+            "ERR Unbalanced '{}' list of streams: for each stream key an ID or '$' must be specified.",
+            name
+        )
+        .into());
+    }
+
+    let ids = args.split_off(args.len() / 2);
+    let keys = args
+        .iter()
+        .map(|key| String::from_utf8(key.to_vec()))
+        .collect::<Result<_, _>>()
+        .map_err(|_| "ERR invalid argument, expected a UTF-8 string")?;
+    let starts = ids
+        .iter()
+        .map(|id| match id.as_ref() {
+            b"$" if grouped => Err(
+                "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read \
+                 the history of this consumer by specifying a proper ID, or use the > ID to \
+                 get new messages. The $ ID would just return an empty result set."
+                    .into(),
+            ),
+            b"$" => Ok(Start::Last),
+            b">" if grouped => Ok(Start::New),
+            id => id_arg(id).map(Start::After),
+        })
+        .collect::<crate::Result<_>>()?;
+
+    Ok(Read {
+        group,
+        count,
+        block,
+        noack,
+        keys,
+        starts,
+    })
+}
+
+/// Run an `XREAD`: the streams with entries after their start, or a null.
+fn read_streams(db: &Db, read: &Read) -> crate::Result<Frame> {
+    let mut streams = Vec::new();
+    for (key, start) in read.keys.iter().zip(&read.starts) {
+        let mut shard = db.lock(key);
+        let Some(stream) = stream(&mut shard, key)? else {
+            continue;
+        };
+        let Start::After(after) = start else {
+            continue;
+        };
+        let Some(first) = after.next() else {
+            continue;
+        };
+
+        let entries: Vec<Frame> = stream
+            .range(first, StreamId::MAX)
+            .take(read.count)
+            .map(|(id, fields)| entry(id, Some(fields)))
+            .collect();
+        if !entries.is_empty() {
+            streams.push(keyed(key, entries));
+        }
+    }
+
+    Ok(match streams.is_empty() {
+        true => Frame::Null,
+        false => Frame::Array(streams),
+    })
+}
+
+/// Run an `XREADGROUP` at Unix time `now`, recording its effects.
+///
+/// Like `XREAD`, replies with a null when there is nothing new. Reading
+/// history replies with the key even without entries.
+fn read_group(db: &Db, read: &Read, now: u64) -> crate::Result<Frame> {
+    let Some((group, consumer)) = &read.group else {
+        return read_streams(db, read);
+    };
+
+    // Check every stream first, so none is read if one is missing.
+    for key in &read.keys {
+        let mut shard = db.lock(key);
+        if stream(&mut shard, key)?
+            .and_then(|s| s.group(group))
+            .is_none()
+        {
+            return Err(format!(
+                "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                key,
+                String::from_utf8_lossy(group)
+            )
+            .into());
+        }
+    }
+
+    let mut streams = Vec::new();
+    for (key, start) in read.keys.iter().zip(&read.starts) {
+        let mut shard = db.lock(key);
+        let Some(stream) = stream_mut(&mut shard, key)? else {
+            continue;
+        };
+        let known = stream
+            .group(group)
+            .is_some_and(|g| g.consumers.contains_key(consumer));
+
+        let (entries, last_delivered) = match start {
+            Start::New => {
+                let delivered = stream
+                    .deliver_new(group, consumer, read.count, now, read.noack)
+                    .unwrap_or_default();
+                let last = delivered.last().map(|(id, _)| *id);
+                let delivered = delivered.into_iter().map(|(id, f)| (id, Some(f)));
+                (delivered.collect(), last)
+            }
+            Start::After(after) => {
+                let delivered = stream
+                    .deliver_pending(group, consumer, *after, read.count, now)
+                    .unwrap_or_default();
+                (delivered, None)
+            }
+            Start::Last => continue,
+        };
+
+        let claimed: Vec<Command> = match stream.group(group) {
+            Some(g) => entries
+                .iter()
+                .filter_map(|(id, _)| Some(claim_command(key, group, *id, g.pending.get(id)?)))
+                .collect(),
+            None => vec![],
+        };
+        if !known {
+            shard.propagate(xgroup_command("createconsumer", key, group, consumer));
+        }
+        for cmd in claimed {
+            shard.propagate(cmd);
+        }
+        if let Some(last) = last_delivered {
+            let last = Bytes::from(last.to_string());
+            shard.propagate(xgroup_command("setid", key, group, &last));
+        }
+
+        if !entries.is_empty() || !matches!(start, Start::New) {
+            let entries = entries.iter().map(|(id, f)| entry(id, f.as_ref()));
+            streams.push(keyed(key, entries.collect()));
+        }
+    }
+
+    Ok(match streams.is_empty() {
+        true => Frame::Null,
+        false => Frame::Array(streams),
+    })
+}
+
+/// Whether `cmd`, an `XREAD` or `XREADGROUP`, asks to wait for entries.
+pub(crate) fn blocks(cmd: &Command) -> bool {
+    let grouped = cmd.name() == "xreadgroup";
+    read_args(&mut cmd.parse(), grouped).is_ok_and(|read| read.block.is_some())
+}
+
+/// `XREAD` and `XREADGROUP` with `BLOCK`.
+///
+/// Reads like without it, but when there is nothing to reply with, waits for
+/// another client to add entries to one of the streams and tries again,
+/// until the timeout passes (`0` waits forever). Replies with a null on
+/// timeout. A shutdown ends the wait like a timeout.
+///
+/// Reading a consumer's history never waits. `$` stands for the last ID
+/// when the command started, not when it tries again.
+pub async fn blocking_read(
+    db: &Db,
+    connection: &mut Connection,
+    cmd: &Command,
+    shutdown: &mut Shutdown,
+) -> crate::Result<Frame> {
+    let grouped = cmd.name() == "xreadgroup";
+    let mut read = read_args(&mut cmd.parse(), grouped)?;
+    let deadline = read.block.flatten().map(|timeout| Instant::now() + timeout);
+
+    for (key, start) in read.keys.iter().zip(&mut read.starts) {
+        if let Start::Last = start {
+            let mut shard = db.lock(key);
+            let last = stream(&mut shard, key)?.map_or(StreamId::MIN, |s| s.last_id());
+            *start = Start::After(last);
+        }
+    }
+
+    // Registered before the first try, so an entry added in between still
+    // wakes the client.
+    let reader = Arc::new(Notify::new());
+    for key in &read.keys {
+        db.lock(key).block_reader(key, reader.clone());
+    }
+
+    let res = loop {
+        match read_once(db, &read).await {
+            Ok(Frame::Null) => {}
+            res => break res,
+        }
+
+        // Replies to pipelined requests before this one must not wait
+        // behind the block.
+        if let Err(err) = connection.flush().await {
+            break Err(err.into());
+        }
+
+        let expired = async {
+            match deadline {
+                Some(deadline) => time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = reader.notified() => {}
+            _ = expired => break Ok(Frame::Null),
+            _ = connection.closed() => break Ok(Frame::Null),
+            _ = shutdown.recv() => break Ok(Frame::Null),
+        }
+    };
+
+    for key in &read.keys {
+        db.lock(key).unblock_reader(key, &reader);
+    }
+    res
+}
+
+/// One try of a blocking read. Group reads are writes, logged as such.
+async fn read_once(db: &Db, read: &Read) -> crate::Result<Frame> {
+    if read.group.is_none() {
+        return db.concurrently(|| read_streams(db, read));
+    }
+
+    let (reply, synced) = db.concurrently(|| db.write(|| (read_group(db, read, now()), None)));
+    if let Some(synced) = synced {
+        synced.wait().await?;
+    }
+    reply
+}
+
+/// `XGROUP CREATE key group <id | $> [MKSTREAM]`,
+/// `XGROUP SETID key group <id | $>`, `XGROUP DESTROY key group`,
+/// `XGROUP CREATECONSUMER key group consumer` and
+/// `XGROUP DELCONSUMER key group consumer`
+///
+/// A new group only sees the entries after the ID it is created with, `$`
+/// being the last one. Deleting a consumer drops its pending entries, and
+/// replies with how many there were.
+pub fn xgroup(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let subcommand = parse.next_string()?.to_ascii_lowercase();
+    if !matches!(
+        subcommand.as_str(),
+        "create" | "setid" | "destroy" | "createconsumer" | "delconsumer"
+    ) {
+        return Err(format!(
+            "ERR unknown subcommand '{}'. Try XGROUP CREATE, SETID, DESTROY, CREATECONSUMER or DELCONSUMER.",
+            subcommand
+        )
+        .into());
+    }
+    let key = parse.next_string()?;
+    let name = parse.next_bytes()?;
+
+    let arg = match subcommand.as_str() {
+        "destroy" => None,
+        _ => Some(parse.next_bytes()?),
+    };
+    let mut mkstream = false;
+    if subcommand == "create" && parse.remaining() > 0 {
+        if !parse.next_string()?.eq_ignore_ascii_case("mkstream") {
+            return Err("ERR syntax error".into());
+        }
+        mkstream = true;
+    }
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let stream = match stream_mut(&mut shard, &key)? {
+        Some(stream) => stream,
+        None if mkstream => {
+            shard.insert(key.clone(), Value::Stream(Stream::new()));
+            stream_mut(&mut shard, &key)?.expect("just inserted")
+        }
+        None => {
+            return Err(
+                "ERR The XGROUP subcommand requires the key to exist. Note that for \
+                        CREATE you may want to use the MKSTREAM option to create an empty \
+                        stream automatically."
+                    .into(),
+            );
+        }
+    };
+
+    let last_id = stream.last_id();
+    let id = |arg: &Bytes| match arg.as_ref() {
+        b"$" => Ok(last_id),
+        arg => id_arg(arg),
+    };
+    let nogroup = || {
+        format!(
+            "NOGROUP No such consumer group '{}' for key name '{}'",
+            String::from_utf8_lossy(&name),
+            key
+        )
+    };
+
+    match (subcommand.as_str(), arg) {
+        ("create", Some(arg)) => {
+            if !stream.create_group(name.clone(), id(&arg)?) {
+                return Err("BUSYGROUP Consumer Group name already exists".into());
+            }
+            Ok(Frame::ok())
+        }
+        ("setid", Some(arg)) => {
+            let last_delivered = id(&arg)?;
+            let group = stream.group_mut(&name).ok_or_else(nogroup)?;
+            group.last_delivered = last_delivered;
+            Ok(Frame::ok())
+        }
+        ("createconsumer", Some(consumer)) => {
+            let group = stream.group_mut(&name).ok_or_else(nogroup)?;
+            if group.consumers.contains_key(&consumer) {
+                return Ok(Frame::Integer(0));
+            }
+            group.seen(&consumer, now());
+            Ok(Frame::Integer(1))
+        }
+        ("delconsumer", Some(consumer)) => {
+            let group = stream.group_mut(&name).ok_or_else(nogroup)?;
+            let before = group.pending.len();
+            group
+                .pending
+                .retain(|_, pending| pending.consumer != consumer);
+            group.consumers.remove(&consumer);
+            Ok(Frame::Integer((before - group.pending.len()) as i64))
+        }
+        _ => Ok(Frame::Integer(stream.destroy_group(&name) as i64)),
+    }
+}
+
+/// `XACK key group id [id ...]`: removes the entries from the group's PEL,
+/// replying with how many were pending.
+pub fn xack(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let group = parse.next_bytes()?;
+    let mut ids = vec![id_arg(&parse.next_bytes()?)?];
+    while parse.remaining() > 0 {
+        ids.push(id_arg(&parse.next_bytes()?)?);
+    }
+
+    let mut shard = db.lock(&key);
+    let Some(group) = stream_mut(&mut shard, &key)?.and_then(|s| s.group_mut(&group)) else {
+        return Ok(Frame::Integer(0));
+    };
+    let acked = ids
+        .iter()
+        .filter(|id| group.pending.remove(id).is_some())
+        .count();
+    Ok(Frame::Integer(acked as i64))
+}
+
+/// `XPENDING key group [[IDLE min-idle-time] start end count [consumer]]`
+///
+/// Without a range, replies with a summary of the group's PEL: how many
+/// entries it holds, the smallest and greatest of their IDs, and how many
+/// each consumer has. With one, lists the pending entries in it, with their
+/// consumer, how long ago they were delivered in milliseconds and how many
+/// times.
+pub fn xpending(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let name = parse.next_bytes()?;
+
+    let mut range = None;
+    if parse.remaining() > 0 {
+        let mut min_idle = 0;
+        let mut start = parse.next_bytes()?;
+        if start.eq_ignore_ascii_case(b"idle") {
+            min_idle = parse.next_int()?.max(0) as u64;
+            start = parse.next_bytes()?;
+        }
+        let start = bound(&start, true)?;
+        let end = bound(&parse.next_bytes()?, false)?;
+        let count = parse.next_int()?.max(0) as usize;
+        let consumer = match parse.remaining() {
+            0 => None,
+            _ => Some(parse.next_bytes()?),
+        };
+        range = Some((min_idle, start, end, count, consumer));
+    }
+    parse.finish()?;
+
+    let mut shard = db.lock(&key);
+    let Some(group) = stream(&mut shard, &key)?.and_then(|s| s.group(&name)) else {
+        return Err(format!(
+            "NOGROUP No such key '{}' or consumer group '{}'",
+            key,
+            String::from_utf8_lossy(&name)
+        )
+        .into());
+    };
+
+    let Some((min_idle, start, end, count, consumer)) = range else {
+        let id = |id: Option<(&StreamId, _)>| match id {
+            Some((id, _)) => Frame::Bulk(Bytes::from(id.to_string())),
+            None => Frame::Null,
+        };
+        let consumers = match group.pending.is_empty() {
+            true => Frame::Null,
+            false => Frame::Array(
+                group
+                    .pending_counts()
+                    .into_iter()
+                    .map(|(consumer, n)| {
+                        Frame::Array(vec![
+                            Frame::Bulk(consumer.clone()),
+                            Frame::Bulk(Bytes::from(n.to_string())),
+                        ])
+                    })
+                    .collect(),
+            ),
+        };
+        return Ok(Frame::Array(vec![
+            Frame::Integer(group.pending.len() as i64),
+            id(group.pending.first_key_value()),
+            id(group.pending.last_key_value()),
+            consumers,
+        ]));
+    };
+
+    let (Some(start), Some(end)) = (start, end) else {
+        return Ok(Frame::Array(vec![]));
+    };
+    if start > end {
+        return Ok(Frame::Array(vec![]));
+    }
+    let now = now();
+    let items = group
+        .pending
+        .range(start..=end)
+        .filter(|(_, p)| consumer.as_ref().is_none_or(|c| *c == p.consumer))
+        .filter(|(_, p)| now.saturating_sub(p.delivered_at) >= min_idle)
+        .take(count)
+        .map(|(id, p)| {
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from(id.to_string())),
+                Frame::Bulk(p.consumer.clone()),
+                Frame::Integer(now.saturating_sub(p.delivered_at) as i64),
+                Frame::Integer(p.deliveries as i64),
+            ])
+        })
+        .collect();
+    Ok(Frame::Array(items))
+}
+
+/// `XCLAIM key group consumer min-idle-time id [id ...] [IDLE ms]
+/// [TIME unix-time-milliseconds] [RETRYCOUNT count] [FORCE] [JUSTID]
+/// [LASTID id]`
+///
+/// Hands the pending entries among the IDs that were idle for at least
+/// `min-idle-time` milliseconds to `consumer`, replying with them, or only
+/// their IDs with `JUSTID`.
+///
+/// * `IDLE` and `TIME` set when they count as delivered, rather than now.
+/// * `RETRYCOUNT` sets their delivery count, which `JUSTID` otherwise leaves
+///   alone and anything else increments.
+/// * `FORCE` claims entries of the stream that are not pending as well.
+/// * `LASTID` moves the group's last delivered ID forward.
+pub fn xclaim(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let group = parse.next_bytes()?;
+    let consumer = parse.next_bytes()?;
+    let min_idle = parse.next_int()?.max(0) as u64;
+
+    // IDs, up to the first option.
+    let mut ids = vec![id_arg(&parse.next_bytes()?)?];
+    let mut option = None;
+    while parse.remaining() > 0 {
+        let arg = parse.next_bytes()?;
+        match id_arg(&arg) {
+            Ok(id) => ids.push(id),
+            Err(_) => {
+                option = Some(arg);
+                break;
+            }
+        }
+    }
+
+    let now = now();
+    let mut claim = Claim {
+        min_idle,
+        ..Claim::default()
+    };
+    let mut last_id = None;
+    while let Some(arg) = option.take() {
+        match arg.to_ascii_uppercase().as_slice() {
+            b"IDLE" => claim.delivered_at = Some(now.saturating_sub(number(&parse.next_bytes()?)?)),
+            b"TIME" => claim.delivered_at = Some(number(&parse.next_bytes()?)?),
+            b"RETRYCOUNT" => claim.deliveries = Some(number(&parse.next_bytes()?)?),
+            b"FORCE" => claim.force = true,
+            b"JUSTID" => claim.justid = true,
+            b"LASTID" => last_id = Some(id_arg(&parse.next_bytes()?)?),
+            _ => return Err("ERR syntax error".into()),
+        }
+        if parse.remaining() > 0 {
+            option = Some(parse.next_bytes()?);
+        }
+    }
+
+    let mut shard = db.lock(&key);
+    let nogroup = || {
+        format!(
+            "NOGROUP No such key '{}' or consumer group '{}'",
+            key,
+            String::from_utf8_lossy(&group)
+        )
+    };
+    let stream = stream_mut(&mut shard, &key)?.ok_or_else(nogroup)?;
+    let claimed = stream
+        .claim(&group, &consumer, &ids, &claim, now)
+        .ok_or_else(nogroup)?;
+
+    let Some(g) = stream.group_mut(&group) else {
+        return Err(nogroup().into());
+    };
+    let moved = match last_id {
+        Some(id) if id > g.last_delivered => {
+            g.last_delivered = id;
+            true
+        }
+        _ => false,
+    };
+    let logged: Vec<Command> = claimed
+        .iter()
+        .filter_map(|id| Some(claim_command(&key, &group, *id, g.pending.get(id)?)))
+        .collect();
+    let reply = claimed
+        .iter()
+        .map(|id| match claim.justid {
+            true => Frame::Bulk(Bytes::from(id.to_string())),
+            false => entry(id, stream.get(id)),
+        })
+        .collect();
+
+    for cmd in logged {
+        shard.propagate(cmd);
+    }
+    if moved && let Some(id) = last_id {
+        let id = Bytes::from(id.to_string());
+        shard.propagate(xgroup_command("setid", &key, &group, &id));
+    }
+    Ok(Frame::Array(reply))
+}
+
+/// The commands rebuilding `stream` at `key`, for an append-only file
+/// rewrite.
+pub(crate) fn rebuild(key: &Bytes, stream: &Stream) -> Vec<Command> {
+    let mut cmds = Vec::new();
+    let id = |id: &StreamId| Bytes::from(id.to_string());
+
+    for (entry, fields) in stream.entries() {
+        let mut args = vec![key.clone(), id(entry)];
+        args.extend(fields.iter().flat_map(|(f, v)| [f.clone(), v.clone()]));
+        cmds.push(Command::new("xadd", args));
+    }
+    // An empty stream is created by adding an entry and trimming it away.
+    if stream.is_empty() {
+        let args = ["MAXLEN", "0", "0-1", "x", "y"].map(Bytes::from);
+        let args = std::iter::once(key.clone()).chain(args);
+        cmds.push(Command::new("xadd", args.collect()));
+    }
+    if stream.entries().next_back().map(|(id, _)| *id) != Some(stream.last_id()) {
+        cmds.push(Command::new(
+            "xsetid",
+            vec![key.clone(), id(&stream.last_id())],
+        ));
+    }
+
+    let key = String::from_utf8_lossy(key);
+    for (name, group) in stream.groups() {
+        let last = id(&group.last_delivered);
+        cmds.push(xgroup_command("create", &key, name, &last));
+        for consumer in group.consumers.keys() {
+            cmds.push(xgroup_command("createconsumer", &key, name, consumer));
+        }
+        for (entry, pending) in &group.pending {
+            cmds.push(claim_command(&key, name, *entry, pending));
+        }
+    }
+    cmds
+}
+
+/// The `XCLAIM` setting the PEL entry `id` of `group` to `pending`.
+fn claim_command(key: &str, group: &Bytes, id: StreamId, pending: &Pending) -> Command {
+    let args = vec![
+        Bytes::copy_from_slice(key.as_bytes()),
+        group.clone(),
+        pending.consumer.clone(),
+        Bytes::from_static(b"0"),
+        Bytes::from(id.to_string()),
+        Bytes::from_static(b"TIME"),
+        Bytes::from(pending.delivered_at.to_string()),
+        Bytes::from_static(b"RETRYCOUNT"),
+        Bytes::from(pending.deliveries.to_string()),
+        Bytes::from_static(b"FORCE"),
+        Bytes::from_static(b"JUSTID"),
+    ];
+    Command::new("xclaim", args)
+}
+
+/// `XGROUP subcommand key group arg`.
+fn xgroup_command(subcommand: &str, key: &str, group: &Bytes, arg: &Bytes) -> Command {
+    let args = vec![
+        Bytes::copy_from_slice(subcommand.as_bytes()),
+        Bytes::copy_from_slice(key.as_bytes()),
+        group.clone(),
+        arg.clone(),
+    ];
+    Command::new("xgroup", args)
+}
+
+/// An entry as replied: its ID and its fields, or a null for an entry no
+/// longer in the stream.
+fn entry(id: &StreamId, fields: Option<&Fields>) -> Frame {
+    let fields = match fields {
+        Some(fields) => Frame::Array(
+            fields
+                .iter()
+                .flat_map(|(f, v)| [Frame::Bulk(f.clone()), Frame::Bulk(v.clone())])
+                .collect(),
+        ),
+        None => Frame::Null,
+    };
+    Frame::Array(vec![Frame::Bulk(Bytes::from(id.to_string())), fields])
+}
+
+/// A stream in the reply of `XREAD`: its key and its entries.
+fn keyed(key: &str, entries: Vec<Frame>) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::copy_from_slice(key.as_bytes())),
+        Frame::Array(entries),
+    ])
+}
+
+/// Parse a full or bare (`ms`) ID.
+fn id_arg(arg: &[u8]) -> crate::Result<StreamId> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| INVALID_ID.into())
+}
+
+/// Parse one end of a range: `-`, `+`, or an ID, made exclusive with a
+/// leading `(`. A bare time covers every sequence number in it. `None` is
+/// an exclusive bound past the smallest or greatest ID.
+fn bound(arg: &[u8], start: bool) -> crate::Result<Option<StreamId>> {
+    match arg {
+        b"-" => return Ok(Some(StreamId::MIN)),
+        b"+" => return Ok(Some(StreamId::MAX)),
+        _ => {}
+    }
+
+    let (exclusive, arg) = match arg.strip_prefix(b"(") {
+        Some(arg) => (true, arg),
+        None => (false, arg),
+    };
+    let mut id = id_arg(arg)?;
+    if !start && !arg.contains(&b'-') {
+        id.seq = u64::MAX;
+    }
+
+    Ok(match (exclusive, start) {
+        (false, _) => Some(id),
+        (true, true) => id.next(),
+        (true, false) => id.prev(),
+    })
+}
+
+/// Parse a non-negative integer.
+fn number(arg: &[u8]) -> crate::Result<u64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "ERR value is not an integer or out of range".into())
+}
+
+/// The current Unix time, in milliseconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The stream stored at `key`, `None` if there is none, or a `WRONGTYPE`
+/// error if the key holds something else.
+fn stream<'a>(shard: &'a mut ShardGuard, key: &str) -> crate::Result<Option<&'a Stream>> {
+    match shard.get(key) {
+        Some(Value::Stream(stream)) => Ok(Some(stream)),
+        Some(_) => Err(WRONGTYPE.into()),
+        None => Ok(None),
+    }
+}
+
+/// Like `stream`, for a command about to change it.
+fn stream_mut<'a>(shard: &'a mut ShardGuard, key: &str) -> crate::Result<Option<&'a mut Stream>> {
+    match shard.get_mut(key) {
+        Some(Value::Stream(stream)) => Ok(Some(stream)),
+        Some(_) => Err(WRONGTYPE.into()),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        assert_eq!(bound(b"-", true).unwrap(), Some(StreamId::MIN));
+        assert_eq!(bound(b"+", false).unwrap(), Some(StreamId::MAX));
+        assert_eq!(bound(b"5", true).unwrap(), Some(StreamId::new(5, 0)));
+        assert_eq!(
+            bound(b"5", false).unwrap(),
+            Some(StreamId::new(5, u64::MAX))
+        );
+        assert_eq!(bound(b"(5-1", true).unwrap(), Some(StreamId::new(5, 2)));
+        assert_eq!(
+            bound(b"(5-0", false).unwrap(),
+            Some(StreamId::new(4, u64::MAX))
+        );
+        assert_eq!(bound(b"(0-0", false).unwrap(), None);
+        assert!(bound(b"(-", true).is_err());
+        assert!(bound(b"x", true).is_err());
+    }
+
+    #[test]
+    fn xadd_is_logged_with_its_id() {
+        let cmd = Command::new(
+            "xadd",
+            ["s", "MAXLEN", "~", "10", "*", "f", "v"]
+                .map(Bytes::from)
+                .to_vec(),
+        );
+        let logged = propagated(&cmd, &Bytes::from("7-0"));
+        assert_eq!(logged.args()[4], "7-0");
+        assert_eq!(logged.args()[5], "f");
+
+        let cmd = Command::new("xadd", ["s", "5-*", "f", "v"].map(Bytes::from).to_vec());
+        assert_eq!(propagated(&cmd, &Bytes::from("5-3")).args()[1], "5-3");
+    }
+}
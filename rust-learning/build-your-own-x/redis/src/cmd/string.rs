@@ -0,0 +1,106 @@
+//! Commands operating on string values.
+
+use crate::cmd::WRONGTYPE;
+use crate::cmd::keys::unix_ms;
+use crate::parse::Parse;
+use crate::{Command, Db, Frame, Value};
+
+use bytes::Bytes;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// `GET key`
+pub fn get(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    parse.finish()?;
+
+    match db.lock(&key).get(&key) {
+        // `Bytes::clone` is a reference count bump, not a copy.
+        Some(Value::String(value)) => Ok(Frame::Bulk(value.clone())),
+        Some(_) => Err(WRONGTYPE.into()),
+        None => Ok(Frame::Null),
+    }
+}
+
+/// `SET key value [EX seconds | PX milliseconds | EXAT timestamp | PXAT
+/// timestamp-ms]`
+///
+/// Setting a key without an expiration clears any TTL it had. An absolute
+/// deadline that already passed deletes the key instead.
+pub fn set(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let value = parse.next_bytes()?;
+
+    match expiration(parse)? {
+        Some(None) => {
+            db.lock(&key).remove(&key);
+        }
+        expire => db.set(key, value, expire.flatten()),
+    }
+    Ok(Frame::ok())
+}
+
+/// Check the arguments of a `SET` without running it.
+pub(crate) fn check_set(cmd: &Command) -> crate::Result<()> {
+    let mut parse = cmd.parse();
+    parse.next_string()?;
+    parse.next_bytes()?;
+    expiration(&mut parse).map(|_| ())
+}
+
+/// The expiration the options of a `SET` ask for, if any: the time left,
+/// or `None` for a deadline already past.
+fn expiration(parse: &mut Parse) -> crate::Result<Option<Option<Duration>>> {
+    let mut expire = None;
+    while parse.remaining() > 0 {
+        let option = parse.next_string()?.to_ascii_uppercase();
+        if expire.is_some() || !["EX", "PX", "EXAT", "PXAT"].contains(&option.as_str()) {
+            return Err("ERR syntax error".into());
+        }
+
+        let unit = if option.starts_with('E') { 1000 } else { 1 };
+        let absolute = option.ends_with("AT");
+        let ms = parse
+            .next_int()?
+            .checked_mul(unit)
+            .filter(|&ms| ms > 0)
+            // The deadline is logged in milliseconds since the epoch, which
+            // has to fit.
+            .filter(|&ms| absolute || unix_ms().checked_add(ms).is_some())
+            .ok_or("ERR invalid expire time in 'set' command")?;
+        let ms = Duration::from_millis(ms as u64);
+        expire = Some(if absolute { unix_time(ms) } else { Some(ms) });
+    }
+    Ok(expire)
+}
+
+/// The form of a `SET` to log: a relative expiration becomes an absolute
+/// `PXAT`, so replaying it later restores the same deadline.
+pub(crate) fn propagated(args: &[Bytes]) -> Vec<Bytes> {
+    let mut args = args.to_vec();
+    for i in 2..args.len().saturating_sub(1) {
+        let unit = match args[i].to_ascii_uppercase().as_slice() {
+            b"EX" => 1000,
+            b"PX" => 1,
+            _ => continue,
+        };
+        let Some(n) = std::str::from_utf8(&args[i + 1])
+            .ok()
+            .and_then(|n| n.parse::<u64>().ok())
+        else {
+            continue;
+        };
+
+        args[i] = Bytes::from_static(b"PXAT");
+        args[i + 1] = Bytes::from(
+            (unix_ms() as u64)
+                .saturating_add(n.saturating_mul(unit))
+                .to_string(),
+        );
+    }
+    args
+}
+
+/// Time left until the Unix timestamp `at`, or `None` if it is in the past.
+fn unix_time(at: Duration) -> Option<Duration> {
+    (UNIX_EPOCH + at).duration_since(SystemTime::now()).ok()
+}
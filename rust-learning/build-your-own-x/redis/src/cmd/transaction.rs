@@ -0,0 +1,256 @@
+//! Transactions: `MULTI`, `EXEC`, `DISCARD`, `WATCH` and `UNWATCH`.
+//!
+//! After `MULTI`, a connection's commands are queued instead of run. `EXEC`
+//! then runs the whole queue through `Db::atomically`, so no other client's
+//! command lands in between. There is no rollback: a command failing at that
+//! point just replies with an error, and the others still run.
+//!
+//! `WATCH` makes the next `EXEC` conditional. Every watched key shares the
+//! connection's flag, which the keyspace raises when one of them is
+//! modified; `EXEC` then gives up and replies with a null. Clients use that
+//! for check-and-set: read under `WATCH`, compute, write under `MULTI`, and
+//! start over if `EXEC` failed.
+
+use crate::acl::User;
+use crate::cmd::{self, list, scripting};
+use crate::parse::Parse;
+use crate::{Command, Db, Frame};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The transaction state of one connection.
+#[derive(Debug)]
+pub struct Transaction {
+    db: Db,
+
+    /// Commands queued since `MULTI`; `None` outside a transaction.
+    queued: Option<Vec<Command>>,
+
+    /// Set when a command was refused while queueing, which makes `EXEC`
+    /// discard the transaction.
+    failed: bool,
+
+    /// The keys being watched.
+    watched: Vec<String>,
+
+    /// Raised by the keyspace when a watched key is modified.
+    dirty: Arc<AtomicBool>,
+}
+
+impl Transaction {
+    pub fn new(db: Db) -> Transaction {
+        Transaction {
+            db,
+            queued: None,
+            failed: false,
+            watched: Vec::new(),
+            dirty: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether the connection is between `MULTI` and `EXEC`.
+    pub fn is_open(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// Run one of the transaction commands. `user` is who runs them; see
+    /// `cmd::execute`.
+    pub async fn handle(&mut self, cmd: &Command, user: Option<&Arc<User>>) -> Frame {
+        let mut parse = cmd.parse();
+
+        let result = match cmd.name() {
+            "multi" => self.multi(&mut parse),
+            "exec" => self.exec(&mut parse, user).await,
+            "discard" => self.discard(&mut parse),
+            "watch" => self.watch(&mut parse),
+            "unwatch" => self.unwatch(&mut parse),
+            name => Err(format!("ERR unknown command '{}'", name).into()),
+        };
+
+        result.unwrap_or_else(|err| Frame::Error(err.to_string()))
+    }
+
+    /// Queue `cmd` for the next `EXEC`.
+    ///
+    /// Commands taking over the connection cannot be part of a transaction;
+    /// they are refused, and the transaction with them.
+    pub fn queue(&mut self, cmd: Command) -> Frame {
+        let Some(queued) = &mut self.queued else {
+            return Frame::Error("ERR no transaction to queue to".into());
+        };
+
+        if cmd.needs_memory() {
+            self.db.evict();
+        }
+        let refused = match cmd.name() {
+            "watch" => Some("ERR WATCH inside MULTI is not allowed".to_string()),
+            "subscribe" | "unsubscribe" | "hello" | "auth" | "acl" | "shutdown" | "asking"
+            | "migrate" => Some(format!(
+                "ERR Command not allowed inside a transaction: '{}'",
+                cmd.name()
+            )),
+            _ => cmd::check_writable(&self.db, &cmd)
+                .err()
+                .map(|err| err.to_string()),
+        };
+        if let Some(err) = refused {
+            self.failed = true;
+            return Frame::Error(err);
+        }
+
+        queued.push(cmd);
+        Frame::Simple("QUEUED".to_string())
+    }
+
+    /// Refuse a command queued in the open transaction, if any, for a reason
+    /// found before it got to `queue`: `EXEC` will discard the transaction.
+    pub fn refuse(&mut self) {
+        if self.is_open() {
+            self.failed = true;
+        }
+    }
+
+    /// `MULTI`
+    fn multi(&mut self, parse: &mut Parse) -> crate::Result<Frame> {
+        parse.finish()?;
+        if self.is_open() {
+            return Err("ERR MULTI calls can not be nested".into());
+        }
+
+        self.queued = Some(Vec::new());
+        Ok(Frame::ok())
+    }
+
+    /// `EXEC`: replies with an array of the replies of the queued commands,
+    /// or a null if a watched key was modified.
+    async fn exec(
+        &mut self,
+        parse: &mut Parse<'_>,
+        user: Option<&Arc<User>>,
+    ) -> crate::Result<Frame> {
+        parse.finish()?;
+        let Some(queued) = self.queued.take() else {
+            return Err("ERR EXEC without MULTI".into());
+        };
+
+        let failed = std::mem::take(&mut self.failed);
+        let result = if failed {
+            Err("EXECABORT Transaction discarded because of previous errors.".into())
+        } else {
+            Ok(run(&self.db, &queued, &self.dirty, user).await)
+        };
+
+        // Whatever happened, the watch is over.
+        self.unwatch_all();
+        result
+    }
+
+    /// `DISCARD`
+    fn discard(&mut self, parse: &mut Parse) -> crate::Result<Frame> {
+        parse.finish()?;
+        if self.queued.take().is_none() {
+            return Err("ERR DISCARD without MULTI".into());
+        }
+
+        self.failed = false;
+        self.unwatch_all();
+        Ok(Frame::ok())
+    }
+
+    /// `WATCH key [key ...]`
+    fn watch(&mut self, parse: &mut Parse) -> crate::Result<Frame> {
+        let mut keys = vec![parse.next_string()?];
+        while parse.remaining() > 0 {
+            keys.push(parse.next_string()?);
+        }
+
+        for key in keys {
+            if !self.watched.contains(&key) {
+                self.db.lock(&key).watch(&key, self.dirty.clone());
+                self.watched.push(key);
+            }
+        }
+        Ok(Frame::ok())
+    }
+
+    /// `UNWATCH`
+    fn unwatch(&mut self, parse: &mut Parse) -> crate::Result<Frame> {
+        parse.finish()?;
+        self.unwatch_all();
+        Ok(Frame::ok())
+    }
+
+    fn unwatch_all(&mut self) {
+        for key in self.watched.drain(..) {
+            self.db.lock(&key).unwatch(&key, &self.dirty);
+        }
+        self.dirty.store(false, Ordering::Release);
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        // The keyspace would otherwise hold on to the flag of a connection
+        // that is gone.
+        self.unwatch_all();
+    }
+}
+
+/// The commands to log for an atomic batch of them: between `MULTI` and
+/// `EXEC`, so that replaying a log cut short in the middle of them leaves
+/// the whole batch out.
+pub(crate) fn wrap(mut logged: Vec<Command>) -> Vec<Command> {
+    if logged.len() > 1 {
+        logged.insert(0, Command::new("multi", vec![]));
+        logged.push(Command::new("exec", vec![]));
+    }
+    logged
+}
+
+/// Run `queued` with nothing else in between, unless `dirty` was raised.
+async fn run(db: &Db, queued: &[Command], dirty: &AtomicBool, user: Option<&Arc<User>>) -> Frame {
+    // Bad arguments are found first, and the commands with them left out.
+    let checked: Vec<_> = queued.iter().map(cmd::check_args).collect();
+
+    let (replies, synced) = db.atomically(|| {
+        db.write(|| {
+            // Checked with every other command held off, so no write can
+            // sneak in between the check and the transaction.
+            if dirty.load(Ordering::Acquire) {
+                return (None, Vec::new());
+            }
+
+            let mut replies = Vec::with_capacity(queued.len());
+            let mut logged = Vec::new();
+            for (cmd, checked) in queued.iter().zip(&checked) {
+                let reply = match cmd.name() {
+                    _ if let Err(err) = checked => Frame::Error(err.to_string()),
+                    // The watch ends with `EXEC` anyway.
+                    "unwatch" => Frame::ok(),
+                    "blpop" | "brpop" => {
+                        list::pop_now(db, cmd).unwrap_or_else(|err| Frame::Error(err.to_string()))
+                    }
+                    "eval" | "evalsha" => scripting::eval_now(db, cmd, user, &mut logged),
+                    _ => cmd::apply(cmd, db),
+                };
+
+                logged.extend(cmd::logged(cmd, &reply));
+                logged.extend(db.take_effects());
+                replies.push(reply);
+            }
+
+            (Some(replies), wrap(logged))
+        })
+    });
+
+    let Some(replies) = replies else {
+        return Frame::Null;
+    };
+    if let Some(synced) = synced
+        && let Err(err) = synced.wait().await
+    {
+        return Frame::Error(err.to_string());
+    }
+    Frame::Array(replies)
+}
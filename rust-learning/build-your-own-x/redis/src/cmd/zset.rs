@@ -0,0 +1,301 @@
+//! Commands operating on sorted set values.
+//!
+//! The data structure itself lives in `crate::zset`; this module parses
+//! requests and shapes replies. Scores are replied as doubles, which RESP2
+//! clients receive as bulk strings.
+
+use crate::cmd::{WRONGTYPE, list};
+use crate::parse::Parse;
+use crate::zset::{ScoreBound, ZSet};
+use crate::{Db, Frame, Value};
+
+use bytes::Bytes;
+
+/// `ZADD key [NX | XX] [GT | LT] [CH] score member [score member ...]`
+///
+/// * `NX` only adds new members, `XX` only updates existing ones.
+/// * `GT` and `LT` only update a member if its new score is greater, or
+///   less, than the current one.
+/// * `CH` replies with the number of members added or updated, instead of
+///   only the added ones.
+pub fn zadd(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+
+    let (mut nx, mut xx, mut gt, mut lt, mut ch) = (false, false, false, false, false);
+    let mut first = loop {
+        let arg = parse.next_bytes()?;
+        match arg.to_ascii_uppercase().as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"GT" => gt = true,
+            b"LT" => lt = true,
+            b"CH" => ch = true,
+            _ => break arg,
+        }
+    };
+
+    if nx && xx {
+        return Err("ERR XX and NX options at the same time are not compatible".into());
+    }
+    if (gt && lt) || (nx && (gt || lt)) {
+        return Err("ERR GT, LT, and/or NX options at the same time are not compatible".into());
+    }
+
+    // Validate every pair before touching the set.
+    let mut pairs = Vec::new();
+    loop {
+        let score = score(&first)?;
+        let member = parse.next_bytes().map_err(|_| "ERR syntax error")?;
+        pairs.push((score, member));
+
+        if parse.remaining() == 0 {
+            break;
+        }
+        first = parse.next_bytes()?;
+    }
+
+    let mut shard = db.lock(&key);
+    let zset = match shard.get_mut(&key) {
+        Some(Value::ZSet(zset)) => zset,
+        Some(_) => return Err(WRONGTYPE.into()),
+        // `XX` never creates the key.
+        None if xx => return Ok(Frame::Integer(0)),
+        None => {
+            shard.insert(key.clone(), Value::ZSet(ZSet::new()));
+            match shard.get_mut(&key) {
+                Some(Value::ZSet(zset)) => zset,
+                _ => unreachable!(),
+            }
+        }
+    };
+
+    let mut added = 0;
+    let mut updated = 0;
+    for (score, member) in pairs {
+        match zset.score(&member) {
+            None if !xx => {
+                zset.insert(member, score);
+                added += 1;
+            }
+            Some(current) if !nx && current != score => {
+                if (gt && score < current) || (lt && score > current) {
+                    continue;
+                }
+                zset.insert(member, score);
+                updated += 1;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Frame::Integer(if ch { added + updated } else { added }))
+}
+
+/// `ZRANGE key start stop [BYSCORE] [REV] [LIMIT offset count] [WITHSCORES]`
+///
+/// By default `start` and `stop` are inclusive ranks, negative ones counting
+/// from the end. With `BYSCORE` they are score bounds, `(` marking an
+/// exclusive one. `REV` walks from the highest score down; with `BYSCORE`
+/// the bounds are then given as `max min`.
+pub fn zrange(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let start = parse.next_bytes()?;
+    let stop = parse.next_bytes()?;
+
+    let mut by_score = false;
+    let mut options = RangeOptions::default();
+    while parse.remaining() > 0 {
+        let option = parse.next_string()?.to_ascii_uppercase();
+        match option.as_str() {
+            "BYSCORE" => by_score = true,
+            "REV" => options.rev = true,
+            option => options.parse(option, parse)?,
+        }
+    }
+
+    if by_score {
+        let (min, max) = if options.rev {
+            (stop, start)
+        } else {
+            (start, stop)
+        };
+        return range_by_score(db, &key, bound(&min)?, bound(&max)?, options);
+    }
+
+    if options.limit.is_some() {
+        return Err(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                .into(),
+        );
+    }
+    let start = int(&start)?;
+    let stop = int(&stop)?;
+
+    let mut shard = db.lock(&key);
+    let Some(zset) = zset(shard.get(&key))? else {
+        return Ok(Frame::Array(vec![]));
+    };
+
+    let len = zset.len();
+    let Some((start, stop)) = list::range(start, stop, len) else {
+        return Ok(Frame::Array(vec![]));
+    };
+
+    // With `REV`, rank 0 is the last element.
+    let (start, end) = if options.rev {
+        (len - 1 - stop, len - start)
+    } else {
+        (start, stop + 1)
+    };
+    Ok(reply(zset.range_by_rank(start, end, options.rev), options))
+}
+
+/// `ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]`
+pub fn zrangebyscore(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    let key = parse.next_string()?;
+    let min = bound(&parse.next_bytes()?)?;
+    let max = bound(&parse.next_bytes()?)?;
+
+    let mut options = RangeOptions::default();
+    while parse.remaining() > 0 {
+        let option = parse.next_string()?.to_ascii_uppercase();
+        options.parse(&option, parse)?;
+    }
+
+    range_by_score(db, &key, min, max, options)
+}
+
+/// Options shared by the range commands.
+#[derive(Debug, Default)]
+struct RangeOptions {
+    rev: bool,
+    with_scores: bool,
+
+    /// `LIMIT offset count`. A negative count means no limit.
+    limit: Option<(i64, i64)>,
+}
+
+impl RangeOptions {
+    /// Parse `WITHSCORES` or `LIMIT offset count`, the name of which was
+    /// just read.
+    fn parse(&mut self, option: &str, parse: &mut Parse) -> crate::Result<()> {
+        match option {
+            "WITHSCORES" => self.with_scores = true,
+            "LIMIT" => self.limit = Some((parse.next_int()?, parse.next_int()?)),
+            _ => return Err("ERR syntax error".into()),
+        }
+        Ok(())
+    }
+}
+
+fn range_by_score(
+    db: &Db,
+    key: &str,
+    min: ScoreBound,
+    max: ScoreBound,
+    options: RangeOptions,
+) -> crate::Result<Frame> {
+    let mut shard = db.lock(key);
+    let Some(zset) = zset(shard.get(key))? else {
+        return Ok(Frame::Array(vec![]));
+    };
+
+    // Locating the range is logarithmic, and so is jumping to the offset,
+    // thanks to ranks.
+    let (mut start, mut end) = zset.score_range(min, max);
+    match options.limit {
+        Some((offset, _)) if offset < 0 => return Ok(Frame::Array(vec![])),
+        Some((offset, count)) => {
+            let offset = offset as usize;
+            if options.rev {
+                end = end.saturating_sub(offset).max(start);
+                if count >= 0 {
+                    start = start.max(end.saturating_sub(count as usize));
+                }
+            } else {
+                start = start.saturating_add(offset).min(end);
+                if count >= 0 {
+                    end = end.min(start.saturating_add(count as usize));
+                }
+            }
+        }
+        None => {}
+    }
+
+    Ok(reply(zset.range_by_rank(start, end, options.rev), options))
+}
+
+fn reply<'a>(members: impl Iterator<Item = (&'a Bytes, f64)>, options: RangeOptions) -> Frame {
+    let mut items = Vec::new();
+    for (member, score) in members {
+        items.push(Frame::Bulk(member.clone()));
+        if options.with_scores {
+            items.push(Frame::Double(score));
+        }
+    }
+    Frame::Array(items)
+}
+
+/// The sorted set in `value`, or a `WRONGTYPE` error if it holds something
+/// else.
+fn zset(value: Option<&Value>) -> crate::Result<Option<&ZSet>> {
+    match value {
+        Some(Value::ZSet(zset)) => Ok(Some(zset)),
+        Some(_) => Err(WRONGTYPE.into()),
+        None => Ok(None),
+    }
+}
+
+/// Parse a score. Infinities are allowed, NaN is not.
+fn score(arg: &[u8]) -> crate::Result<f64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| "ERR value is not a valid float".into())
+}
+
+/// Parse a score range bound: a score, `(score` for an exclusive bound, or
+/// `-inf` / `+inf`.
+fn bound(arg: &[u8]) -> crate::Result<ScoreBound> {
+    let (exclusive, arg) = match arg.strip_prefix(b"(") {
+        Some(rest) => (true, rest),
+        None => (false, arg),
+    };
+    let score = score(arg).map_err(|_| "ERR min or max is not a float")?;
+
+    Ok(if exclusive {
+        ScoreBound::Exclusive(score)
+    } else {
+        ScoreBound::Inclusive(score)
+    })
+}
+
+fn int(arg: &[u8]) -> crate::Result<i64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "ERR value is not an integer or out of range".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bounds() {
+        assert_eq!(bound(b"1.5").unwrap(), ScoreBound::Inclusive(1.5));
+        assert_eq!(bound(b"(2").unwrap(), ScoreBound::Exclusive(2.0));
+        assert_eq!(
+            bound(b"-inf").unwrap(),
+            ScoreBound::Inclusive(f64::NEG_INFINITY)
+        );
+        assert_eq!(
+            bound(b"(+inf").unwrap(),
+            ScoreBound::Exclusive(f64::INFINITY)
+        );
+        assert!(bound(b"nan").is_err());
+        assert!(bound(b"(").is_err());
+        assert!(score(b"abc").is_err());
+    }
+}
@@ -0,0 +1,592 @@
+//! Server settings, from the command line and an optional configuration
+//! file.
+//!
+//! The file uses the `redis.conf` format: one `name value` pair per line,
+//! with `#` starting a comment. Command-line flags are the same settings
+//! spelled `--name value`, and override the file, so
+//!
+//! ```text
+//! redis /etc/redis.conf --port 6380
+//! ```
+//!
+//! runs with everything from the file except the port.
+//!
+//! Some settings may also change while the server runs, through
+//! `CONFIG SET`; see `MUTABLE`.
+
+use crate::aof::Fsync;
+use crate::db::{Eviction, MAX_SHARDS};
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+/// Default number of keyspace shards.
+///
+/// A few times the number of cores a typical machine has; enough that two
+/// busy connections are unlikely to land on the same lock.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Port clients connect to by default.
+pub const DEFAULT_PORT: u16 = 6379;
+
+/// Default cap on simultaneous clients, as in Redis.
+pub const DEFAULT_MAXCLIENTS: usize = 10_000;
+
+/// Every setting, in the order `CONFIG GET *` lists them.
+pub const NAMES: &[&str] = &[
+    "bind",
+    "port",
+    "tls-port",
+    "tls-cert-file",
+    "tls-key-file",
+    "maxclients",
+    "timeout",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "shards",
+    "dir",
+    "dbfilename",
+    "appendonly",
+    "appendfilename",
+    "appendfsync",
+    "replicaof",
+    "cluster-enabled",
+    "masteruser",
+    "masterauth",
+    "requirepass",
+];
+
+/// The settings `CONFIG SET` may change. The others are read once, at
+/// startup.
+pub const MUTABLE: &[&str] = &[
+    "maxclients",
+    "timeout",
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "dir",
+    "dbfilename",
+    "appendonly",
+    "masteruser",
+    "masterauth",
+    "requirepass",
+];
+
+/// Server settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address to accept clients on.
+    pub bind: IpAddr,
+
+    /// Port to accept clients on.
+    pub port: u16,
+
+    /// Port to accept clients on over TLS, `0` for none.
+    pub tls_port: u16,
+
+    /// The certificate chain presented on `tls_port`, PEM.
+    pub tls_cert_file: Option<PathBuf>,
+
+    /// The private key of that certificate, PEM.
+    pub tls_key_file: Option<PathBuf>,
+
+    /// Most clients connected at once; more are turned away.
+    pub maxclients: usize,
+
+    /// Seconds after which an idle client is disconnected, `0` for never.
+    pub timeout: u64,
+
+    /// Most memory the keyspace may use, in bytes, `0` for no limit.
+    pub maxmemory: usize,
+
+    /// What to do when the keyspace is full.
+    pub maxmemory_policy: Eviction,
+
+    /// How many keys eviction looks at to pick one.
+    pub maxmemory_samples: usize,
+
+    /// Number of shards the keyspace is split into.
+    pub shards: usize,
+
+    /// Directory holding the persistence files.
+    pub dir: PathBuf,
+
+    /// Whether write commands are logged to an append-only file.
+    pub appendonly: bool,
+
+    /// Name of the snapshot file, within `dir`.
+    pub dbfilename: String,
+
+    /// Name of the append-only file, within `dir`.
+    pub appendfilename: String,
+
+    /// When the append-only file is synced to disk.
+    pub appendfsync: Fsync,
+
+    /// Host and port of the primary, when running as a replica.
+    pub replicaof: Option<(String, u16)>,
+
+    /// Whether the server runs as a node of a cluster.
+    pub cluster_enabled: bool,
+
+    /// The user a replica logs into its primary as, and a cluster node into
+    /// the others, `default` if not set.
+    pub masteruser: Option<String>,
+
+    /// The password that user logs in with, if it needs one.
+    pub masterauth: Option<String>,
+
+    /// The password of the `default` user; without one, anybody connecting
+    /// is logged in as it.
+    pub requirepass: Option<String>,
+
+    /// ACL users, from `user` lines: a name then `ACL SETUSER` rules, as in
+    /// `user alice on >secret ~cache:* +@read`. Only read at startup; see
+    /// `Acl::from_config`.
+    pub users: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            tls_port: 0,
+            tls_cert_file: None,
+            tls_key_file: None,
+            maxclients: DEFAULT_MAXCLIENTS,
+            timeout: 0,
+            maxmemory: 0,
+            maxmemory_policy: Eviction::default(),
+            maxmemory_samples: 5,
+            shards: DEFAULT_SHARDS,
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_string(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: Fsync::default(),
+            replicaof: None,
+            cluster_enabled: false,
+            masteruser: None,
+            masterauth: None,
+            requirepass: None,
+            users: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Build a `Config` from command-line arguments.
+    ///
+    /// `args` should not include the program name. Like `redis-server`, it
+    /// may start with the path of a configuration file, then have flags of
+    /// the form `--name value...`, which win over the file.
+    pub fn from_args<I>(args: I) -> Result<Config, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Config::default();
+        let mut args = args.into_iter().peekable();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            config.load_file(Path::new(&path))?;
+        }
+
+        while let Some(flag) = args.next() {
+            let Some(name) = flag.strip_prefix("--") else {
+                return Err(format!("unexpected argument '{}'", flag));
+            };
+
+            // A setting takes every argument up to the next flag.
+            let mut values = Vec::new();
+            while let Some(value) = args.next_if(|arg| !arg.starts_with("--")) {
+                values.push(value);
+            }
+            if values.is_empty() {
+                return Err(format!("missing value for {}", flag));
+            }
+
+            config.set(name, &values.join(" "))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Apply the configuration file at `path` on top of the current
+    /// settings.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("can't read '{}': {}", path.display(), err))?;
+        self.parse(&text)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    /// Apply settings in the configuration file format.
+    pub fn parse(&mut self, text: &str) -> Result<(), String> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            // Values may be quoted, to keep spaces in paths.
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+
+            self.set(name, value)
+                .map_err(|err| format!("line {}: {}", n + 1, err))?;
+        }
+        Ok(())
+    }
+
+    /// Change the setting `name`, given in the file format.
+    ///
+    /// Anything is accepted here; whether a setting may change at runtime
+    /// is up to `CONFIG SET`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name.to_ascii_lowercase().as_str() {
+            "bind" => {
+                self.bind = value
+                    .parse()
+                    .map_err(|_| format!("invalid bind address '{}'", value))?;
+            }
+            "port" => self.port = port(value)?,
+            "tls-port" => self.tls_port = port(value)?,
+            "tls-cert-file" => self.tls_cert_file = non_empty(value).map(PathBuf::from),
+            "tls-key-file" => self.tls_key_file = non_empty(value).map(PathBuf::from),
+            "maxclients" => {
+                self.maxclients = match value.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("invalid maxclients '{}'", value)),
+                };
+            }
+            "timeout" => {
+                self.timeout = value
+                    .parse()
+                    .map_err(|_| format!("invalid timeout '{}'", value))?;
+            }
+            "maxmemory" => self.maxmemory = memory(value)?,
+            "maxmemory-policy" => self.maxmemory_policy = value.parse()?,
+            "maxmemory-samples" => {
+                self.maxmemory_samples = match value.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("invalid maxmemory-samples '{}'", value)),
+                };
+            }
+            "shards" => {
+                self.shards = match value.parse() {
+                    Ok(n) if (1..=MAX_SHARDS).contains(&n) => n,
+                    _ => return Err(format!("invalid shard count '{}'", value)),
+                };
+            }
+            "dir" => self.dir = PathBuf::from(value),
+            "dbfilename" => self.dbfilename = file_name(value)?,
+            "appendonly" => self.appendonly = yes_no(value)?,
+            "appendfilename" => self.appendfilename = file_name(value)?,
+            "appendfsync" => self.appendfsync = value.parse()?,
+            "replicaof" => {
+                self.replicaof = match value.split_whitespace().collect::<Vec<_>>()[..] {
+                    [host, p] => Some((host.to_string(), port(p)?)),
+                    _ => return Err(format!("expected a host and a port, got '{}'", value)),
+                };
+            }
+            "cluster-enabled" => self.cluster_enabled = yes_no(value)?,
+            "masteruser" => self.masteruser = non_empty(value),
+            "masterauth" => self.masterauth = non_empty(value),
+            "requirepass" => self.requirepass = non_empty(value),
+            // Unlike the others, this one may be given many times.
+            "user" => self.users.push(value.to_string()),
+            _ => return Err(format!("unknown option '{}'", name)),
+        }
+        Ok(())
+    }
+
+    /// The value of the setting `name`, in the file format.
+    pub fn get(&self, name: &str) -> Option<String> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "bind" => self.bind.to_string(),
+            "port" => self.port.to_string(),
+            "tls-port" => self.tls_port.to_string(),
+            "tls-cert-file" => path(&self.tls_cert_file),
+            "tls-key-file" => path(&self.tls_key_file),
+            "maxclients" => self.maxclients.to_string(),
+            "timeout" => self.timeout.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.to_string(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "shards" => self.shards.to_string(),
+            "dir" => self.dir.display().to_string(),
+            "dbfilename" => self.dbfilename.clone(),
+            "appendonly" => if self.appendonly { "yes" } else { "no" }.to_string(),
+            "appendfilename" => self.appendfilename.clone(),
+            "appendfsync" => self.appendfsync.to_string(),
+            "replicaof" => match &self.replicaof {
+                Some((host, port)) => format!("{} {}", host, port),
+                None => String::new(),
+            },
+            "cluster-enabled" => if self.cluster_enabled { "yes" } else { "no" }.to_string(),
+            "masteruser" => self.masteruser.clone().unwrap_or_default(),
+            "masterauth" => self.masterauth.clone().unwrap_or_default(),
+            "requirepass" => self.requirepass.clone().unwrap_or_default(),
+            _ => return None,
+        })
+    }
+
+    /// Path of the snapshot file.
+    pub fn rdb_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    /// Path of the append-only file.
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+}
+
+fn port(value: &str) -> Result<u16, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid port '{}'", value))
+}
+
+/// A number of bytes, optionally with a unit: `k`, `m` and `g` are powers
+/// of 1000, `kb`, `mb` and `gb` powers of 1024, as in `redis.conf`.
+fn memory(value: &str) -> Result<usize, String> {
+    let lower = value.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory size '{}'", value)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid memory size '{}'", value))
+}
+
+/// An optional setting, where an empty value means it is not set.
+fn non_empty(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn path(value: &Option<PathBuf>) -> String {
+    value
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default()
+}
+
+fn yes_no(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("expected yes or no, got '{}'", value)),
+    }
+}
+
+/// File names live in `dir`; they can't point elsewhere.
+fn file_name(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains('/') {
+        return Err(format!("invalid file name '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults_without_flags() {
+        let config = Config::from_args(args(&[])).unwrap();
+        assert_eq!(config.shards, DEFAULT_SHARDS);
+    }
+
+    #[test]
+    fn parses_shards() {
+        let config = Config::from_args(args(&["--shards", "64"])).unwrap();
+        assert_eq!(config.shards, 64);
+    }
+
+    #[test]
+    fn parses_persistence_options() {
+        let config = Config::from_args(args(&[
+            "--dir",
+            "/var/lib/redis",
+            "--appendonly",
+            "yes",
+            "--appendfsync",
+            "always",
+        ]))
+        .unwrap();
+        assert!(config.appendonly);
+        assert_eq!(config.rdb_path(), PathBuf::from("/var/lib/redis/dump.rdb"));
+        assert_eq!(config.appendfsync, Fsync::Always);
+        assert_eq!(
+            config.aof_path(),
+            PathBuf::from("/var/lib/redis/appendonly.aof")
+        );
+
+        assert!(Config::from_args(args(&["--appendonly", "maybe"])).is_err());
+        assert!(Config::from_args(args(&["--appendfsync", "often"])).is_err());
+    }
+
+    #[test]
+    fn parses_replication_options() {
+        let config = Config::from_args(args(&[
+            "--port",
+            "6380",
+            "--replicaof",
+            "localhost",
+            "6379",
+        ]))
+        .unwrap();
+        assert_eq!(config.port, 6380);
+        assert_eq!(config.replicaof, Some(("localhost".to_string(), 6379)));
+
+        assert!(Config::from_args(args(&["--replicaof", "localhost"])).is_err());
+        assert!(Config::from_args(args(&["--port", "70000"])).is_err());
+    }
+
+    #[test]
+    fn parses_tls_options() {
+        let config = Config::from_args(args(&[
+            "--tls-port",
+            "6380",
+            "--tls-cert-file",
+            "/etc/redis/redis.crt",
+            "--tls-key-file",
+            "/etc/redis/redis.key",
+        ]))
+        .unwrap();
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.tls_port, 6380);
+        assert_eq!(config.get("tls-cert-file").unwrap(), "/etc/redis/redis.crt");
+        assert_eq!(
+            config.tls_key_file,
+            Some(PathBuf::from("/etc/redis/redis.key"))
+        );
+        assert_eq!(Config::default().get("tls-port").unwrap(), "0");
+    }
+
+    #[test]
+    fn cluster_mode_is_set_at_startup() {
+        let config = Config::from_args(args(&["--cluster-enabled", "yes"])).unwrap();
+        assert!(config.cluster_enabled);
+        assert!(!Config::default().cluster_enabled);
+
+        assert_eq!(config.get("cluster-enabled").unwrap(), "yes");
+        assert!(!MUTABLE.contains(&"cluster-enabled"));
+        assert!(Config::from_args(args(&["--cluster-enabled", "maybe"])).is_err());
+    }
+
+    #[test]
+    fn parses_a_config_file() {
+        let mut config = Config::default();
+        config
+            .parse(
+                "# Listen everywhere\n\
+                 bind 0.0.0.0\n\
+                 \n\
+                 port 7000\n\
+                 maxclients 64\n\
+                 timeout 300\n\
+                 dir \"/var/lib/my redis\"\n\
+                 appendonly yes\n\
+                 replicaof 10.0.0.1 6379\n\
+                 requirepass hunter2\n\
+                 user alice on >secret ~cache:* +@read\n\
+                 user bob on nopass +ping\n",
+            )
+            .unwrap();
+        assert_eq!(config.bind.to_string(), "0.0.0.0");
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.maxclients, 64);
+        assert_eq!(config.timeout, 300);
+        assert_eq!(config.dir, PathBuf::from("/var/lib/my redis"));
+        assert!(config.appendonly);
+        assert_eq!(config.get("replicaof").unwrap(), "10.0.0.1 6379");
+        assert_eq!(config.requirepass.as_deref(), Some("hunter2"));
+        assert_eq!(
+            config.users,
+            ["alice on >secret ~cache:* +@read", "bob on nopass +ping"]
+        );
+
+        let err = Config::default()
+            .parse("port 1\nmaxclients 0\n")
+            .unwrap_err();
+        assert!(err.starts_with("line 2:"), "{}", err);
+        assert!(Config::default().parse("bind localhost").is_err());
+        assert!(Config::default().parse("dbfilename ../dump.rdb").is_err());
+    }
+
+    #[test]
+    fn parses_memory_sizes() {
+        let config = Config::from_args(args(&[
+            "--maxmemory",
+            "100mb",
+            "--maxmemory-policy",
+            "allkeys-lru",
+        ]))
+        .unwrap();
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+        assert_eq!(config.maxmemory_policy, Eviction::AllKeysLru);
+
+        assert_eq!(memory("1000").unwrap(), 1000);
+        assert_eq!(memory("2k").unwrap(), 2000);
+        assert_eq!(memory("1GB").unwrap(), 1 << 30);
+        assert!(memory("12tb").is_err());
+        assert!(memory("mb").is_err());
+        assert!(Config::from_args(args(&["--maxmemory-policy", "volatile-lru"])).is_err());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = std::env::temp_dir().join(format!("redis-conf-{}.conf", std::process::id()));
+        std::fs::write(&path, "port 7000\ntimeout 10\n").unwrap();
+
+        let config = Config::from_args(args(&[path.to_str().unwrap(), "--port", "7001"])).unwrap();
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.timeout, 10);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(Config::from_args(args(&[path.to_str().unwrap()])).is_err());
+    }
+
+    #[test]
+    fn every_setting_reads_back() {
+        let config = Config::default();
+        for name in NAMES {
+            let value = config.get(name).unwrap();
+            if !value.is_empty() {
+                config.clone().set(name, &value).unwrap();
+            }
+        }
+        assert_eq!(config.get("appendonly").unwrap(), "no");
+        assert_eq!(config.get("nope"), None);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(Config::from_args(args(&["--shards", "0"])).is_err());
+        assert!(Config::from_args(args(&["--shards", "100000"])).is_err());
+        assert!(Config::from_args(args(&["--shards"])).is_err());
+        assert!(Config::from_args(args(&["--nope", "1"])).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::frame::{Decoder, Frame, Protocol};
+
+use bytes::BytesMut;
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Pending replies beyond this many bytes are written out without waiting
+/// for the end of the batch.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// A byte stream to a peer that a `Connection` can run over: a plain TCP
+/// socket, or one wrapped in TLS by the `tls` module.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Debug + 'static {
+    /// Address of the peer.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Stream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+/// Send and receive `Frame` values from a remote peer.
+///
+/// To read frames, the `Connection` uses an internal buffer, which is filled
+/// up until there are enough bytes to create a full frame. Once this happens,
+/// the `Connection` creates the frame and returns it to the caller.
+///
+/// Written frames are encoded into a write buffer and only sent when `flush`
+/// is called, so a batch of pipelined replies goes out in a single write.
+/// Replies are encoded for the protocol version negotiated with `HELLO`,
+/// RESP2 until then.
+#[derive(Debug)]
+pub struct Connection {
+    stream: Box<dyn Stream>,
+
+    // The buffer for reading frames, and the decoder state for a frame that
+    // has only partly arrived.
+    buffer: BytesMut,
+    decoder: Decoder,
+
+    // Encoded frames waiting for `flush`.
+    out: BytesMut,
+
+    protocol: Protocol,
+}
+
+impl Connection {
+    /// Create a new `Connection`, backed by `socket`. Read and write buffers
+    /// are initialized.
+    pub fn new(socket: impl Stream) -> Connection {
+        Connection {
+            stream: Box::new(socket),
+            buffer: BytesMut::with_capacity(4 * 1024),
+            decoder: Decoder::new(),
+            out: BytesMut::with_capacity(4 * 1024),
+            protocol: Protocol::default(),
+        }
+    }
+
+    /// The protocol version replies are encoded with.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Address of the peer.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Switch the protocol version used for replies from now on.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Return the next frame if it is already fully buffered, without
+    /// touching the socket.
+    ///
+    /// Pipelining clients send many requests at once; this lets the caller
+    /// drain everything that arrived with the last read before replying.
+    pub fn buffered_frame(&mut self) -> crate::Result<Option<Frame>> {
+        self.decoder.decode(&mut self.buffer)
+    }
+
+    /// Read a single `Frame` value from the underlying stream.
+    ///
+    /// The function waits until it has retrieved enough data to parse a frame.
+    /// Any data remaining in the read buffer after the frame has been parsed is
+    /// kept there for the next call to `read_frame`.
+    ///
+    /// Returns `None` if the peer closed the connection between frames, and
+    /// an error if it closed the connection in the middle of one.
+    pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        loop {
+            // Attempt to parse a frame from the buffered data. If enough data
+            // has been buffered, the frame is returned.
+            if let Some(frame) = self.decoder.decode(&mut self.buffer)? {
+                return Ok(Some(frame));
+            }
+
+            // There is not enough buffered data to read a frame. Attempt to
+            // read more data from the socket. `0` indicates "end of stream".
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() && !self.decoder.is_partial() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+
+    /// Wait until the peer closes the connection.
+    ///
+    /// Anything the peer sends in the meantime is kept in the read buffer for
+    /// the next `read_frame`. Used while a command blocks, so a client that
+    /// goes away is noticed instead of being handed data it will never read.
+    pub async fn closed(&mut self) -> io::Result<()> {
+        while self.stream.read_buf(&mut self.buffer).await? != 0 {}
+        Ok(())
+    }
+
+    /// Queue a single `Frame` value to be written to the stream.
+    ///
+    /// The frame is only buffered; call `flush` to send it. Should a client
+    /// pipeline enough requests for the replies to pile up, they are flushed
+    /// early so the buffer does not grow without bound.
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        frame.encode(&mut self.out, self.protocol);
+
+        if self.out.len() >= MAX_PENDING {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Queue data that already is encoded frames, as when relaying a stream
+    /// of them.
+    pub async fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.extend_from_slice(data);
+
+        if self.out.len() >= MAX_PENDING {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Send every queued frame to the peer.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if !self.out.is_empty() {
+            self.stream.write_all(&self.out).await?;
+            self.out.clear();
+        }
+        Ok(())
+    }
+}
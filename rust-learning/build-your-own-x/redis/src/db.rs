@@ -0,0 +1,1407 @@
+use crate::acl::Acl;
+use crate::aof::{Aof, Synced};
+use crate::blocking::Waiter;
+use crate::cluster::Cluster;
+use crate::replication::{Feed, Link};
+use crate::shutdown::SaveMode;
+use crate::{Command, Config, Value};
+
+use bytes::Bytes;
+use rhai::AST;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
+use std::time::Duration;
+use tokio::sync::{Notify, broadcast, watch};
+use tokio::time::{self, Instant};
+
+/// The keyspace shared by every connection.
+///
+/// Keys are spread over a fixed number of shards, each behind its own
+/// `Mutex`, so connections touching different keys rarely wait on each other.
+/// A `std::sync::Mutex` is fine here: a lock is only ever held for the
+/// duration of a single command and never across an `.await`.
+///
+/// Keys may carry an expiration. An expired key reads as missing straight
+/// away; a background task evicts it from memory once its deadline passes.
+///
+/// The memory used by each value is estimated as it changes. Past
+/// `maxmemory`, keys are evicted to make room, see `Db::evict`.
+///
+/// Commands normally interleave freely. A transaction can instead run a batch
+/// of them with nothing else in between, see `Db::atomically`, and clients
+/// can watch keys to learn whether anyone modified them.
+///
+/// The `Db` also hosts the pub/sub channels, which live outside the keyspace,
+/// the append-only file and the replication stream write commands are sent
+/// to, the script cache, the users, the state of the cluster and the
+/// settings in effect.
+///
+/// Cloning a `Db` is cheap; it only bumps a reference count.
+#[derive(Debug, Clone)]
+pub struct Db {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    shards: Box<[Mutex<Shard>]>,
+
+    /// Read-locked by every command, write-locked by transactions, which
+    /// thereby run alone.
+    ///
+    /// Like `write_order`, it guards no data: one poisoned by a command
+    /// that panicked is used as it is, rather than taking every later
+    /// command down too.
+    exec_gate: RwLock<()>,
+
+    /// Pub/sub channels. A channel exists for as long as someone may still
+    /// be subscribed to it.
+    pub_sub: Mutex<HashMap<String, broadcast::Sender<Bytes>>>,
+
+    /// Wakes the purge task when a key gets a deadline earlier than the ones
+    /// it is sleeping towards, or when the last `Db` handle goes away.
+    background_task: Arc<Notify>,
+
+    /// The append-only file, once persistence is enabled.
+    aof: RwLock<Option<Aof>>,
+
+    /// The stream of writes sent to replicas, once one attached.
+    feed: RwLock<Option<Arc<Feed>>>,
+
+    /// The link to the primary, on a replica.
+    link: RwLock<Option<Arc<Link>>>,
+
+    /// Held while a write command runs and gets logged, so that writes to
+    /// the same key reach the log in the order they were applied.
+    write_order: Mutex<()>,
+
+    /// Effects to log after the running write command, recorded with
+    /// `ShardGuard::propagate`.
+    effects: Mutex<Vec<Command>>,
+
+    /// Compiled scripts, by the SHA1 digest of their source.
+    scripts: Mutex<HashMap<String, Arc<AST>>>,
+
+    /// The settings in effect, some of which `CONFIG SET` changes.
+    config: RwLock<Config>,
+
+    /// The users clients log in as.
+    acl: RwLock<Acl>,
+
+    /// The cluster this server is a node of, in cluster mode.
+    cluster: RwLock<Option<Cluster>>,
+
+    /// Set while a `BGSAVE` is running.
+    saving: AtomicBool,
+
+    /// Unix time, in seconds, of the last successful snapshot.
+    last_save: AtomicU64,
+
+    /// Estimated memory used by the keyspace, in bytes: the sum of every
+    /// shard's `used`.
+    used: AtomicUsize,
+
+    /// Set once a shutdown is requested, with what to save.
+    shutdown: watch::Sender<Option<SaveMode>>,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<String, Entry>,
+
+    /// Keys with a TTL, ordered by deadline. The key is part of the tuple so
+    /// two keys expiring at the same instant do not collide.
+    expirations: BTreeSet<(Instant, String)>,
+
+    /// Clients blocked on a key, oldest first.
+    blocked: HashMap<String, VecDeque<Arc<Waiter>>>,
+
+    /// Clients blocked reading the stream at a key.
+    readers: HashMap<String, Vec<Arc<Notify>>>,
+
+    /// Flags of the clients watching a key, raised when it is modified.
+    watched: HashMap<String, Vec<Arc<AtomicBool>>>,
+
+    /// Every key, ordered by `scan_position`, so `SCAN` can resume where it
+    /// left off and eviction can pick keys at random.
+    keys: BTreeSet<(u64, String)>,
+
+    /// Estimated memory used by the entries, in bytes.
+    used: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+
+    /// What the entry counts for in `Shard::used`.
+    size: usize,
+
+    /// When a command last read or wrote the value.
+    accessed: Instant,
+
+    /// How often the value is accessed, on a logarithmic scale; see
+    /// `Entry::hit`.
+    hits: u8,
+}
+
+/// How keys are picked for eviction once `maxmemory` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    /// Evict nothing: refuse writes needing more memory instead.
+    #[default]
+    NoEviction,
+
+    /// The least recently used key.
+    AllKeysLru,
+
+    /// The least frequently used key.
+    AllKeysLfu,
+
+    /// Among keys with a TTL, the one closest to expiring.
+    VolatileTtl,
+
+    /// Any key.
+    AllKeysRandom,
+}
+
+/// Memory counted for an entry on top of its key and value: the slot in the
+/// map, the `Entry` itself and the copy of the key in `Shard::keys`.
+const ENTRY_OVERHEAD: usize = 96;
+
+/// Bits of a `SCAN` cursor giving a position within a shard; the bits above
+/// them give the shard.
+const CURSOR_BITS: u32 = 48;
+
+/// The most shards a keyspace can have, for their index to fit in a cursor.
+pub const MAX_SHARDS: usize = 1 << (64 - CURSOR_BITS);
+
+/// `hits` of a new key, so it is not the first to go just because it has
+/// not been read yet.
+const LFU_INIT: u8 = 5;
+
+/// How much harder `hits` gets to increase as it grows.
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// `hits` drops by one for every such period without access.
+const LFU_DECAY: Duration = Duration::from_secs(60);
+
+impl Db {
+    /// Create a keyspace split into `num_shards` shards.
+    ///
+    /// This spawns the task purging expired keys, so it must be called from
+    /// within a Tokio runtime. The task exits once every handle is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_shards` is zero or over `MAX_SHARDS`.
+    pub fn new(num_shards: usize) -> Db {
+        assert!(num_shards > 0, "a keyspace needs at least one shard");
+        assert!(
+            num_shards <= MAX_SHARDS,
+            "too many shards for a scan cursor"
+        );
+
+        let shards = (0..num_shards)
+            .map(|_| Mutex::new(Shard::default()))
+            .collect();
+        let background_task = Arc::new(Notify::new());
+
+        let shared = Arc::new(Shared {
+            shards,
+            exec_gate: RwLock::new(()),
+            pub_sub: Mutex::new(HashMap::new()),
+            background_task: background_task.clone(),
+            aof: RwLock::new(None),
+            feed: RwLock::new(None),
+            link: RwLock::new(None),
+            write_order: Mutex::new(()),
+            effects: Mutex::new(Vec::new()),
+            scripts: Mutex::new(HashMap::new()),
+            config: RwLock::new(Config::default()),
+            acl: RwLock::new(Acl::default()),
+            cluster: RwLock::new(None),
+            saving: AtomicBool::new(false),
+            last_save: AtomicU64::new(0),
+            used: AtomicUsize::new(0),
+            shutdown: watch::channel(None).0,
+        });
+
+        // The task only holds a weak reference, otherwise the keyspace would
+        // keep itself alive forever.
+        tokio::spawn(purge_expired_tasks(
+            Arc::downgrade(&shared),
+            background_task,
+        ));
+
+        Db { shared }
+    }
+
+    /// Number of shards the keyspace is split into.
+    pub fn num_shards(&self) -> usize {
+        self.shared.shards.len()
+    }
+
+    /// Lock the shard holding `key`.
+    ///
+    /// Commands use the guard to read and modify values in place. Keep it
+    /// short-lived: it blocks every other key in the same shard.
+    pub fn lock(&self, key: &str) -> ShardGuard<'_> {
+        let index = shard_index(key, self.shared.shards.len());
+        let shard = self.shared.shards[index].lock().unwrap();
+        ShardGuard {
+            shared: &self.shared,
+            used: shard.used,
+            shard,
+            now: Instant::now(),
+            wake_purger: false,
+            resized: Vec::new(),
+        }
+    }
+
+    /// Get a copy of the value stored at `key`, if any.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.lock(key).get(key).cloned()
+    }
+
+    /// Store the string `value` at `key`, replacing whatever was there.
+    ///
+    /// The key expires after `expire` if one is given; otherwise it lives
+    /// until overwritten, even if the old value had a TTL.
+    pub fn set(&self, key: String, value: Bytes, expire: Option<Duration>) {
+        self.insert(key, Value::String(value), expire);
+    }
+
+    /// Like `set`, for a value of any type.
+    pub fn insert(&self, key: String, value: Value, expire: Option<Duration>) {
+        let expires_at = expire.and_then(deadline);
+        let mut shard = self.lock(&key);
+        shard.insert(key.clone(), value);
+        shard.expire_at(&key, expires_at);
+    }
+
+    /// Remove every key.
+    pub fn clear(&self) {
+        for shard in self.shared.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let used = shard.used;
+            for (_, key) in std::mem::take(&mut shard.keys) {
+                shard.remove(&key);
+            }
+            self.shared.account(used, shard.used);
+        }
+    }
+
+    /// Run `f`, a single command, alongside any other command but not during
+    /// a transaction.
+    pub fn concurrently<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _gate = self
+            .shared
+            .exec_gate
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        f()
+    }
+
+    /// Run `f` with no other command running, so that everything it does
+    /// appears to happen at once. This is how `EXEC` runs a transaction.
+    pub fn atomically<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _gate = self
+            .shared
+            .exec_gate
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        f()
+    }
+
+    /// Run `f` while no write can run through `Db::write`, so that `f` sees
+    /// the keyspace at a point between two logged writes.
+    pub fn between_writes<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _order = self
+            .shared
+            .write_order
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        f()
+    }
+
+    /// Copy every live key, with its value and time to live.
+    ///
+    /// All shards are locked while copying, so the copy is a single point in
+    /// time: a command touching several keys is either entirely in it or
+    /// not at all. Values are reference counted bytes, so copying is mostly
+    /// a matter of cloning the collections holding them.
+    pub fn snapshot(&self) -> Vec<(String, Value, Option<Duration>)> {
+        // Locks are always taken in the same order, and nothing else ever
+        // holds two shards at once, so this cannot deadlock.
+        let shards: Vec<_> = self
+            .shared
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap())
+            .collect();
+        let now = Instant::now();
+
+        let mut entries = Vec::new();
+        for shard in &shards {
+            for (key, entry) in &shard.entries {
+                let ttl = match entry.expires_at {
+                    Some(when) if when <= now => continue,
+                    Some(when) => Some(when - now),
+                    None => None,
+                };
+                entries.push((key.clone(), entry.value.clone(), ttl));
+            }
+        }
+        entries
+    }
+
+    /// Walk the keyspace a few keys at a time, for `SCAN` and `KEYS`.
+    ///
+    /// Starting at `cursor`, `0` on the first call, looks at about `count`
+    /// keys and returns the live ones `keep` accepts, along with the cursor
+    /// to continue from, or `0` once every key was looked at. Shards are
+    /// locked one at a time, never all at once.
+    ///
+    /// Keys are walked in the order of `scan_position`, which only depends on
+    /// the key, not on where it sits in its shard's table. A key present from
+    /// the first call to the last is therefore returned exactly once, however
+    /// the keyspace changes in between; one added or removed meanwhile may or
+    /// may not be.
+    pub fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        mut keep: impl FnMut(&str, &Value) -> bool,
+    ) -> (u64, Vec<String>) {
+        let shards = &self.shared.shards;
+        let mut index = (cursor >> CURSOR_BITS) as usize;
+        let mut from = cursor & ((1 << CURSOR_BITS) - 1);
+        let mut found = Vec::new();
+        let mut seen = 0;
+
+        while index < shards.len() {
+            let shard = shards[index].lock().unwrap();
+            let now = Instant::now();
+            let mut last = None;
+
+            for (position, key) in shard.keys.range((from, String::new())..) {
+                // Keys sharing a position are all taken together, as the
+                // cursor can't point between them.
+                if seen >= count && last != Some(*position) {
+                    return ((index as u64) << CURSOR_BITS | position, found);
+                }
+                seen += 1;
+                last = Some(*position);
+
+                let entry = &shard.entries[key];
+                if entry.expires_at.is_some_and(|when| when <= now) {
+                    continue;
+                }
+                if keep(key, &entry.value) {
+                    found.push(key.clone());
+                }
+            }
+
+            index += 1;
+            from = 0;
+            if seen >= count && index < shards.len() {
+                return ((index as u64) << CURSOR_BITS, found);
+            }
+        }
+        (0, found)
+    }
+
+    /// Time left before `key` expires.
+    ///
+    /// Returns `None` if the key does not exist and `Some(None)` if it exists
+    /// but never expires.
+    pub fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        self.lock(key).ttl(key)
+    }
+
+    /// Make `key` expire after `ttl`. Returns `false` if it does not exist.
+    pub fn expire(&self, key: &str, ttl: Duration) -> bool {
+        let expires_at = deadline(ttl);
+        self.lock(key).expire_at(key, expires_at)
+    }
+
+    /// Remove the expiration of `key`. Returns `true` if it had one.
+    pub fn persist(&self, key: &str) -> bool {
+        let mut shard = self.lock(key);
+        match shard.ttl(key) {
+            Some(Some(_)) => shard.expire_at(key, None),
+            _ => false,
+        }
+    }
+
+    /// Start (or stop) logging write commands to `aof`.
+    pub fn set_aof(&self, aof: Option<Aof>) {
+        *self.shared.aof.write().unwrap() = aof;
+    }
+
+    /// The append-only file write commands are logged to, if enabled.
+    pub fn aof(&self) -> Option<Aof> {
+        self.shared.aof.read().unwrap().clone()
+    }
+
+    /// Run `apply`, a write to the keyspace, and log what it did.
+    ///
+    /// `apply` returns its result along with the commands to log, if any.
+    /// Effects it recorded with `ShardGuard::propagate` are logged right
+    /// after those commands.
+    ///
+    /// The commands are also sent to replicas, if any ever attached.
+    ///
+    /// Without either this is just `apply()`. Otherwise writes run one at a
+    /// time so they are logged in the order they took effect; reads are not
+    /// held up. The returned `Synced`, if any, must complete before the write
+    /// is acknowledged.
+    pub fn write<T, L>(&self, apply: impl FnOnce() -> (T, L)) -> (T, Option<Synced>)
+    where
+        L: IntoIterator<Item = Command>,
+    {
+        let aof = self.aof();
+        let feed = self.feed();
+        if aof.is_none() && feed.is_none() {
+            return (apply().0, None);
+        }
+
+        let _order = self
+            .shared
+            .write_order
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (result, cmds) = apply();
+
+        let logged: Vec<Command> = cmds.into_iter().chain(self.take_effects()).collect();
+        if let Some(feed) = feed {
+            feed.append(&logged);
+        }
+        (result, aof.and_then(|aof| aof.append(&logged)))
+    }
+
+    /// Take the effects recorded with `ShardGuard::propagate` so far, for
+    /// a caller running several commands in one `Db::write` to log each
+    /// command's effects right after it.
+    pub(crate) fn take_effects(&self) -> Vec<Command> {
+        std::mem::take(&mut *self.shared.effects.lock().unwrap())
+    }
+
+    /// The script cache of `EVAL` and `SCRIPT LOAD`.
+    pub(crate) fn scripts(&self) -> MutexGuard<'_, HashMap<String, Arc<AST>>> {
+        self.shared.scripts.lock().unwrap()
+    }
+
+    /// The replication stream, if a replica ever attached.
+    pub fn feed(&self) -> Option<Arc<Feed>> {
+        self.shared.feed.read().unwrap().clone()
+    }
+
+    /// The replication stream, created if needed.
+    ///
+    /// Must be called through `Db::atomically`: a write running at the same
+    /// time could miss the stream.
+    pub(crate) fn start_feed(&self) -> Arc<Feed> {
+        self.shared
+            .feed
+            .write()
+            .unwrap()
+            .get_or_insert_with(|| Arc::new(Feed::new()))
+            .clone()
+    }
+
+    /// The link to the primary, if this is a replica.
+    pub fn link(&self) -> Option<Arc<Link>> {
+        self.shared.link.read().unwrap().clone()
+    }
+
+    pub fn set_link(&self, link: Option<Arc<Link>>) {
+        *self.shared.link.write().unwrap() = link;
+    }
+
+    /// Whether this is a replica, which only takes writes from its primary.
+    pub fn is_replica(&self) -> bool {
+        self.shared.link.read().unwrap().is_some()
+    }
+
+    /// The settings in effect. Only hold on to the guard briefly.
+    pub fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.shared.config.read().unwrap()
+    }
+
+    pub(crate) fn config_mut(&self) -> RwLockWriteGuard<'_, Config> {
+        self.shared.config.write().unwrap()
+    }
+
+    pub fn set_config(&self, config: Config) {
+        *self.shared.config.write().unwrap() = config;
+    }
+
+    /// The users, and what each may do.
+    pub fn acl(&self) -> RwLockReadGuard<'_, Acl> {
+        self.shared.acl.read().unwrap()
+    }
+
+    pub(crate) fn acl_mut(&self) -> RwLockWriteGuard<'_, Acl> {
+        self.shared.acl.write().unwrap()
+    }
+
+    pub fn set_acl(&self, acl: Acl) {
+        *self.shared.acl.write().unwrap() = acl;
+    }
+
+    /// The cluster this server is a node of, `None` outside cluster mode.
+    pub fn cluster(&self) -> RwLockReadGuard<'_, Option<Cluster>> {
+        self.shared.cluster.read().unwrap()
+    }
+
+    pub(crate) fn cluster_mut(&self) -> RwLockWriteGuard<'_, Option<Cluster>> {
+        self.shared.cluster.write().unwrap()
+    }
+
+    /// Turn cluster mode on, as a node of `cluster`, or off with `None`.
+    pub fn set_cluster(&self, cluster: Option<Cluster>) {
+        *self.cluster_mut() = cluster;
+    }
+
+    /// Where snapshots are written.
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.config().rdb_path()
+    }
+
+    /// Write snapshots to `path`, by changing `dir` and `dbfilename`.
+    pub fn set_snapshot_path(&self, path: PathBuf) {
+        let mut config = self.config_mut();
+        config.dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if let Some(name) = path.file_name() {
+            config.dbfilename = name.to_string_lossy().into_owned();
+        }
+    }
+
+    /// Estimated memory used by the keyspace, in bytes.
+    pub fn used_memory(&self) -> usize {
+        self.shared.used.load(Ordering::Acquire)
+    }
+
+    /// Whether the keyspace uses more memory than `maxmemory` allows.
+    pub fn is_full(&self) -> bool {
+        let maxmemory = self.config().maxmemory;
+        maxmemory > 0 && self.used_memory() > maxmemory
+    }
+
+    /// Evict keys until the keyspace fits in `maxmemory` again, picking
+    /// them as `maxmemory-policy` says. Called before commands that may use
+    /// more memory; it may not be enough, or be forbidden by the policy, in
+    /// which case the command is refused.
+    ///
+    /// Each key is removed as a logged `DEL`, so the append-only file and
+    /// replicas drop it too. Replicas never evict on their own: they hold
+    /// what their primary has.
+    ///
+    /// Keys are picked the way Redis does, approximately: out of a few
+    /// sampled at random from one shard, the best candidate goes.
+    pub fn evict(&self) {
+        let (maxmemory, policy, samples) = {
+            let config = self.config();
+            (
+                config.maxmemory,
+                config.maxmemory_policy,
+                config.maxmemory_samples,
+            )
+        };
+        if maxmemory == 0 || policy == Eviction::NoEviction || self.is_replica() {
+            return;
+        }
+
+        while self.used_memory() > maxmemory {
+            let Some(key) = self.pick_victim(policy, samples) else {
+                return;
+            };
+            self.concurrently(|| {
+                self.write(|| {
+                    let evicted = self.lock(&key).remove(&key).is_some();
+                    let del = Command::new("del", vec![Bytes::from(key.clone())]);
+                    ((), evicted.then_some(del))
+                })
+            });
+        }
+    }
+
+    /// A key to evict, from the first shard after a random one that has a
+    /// candidate.
+    fn pick_victim(&self, policy: Eviction, samples: usize) -> Option<String> {
+        let shards = &self.shared.shards;
+        let start = random() as usize % shards.len();
+        let now = Instant::now();
+
+        (0..shards.len()).find_map(|i| {
+            let shard = shards[(start + i) % shards.len()].lock().unwrap();
+            shard.victim(policy, samples, now)
+        })
+    }
+
+    /// Ask the server to shut down, saving as `save` says; see
+    /// `server::run`.
+    pub fn shutdown(&self, save: SaveMode) {
+        self.shared.shutdown.send_replace(Some(save));
+    }
+
+    /// Wait for a shutdown to be requested.
+    pub async fn shutdown_requested(&self) -> SaveMode {
+        let mut rx = self.shared.shutdown.subscribe();
+        // The sender lives as long as `self`, so waiting can't fail.
+        let save = rx.wait_for(Option::is_some).await.map(|save| *save);
+        match save {
+            Ok(save) => save.unwrap_or_default(),
+            Err(_) => std::future::pending().await,
+        }
+    }
+
+    /// Mark a background save as started. Returns `false` if one already is
+    /// running.
+    pub(crate) fn start_background_save(&self) -> bool {
+        !self.shared.saving.swap(true, Ordering::AcqRel)
+    }
+
+    /// Record the end of a save. `ok` tells whether it succeeded.
+    pub(crate) fn finish_save(&self, background: bool, ok: bool) {
+        if ok {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            self.shared
+                .last_save
+                .store(now.as_secs(), Ordering::Release);
+        }
+        if background {
+            self.shared.saving.store(false, Ordering::Release);
+        }
+    }
+
+    /// Unix time, in seconds, of the last successful snapshot; `0` if there
+    /// was none.
+    pub fn last_save(&self) -> u64 {
+        self.shared.last_save.load(Ordering::Acquire)
+    }
+
+    /// Subscribe to `channel`, creating it if needed.
+    pub fn subscribe(&self, channel: String) -> broadcast::Receiver<Bytes> {
+        let mut pub_sub = self.shared.pub_sub.lock().unwrap();
+        match pub_sub.get(&channel) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                // Messages are buffered per channel; a subscriber that falls
+                // more than this many behind starts missing messages.
+                let (tx, rx) = broadcast::channel(1024);
+                pub_sub.insert(channel, tx);
+                rx
+            }
+        }
+    }
+
+    /// Publish `message` on `channel`, returning the number of subscribers
+    /// that will receive it.
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let mut pub_sub = self.shared.pub_sub.lock().unwrap();
+        let Some(tx) = pub_sub.get(channel) else {
+            return 0;
+        };
+
+        match tx.send(message) {
+            Ok(receivers) => receivers,
+            Err(_) => {
+                // Every subscriber is gone; forget the channel.
+                pub_sub.remove(channel);
+                0
+            }
+        }
+    }
+}
+
+/// Exclusive access to one shard of the keyspace, obtained from `Db::lock`.
+///
+/// Every lookup through the guard treats expired keys as missing (and drops
+/// them on the spot). The time used for that is taken once, when the guard
+/// is created, so a command sees a consistent view of which keys are alive.
+pub struct ShardGuard<'a> {
+    shared: &'a Shared,
+    shard: MutexGuard<'a, Shard>,
+    now: Instant,
+
+    /// Set when a deadline earlier than any other in the shard was added.
+    wake_purger: bool,
+
+    /// `Shard::used` when the guard was created.
+    used: usize,
+
+    /// Keys whose value was handed out with `get_mut`, to measure again
+    /// once the command is done with them. Measuring walks the whole value,
+    /// which is why reads don't land here.
+    resized: Vec<String>,
+}
+
+impl ShardGuard<'_> {
+    /// The value stored at `key`, if any.
+    pub fn get(&mut self, key: &str) -> Option<&Value> {
+        let now = self.now;
+        let entry = self.shard.live(key, now)?;
+        entry.hit(now);
+        Some(&entry.value)
+    }
+
+    /// Mutable access to the value stored at `key`, if any.
+    ///
+    /// A command emptying a collection through this reference is expected to
+    /// `remove` the key afterwards; Redis never keeps empty collections.
+    ///
+    /// Watchers of `key` are told it was modified, whether or not the caller
+    /// ends up changing the value, so commands that only read use `get`.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.shard.live(key, self.now)?;
+        self.shard.touch(key);
+        if !self.resized.iter().any(|k| k == key) {
+            self.resized.push(key.to_string());
+        }
+
+        let entry = self.shard.entries.get_mut(key)?;
+        entry.hit(self.now);
+        Some(&mut entry.value)
+    }
+
+    /// Store `value` at `key`, replacing whatever was there along with its
+    /// expiration.
+    pub fn insert(&mut self, key: String, value: Value) {
+        self.shard.remove(&key);
+        self.shard.touch(&key);
+        self.shard.add(key, value, self.now);
+    }
+
+    /// Remove `key`, returning its value if it existed.
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.shard.live(key, self.now)?;
+        self.shard.remove(key).map(|e| e.value)
+    }
+
+    /// Time left before `key` expires; see `Db::ttl`.
+    pub fn ttl(&mut self, key: &str) -> Option<Option<Duration>> {
+        let now = self.now;
+        let entry = self.shard.live(key, now)?;
+        Some(entry.expires_at.map(|when| when - now))
+    }
+
+    /// Set or clear the deadline of `key`. Returns `false` if the key does
+    /// not exist.
+    pub fn expire_at(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        if self.shard.live(key, self.now).is_none() {
+            return false;
+        }
+
+        if self.shard.expire_at(key, expires_at) {
+            self.wake_purger = true;
+        }
+        true
+    }
+}
+
+impl ShardGuard<'_> {
+    /// Log `cmd` after the write command being run through `Db::write`. For
+    /// effects the command itself does not describe, like the pops serving
+    /// clients blocked on the key it pushed to.
+    pub fn propagate(&mut self, cmd: Command) {
+        if self.shared.aof.read().unwrap().is_some() || self.shared.feed.read().unwrap().is_some() {
+            self.shared.effects.lock().unwrap().push(cmd);
+        }
+    }
+
+    /// Raise `flag` the next time `key` is modified, expires or is deleted.
+    pub fn watch(&mut self, key: &str, flag: Arc<AtomicBool>) {
+        self.shard
+            .watched
+            .entry(key.to_string())
+            .or_default()
+            .push(flag);
+    }
+
+    /// Stop raising `flag` for `key`.
+    pub fn unwatch(&mut self, key: &str, flag: &Arc<AtomicBool>) {
+        if let Some(flags) = self.shard.watched.get_mut(key) {
+            flags.retain(|f| !Arc::ptr_eq(f, flag));
+            if flags.is_empty() {
+                self.shard.watched.remove(key);
+            }
+        }
+    }
+
+    /// Park `waiter` on `key`, behind any client already blocked there.
+    pub fn block(&mut self, key: &str, waiter: Arc<Waiter>) {
+        self.shard
+            .blocked
+            .entry(key.to_string())
+            .or_default()
+            .push_back(waiter);
+    }
+
+    /// Forget `waiter` on `key`, if it is still registered there.
+    pub fn unblock(&mut self, key: &str, waiter: &Arc<Waiter>) {
+        if let Some(queue) = self.shard.blocked.get_mut(key) {
+            queue.retain(|w| !Arc::ptr_eq(w, waiter));
+            if queue.is_empty() {
+                self.shard.blocked.remove(key);
+            }
+        }
+    }
+
+    /// Take the oldest client blocked on `key` that is still waiting.
+    pub fn next_waiter(&mut self, key: &str) -> Option<Arc<Waiter>> {
+        let queue = self.shard.blocked.get_mut(key)?;
+
+        let mut next = None;
+        while let Some(waiter) = queue.pop_front() {
+            // Clients served through another key, or that timed out, are
+            // dropped here.
+            if !waiter.is_done() {
+                next = Some(waiter);
+                break;
+            }
+        }
+
+        if queue.is_empty() {
+            self.shard.blocked.remove(key);
+        }
+        next
+    }
+
+    /// Wake `reader` whenever an entry is added to the stream at `key`,
+    /// until `unblock_reader`.
+    pub fn block_reader(&mut self, key: &str, reader: Arc<Notify>) {
+        self.shard
+            .readers
+            .entry(key.to_string())
+            .or_default()
+            .push(reader);
+    }
+
+    /// Stop waking `reader` for `key`.
+    pub fn unblock_reader(&mut self, key: &str, reader: &Arc<Notify>) {
+        if let Some(readers) = self.shard.readers.get_mut(key) {
+            readers.retain(|r| !Arc::ptr_eq(r, reader));
+            if readers.is_empty() {
+                self.shard.readers.remove(key);
+            }
+        }
+    }
+
+    /// Wake every client blocked reading the stream at `key`.
+    pub fn wake_readers(&mut self, key: &str) {
+        for reader in self.shard.readers.get(key).into_iter().flatten() {
+            reader.notify_one();
+        }
+    }
+}
+
+impl Drop for ShardGuard<'_> {
+    fn drop(&mut self) {
+        for key in std::mem::take(&mut self.resized) {
+            self.shard.resize(&key);
+        }
+        self.shared.account(self.used, self.shard.used);
+
+        if self.wake_purger {
+            self.shared.background_task.notify_one();
+        }
+    }
+}
+
+impl Shared {
+    /// Evict every expired key and return the next deadline, if any.
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut next = None;
+
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let used = shard.used;
+
+            while let Some((when, key)) = shard.expirations.first().cloned() {
+                if when > now {
+                    next = Some(next.map_or(when, |n: Instant| n.min(when)));
+                    break;
+                }
+
+                shard.remove(&key);
+            }
+
+            self.account(used, shard.used);
+        }
+
+        next
+    }
+
+    /// Carry a change of a shard's `used` over to the total.
+    fn account(&self, before: usize, after: usize) {
+        if after > before {
+            self.used.fetch_add(after - before, Ordering::AcqRel);
+        } else {
+            self.used.fetch_sub(before - after, Ordering::AcqRel);
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // Let the purge task notice it is no longer needed.
+        self.background_task.notify_one();
+    }
+}
+
+impl Shard {
+    /// Look up `key`, treating an expired entry as missing. Expired entries
+    /// found this way are removed on the spot.
+    fn live(&mut self, key: &str, now: Instant) -> Option<&mut Entry> {
+        let expired = match self.entries.get(key)?.expires_at {
+            Some(when) => when <= now,
+            None => false,
+        };
+
+        if expired {
+            self.remove(key);
+            return None;
+        }
+
+        self.entries.get_mut(key)
+    }
+
+    /// Add a new entry. There must be none at `key` yet.
+    fn add(&mut self, key: String, value: Value, now: Instant) {
+        let size = ENTRY_OVERHEAD + key.len() + value.footprint();
+        self.used += size;
+        self.keys
+            .insert((scan_position(key.as_bytes()), key.clone()));
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                expires_at: None,
+                size,
+                accessed: now,
+                hits: LFU_INIT,
+            },
+        );
+    }
+
+    /// Remove `key` along with its expiration and memory bookkeeping.
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.touch(key);
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+
+        self.used -= entry.size;
+        self.keys
+            .remove(&(scan_position(key.as_bytes()), key.to_string()));
+        Some(entry)
+    }
+
+    /// Measure the value at `key` again, after it was modified in place.
+    fn resize(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            let size = ENTRY_OVERHEAD + key.len() + entry.value.footprint();
+            self.used = self.used - entry.size + size;
+            entry.size = size;
+        }
+    }
+
+    /// The best key to evict out of `samples` picked at random, or the one
+    /// closest to expiring for `volatile-ttl`.
+    fn victim(&self, policy: Eviction, samples: usize, now: Instant) -> Option<String> {
+        if policy == Eviction::VolatileTtl {
+            return self.expirations.first().map(|(_, key)| key.clone());
+        }
+        if self.keys.is_empty() {
+            return None;
+        }
+
+        // The key at or after a random position: positions are hashes, so
+        // every key is about as likely to be picked.
+        let mut sampled = (0..samples.max(1))
+            .map(|_| (random() >> (64 - CURSOR_BITS), String::new()))
+            .filter_map(|at| self.keys.range(at..).next().or(self.keys.first()))
+            .map(|(_, key)| (key, &self.entries[key]));
+        let victim = match policy {
+            Eviction::AllKeysLru => sampled.min_by_key(|(_, e)| e.accessed),
+            Eviction::AllKeysLfu => sampled.min_by_key(|(_, e)| (e.frequency(now), e.accessed)),
+            _ => sampled.next(),
+        };
+        victim.map(|(key, _)| key.clone())
+    }
+
+    /// Tell the clients watching `key` that it was modified. They only need
+    /// to hear it once, so they are forgotten right away.
+    fn touch(&mut self, key: &str) {
+        if self.watched.is_empty() {
+            return;
+        }
+        for flag in self.watched.remove(key).into_iter().flatten() {
+            flag.store(true, Ordering::Release);
+        }
+    }
+
+    /// Set or clear the deadline of the existing entry at `key`.
+    ///
+    /// Returns `true` if the new deadline is earlier than every other one in
+    /// this shard, meaning the purge task may have to wake up sooner.
+    fn expire_at(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+
+        let prev = std::mem::replace(&mut entry.expires_at, expires_at);
+        self.touch(key);
+        if let Some(prev) = prev {
+            self.expirations.remove(&(prev, key.to_string()));
+        }
+
+        let Some(when) = expires_at else {
+            return false;
+        };
+
+        let notify = self
+            .expirations
+            .first()
+            .is_none_or(|(next, _)| when < *next);
+        self.expirations.insert((when, key.to_string()));
+        notify
+    }
+}
+
+impl Entry {
+    /// Record an access at `now`.
+    ///
+    /// `hits` works like Redis's LFU counter: it decays with time, and
+    /// grows on access with a probability that falls as it gets higher, so
+    /// eight bits tell a key read a few times from one read millions of
+    /// times.
+    fn hit(&mut self, now: Instant) {
+        self.hits = self.frequency(now);
+        if self.hits < u8::MAX {
+            let above_init = self.hits.saturating_sub(LFU_INIT) as f64;
+            let odds = 1.0 / (above_init * LFU_LOG_FACTOR + 1.0);
+            if (random() as f64 / u64::MAX as f64) < odds {
+                self.hits += 1;
+            }
+        }
+        self.accessed = now;
+    }
+
+    /// `hits` as of `now`, decayed for the time since the last access.
+    fn frequency(&self, now: Instant) -> u8 {
+        let idle = now.saturating_duration_since(self.accessed);
+        let periods = idle.as_secs() / LFU_DECAY.as_secs();
+        self.hits.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+}
+
+/// The instant `ttl` from now, worked out before taking a shard lock. One
+/// too far away to represent is as good as never.
+fn deadline(ttl: Duration) -> Option<Instant> {
+    Instant::now().checked_add(ttl)
+}
+
+/// A fast, non-cryptographic random number, for sampling keys.
+fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+    }
+
+    // xorshift64
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Eviction, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(Eviction::NoEviction),
+            "allkeys-lru" => Ok(Eviction::AllKeysLru),
+            "allkeys-lfu" => Ok(Eviction::AllKeysLfu),
+            "volatile-ttl" => Ok(Eviction::VolatileTtl),
+            "allkeys-random" => Ok(Eviction::AllKeysRandom),
+            _ => Err(format!("invalid maxmemory policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Eviction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Eviction::NoEviction => "noeviction",
+            Eviction::AllKeysLru => "allkeys-lru",
+            Eviction::AllKeysLfu => "allkeys-lfu",
+            Eviction::VolatileTtl => "volatile-ttl",
+            Eviction::AllKeysRandom => "allkeys-random",
+        })
+    }
+}
+
+/// Background task evicting expired keys.
+///
+/// Sleeps until the earliest deadline across all shards, or until notified
+/// that an earlier one was added.
+async fn purge_expired_tasks(shared: Weak<Shared>, notify: Arc<Notify>) {
+    loop {
+        let Some(strong) = shared.upgrade() else {
+            return;
+        };
+        let next = strong.purge_expired_keys();
+        drop(strong);
+
+        match next {
+            Some(when) => {
+                tokio::select! {
+                    _ = time::sleep_until(when) => {}
+                    _ = notify.notified() => {}
+                }
+            }
+            None => notify.notified().await,
+        }
+    }
+}
+
+/// Where `key`, or a member of a collection, comes in the order `SCAN` and
+/// friends walk them: a hash of its bytes, cut to `CURSOR_BITS` so it fits
+/// in a cursor.
+///
+/// Like `shard_index`, it is the same for the lifetime of the process, and
+/// unlike a slot in a hash table, it does not change as the table grows.
+pub(crate) fn scan_position(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    hasher.finish() >> (64 - CURSOR_BITS)
+}
+
+/// Pick the shard responsible for `key`.
+///
+/// `DefaultHasher::new()` always starts from the same keys, so a given key
+/// maps to the same shard for the lifetime of the process.
+fn shard_index(key: &str, num_shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % num_shards as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::AssertUnwindSafe;
+
+    #[tokio::test]
+    async fn set_then_get() {
+        let db = Db::new(4);
+        db.set("hello".to_string(), Bytes::from("world"), None);
+
+        assert_eq!(db.get("hello"), Some(Value::from(Bytes::from("world"))));
+        assert_eq!(db.get("missing"), None);
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_keyspace() {
+        let db = Db::new(8);
+        let other = db.clone();
+        other.set("k".to_string(), Bytes::from("v"), None);
+
+        assert_eq!(db.get("k"), Some(Value::from(Bytes::from("v"))));
+    }
+
+    #[test]
+    fn keys_spread_over_shards() {
+        let mut used = [false; 8];
+        for i in 0..1000 {
+            used[shard_index(&format!("key:{}", i), 8)] = true;
+        }
+
+        assert!(used.iter().all(|&u| u));
+    }
+
+    #[test]
+    fn shard_is_stable_for_a_key() {
+        assert_eq!(shard_index("user:42", 16), shard_index("user:42", 16));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_keys_read_as_missing() {
+        let db = Db::new(2);
+        db.set(
+            "k".to_string(),
+            Bytes::from("v"),
+            Some(Duration::from_secs(10)),
+        );
+
+        time::advance(Duration::from_secs(9)).await;
+        assert!(db.get("k").is_some());
+
+        time::advance(Duration::from_secs(2)).await;
+        assert_eq!(db.get("k"), None);
+        assert_eq!(db.ttl("k"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn purge_task_evicts_in_the_background() {
+        let db = Db::new(2);
+        db.set(
+            "a".to_string(),
+            Bytes::from("1"),
+            Some(Duration::from_secs(5)),
+        );
+        db.set(
+            "b".to_string(),
+            Bytes::from("2"),
+            Some(Duration::from_secs(1)),
+        );
+
+        // Sleeping lets the purge task run at each deadline.
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(entry_count(&db), 1);
+
+        time::sleep(Duration::from_secs(4)).await;
+        assert_eq!(entry_count(&db), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overwrite_and_persist_clear_the_ttl() {
+        let db = Db::new(1);
+        db.set(
+            "k".to_string(),
+            Bytes::from("v"),
+            Some(Duration::from_secs(1)),
+        );
+        db.set("k".to_string(), Bytes::from("v2"), None);
+        assert_eq!(db.ttl("k"), Some(None));
+
+        db.set(
+            "j".to_string(),
+            Bytes::from("v"),
+            Some(Duration::from_secs(1)),
+        );
+        assert!(db.persist("j"));
+        assert!(!db.persist("j"));
+
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(db.get("k"), Some(Value::from(Bytes::from("v2"))));
+        assert_eq!(db.get("j"), Some(Value::from(Bytes::from("v"))));
+    }
+
+    #[tokio::test]
+    async fn a_panic_in_a_transaction_leaves_the_gate_usable() {
+        let db = Db::new(1);
+        let panics = |f: &dyn Fn()| std::panic::catch_unwind(AssertUnwindSafe(f)).is_err();
+        assert!(panics(&|| db.atomically(|| panic!("oops"))));
+        assert!(panics(&|| db.between_writes(|| panic!("oops"))));
+
+        assert_eq!(db.concurrently(|| 1), 1);
+        assert_eq!(db.atomically(|| 2), 2);
+        assert_eq!(db.between_writes(|| 3), 3);
+    }
+
+    #[tokio::test]
+    async fn deadlines_too_far_away_never_come() {
+        let db = Db::new(1);
+        db.set("k".to_string(), Bytes::from("v"), Some(Duration::MAX));
+        assert_eq!(db.ttl("k"), Some(None));
+        db.set("k".to_string(), Bytes::from("v"), None);
+        assert!(db.expire("k", Duration::MAX));
+        assert_eq!(db.get("k"), Some(Value::from(Bytes::from("v"))));
+    }
+
+    #[tokio::test]
+    async fn only_writes_are_measured_again() {
+        let db = Db::new(1);
+        db.insert("l".to_string(), Value::List(VecDeque::new()), None);
+
+        let mut shard = db.lock("l");
+        assert!(shard.get("l").is_some());
+        assert!(shard.resized.is_empty());
+        assert!(shard.get_mut("l").is_some());
+        assert_eq!(shard.resized, ["l"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn memory_use_follows_the_keyspace() {
+        let db = Db::new(2);
+        assert_eq!(db.used_memory(), 0);
+
+        db.set("a".to_string(), Bytes::from("1"), None);
+        db.set(
+            "b".to_string(),
+            Bytes::from("2"),
+            Some(Duration::from_secs(1)),
+        );
+        db.insert("l".to_string(), Value::List(VecDeque::new()), None);
+        let small = db.used_memory();
+        assert!(small > 0);
+
+        // Growing a value in place is measured once the guard goes.
+        {
+            let mut shard = db.lock("l");
+            let Some(Value::List(list)) = shard.get_mut("l") else {
+                unreachable!();
+            };
+            list.extend((0..100).map(|_| Bytes::from("0123456789")));
+        }
+        assert!(db.used_memory() > small + 100 * 10);
+
+        assert!(db.lock("l").remove("l").is_some());
+        let empty_list = ENTRY_OVERHEAD + "l".len() + Value::List(VecDeque::new()).footprint();
+        assert_eq!(db.used_memory(), small - empty_list);
+
+        // Expired keys stop counting once purged.
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(entry_count(&db), 1);
+        db.clear();
+        assert_eq!(db.used_memory(), 0);
+
+        // Keys can still be found for eviction after all that.
+        for i in 0..10 {
+            db.set(format!("k{}", i), Bytes::from("v"), None);
+        }
+        db.lock("k3").remove("k3");
+        for shard in db.shared.shards.iter() {
+            let shard = shard.lock().unwrap();
+            assert_eq!(shard.keys.len(), shard.entries.len());
+            for (position, key) in &shard.keys {
+                assert_eq!(*position, scan_position(key.as_bytes()));
+                assert!(shard.entries.contains_key(key));
+            }
+        }
+    }
+
+    fn entry_count(db: &Db) -> usize {
+        db.shared
+            .shards
+            .iter()
+            .map(|s| s.lock().unwrap().entries.len())
+            .sum()
+    }
+}
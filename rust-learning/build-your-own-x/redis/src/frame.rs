@@ -0,0 +1,760 @@
+//! Provides a type representing a Redis protocol frame as well as a
+//! streaming decoder and an encoder for both RESP2 and RESP3.
+//!
+//! RESP3 adds a handful of types (maps, sets, doubles, booleans, big numbers,
+//! verbatim strings and out-of-band pushes). Clients speak RESP2 until they
+//! switch with `HELLO 3`, so encoding takes the negotiated `Protocol` and
+//! downgrades RESP3-only types the way Redis does.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
+
+/// Largest bulk string accepted from a client, like Redis' default
+/// `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Most the decoder reserves ahead of a partially received bulk string.
+const MAX_RESERVE: usize = 1024 * 1024;
+
+/// Largest number of elements accepted in one aggregate.
+const MAX_AGGREGATE_LEN: usize = 1024 * 1024 * 1024;
+
+/// Longest line accepted for a header or a simple string, as Redis does.
+/// Without it, a peer that never sends `\r\n` grows the buffer forever.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Deepest aggregates may nest. Frames are dropped and printed
+/// recursively, so a deeper one could overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A frame in the Redis protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+
+    // RESP3 only
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    Verbatim { format: [u8; 3], text: Bytes },
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    Push(Vec<Frame>),
+}
+
+/// The protocol version spoken on a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Frame {
+    /// Shorthand for the `+OK` reply most write commands answer with.
+    pub fn ok() -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+
+    /// Append the wire encoding of this frame to `dst`.
+    ///
+    /// With `Protocol::Resp2`, RESP3-only types are sent as their closest
+    /// RESP2 equivalent: maps as flat arrays of keys and values, sets and
+    /// pushes as arrays, doubles and big numbers as bulk strings and booleans
+    /// as `1`/`0`.
+    pub fn encode(&self, dst: &mut BytesMut, protocol: Protocol) {
+        let resp3 = protocol == Protocol::Resp3;
+
+        match self {
+            Frame::Simple(val) => put_line(dst, b'+', val.as_bytes()),
+            Frame::Error(val) => {
+                if !val.contains(['\r', '\n']) {
+                    put_line(dst, b'-', val.as_bytes());
+                } else if resp3 {
+                    put_blob(dst, b'!', val.as_bytes());
+                } else {
+                    // RESP2 has no way to carry a line break in an error.
+                    put_line(dst, b'-', val.replace(['\r', '\n'], " ").as_bytes());
+                }
+            }
+            Frame::Integer(val) => put_line(dst, b':', val.to_string().as_bytes()),
+            Frame::Bulk(val) => put_blob(dst, b'$', val),
+            Frame::Null if resp3 => dst.put_slice(b"_\r\n"),
+            Frame::Null => dst.put_slice(b"$-1\r\n"),
+            Frame::Array(val) => put_aggregate(dst, b'*', val, protocol),
+            Frame::Boolean(val) if resp3 => put_line(dst, b'#', if *val { b"t" } else { b"f" }),
+            Frame::Boolean(val) => put_line(dst, b':', if *val { b"1" } else { b"0" }),
+            Frame::Double(val) if resp3 => put_line(dst, b',', format_double(*val).as_bytes()),
+            Frame::Double(val) => put_blob(dst, b'$', format_double(*val).as_bytes()),
+            Frame::BigNumber(val) if resp3 => put_line(dst, b'(', val.as_bytes()),
+            Frame::BigNumber(val) => put_blob(dst, b'$', val.as_bytes()),
+            Frame::Verbatim { format, text } if resp3 => {
+                put_len(dst, b'=', format.len() + 1 + text.len());
+                dst.put_slice(format);
+                dst.put_u8(b':');
+                dst.put_slice(text);
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Verbatim { text, .. } => put_blob(dst, b'$', text),
+            Frame::Map(pairs) => {
+                if resp3 {
+                    put_len(dst, b'%', pairs.len());
+                } else {
+                    put_len(dst, b'*', pairs.len() * 2);
+                }
+                for (key, value) in pairs {
+                    key.encode(dst, protocol);
+                    value.encode(dst, protocol);
+                }
+            }
+            Frame::Set(val) => put_aggregate(dst, if resp3 { b'~' } else { b'*' }, val, protocol),
+            Frame::Push(val) => put_aggregate(dst, if resp3 { b'>' } else { b'*' }, val, protocol),
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Simple(response) => response.fmt(fmt),
+            Frame::Error(msg) => write!(fmt, "error: {}", msg),
+            Frame::Integer(num) => num.fmt(fmt),
+            Frame::Bulk(msg) | Frame::Verbatim { text: msg, .. } => {
+                String::from_utf8_lossy(msg).fmt(fmt)
+            }
+            Frame::Null => "(nil)".fmt(fmt),
+            Frame::Boolean(val) => val.fmt(fmt),
+            Frame::Double(val) => format_double(*val).fmt(fmt),
+            Frame::BigNumber(val) => val.fmt(fmt),
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    part.fmt(fmt)?;
+                }
+
+                Ok(())
+            }
+            Frame::Map(pairs) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{} => {}", key, value)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Format a double the way Redis replies with one.
+pub(crate) fn format_double(val: f64) -> String {
+    if val.is_nan() {
+        "nan".to_string()
+    } else if val.is_infinite() {
+        if val > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else if val != 0.0 && (val.abs() >= 1e17 || val.abs() < 1e-5) {
+        // Plain notation would spell out every digit of huge or tiny
+        // values; switch to an exponent instead.
+        format!("{:e}", val)
+    } else {
+        val.to_string()
+    }
+}
+
+/// Incremental frame decoder.
+///
+/// Bytes are consumed from the read buffer as soon as a complete element is
+/// available, while the aggregates still waiting for children are kept on a
+/// stack. A large array trickling in over many reads is therefore only
+/// scanned once, instead of being re-parsed from the start on every read.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    stack: Vec<Aggregate>,
+}
+
+/// An aggregate whose header has been read but not all of its children.
+#[derive(Debug)]
+struct Aggregate {
+    kind: Kind,
+    len: usize,
+    items: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Array,
+    Map,
+    Set,
+    Push,
+    /// RESP3 attributes carry metadata about the next reply. They are read
+    /// and dropped.
+    Attribute,
+}
+
+/// Result of looking at the next element in the buffer.
+enum Token {
+    /// A complete scalar, `len` bytes long on the wire.
+    Scalar(Frame, usize),
+    /// A blob (bulk string, blob error, verbatim string). The payload starts
+    /// after `header` bytes and is `len` bytes long.
+    Blob(u8, usize, usize),
+    /// An aggregate header announcing `len` children, `header` bytes long.
+    Aggregate(Kind, usize, usize),
+    /// More data is needed. At least `needed` bytes in total, if known.
+    Incomplete(Option<usize>),
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Returns `true` if part of a frame has been consumed but the frame is
+    /// not complete yet.
+    pub fn is_partial(&self) -> bool {
+        !self.stack.is_empty()
+    }
+
+    /// Decode the next complete frame from `buf`, consuming its bytes.
+    ///
+    /// Returns `Ok(None)` if `buf` does not hold a complete frame yet; the
+    /// bytes of any complete elements are still consumed and remembered, so
+    /// the caller simply appends more data and calls `decode` again. An error
+    /// means the peer is not speaking RESP and the connection should be
+    /// closed.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> crate::Result<Option<Frame>> {
+        loop {
+            let mut frame = match next_token(buf)? {
+                Token::Incomplete(needed) => {
+                    if let Some(needed) = needed {
+                        // Make room for the payload up front rather than
+                        // growing the buffer read by read. Capped, so a bogus
+                        // length cannot make us allocate before data arrives.
+                        let missing = needed.saturating_sub(buf.len());
+                        buf.reserve(missing.min(MAX_RESERVE));
+                    }
+                    return Ok(None);
+                }
+                Token::Scalar(frame, len) => {
+                    buf.advance(len);
+                    frame
+                }
+                Token::Blob(kind, header, len) => {
+                    // Copy the payload out rather than splitting it off: a
+                    // small value kept in the keyspace would otherwise pin
+                    // the whole read buffer in memory.
+                    let data = Bytes::copy_from_slice(&buf[header..header + len]);
+                    buf.advance(header + len + 2);
+                    blob(kind, data)?
+                }
+                Token::Aggregate(kind, header, len) => {
+                    buf.advance(header);
+                    if len == 0 && kind == Kind::Attribute {
+                        continue;
+                    } else if len == 0 {
+                        kind.build(Vec::new())
+                    } else if self.stack.len() >= MAX_DEPTH {
+                        return Err("protocol error; aggregates nested too deeply".into());
+                    } else {
+                        let items = Vec::with_capacity(len.min(1024));
+                        self.stack.push(Aggregate { kind, len, items });
+                        continue;
+                    }
+                }
+            };
+
+            // Hand the finished element to its parent, completing as many
+            // aggregates as this finishes.
+            loop {
+                let Some(parent) = self.stack.last_mut() else {
+                    return Ok(Some(frame));
+                };
+
+                parent.items.push(frame);
+                if parent.items.len() < parent.len {
+                    break;
+                }
+
+                let done = self.stack.pop().unwrap();
+                if done.kind == Kind::Attribute {
+                    // The attribute is complete; the reply it describes is
+                    // still to come.
+                    break;
+                }
+                frame = done.kind.build(done.items);
+            }
+        }
+    }
+}
+
+impl Kind {
+    fn build(self, items: Vec<Frame>) -> Frame {
+        match self {
+            Kind::Array => Frame::Array(items),
+            Kind::Set => Frame::Set(items),
+            Kind::Push => Frame::Push(items),
+            Kind::Map | Kind::Attribute => {
+                let mut pairs = Vec::with_capacity(items.len() / 2);
+                let mut items = items.into_iter();
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    pairs.push((key, value));
+                }
+                Frame::Map(pairs)
+            }
+        }
+    }
+}
+
+/// Look at the element at the start of `src` without consuming it.
+fn next_token(src: &[u8]) -> crate::Result<Token> {
+    let Some(&kind) = src.first() else {
+        return Ok(Token::Incomplete(None));
+    };
+    // Only as far as the longest line could reach, so a long one isn't
+    // scanned again on every read either.
+    let window = &src[1..src.len().min(MAX_LINE_LEN + 3)];
+    let Some((line, header)) = get_line(window) else {
+        if window.len() == MAX_LINE_LEN + 2 {
+            return Err("protocol error; line too long".into());
+        }
+        return Ok(Token::Incomplete(None));
+    };
+    // Account for the type byte.
+    let header = header + 1;
+
+    let scalar = |frame| Ok(Token::Scalar(frame, header));
+
+    match kind {
+        b'+' => scalar(Frame::Simple(get_string(line)?)),
+        b'-' => scalar(Frame::Error(get_string(line)?)),
+        b':' => scalar(Frame::Integer(get_decimal(line)?)),
+        b'_' if line.is_empty() => scalar(Frame::Null),
+        b'#' => match line {
+            b"t" => scalar(Frame::Boolean(true)),
+            b"f" => scalar(Frame::Boolean(false)),
+            _ => Err(invalid()),
+        },
+        b',' => scalar(Frame::Double(get_double(line)?)),
+        b'(' => {
+            let digits = line.strip_prefix(b"-").unwrap_or(line);
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                return Err(invalid());
+            }
+            scalar(Frame::BigNumber(get_string(line)?))
+        }
+        b'$' | b'!' | b'=' => {
+            let len = get_decimal(line)?;
+            if len == -1 && kind == b'$' {
+                return scalar(Frame::Null);
+            }
+            let len = usize::try_from(len).map_err(|_| invalid())?;
+            if len > MAX_BULK_LEN {
+                return Err("protocol error; invalid bulk length".into());
+            }
+
+            let total = header + len + 2;
+            if src.len() < total {
+                return Ok(Token::Incomplete(Some(total)));
+            }
+            if &src[header + len..total] != b"\r\n" {
+                return Err(invalid());
+            }
+
+            Ok(Token::Blob(kind, header, len))
+        }
+        b'*' | b'%' | b'~' | b'>' | b'|' => {
+            let len = get_decimal(line)?;
+            if len == -1 && kind == b'*' {
+                return scalar(Frame::Null);
+            }
+            let len = usize::try_from(len).map_err(|_| invalid())?;
+            if len > MAX_AGGREGATE_LEN {
+                return Err("protocol error; invalid multibulk length".into());
+            }
+
+            let (kind, len) = match kind {
+                b'*' => (Kind::Array, len),
+                b'~' => (Kind::Set, len),
+                b'>' => (Kind::Push, len),
+                // Maps and attributes count pairs.
+                b'%' => (Kind::Map, len * 2),
+                _ => (Kind::Attribute, len * 2),
+            };
+            Ok(Token::Aggregate(kind, header, len))
+        }
+        actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
+    }
+}
+
+/// Build the frame for a blob once its payload is available.
+fn blob(kind: u8, data: Bytes) -> crate::Result<Frame> {
+    match kind {
+        b'$' => Ok(Frame::Bulk(data)),
+        b'!' => Ok(Frame::Error(String::from_utf8_lossy(&data).into_owned())),
+        _ => {
+            // Verbatim strings start with a three letter format and a colon.
+            if data.len() < 4 || data[3] != b':' {
+                return Err(invalid());
+            }
+            let format = [data[0], data[1], data[2]];
+            Ok(Frame::Verbatim {
+                format,
+                text: data.slice(4..),
+            })
+        }
+    }
+}
+
+fn invalid() -> crate::Error {
+    "protocol error; invalid frame format".into()
+}
+
+/// Find a `\r\n` terminated line, returning it and the number of bytes it
+/// takes including the terminator.
+fn get_line(src: &[u8]) -> Option<(&[u8], usize)> {
+    let end = src.windows(2).position(|w| w == b"\r\n")?;
+    Some((&src[..end], end + 2))
+}
+
+fn get_string(line: &[u8]) -> crate::Result<String> {
+    String::from_utf8(line.to_vec()).map_err(|_| invalid())
+}
+
+fn get_decimal(line: &[u8]) -> crate::Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)
+}
+
+fn get_double(line: &[u8]) -> crate::Result<f64> {
+    match line {
+        b"inf" | b"+inf" => Ok(f64::INFINITY),
+        b"-inf" => Ok(f64::NEG_INFINITY),
+        b"nan" => Ok(f64::NAN),
+        _ => std::str::from_utf8(line)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(invalid),
+    }
+}
+
+fn put_line(dst: &mut BytesMut, kind: u8, line: &[u8]) {
+    dst.put_u8(kind);
+    dst.put_slice(line);
+    dst.put_slice(b"\r\n");
+}
+
+fn put_len(dst: &mut BytesMut, kind: u8, len: usize) {
+    put_line(dst, kind, len.to_string().as_bytes());
+}
+
+fn put_blob(dst: &mut BytesMut, kind: u8, data: &[u8]) {
+    put_len(dst, kind, data.len());
+    dst.put_slice(data);
+    dst.put_slice(b"\r\n");
+}
+
+fn put_aggregate(dst: &mut BytesMut, kind: u8, items: &[Frame], protocol: Protocol) {
+    put_len(dst, kind, items.len());
+    for item in items {
+        item.encode(dst, protocol);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small deterministic PRNG (xorshift64*), enough to drive the fuzz-style
+    /// tests without pulling in a dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn bytes(&mut self, max: u64) -> Bytes {
+            let len = self.below(max + 1);
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+
+        fn line(&mut self) -> String {
+            let len = self.below(12);
+            (0..len)
+                .map(|_| (b' ' + self.below(95) as u8) as char)
+                .collect()
+        }
+    }
+
+    fn random_frame(rng: &mut Rng, depth: u32) -> Frame {
+        let kinds = if depth == 0 { 9 } else { 13 };
+        match rng.below(kinds) {
+            0 => Frame::Simple(rng.line()),
+            1 => Frame::Error(rng.line()),
+            2 => Frame::Integer(rng.next() as i64),
+            3 => Frame::Bulk(rng.bytes(40)),
+            4 => Frame::Null,
+            5 => Frame::Boolean(rng.below(2) == 1),
+            6 => match rng.below(4) {
+                0 => Frame::Double(f64::INFINITY),
+                1 => Frame::Double(f64::NEG_INFINITY),
+                _ => Frame::Double((rng.next() as i64) as f64 / 1024.0),
+            },
+            7 => Frame::BigNumber(format!("-{}{:020}", rng.below(1000) + 1, rng.next())),
+            8 => Frame::Verbatim {
+                format: *b"txt",
+                text: rng.bytes(20),
+            },
+            kind => {
+                let len = rng.below(5) as usize;
+                let mut items = || (0..len).map(|_| random_frame(rng, depth - 1)).collect();
+                match kind {
+                    9 => Frame::Array(items()),
+                    10 => Frame::Set(items()),
+                    11 => Frame::Push(items()),
+                    _ => {
+                        let items: Vec<Frame> = items();
+                        let mut pairs = Vec::new();
+                        for chunk in items.chunks_exact(2) {
+                            pairs.push((chunk[0].clone(), chunk[1].clone()));
+                        }
+                        Frame::Map(pairs)
+                    }
+                }
+            }
+        }
+    }
+
+    fn encode(frame: &Frame, protocol: Protocol) -> BytesMut {
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf, protocol);
+        buf
+    }
+
+    /// Decode everything in `input`, feeding it to the decoder in chunks of
+    /// the given sizes (cycled) to simulate data arriving over many reads.
+    fn decode_in_chunks(input: &[u8], chunks: &[usize]) -> Vec<Frame> {
+        let mut decoder = Decoder::new();
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        let mut pos = 0;
+
+        for &chunk in chunks.iter().cycle() {
+            if pos == input.len() {
+                break;
+            }
+            let end = (pos + chunk).min(input.len());
+            buf.extend_from_slice(&input[pos..end]);
+            pos = end;
+
+            while let Some(frame) = decoder.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        assert!(buf.is_empty(), "leftover bytes: {:?}", buf);
+        assert!(!decoder.is_partial());
+        frames
+    }
+
+    #[test]
+    fn parses_command_array() {
+        let frames = decode_in_chunks(b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n", &[usize::MAX]);
+        assert_eq!(
+            frames,
+            vec![Frame::Array(vec![
+                Frame::Bulk(Bytes::from("GET")),
+                Frame::Bulk(Bytes::from("hello")),
+            ])]
+        );
+    }
+
+    #[test]
+    fn partial_input_is_kept_for_later() {
+        let mut decoder = Decoder::new();
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$5\r\nhel"[..]);
+
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        assert!(decoder.is_partial());
+
+        buf.extend_from_slice(b"lo\r\n");
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn parses_resp3_types() {
+        let input = b"%2\r\n+a\r\n#t\r\n$1\r\nb\r\n,-1.5\r\n~1\r\n(123\r\n_\r\n\
+                      =7\r\ntxt:abc\r\n!5\r\noops!\r\n>1\r\n:1\r\n";
+        let frames = decode_in_chunks(input, &[3]);
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Map(vec![
+                    (Frame::Simple("a".into()), Frame::Boolean(true)),
+                    (Frame::Bulk("b".into()), Frame::Double(-1.5)),
+                ]),
+                Frame::Set(vec![Frame::BigNumber("123".into())]),
+                Frame::Null,
+                Frame::Verbatim {
+                    format: *b"txt",
+                    text: "abc".into()
+                },
+                Frame::Error("oops!".into()),
+                Frame::Push(vec![Frame::Integer(1)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn attributes_are_skipped() {
+        let frames = decode_in_chunks(b"|1\r\n+ttl\r\n:3\r\n+OK\r\n", &[usize::MAX]);
+        assert_eq!(frames, vec![Frame::ok()]);
+
+        let frames = decode_in_chunks(b"|0\r\n+OK\r\n*2\r\n|0\r\n:1\r\n:2\r\n", &[1]);
+        assert_eq!(
+            frames,
+            vec![
+                Frame::ok(),
+                Frame::Array(vec![Frame::Integer(1), Frame::Integer(2)])
+            ]
+        );
+    }
+
+    #[test]
+    fn resp2_downgrades_resp3_types() {
+        let map = Frame::Map(vec![(Frame::Bulk("k".into()), Frame::Boolean(true))]);
+        assert_eq!(
+            &encode(&map, Protocol::Resp2)[..],
+            b"*2\r\n$1\r\nk\r\n:1\r\n"
+        );
+        assert_eq!(
+            &encode(&map, Protocol::Resp3)[..],
+            b"%1\r\n$1\r\nk\r\n#t\r\n"
+        );
+
+        assert_eq!(&encode(&Frame::Null, Protocol::Resp2)[..], b"$-1\r\n");
+        assert_eq!(&encode(&Frame::Null, Protocol::Resp3)[..], b"_\r\n");
+
+        assert_eq!(
+            &encode(&Frame::Double(2.5), Protocol::Resp2)[..],
+            b"$3\r\n2.5\r\n"
+        );
+        assert_eq!(
+            &encode(&Frame::Double(f64::NAN), Protocol::Resp3)[..],
+            b",nan\r\n"
+        );
+
+        let push = Frame::Push(vec![Frame::Integer(-2)]);
+        assert_eq!(&encode(&push, Protocol::Resp2)[..], b"*1\r\n:-2\r\n");
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        for input in [
+            &b"?oops\r\n"[..],
+            b"$3\r\nabcd\r\n",
+            b"#x\r\n",
+            b"(12a\r\n",
+            b"*-5\r\n",
+        ] {
+            let mut buf = BytesMut::from(input);
+            assert!(Decoder::new().decode(&mut buf).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let mut nested = b"*1\r\n".repeat(MAX_DEPTH - 1);
+        nested.extend_from_slice(b":1\r\n");
+        let mut buf = BytesMut::from(&nested[..]);
+        assert!(Decoder::new().decode(&mut buf).unwrap().is_some());
+
+        // Refused as soon as the header too many arrives.
+        let mut buf = BytesMut::from(&b"*1\r\n".repeat(MAX_DEPTH + 1)[..]);
+        assert!(Decoder::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let mut line = vec![b'+'];
+        line.extend_from_slice(&[b'a'; MAX_LINE_LEN]);
+        line.extend_from_slice(b"\r\n");
+        let mut buf = BytesMut::from(&line[..]);
+        assert!(Decoder::new().decode(&mut buf).unwrap().is_some());
+
+        // Refused without waiting for the end of the line.
+        let mut buf = BytesMut::from(&line[..MAX_LINE_LEN + 2]);
+        buf.extend_from_slice(b"a");
+        assert!(Decoder::new().decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&line[..MAX_LINE_LEN + 2]);
+        assert!(Decoder::new().decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn fuzz_roundtrip_with_partial_reads() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+
+        for _ in 0..500 {
+            let frames: Vec<Frame> = (0..rng.below(4) + 1)
+                .map(|_| random_frame(&mut rng, 3))
+                .collect();
+
+            let mut input = BytesMut::new();
+            for frame in &frames {
+                frame.encode(&mut input, Protocol::Resp3);
+            }
+
+            // One byte at a time, random chunk sizes, and all at once.
+            let chunks: Vec<usize> = (0..8).map(|_| rng.below(16) as usize + 1).collect();
+            assert_eq!(decode_in_chunks(&input, &[1]), frames);
+            assert_eq!(decode_in_chunks(&input, &chunks), frames);
+            assert_eq!(decode_in_chunks(&input, &[usize::MAX]), frames);
+        }
+    }
+
+    #[test]
+    fn fuzz_random_bytes_never_panic() {
+        let mut rng = Rng(42);
+
+        for _ in 0..2000 {
+            // Start from a valid encoding and corrupt a few bytes, which gets
+            // much deeper into the decoder than purely random input.
+            let mut input = encode(&random_frame(&mut rng, 2), Protocol::Resp3);
+            for _ in 0..rng.below(3) + 1 {
+                if !input.is_empty() {
+                    let i = rng.below(input.len() as u64) as usize;
+                    input[i] = rng.next() as u8;
+                }
+            }
+
+            let mut decoder = Decoder::new();
+            let mut buf = BytesMut::new();
+            for chunk in input.chunks(rng.below(7) as usize + 1) {
+                buf.extend_from_slice(chunk);
+                match decoder.decode(&mut buf) {
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Glob-style pattern matching, as used by `CONFIG GET`, `KEYS` and
+//! friends.
+//!
+//! The syntax is Redis's:
+//!
+//! * `?` matches any one byte;
+//! * `*` matches any run of bytes, including none;
+//! * `[abc]`, `[a-z]` and `[^abc]` match one byte in, or not in, a set;
+//! * `\` makes the next byte match itself.
+//!
+//! Matching works on bytes, not characters, so `?` only matches the whole
+//! of a non-ASCII character if it is a single byte long.
+
+/// Whether `text` matches `pattern` in full.
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+
+    // The pattern position just after the last `*` seen, and how much of the
+    // text that star covers so far. When the rest of the pattern fails to
+    // match, the star swallows one more byte and the rest is tried again.
+    let mut star = None;
+
+    loop {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if t == text.len() {
+            break;
+        }
+        if let Some(next) = step(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+            continue;
+        }
+        match star {
+            Some((after, covered)) => {
+                p = after;
+                t = covered + 1;
+                star = Some((after, t));
+            }
+            None => return false,
+        }
+    }
+
+    p == pattern.len()
+}
+
+/// Match the single-byte element of `pattern` at `p` against `c`, returning
+/// where the next element starts if it matched.
+fn step(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => class(pattern, p + 1, c),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        b => (b == c).then_some(p + 1),
+    }
+}
+
+/// Match the set starting at `i`, just after its `[`. Like Redis, a set
+/// missing its `]` runs to the end of the pattern.
+fn class(pattern: &[u8], mut i: usize, c: u8) -> Option<usize> {
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = (
+                pattern[i].min(pattern[i + 2]),
+                pattern[i].max(pattern[i + 2]),
+            );
+            matched |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+
+    (matched != negate).then_some((i + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: &str, text: &str) -> bool {
+        matches(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(check("*", ""));
+        assert!(check("*", "anything"));
+        assert!(check("user:*", "user:42"));
+        assert!(!check("user:*", "session:42"));
+        assert!(check("*:42", "user:42"));
+        assert!(check("a*b*c", "axxbyyc"));
+        assert!(!check("a*b*c", "axxbyy"));
+        assert!(check("a*b", "abab"));
+        assert!(check("h?llo", "hello"));
+        assert!(!check("h?llo", "hllo"));
+        assert!(check("**", "x"));
+        assert!(!check("", "x"));
+    }
+
+    #[test]
+    fn sets_and_escapes() {
+        assert!(check("h[ae]llo", "hallo"));
+        assert!(!check("h[ae]llo", "hillo"));
+        assert!(check("h[^e]llo", "hallo"));
+        assert!(!check("h[^e]llo", "hello"));
+        assert!(check("h[a-c]llo", "hbllo"));
+        assert!(check("h[c-a]llo", "hbllo"));
+        assert!(!check("h[a-c]llo", "hdllo"));
+        assert!(check("[-]", "-"));
+        assert!(check(r"\*", "*"));
+        assert!(!check(r"\*", "x"));
+        assert!(check(r"[\]]", "]"));
+        assert!(check("[ab", "a"));
+    }
+}
@@ -0,0 +1,60 @@
+//! A small Redis-compatible server built on top of Tokio.
+//!
+//! The binary in `main.rs` only parses the configuration and binds the
+//! listener; everything else lives here so the examples and tests can start
+//! a server in-process.
+
+pub mod acl;
+pub use acl::Acl;
+
+pub mod aof;
+
+mod blocking;
+
+pub mod cluster;
+pub use cluster::Cluster;
+
+pub mod cmd;
+pub use cmd::Command;
+
+pub mod config;
+pub use config::Config;
+
+mod connection;
+pub use connection::{Connection, Stream};
+
+pub mod db;
+pub use db::Db;
+
+pub mod frame;
+pub use frame::Frame;
+
+mod glob;
+
+mod parse;
+
+pub mod rdb;
+
+pub mod replication;
+
+pub mod server;
+
+pub mod shutdown;
+
+pub mod stream;
+
+pub mod tls;
+
+pub mod value;
+pub use value::Value;
+
+pub mod zset;
+
+/// Error returned by most functions.
+///
+/// Boxing keeps things simple while the server is still small; the errors
+/// end up logged or turned into `-ERR` replies either way.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// A specialized `Result` type for server operations.
+pub type Result<T> = std::result::Result<T, Error>;
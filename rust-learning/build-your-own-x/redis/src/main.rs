@@ -1,29 +1,103 @@
-use tokio::net::{TcpListener, TcpStream};
-use mini_redis::{Connection, Frame};
+use redis::aof::{self, Aof};
+use redis::shutdown::SaveMode;
+use redis::tls::TlsListener;
+use redis::{Acl, Cluster, Config, Db, cluster, rdb, replication, server};
+use std::{io, process};
+use tokio::net::TcpListener;
+use tokio::signal;
 
 #[tokio::main]
 async fn main() {
-    // Bind the listener to the address
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
+    let config = Config::from_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("redis: {}", err);
+        process::exit(1);
+    });
 
-    loop {
-        // The second item contains the IP and port of the new connection.
-        let (socket, _) = listener.accept().await.unwrap();
-        process(socket).await;
+    // Whatever goes wrong from here on is reported, not panicked over.
+    if let Err(err) = run(config).await {
+        eprintln!("redis: {}", err);
+        process::exit(1);
     }
 }
 
-async fn process(socket: TcpStream) {
-    // The 'Conncetion' lets us read/write redis **frames** instead of
-    // byte streams. The 'Connection' is defined by mini-redis.
-    let mut connection = Connection::new(socket);
+async fn run(config: Config) -> redis::Result<()> {
+    // Bind the listener to the address
+    let listener = TcpListener::bind((config.bind, config.port))
+        .await
+        .map_err(|err| format!("can't listen on {}:{}: {}", config.bind, config.port, err))?;
+
+    // Clients may also come over TLS, on a port of their own.
+    let tls = match config.tls_port {
+        0 => None,
+        port => {
+            let (Some(cert), Some(key)) = (&config.tls_cert_file, &config.tls_key_file) else {
+                return Err("tls-port needs tls-cert-file and tls-key-file".into());
+            };
+            let listener = TcpListener::bind((config.bind, port))
+                .await
+                .map_err(|err| format!("can't listen on {}:{}: {}", config.bind, port, err))?;
+            Some(TlsListener::new(listener, cert, key)?)
+        }
+    };
+
+    let db = Db::new(config.shards);
+    db.set_acl(Acl::from_config(&config)?);
+    db.set_config(config.clone());
 
-    if let Some(frame) = connection.read_frame().await.unwrap() {
-        println!("GOT: {:?}", frame);
+    // Like Redis, the append-only file wins when enabled: it is the more
+    // up to date of the two.
+    if config.appendonly {
+        // Replay before attaching the file, or every command would be
+        // appended to it a second time.
+        let path = config.aof_path();
+        aof::load(&path, &db).map_err(|err| format!("can't load '{}': {}", path.display(), err))?;
+        let aof = Aof::open(&path, config.appendfsync)
+            .map_err(|err| format!("can't open '{}': {}", path.display(), err))?;
+        db.set_aof(Some(aof));
+    } else {
+        let path = config.rdb_path();
+        rdb::load(&path, &db).map_err(|err| format!("can't load '{}': {}", path.display(), err))?;
+    }
+
+    // A replica starts from whatever it had, then catches up with its
+    // primary.
+    if let Some((host, port)) = config.replicaof {
+        tokio::spawn(replication::follow(db.clone(), host, port, config.port));
+    }
 
-        // Respond with an error
-        let response = Frame::Error("unimplemented".to_string());
-        connection.write_frame(&response).await.unwrap();
+    // Other nodes reach this one at the address clients do.
+    if config.cluster_enabled {
+        let host = match config.bind {
+            addr if addr.is_unspecified() => "127.0.0.1".to_string(),
+            addr => addr.to_string(),
+        };
+        db.set_cluster(Some(Cluster::new(&host, config.port)));
+        tokio::spawn(cluster::gossip(db.clone()));
+    }
+
+    // `SIGINT` or `SIGTERM` shut the server down like `SHUTDOWN` does.
+    let handle = db.clone();
+    tokio::spawn(async move {
+        match terminated().await {
+            Ok(()) => handle.shutdown(SaveMode::Default),
+            Err(err) => eprintln!("redis: can't listen for signals: {}", err),
+        }
+    });
+
+    server::serve(listener, tls, db).await
+}
+
+/// Wait for a signal asking the process to stop.
+#[cfg(unix)]
+async fn terminated() -> io::Result<()> {
+    let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        res = signal::ctrl_c() => res,
+        _ = sigterm.recv() => Ok(()),
     }
 }
 
+#[cfg(not(unix))]
+async fn terminated() -> io::Result<()> {
+    signal::ctrl_c().await
+}
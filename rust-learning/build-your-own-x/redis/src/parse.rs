@@ -0,0 +1,95 @@
+use bytes::Bytes;
+use std::slice;
+
+/// Utility for walking the arguments of a command.
+///
+/// Each `next_*` method consumes one argument. Running out of arguments is
+/// reported as the usual "wrong number of arguments" error, so handlers can
+/// just use `?` and the client gets the reply Redis would give.
+#[derive(Debug)]
+pub struct Parse<'a> {
+    name: &'a str,
+    args: slice::Iter<'a, Bytes>,
+}
+
+impl<'a> Parse<'a> {
+    /// Create a `Parse` over the arguments of the command called `name`.
+    pub fn new(name: &'a str, args: &'a [Bytes]) -> Parse<'a> {
+        Parse {
+            name,
+            args: args.iter(),
+        }
+    }
+
+    /// Number of arguments not consumed yet.
+    pub fn remaining(&self) -> usize {
+        self.args.len()
+    }
+
+    /// Return the next argument as raw bytes.
+    pub fn next_bytes(&mut self) -> crate::Result<Bytes> {
+        match self.args.next() {
+            Some(arg) => Ok(arg.clone()),
+            None => {
+                Err(format!("ERR wrong number of arguments for '{}' command", self.name).into())
+            }
+        }
+    }
+
+    /// Return the next argument as a string. Redis strings are binary safe,
+    /// but keys and options here are required to be UTF-8.
+    pub fn next_string(&mut self) -> crate::Result<String> {
+        let arg = self.next_bytes()?;
+        String::from_utf8(arg.to_vec())
+            .map_err(|_| "ERR invalid argument, expected a UTF-8 string".into())
+    }
+
+    /// Return the next argument as a signed integer.
+    pub fn next_int(&mut self) -> crate::Result<i64> {
+        let arg = self.next_bytes()?;
+        std::str::from_utf8(&arg)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| "ERR value is not an integer or out of range".into())
+    }
+
+    /// Ensure there are no more arguments.
+    pub fn finish(&mut self) -> crate::Result<()> {
+        if self.args.len() == 0 {
+            Ok(())
+        } else {
+            Err("ERR syntax error".into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walks_arguments() {
+        let args = [Bytes::from("key"), Bytes::from("-12")];
+        let mut parse = Parse::new("cmd", &args);
+
+        assert_eq!(parse.next_string().unwrap(), "key");
+        assert_eq!(parse.next_int().unwrap(), -12);
+        parse.finish().unwrap();
+
+        let err = parse.next_bytes().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR wrong number of arguments for 'cmd' command"
+        );
+    }
+
+    #[test]
+    fn reports_bad_integers_and_leftovers() {
+        let args = [Bytes::from("ten"), Bytes::from("extra")];
+        let mut parse = Parse::new("cmd", &args);
+
+        assert!(parse.next_int().is_err());
+        assert_eq!(parse.remaining(), 1);
+        assert!(parse.finish().is_err());
+    }
+}
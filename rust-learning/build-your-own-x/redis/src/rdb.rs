@@ -0,0 +1,525 @@
+//! Point-in-time snapshots of the keyspace, in a compact binary format
+//! modelled on Redis' RDB files.
+//!
+//! A file is laid out as:
+//!
+//! ```text
+//! "REDIS-RS" version:u32
+//! ( [0xFC expires-at-ms:u64] type:u8 key value )*
+//! 0xFF checksum:u64
+//! ```
+//!
+//! Integers are big-endian. Lengths, and strings, which are a length
+//! followed by the bytes, use a variable-length encoding (LEB128). The
+//! checksum is the CRC-64 of everything before it, so a file cut short or
+//! damaged on disk is refused instead of loading half a keyspace.
+//!
+//! Deadlines are stored as Unix timestamps, so keys keep expiring at the
+//! right moment across restarts.
+
+use crate::stream::{Group, Pending, Stream, StreamId};
+use crate::zset::ZSet;
+use crate::{Db, Value};
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8] = b"REDIS-RS";
+
+/// Version of the format written by this build. Files with a higher
+/// version are refused.
+pub const VERSION: u32 = 2;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+const TYPE_STREAM: u8 = 5;
+
+const OP_EXPIRE_MS: u8 = 0xFC;
+const OP_EOF: u8 = 0xFF;
+
+/// Write a snapshot of `db` to `path`, blocking until it is on disk.
+pub fn save(db: &Db, path: &Path) -> crate::Result<()> {
+    let now = SystemTime::now();
+    let entries = db.snapshot();
+    write(path, &encode(&entries, now))
+}
+
+/// Copy the keyspace and write the snapshot from a blocking thread, leaving
+/// the runtime free to serve clients. The copy itself is taken before this
+/// returns, so later writes do not leak into the snapshot.
+///
+/// `done` runs with the outcome once the file is written.
+pub fn save_in_background(
+    db: &Db,
+    path: PathBuf,
+    done: impl FnOnce(crate::Result<()>) + Send + 'static,
+) {
+    let now = SystemTime::now();
+    let entries = db.snapshot();
+
+    tokio::task::spawn_blocking(move || {
+        done(write(&path, &encode(&entries, now)));
+    });
+}
+
+/// Load the snapshot at `path` into `db`, returning the number of keys
+/// restored. A missing file is an empty snapshot; keys whose deadline passed
+/// while the server was down are skipped.
+pub fn load(path: &Path, db: &Db) -> crate::Result<usize> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(restore(decode(&data)?, db))
+}
+
+/// Insert decoded `entries` into `db`, returning how many were. Keys whose
+/// deadline already passed are skipped.
+pub fn restore(entries: Vec<(String, Value, Option<SystemTime>)>, db: &Db) -> usize {
+    let now = SystemTime::now();
+    let mut count = 0;
+    for (key, value, expires_at) in entries {
+        let ttl = match expires_at {
+            Some(at) => match at.duration_since(now) {
+                Ok(ttl) => Some(ttl),
+                Err(_) => continue,
+            },
+            None => None,
+        };
+        db.insert(key, value, ttl);
+        count += 1;
+    }
+    count
+}
+
+/// Write `data` to `path` atomically: readers see either the old file or
+/// the complete new one, never a partial write.
+fn write(path: &Path, data: &[u8]) -> crate::Result<()> {
+    let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Encode `entries`, as returned by `Db::snapshot`, taken at `now`.
+pub fn encode(entries: &[(String, Value, Option<Duration>)], now: SystemTime) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_be_bytes());
+
+    for (key, value, ttl) in entries {
+        if let Some(ttl) = ttl {
+            let at = (now + *ttl)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            out.push(OP_EXPIRE_MS);
+            out.extend_from_slice(&at.to_be_bytes());
+        }
+
+        let kind = match value {
+            Value::String(_) => TYPE_STRING,
+            Value::List(_) => TYPE_LIST,
+            Value::Hash(_) => TYPE_HASH,
+            Value::Set(_) => TYPE_SET,
+            Value::ZSet(_) => TYPE_ZSET,
+            Value::Stream(_) => TYPE_STREAM,
+        };
+        out.push(kind);
+        put_bytes(&mut out, key.as_bytes());
+
+        match value {
+            Value::String(s) => put_bytes(&mut out, s),
+            Value::List(list) => {
+                put_len(&mut out, list.len());
+                for item in list {
+                    put_bytes(&mut out, item);
+                }
+            }
+            Value::Hash(hash) => {
+                put_len(&mut out, hash.len());
+                for (field, value) in hash {
+                    put_bytes(&mut out, field);
+                    put_bytes(&mut out, value);
+                }
+            }
+            Value::Set(set) => {
+                put_len(&mut out, set.len());
+                for member in set {
+                    put_bytes(&mut out, member);
+                }
+            }
+            Value::ZSet(zset) => {
+                put_len(&mut out, zset.len());
+                for (member, score) in zset.range_by_rank(0, zset.len(), false) {
+                    put_bytes(&mut out, member);
+                    out.extend_from_slice(&score.to_bits().to_be_bytes());
+                }
+            }
+            Value::Stream(stream) => put_stream(&mut out, stream),
+        }
+    }
+
+    out.push(OP_EOF);
+    let checksum = crc64(&out);
+    out.extend_from_slice(&checksum.to_be_bytes());
+    out
+}
+
+/// Decode a snapshot into keys, values and deadlines.
+pub fn decode(data: &[u8]) -> crate::Result<Vec<(String, Value, Option<SystemTime>)>> {
+    if data.len() < MAGIC.len() + 4 + 1 + 8 || !data.starts_with(MAGIC) {
+        return Err("not a snapshot file".into());
+    }
+
+    let (body, checksum) = data.split_at(data.len() - 8);
+    if crc64(body).to_be_bytes() != checksum {
+        return Err("snapshot checksum mismatch".into());
+    }
+
+    let mut reader = Reader {
+        data: body,
+        pos: MAGIC.len(),
+    };
+    let version = reader.u32()?;
+    if version > VERSION {
+        return Err(format!("snapshot version {} is newer than {}", version, VERSION).into());
+    }
+
+    let mut entries = Vec::new();
+    loop {
+        let mut expires_at = None;
+        let mut kind = reader.byte()?;
+
+        if kind == OP_EXPIRE_MS {
+            expires_at = Some(UNIX_EPOCH + Duration::from_millis(reader.u64()?));
+            kind = reader.byte()?;
+        }
+        if kind == OP_EOF {
+            break;
+        }
+
+        let key =
+            String::from_utf8(reader.bytes()?.to_vec()).map_err(|_| "snapshot key is not UTF-8")?;
+
+        let value = match kind {
+            TYPE_STRING => Value::String(reader.bytes()?),
+            TYPE_LIST => {
+                let len = reader.len()?;
+                let mut list = VecDeque::new();
+                for _ in 0..len {
+                    list.push_back(reader.bytes()?);
+                }
+                Value::List(list)
+            }
+            TYPE_HASH => {
+                let len = reader.len()?;
+                let mut hash = HashMap::new();
+                for _ in 0..len {
+                    hash.insert(reader.bytes()?, reader.bytes()?);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_SET => {
+                let len = reader.len()?;
+                let mut set = HashSet::new();
+                for _ in 0..len {
+                    set.insert(reader.bytes()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_ZSET => {
+                let len = reader.len()?;
+                let mut zset = ZSet::new();
+                for _ in 0..len {
+                    let member = reader.bytes()?;
+                    let score = f64::from_bits(reader.u64()?);
+                    if score.is_nan() {
+                        return Err("snapshot holds a NaN score".into());
+                    }
+                    zset.insert(member, score);
+                }
+                Value::ZSet(zset)
+            }
+            TYPE_STREAM => Value::Stream(reader.stream()?),
+            kind => return Err(format!("unknown value type {} in snapshot", kind).into()),
+        };
+
+        entries.push((key, value, expires_at));
+    }
+
+    if reader.pos != body.len() {
+        return Err("trailing data after the end of the snapshot".into());
+    }
+    Ok(entries)
+}
+
+fn put_len(out: &mut Vec<u8>, mut len: usize) {
+    // LEB128: seven bits at a time, high bit set on all but the last byte.
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_len(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+fn put_id(out: &mut Vec<u8>, id: StreamId) {
+    out.extend_from_slice(&id.ms.to_be_bytes());
+    out.extend_from_slice(&id.seq.to_be_bytes());
+}
+
+/// A stream is its last ID, its entries, then its groups, each with its
+/// consumers and PEL:
+///
+/// ```text
+/// last-id len ( id len ( field value )* )*
+/// len ( name last-delivered len ( name seen-at:u64 )* len ( id consumer delivered-at:u64 deliveries:u64 )* )*
+/// ```
+///
+/// IDs are their two halves as `u64`s.
+fn put_stream(out: &mut Vec<u8>, stream: &Stream) {
+    put_id(out, stream.last_id());
+    put_len(out, stream.len());
+    for (id, fields) in stream.entries() {
+        put_id(out, *id);
+        put_len(out, fields.len());
+        for (field, value) in fields {
+            put_bytes(out, field);
+            put_bytes(out, value);
+        }
+    }
+
+    put_len(out, stream.groups().len());
+    for (name, group) in stream.groups() {
+        put_bytes(out, name);
+        put_id(out, group.last_delivered);
+        put_len(out, group.consumers.len());
+        for (consumer, seen_at) in &group.consumers {
+            put_bytes(out, consumer);
+            out.extend_from_slice(&seen_at.to_be_bytes());
+        }
+        put_len(out, group.pending.len());
+        for (id, pending) in &group.pending {
+            put_id(out, *id);
+            put_bytes(out, &pending.consumer);
+            out.extend_from_slice(&pending.delivered_at.to_be_bytes());
+            out.extend_from_slice(&pending.deliveries.to_be_bytes());
+        }
+    }
+}
+
+/// Cursor over the body of a snapshot.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> crate::Result<&[u8]> {
+        if self.data.len() - self.pos < n {
+            return Err("unexpected end of snapshot".into());
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> crate::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> crate::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> crate::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> crate::Result<usize> {
+        let mut len: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            len |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(len).map_err(|_| "snapshot length overflow".into());
+            }
+        }
+        Err("snapshot length overflow".into())
+    }
+
+    fn bytes(&mut self) -> crate::Result<Bytes> {
+        let len = self.len()?;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+
+    fn id(&mut self) -> crate::Result<StreamId> {
+        Ok(StreamId::new(self.u64()?, self.u64()?))
+    }
+
+    fn stream(&mut self) -> crate::Result<Stream> {
+        let last_id = self.id()?;
+        let mut stream = Stream::new();
+        for _ in 0..self.len()? {
+            let id = self.id()?;
+            let mut fields = Vec::new();
+            for _ in 0..self.len()? {
+                fields.push((self.bytes()?, self.bytes()?));
+            }
+            stream.add(id, fields)?;
+        }
+        stream.set_last_id(last_id)?;
+
+        for _ in 0..self.len()? {
+            let name = self.bytes()?;
+            let mut group = Group {
+                last_delivered: self.id()?,
+                ..Group::default()
+            };
+            for _ in 0..self.len()? {
+                group.consumers.insert(self.bytes()?, self.u64()?);
+            }
+            for _ in 0..self.len()? {
+                let id = self.id()?;
+                let pending = Pending {
+                    consumer: self.bytes()?,
+                    delivered_at: self.u64()?,
+                    deliveries: self.u64()?,
+                };
+                group.pending.insert(id, pending);
+            }
+            stream.groups_mut().insert(name, group);
+        }
+        Ok(stream)
+    }
+}
+
+/// CRC-64 with the Jones polynomial, the variant Redis uses for its RDB
+/// files.
+fn crc64(data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+    const TABLE: [u64; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u64;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ POLY
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    data.iter().fold(0, |crc, &byte| {
+        TABLE[((crc ^ byte as u64) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<(String, Value, Option<Duration>)> {
+        let mut zset = ZSet::new();
+        zset.insert("ann".into(), 1.5);
+        zset.insert("bob".into(), f64::NEG_INFINITY);
+
+        let mut stream = Stream::new();
+        let id = StreamId::new(1_700_000_000_000, 0);
+        stream.add(id, vec![("f".into(), "v".into())]).unwrap();
+        stream
+            .set_last_id(StreamId::new(1_700_000_000_001, 3))
+            .unwrap();
+        stream.create_group("g".into(), StreamId::MIN);
+        stream.deliver_new(b"g", &"ann".into(), 1, 1_700_000_000_500, false);
+
+        vec![
+            ("s".into(), Value::String("hello".into()), None),
+            (
+                "l".into(),
+                Value::List(["a".into(), "".into(), vec![7; 300].into()].into()),
+                Some(Duration::from_secs(60)),
+            ),
+            (
+                "h".into(),
+                Value::Hash([("f".into(), "v".into())].into()),
+                None,
+            ),
+            (
+                "set".into(),
+                Value::Set(["x".into(), "y".into()].into()),
+                None,
+            ),
+            ("z".into(), Value::ZSet(zset), None),
+            ("x".into(), Value::Stream(stream), None),
+        ]
+    }
+
+    #[test]
+    fn crc64_matches_redis() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn round_trips_every_type() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let entries = sample();
+        let decoded = decode(&encode(&entries, now)).unwrap();
+
+        assert_eq!(decoded.len(), entries.len());
+        for ((key, value, ttl), (k, v, at)) in entries.into_iter().zip(decoded) {
+            assert_eq!(key, k);
+            assert_eq!(value, v);
+            assert_eq!(ttl.map(|ttl| now + ttl), at);
+        }
+    }
+
+    #[test]
+    fn refuses_damaged_files() {
+        let data = encode(&sample(), SystemTime::now());
+
+        let mut flipped = data.clone();
+        flipped[20] ^= 1;
+        assert_eq!(
+            decode(&flipped).unwrap_err().to_string(),
+            "snapshot checksum mismatch"
+        );
+
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        assert!(decode(b"GIF89a").is_err());
+
+        // A future version, with a valid checksum.
+        let mut newer = data[..data.len() - 8].to_vec();
+        newer[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        let checksum = crc64(&newer);
+        newer.extend_from_slice(&checksum.to_be_bytes());
+        assert!(decode(&newer).unwrap_err().to_string().contains("newer"));
+    }
+}
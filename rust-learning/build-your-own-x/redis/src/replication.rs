@@ -0,0 +1,577 @@
+//! Primary/replica replication.
+//!
+//! A replica connects to its primary like a client, logs in as
+//! `masteruser` with `masterauth` if the primary needs a password,
+//! introduces itself with `REPLCONF listening-port`, then asks for the data
+//! with `PSYNC replid offset`. The primary answers one of two ways:
+//!
+//! * `+FULLRESYNC replid offset`, followed by a snapshot of the keyspace as
+//!   a bulk string. The replica replaces its keyspace with it.
+//! * `+CONTINUE`, when the replica was already in sync with this primary and
+//!   the primary still has everything it missed in its backlog.
+//!
+//! Either way, the primary then streams every write command it applies, in
+//! the form logged to the append-only file. Both sides count the bytes of
+//! that stream: the offset tells how far a replica got, and a replica
+//! reconnecting after a short outage resumes from it. Every second the
+//! replica reports its offset with `REPLCONF ACK offset`.
+//!
+//! Replicas refuse writes from their own clients. Keys with a deadline
+//! expire on each side on their own; the deadlines are absolute, so they
+//! agree.
+
+use crate::acl::DEFAULT_USER;
+use crate::cmd::transaction;
+use crate::frame::Protocol;
+use crate::parse::Parse;
+use crate::{Command, Connection, Db, Frame, rdb};
+
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, broadcast};
+use tokio::time;
+
+/// Bytes of the stream kept for replicas resuming after an outage.
+const BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// Chunks of the stream a replica may fall behind by before the primary
+/// drops it. It then resumes from the backlog, or syncs from scratch.
+const MAX_LAG: usize = 4096;
+
+/// How often replicas report their offset, and how long they wait before
+/// reconnecting to a primary they lost.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Error replied to writes sent to a replica.
+pub const READONLY: &str = "READONLY You can't write against a read only replica.";
+
+/// The stream of write commands of a primary, created when the first replica
+/// attaches.
+#[derive(Debug)]
+pub struct Feed {
+    /// Names this stream. A replica can only resume a stream with the same
+    /// id.
+    replid: String,
+
+    backlog: Mutex<Backlog>,
+    tx: broadcast::Sender<Bytes>,
+
+    /// Attached replicas by client id: their address, listening port and
+    /// last acknowledged offset.
+    replicas: Mutex<HashMap<u64, (SocketAddr, u16, u64)>>,
+
+    /// Notified when a replica detaches.
+    detached: Notify,
+}
+
+/// The tail of the stream.
+#[derive(Debug, Default)]
+struct Backlog {
+    data: VecDeque<u8>,
+
+    /// Offset of the end of the stream: the number of bytes sent so far.
+    offset: u64,
+}
+
+impl Feed {
+    pub(crate) fn new() -> Feed {
+        Feed {
+            replid: new_replid(),
+            backlog: Mutex::new(Backlog::default()),
+            tx: broadcast::channel(MAX_LAG).0,
+            replicas: Mutex::new(HashMap::new()),
+            detached: Notify::new(),
+        }
+    }
+
+    /// Send `cmds` to the replicas.
+    ///
+    /// Called with writes held in order by `Db::write`, so every replica
+    /// sees them in the order they were applied.
+    pub(crate) fn append(&self, cmds: &[Command]) {
+        if cmds.is_empty() {
+            return;
+        }
+
+        let mut data = BytesMut::new();
+        for cmd in cmds {
+            cmd.to_frame().encode(&mut data, Protocol::Resp2);
+        }
+
+        let mut backlog = self.backlog.lock().unwrap();
+        backlog.offset += data.len() as u64;
+        backlog.data.extend(&data[..]);
+        let excess = backlog.data.len().saturating_sub(BACKLOG_SIZE);
+        backlog.data.drain(..excess);
+
+        // Nobody listening is fine: a replica attaching later starts from a
+        // snapshot taken after this write, or resumes from the backlog.
+        let _ = self.tx.send(data.freeze());
+    }
+
+    /// End the stream for every attached replica: they are sent what was
+    /// appended so far, then their link is closed.
+    ///
+    /// Called on shutdown, between writes.
+    pub(crate) fn close(&self) {
+        // An empty message can't be a command: it marks the end.
+        let _ = self.tx.send(Bytes::new());
+    }
+
+    /// Wait until no replica is attached.
+    pub(crate) async fn detached(&self) {
+        loop {
+            let notified = self.detached.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.replicas.lock().unwrap().is_empty() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Offset of the end of the stream.
+    pub fn offset(&self) -> u64 {
+        self.backlog.lock().unwrap().offset
+    }
+
+    /// The stream since `offset`, if the backlog still has all of it.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let backlog = self.backlog.lock().unwrap();
+        let start = backlog.offset - backlog.data.len() as u64;
+        if offset < start || offset > backlog.offset {
+            return None;
+        }
+        Some(
+            backlog
+                .data
+                .range((offset - start) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+/// A replica's link to its primary.
+#[derive(Debug)]
+pub struct Link {
+    host: String,
+    port: u16,
+    state: Mutex<LinkState>,
+}
+
+#[derive(Debug, Default)]
+struct LinkState {
+    /// As reported by `ROLE`: `connect`, `sync` or `connected`.
+    status: &'static str,
+
+    /// The stream followed, and how far into it this replica got. Empty
+    /// before the first sync.
+    replid: String,
+    offset: u64,
+}
+
+impl Link {
+    /// The stream followed and the offset reached, for `PSYNC`.
+    fn position(&self) -> (String, u64) {
+        let state = self.state.lock().unwrap();
+        (state.replid.clone(), state.offset)
+    }
+
+    fn set_status(&self, status: &'static str) {
+        self.state.lock().unwrap().status = status;
+    }
+
+    fn advance(&self, len: u64) {
+        self.state.lock().unwrap().offset += len;
+    }
+}
+
+/// Make `db` a replica of the primary at `host:port`, and keep it in sync.
+///
+/// `listening_port` is the port this server accepts clients on, reported to
+/// the primary. Runs until the runtime shuts down, reconnecting whenever
+/// the link is lost.
+pub async fn follow(db: Db, host: String, port: u16, listening_port: u16) {
+    let link = Arc::new(Link {
+        host,
+        port,
+        state: Mutex::new(LinkState {
+            status: "connect",
+            ..LinkState::default()
+        }),
+    });
+    db.set_link(Some(link.clone()));
+
+    loop {
+        if let Err(err) = sync(&db, &link, listening_port).await {
+            eprintln!(
+                "replication: lost primary {}:{}: {}",
+                link.host, link.port, err
+            );
+        }
+        link.set_status("connect");
+        time::sleep(INTERVAL).await;
+    }
+}
+
+/// Connect to the primary, sync, then apply its stream until the link
+/// breaks.
+async fn sync(db: &Db, link: &Link, listening_port: u16) -> crate::Result<()> {
+    let socket = TcpStream::connect((link.host.as_str(), link.port)).await?;
+    let mut conn = Connection::new(socket);
+
+    login(db, &mut conn).await?;
+    request(&mut conn, &["PING"]).await?;
+    request(
+        &mut conn,
+        &["REPLCONF", "listening-port", &listening_port.to_string()],
+    )
+    .await?;
+
+    let (replid, offset) = link.position();
+    let replid = if replid.is_empty() {
+        "?".into()
+    } else {
+        replid
+    };
+    let reply = request(&mut conn, &["PSYNC", &replid, &offset.to_string()]).await?;
+
+    link.set_status("sync");
+    match reply.split(' ').collect::<Vec<_>>()[..] {
+        ["FULLRESYNC", replid, offset] => {
+            let offset = offset.parse().map_err(|_| "bad FULLRESYNC offset")?;
+            let snapshot = match conn.read_frame().await? {
+                Some(Frame::Bulk(data)) => data,
+                frame => return Err(format!("expected a snapshot, got {:?}", frame).into()),
+            };
+
+            let entries = rdb::decode(&snapshot)?;
+            db.atomically(|| {
+                db.clear();
+                rdb::restore(entries, db);
+            });
+
+            let mut state = link.state.lock().unwrap();
+            state.replid = replid.to_string();
+            state.offset = offset;
+        }
+        ["CONTINUE"] => {}
+        _ => return Err(format!("unexpected PSYNC reply '{}'", reply).into()),
+    }
+    link.set_status("connected");
+
+    let mut ticks = time::interval(INTERVAL);
+    // Commands between `MULTI` and `EXEC`, applied together on `EXEC`.
+    let mut transaction: Option<Vec<Command>> = None;
+    let mut scratch = BytesMut::new();
+
+    loop {
+        tokio::select! {
+            frame = conn.read_frame() => {
+                let Some(frame) = frame? else {
+                    return Err("connection closed".into());
+                };
+
+                // The stream is made of commands encoded the one way
+                // `Feed::append` does, so encoding one again gives its size.
+                scratch.clear();
+                frame.encode(&mut scratch, Protocol::Resp2);
+                let cmd = Command::from_frame(frame)?;
+
+                match (cmd.name(), &mut transaction) {
+                    ("multi", None) => transaction = Some(Vec::new()),
+                    ("exec", Some(_)) => {
+                        let cmds = transaction.take().unwrap_or_default();
+                        db.atomically(|| apply(db, cmds));
+                    }
+                    (_, Some(queued)) => queued.push(cmd),
+                    (_, None) => db.concurrently(|| apply(db, vec![cmd])),
+                }
+                link.advance(scratch.len() as u64);
+            }
+            _ = ticks.tick() => {
+                let (_, offset) = link.position();
+                let ack = Command::new(
+                    "replconf",
+                    vec![Bytes::from_static(b"ACK"), Bytes::from(offset.to_string())],
+                );
+                conn.write_frame(&ack.to_frame()).await?;
+                conn.flush().await?;
+            }
+        }
+    }
+}
+
+/// Apply commands received from the primary, logging them to this server's
+/// own append-only file and replicas.
+fn apply(db: &Db, cmds: Vec<Command>) {
+    // Nobody waits for the data to be on disk; the primary has it.
+    let _ = db.write(|| {
+        for cmd in &cmds {
+            if let Frame::Error(err) = crate::cmd::apply(cmd, db) {
+                eprintln!("replication: '{}' failed: {}", cmd.name(), err);
+            }
+        }
+        ((), transaction::wrap(cmds))
+    });
+}
+
+/// Log in to another server over `conn` as `masteruser` with
+/// `masterauth`, if set.
+pub(crate) async fn login(db: &Db, conn: &mut Connection) -> crate::Result<()> {
+    let auth = {
+        let config = db.config();
+        let user = config.masteruser.as_deref().unwrap_or(DEFAULT_USER);
+        config
+            .masterauth
+            .clone()
+            .map(|password| (user.to_string(), password))
+    };
+    if let Some((user, password)) = auth {
+        request(conn, &["AUTH", &user, &password]).await?;
+    }
+    Ok(())
+}
+
+/// Send a request to the primary during the handshake, returning its
+/// simple string reply.
+async fn request(conn: &mut Connection, parts: &[&str]) -> crate::Result<String> {
+    let args = parts[1..]
+        .iter()
+        .map(|p| Bytes::copy_from_slice(p.as_bytes()))
+        .collect();
+    conn.write_frame(&Command::new(parts[0], args).to_frame())
+        .await?;
+    conn.flush().await?;
+
+    match conn.read_frame().await? {
+        Some(Frame::Simple(reply)) => Ok(reply),
+        Some(Frame::Error(err)) => Err(format!("primary replied: {}", err).into()),
+        frame => Err(format!("unexpected reply {:?}", frame).into()),
+    }
+}
+
+/// `REPLCONF listening-port port | ACK offset | capa ...`, outside of a
+/// replication link. Records the port a replica-to-be listens on.
+pub fn replconf(listening_port: &mut Option<u16>, parse: &mut Parse) -> crate::Result<Frame> {
+    while parse.remaining() > 0 {
+        let option = parse.next_string()?.to_ascii_lowercase();
+        let value = parse.next_string()?;
+        if option == "listening-port" {
+            *listening_port = Some(value.parse().map_err(|_| "ERR invalid listening port")?);
+        }
+    }
+    Ok(Frame::ok())
+}
+
+/// `PSYNC replid offset`: serve a replica over `connection` until it goes
+/// away.
+///
+/// `id` is the client id of the connection, `listening_port` the port the
+/// replica reported with `REPLCONF`.
+pub async fn serve(
+    db: &Db,
+    connection: &mut Connection,
+    cmd: &Command,
+    id: u64,
+    listening_port: Option<u16>,
+) -> crate::Result<()> {
+    let mut parse = cmd.parse();
+    let replid = parse.next_string()?;
+    let offset = parse.next_int()?;
+    parse.finish()?;
+
+    // With every command held off, nothing can be written between the copy
+    // or the resume point and the subscription to the stream.
+    let (feed, mut rx, start) = db.atomically(|| {
+        let feed = db.start_feed();
+        let rx = feed.tx.subscribe();
+
+        let resumed = match u64::try_from(offset) {
+            Ok(offset) if replid == feed.replid => feed.since(offset),
+            _ => None,
+        };
+        let start = match resumed {
+            Some(missed) => Err(missed),
+            None => Ok((feed.offset(), SystemTime::now(), db.snapshot())),
+        };
+        (feed, rx, start)
+    });
+
+    match start {
+        Ok((offset, now, entries)) => {
+            let reply = format!("FULLRESYNC {} {}", feed.replid, offset);
+            connection.write_frame(&Frame::Simple(reply)).await?;
+
+            let snapshot = tokio::task::spawn_blocking(move || rdb::encode(&entries, now)).await?;
+            connection
+                .write_frame(&Frame::Bulk(Bytes::from(snapshot)))
+                .await?;
+        }
+        Err(missed) => {
+            connection
+                .write_frame(&Frame::Simple("CONTINUE".into()))
+                .await?;
+            connection.write_raw(&missed).await?;
+        }
+    }
+    connection.flush().await?;
+
+    let addr = connection.peer_addr()?;
+    let port = listening_port.unwrap_or(addr.port());
+    let _registration = Registration::new(&feed, id, (addr, port, 0));
+
+    loop {
+        tokio::select! {
+            data = rx.recv() => match data {
+                Ok(data) if data.is_empty() => return Ok(()),
+                Ok(data) => {
+                    connection.write_raw(&data).await?;
+                    connection.flush().await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    return Err("replica fell too far behind".into());
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            frame = connection.read_frame() => {
+                let Some(frame) = frame? else {
+                    return Ok(());
+                };
+
+                // Only acknowledgements are expected from a replica.
+                let cmd = Command::from_frame(frame)?;
+                let mut parse = cmd.parse();
+                if cmd.name() == "replconf"
+                    && parse.next_string()?.eq_ignore_ascii_case("ack")
+                {
+                    let ack = parse.next_int()?.max(0) as u64;
+                    if let Some(replica) = feed.replicas.lock().unwrap().get_mut(&id) {
+                        replica.2 = ack;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Lists a replica in its primary's `ROLE` for as long as it is attached.
+struct Registration<'a> {
+    feed: &'a Feed,
+    id: u64,
+}
+
+impl Registration<'_> {
+    fn new(feed: &Feed, id: u64, replica: (SocketAddr, u16, u64)) -> Registration<'_> {
+        feed.replicas.lock().unwrap().insert(id, replica);
+        Registration { feed, id }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.feed.replicas.lock().unwrap().remove(&self.id);
+        self.feed.detached.notify_waiters();
+    }
+}
+
+/// `ROLE`
+///
+/// A primary replies with `master`, its offset and its replicas, each as
+/// its address, port and acknowledged offset. A replica replies with
+/// `slave`, its primary's address and port, the state of the link and its
+/// offset.
+pub fn role(db: &Db, parse: &mut Parse) -> crate::Result<Frame> {
+    parse.finish()?;
+
+    if let Some(link) = db.link() {
+        let state = link.state.lock().unwrap();
+        return Ok(Frame::Array(vec![
+            bulk("slave"),
+            bulk(&link.host),
+            Frame::Integer(link.port as i64),
+            bulk(state.status),
+            Frame::Integer(state.offset as i64),
+        ]));
+    }
+
+    let (offset, replicas) = match db.feed() {
+        Some(feed) => {
+            let mut replicas: Vec<_> = feed.replicas.lock().unwrap().values().copied().collect();
+            replicas.sort();
+            (feed.offset(), replicas)
+        }
+        None => (0, Vec::new()),
+    };
+    let replicas = replicas
+        .into_iter()
+        .map(|(addr, port, ack)| {
+            Frame::Array(vec![
+                bulk(&addr.ip().to_string()),
+                bulk(&port.to_string()),
+                bulk(&ack.to_string()),
+            ])
+        })
+        .collect();
+
+    Ok(Frame::Array(vec![
+        bulk("master"),
+        Frame::Integer(offset as i64),
+        Frame::Array(replicas),
+    ]))
+}
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+}
+
+/// A fresh replication id: 40 hex characters, unique to this process and
+/// moment.
+fn new_replid() -> String {
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seed = format!("{}-{}", std::process::id(), now.as_nanos());
+    sha1_smol::Sha1::from(seed).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(parts: &[&str]) -> Command {
+        let args = parts[1..]
+            .iter()
+            .map(|p| Bytes::copy_from_slice(p.as_bytes()))
+            .collect();
+        Command::new(parts[0], args)
+    }
+
+    #[test]
+    fn backlog_serves_recent_offsets() {
+        let feed = Feed::new();
+        feed.append(&[cmd(&["SET", "a", "1"])]);
+        let first = feed.offset();
+        feed.append(&[cmd(&["DEL", "a"])]);
+
+        assert_eq!(feed.since(feed.offset()), Some(vec![]));
+        assert_eq!(
+            feed.since(first),
+            Some(b"*2\r\n$3\r\ndel\r\n$1\r\na\r\n".to_vec())
+        );
+        assert_eq!(feed.since(feed.offset() + 1), None);
+
+        // Once the backlog wrapped, old offsets are gone.
+        let big = "x".repeat(BACKLOG_SIZE);
+        feed.append(&[cmd(&["SET", "a", &big])]);
+        assert_eq!(feed.since(first), None);
+        assert!(feed.since(feed.offset() - 10).is_some());
+    }
+}
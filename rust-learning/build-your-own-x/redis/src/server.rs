@@ -0,0 +1,470 @@
+use crate::acl::{NOAUTH, User};
+use crate::cmd::transaction::Transaction;
+use crate::cmd::{self, acl, cluster, connection, list, persistence, pubsub, stream};
+use crate::shutdown::{SaveMode, Shutdown};
+use crate::tls::TlsListener;
+use crate::{Command, Connection, Db, Frame, Stream, rdb, replication};
+
+use std::future;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time;
+
+/// Source of the ids handed out to connections, as reported by `HELLO`.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How long a shutdown waits for connections to finish what they started,
+/// and then for replicas to receive the last writes.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting again after failing to, for instance
+/// with every file descriptor in use.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Per-connection handler. Reads requests from `connection` and applies the
+/// commands to `db`.
+#[derive(Debug)]
+struct Handler {
+    id: u64,
+    db: Db,
+    connection: Connection,
+
+    /// The name of the user the connection is logged in as, `None` until
+    /// it authenticates.
+    user: Option<String>,
+
+    /// `MULTI` / `WATCH` state.
+    transaction: Transaction,
+
+    /// Whether the previous command was `ASKING`, letting this one use a
+    /// slot being imported.
+    asking: bool,
+
+    /// The port a replica connecting through here listens on, as it said
+    /// with `REPLCONF listening-port`.
+    listening_port: Option<u16>,
+
+    /// Tells the handler the server is shutting down.
+    shutdown: Shutdown,
+
+    /// Dropped once the handler is done: `run` waits for every one of them
+    /// to go before the final save. A replica link lets go of it early, as
+    /// it keeps streaming until after that save.
+    shutdown_complete: Option<mpsc::Sender<()>>,
+}
+
+/// A connected client, counted towards `maxclients` until dropped.
+#[derive(Debug)]
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Accept connections on `listener`, serving each one from its own task
+/// against the shared `db`, until a shutdown is requested with
+/// `Db::shutdown`.
+///
+/// Then the listener is closed, and connections finish the requests they
+/// already read, reply and hang up. Once they are all gone, or
+/// `DRAIN_TIMEOUT` passed, the append-only file is synced and a snapshot
+/// written as the `SaveMode` says. Last, replicas get the stream up to that
+/// point.
+///
+/// Returns an error if the final save fails. Failing to accept a connection
+/// is logged, and accepting tried again a little later.
+pub async fn run(listener: TcpListener, db: Db) -> crate::Result<()> {
+    serve(listener, None, db).await
+}
+
+/// Like `run`, also accepting clients over TLS on `tls` when given. Both
+/// kinds count towards `maxclients`, and are served the same once the TLS
+/// handshake is done.
+pub async fn serve(listener: TcpListener, tls: Option<TlsListener>, db: Db) -> crate::Result<()> {
+    let clients = Arc::new(AtomicUsize::new(0));
+
+    // Dropping the sender is the shutdown signal: every receiver sees the
+    // channel close.
+    let (notify_shutdown, _) = broadcast::channel::<()>(1);
+
+    // Every connection task holds a sender; `recv` returns `None` once they
+    // are all gone.
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+    let save = loop {
+        // A plain socket is ready at once; a TLS one after its handshake,
+        // which happens in the connection's task.
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((socket, _)) => spawn_client(
+                    future::ready(Ok(socket)),
+                    &db,
+                    &clients,
+                    &notify_shutdown,
+                    &shutdown_complete_tx,
+                ),
+                Err(err) => accept_failed(err).await,
+            },
+            res = accept_tls(tls.as_ref()) => match res {
+                Ok(handshake) => spawn_client(
+                    handshake,
+                    &db,
+                    &clients,
+                    &notify_shutdown,
+                    &shutdown_complete_tx,
+                ),
+                Err(err) => accept_failed(err).await,
+            },
+            save = db.shutdown_requested() => break save,
+        }
+    };
+
+    drop(listener);
+    drop(tls);
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    if time::timeout(DRAIN_TIMEOUT, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        eprintln!("shutdown: some connections are still busy, saving anyway");
+    }
+
+    finish(&db, save).await
+}
+
+/// Make what was acknowledged durable, then let replicas catch up, before
+/// the server goes away.
+async fn finish(db: &Db, save: SaveMode) -> crate::Result<()> {
+    if let Some(aof) = db.aof() {
+        aof.sync().await?;
+    }
+
+    let snapshot = match save {
+        SaveMode::Default => db.aof().is_none(),
+        SaveMode::Save => true,
+        SaveMode::NoSave => false,
+    };
+    if snapshot {
+        let handle = db.clone();
+        let path = db.snapshot_path();
+        let res = tokio::task::spawn_blocking(move || rdb::save(&handle, &path)).await?;
+        db.finish_save(false, res.is_ok());
+        res.map_err(|err| format!("can't save the snapshot: {}", err))?;
+    }
+
+    if let Some(feed) = db.feed() {
+        // Between writes, so the end of the stream comes after all of them.
+        db.between_writes(|| feed.close());
+        let _ = time::timeout(DRAIN_TIMEOUT, feed.detached()).await;
+    }
+    Ok(())
+}
+
+/// Report a connection that could not be accepted, then give whatever
+/// caused it, like running out of file descriptors, time to pass.
+async fn accept_failed(err: io::Error) {
+    eprintln!("accept error: {}", err);
+    time::sleep(ACCEPT_BACKOFF).await;
+}
+
+/// Accept a client on the TLS port, if there is one; otherwise never
+/// returns.
+async fn accept_tls(
+    tls: Option<&TlsListener>,
+) -> io::Result<impl Future<Output = io::Result<impl Stream>> + Send + 'static> {
+    match tls {
+        Some(tls) => tls.accept().await,
+        None => future::pending().await,
+    }
+}
+
+/// Serve a client that just connected from its own task, once `handshake`
+/// gives its stream.
+fn spawn_client<S: Stream>(
+    handshake: impl Future<Output = io::Result<S>> + Send + 'static,
+    db: &Db,
+    clients: &Arc<AtomicUsize>,
+    notify_shutdown: &broadcast::Sender<()>,
+    shutdown_complete: &mpsc::Sender<()>,
+) {
+    // Count the client first, then check, so two arriving together can't
+    // both take the last place.
+    let connected = clients.fetch_add(1, Ordering::AcqRel) + 1;
+    let slot = Slot(clients.clone());
+    let refused = connected > db.config().maxclients;
+
+    // Clone the handle to the keyspace. Only the `Arc` is cloned, so every
+    // task sees the same map.
+    let db = db.clone();
+    let shutdown = Shutdown::new(notify_shutdown.subscribe());
+    let shutdown_complete = shutdown_complete.clone();
+
+    // A new task is spawned for each inbound socket. The socket is moved to
+    // the new task and processed there, so a slow client never holds up the
+    // accept loop or any other connection.
+    tokio::spawn(async move {
+        match handshake.await {
+            Ok(socket) if refused => refuse(socket).await,
+            Ok(socket) => process(socket, db, shutdown, shutdown_complete).await,
+            Err(err) => eprintln!("handshake error: {}", err),
+        }
+        drop(slot);
+    });
+}
+
+/// Tell a client over the `maxclients` limit, then hang up.
+async fn refuse(socket: impl Stream) {
+    let mut connection = Connection::new(socket);
+    let error = Frame::Error("ERR max number of clients reached".to_string());
+    if connection.write_frame(&error).await.is_ok() {
+        let _ = connection.flush().await;
+    }
+}
+
+async fn process(
+    socket: impl Stream,
+    db: Db,
+    shutdown: Shutdown,
+    shutdown_complete: mpsc::Sender<()>,
+) {
+    let user = db.acl().initial_user();
+    let mut handler = Handler {
+        id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+        user,
+        transaction: Transaction::new(db.clone()),
+        asking: false,
+        listening_port: None,
+        shutdown,
+        shutdown_complete: Some(shutdown_complete),
+        db,
+        // The 'Conncetion' lets us read/write redis **frames** instead of
+        // byte streams.
+        connection: Connection::new(socket),
+    };
+
+    if let Err(err) = handler.run().await {
+        eprintln!("connection error: {}", err);
+    }
+}
+
+impl Handler {
+    /// Process a single connection.
+    ///
+    /// Keeps reading frames until the client hangs up or the server shuts
+    /// down. `read_frame` returns `None` on a clean disconnect and an error
+    /// if the peer went away in the middle of a frame; either way this
+    /// connection is done.
+    ///
+    /// Clients may pipeline requests, sending many before reading any reply.
+    /// Every request that arrived with the same read is executed in order and
+    /// the replies are flushed together with a single write. A shutdown lets
+    /// that happen: it only stops the handler from reading more.
+    async fn run(&mut self) -> crate::Result<()> {
+        while !self.shutdown.is_shutdown() {
+            let timeout = self.db.config().timeout;
+            let frame = tokio::select! {
+                res = read_frame(&mut self.connection, timeout) => res,
+                _ = self.shutdown.recv() => return Ok(()),
+            };
+            let frame = match frame {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(err) => return self.hang_up(err).await,
+            };
+            let mut next = Some(frame);
+
+            while let Some(frame) = next {
+                if !self.handle(frame).await? {
+                    return Ok(());
+                }
+                next = match self.connection.buffered_frame() {
+                    Ok(next) => next,
+                    Err(err) => return self.hang_up(err).await,
+                };
+            }
+
+            self.connection.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Tell the client why its connection is being closed, after the
+    /// replies to what it sent before, if it is still there to hear it.
+    async fn hang_up(&mut self, err: crate::Error) -> crate::Result<()> {
+        let reply = Frame::Error(format!("ERR {}", err));
+        if self.connection.write_frame(&reply).await.is_ok() {
+            let _ = self.connection.flush().await;
+        }
+        Err(err)
+    }
+
+    /// Execute one request and queue its reply. Returns `false` if the client
+    /// went away in the process.
+    async fn handle(&mut self, frame: Frame) -> crate::Result<bool> {
+        let cmd = match Command::from_frame(frame) {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                let response = Frame::Error(format!("ERR {}", err));
+                self.connection.write_frame(&response).await?;
+                return Ok(true);
+            }
+        };
+
+        // In cluster mode, keys this node doesn't serve are redirected.
+        let asking = mem::take(&mut self.asking);
+        let user = match self
+            .authorize(&cmd)
+            .and_then(|user| crate::cluster::route(&self.db, &cmd, asking).map(|()| user))
+        {
+            Ok(user) => user,
+            Err(err) => {
+                if !matches!(cmd.name(), "multi" | "exec" | "discard") {
+                    self.transaction.refuse();
+                }
+                self.connection
+                    .write_frame(&Frame::Error(err.to_string()))
+                    .await?;
+                return Ok(true);
+            }
+        };
+
+        let response = match cmd.name() {
+            "multi" | "exec" | "discard" => self.transaction.handle(&cmd, user.as_ref()).await,
+            // Inside a transaction, everything else waits for `EXEC`.
+            _ if self.transaction.is_open() => self.transaction.queue(cmd),
+            "watch" | "unwatch" => self.transaction.handle(&cmd, user.as_ref()).await,
+            // (Un)subscribing takes over the connection until the client
+            // has left every channel.
+            "subscribe" | "unsubscribe" if let Some(user) = &user => {
+                let disconnected = pubsub::subscriber_mode(
+                    &self.db,
+                    &mut self.connection,
+                    cmd,
+                    user,
+                    &mut self.shutdown,
+                )
+                .await?;
+                return Ok(!disconnected);
+            }
+            "hello" => {
+                let mut parse = cmd.parse();
+                connection::hello(
+                    &self.db,
+                    &mut self.connection,
+                    self.id,
+                    &mut self.user,
+                    &mut parse,
+                )
+                .unwrap_or_else(|err| Frame::Error(err.to_string()))
+            }
+            "auth" => acl::auth(&self.db, &mut self.user, &mut cmd.parse())
+                .unwrap_or_else(|err| Frame::Error(err.to_string())),
+            "acl" => acl::acl(&self.db, self.user.as_deref(), &mut cmd.parse())
+                .unwrap_or_else(|err| Frame::Error(err.to_string())),
+            // Blocking pops may have to wait for another client.
+            "blpop" | "brpop" => match cmd::check_writable(&self.db, &cmd) {
+                Ok(()) => {
+                    list::blocking_pop(&self.db, &mut self.connection, &cmd, &mut self.shutdown)
+                        .await
+                }
+                Err(err) => Err(err),
+            }
+            .unwrap_or_else(|err| Frame::Error(err.to_string())),
+            // So may stream reads, for another client to add entries.
+            "xread" | "xreadgroup" if stream::blocks(&cmd) => {
+                match cmd::check_writable(&self.db, &cmd) {
+                    Ok(()) => {
+                        stream::blocking_read(
+                            &self.db,
+                            &mut self.connection,
+                            &cmd,
+                            &mut self.shutdown,
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                }
+                .unwrap_or_else(|err| Frame::Error(err.to_string()))
+            }
+            "asking" => match cluster::asking(&self.db, &mut cmd.parse()) {
+                Ok(reply) => {
+                    self.asking = true;
+                    reply
+                }
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            // Keys go to another server, which may take a while.
+            "migrate" => cluster::migrate(&self.db, &cmd)
+                .await
+                .unwrap_or_else(|err| Frame::Error(err.to_string())),
+            "replconf" => replication::replconf(&mut self.listening_port, &mut cmd.parse())
+                .unwrap_or_else(|err| Frame::Error(err.to_string())),
+            // A replica syncing: the connection now carries the replication
+            // stream, until the replica goes away.
+            "psync" => {
+                self.shutdown_complete.take();
+                let res = replication::serve(
+                    &self.db,
+                    &mut self.connection,
+                    &cmd,
+                    self.id,
+                    self.listening_port,
+                )
+                .await;
+                if let Err(err) = res {
+                    eprintln!("replication: replica {} dropped: {}", self.id, err);
+                }
+                return Ok(false);
+            }
+            // Hang up, with the replies to earlier requests; `run` takes it
+            // from there.
+            "shutdown" => match persistence::shutdown(&self.db, &mut cmd.parse()) {
+                Ok(()) => {
+                    self.connection.flush().await?;
+                    return Ok(false);
+                }
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            _ => cmd::execute(&cmd, &self.db, user.as_ref()).await,
+        };
+
+        self.connection.write_frame(&response).await?;
+        Ok(true)
+    }
+
+    /// The user to run `cmd` as, once checked it may. `AUTH` and `HELLO`
+    /// need none, as they are how a connection logs in.
+    fn authorize(&self, cmd: &Command) -> crate::Result<Option<Arc<User>>> {
+        if let "auth" | "hello" = cmd.name() {
+            return Ok(None);
+        }
+
+        let acl = self.db.acl();
+        let user = self.user.as_deref().and_then(|name| acl.user(name));
+        let user = user.ok_or(NOAUTH)?;
+        user.check(cmd)?;
+        Ok(Some(user))
+    }
+}
+
+/// Wait for the next request. A client idle for longer than `timeout`
+/// seconds, if not zero, is treated as gone.
+async fn read_frame(connection: &mut Connection, timeout: u64) -> crate::Result<Option<Frame>> {
+    if timeout == 0 {
+        return connection.read_frame().await;
+    }
+
+    match time::timeout(Duration::from_secs(timeout), connection.read_frame()).await {
+        Ok(res) => res,
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,76 @@
+//! Stopping the server without losing acknowledged writes.
+//!
+//! A shutdown is requested through the `Db`, by the `SHUTDOWN` command or by
+//! `main` on `SIGINT`/`SIGTERM`. `server::run` then stops accepting
+//! connections and broadcasts the news to every connection task. Each one
+//! finishes the requests it already read, flushes the replies and hangs up.
+//! Once they are all gone, the data is made durable one last time.
+
+use std::str::FromStr;
+use tokio::sync::broadcast;
+
+/// Listens for the server shutdown signal.
+///
+/// The signal is a single value sent on a `broadcast` channel; each
+/// connection task holds its own receiver. Once it was seen, `recv` returns
+/// right away.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    /// `true` once the signal was received.
+    is_shutdown: bool,
+
+    notify: broadcast::Receiver<()>,
+}
+
+impl Shutdown {
+    pub(crate) fn new(notify: broadcast::Receiver<()>) -> Shutdown {
+        Shutdown {
+            is_shutdown: false,
+            notify,
+        }
+    }
+
+    pub(crate) fn is_shutdown(&self) -> bool {
+        self.is_shutdown
+    }
+
+    /// Wait for the shutdown signal, if it was not received yet.
+    pub(crate) async fn recv(&mut self) {
+        if self.is_shutdown {
+            return;
+        }
+
+        // Only one value is ever sent, so the channel can't lag. A closed
+        // channel means the server is going away too.
+        let _ = self.notify.recv().await;
+        self.is_shutdown = true;
+    }
+}
+
+/// What to write on the way out, as chosen with `SHUTDOWN [NOSAVE|SAVE]`.
+///
+/// The append-only file is synced to disk in every case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaveMode {
+    /// A snapshot, unless the append-only file already has everything.
+    #[default]
+    Default,
+
+    /// A snapshot, always.
+    Save,
+
+    /// No snapshot.
+    NoSave,
+}
+
+impl FromStr for SaveMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SaveMode, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "save" => Ok(SaveMode::Save),
+            "nosave" => Ok(SaveMode::NoSave),
+            _ => Err(format!("invalid save mode '{}'", s)),
+        }
+    }
+}
@@ -0,0 +1,523 @@
+//! Streams.
+//!
+//! A stream is an append-only log. Each entry is a list of field/value pairs
+//! under an ID made of a Unix time in milliseconds and a sequence number,
+//! `1700000000000-0`. IDs only ever grow, so the log is kept in a `BTreeMap`
+//! ordered by ID and every range read is a walk over part of it.
+//!
+//! Consumer groups share the work of reading a stream. A group remembers the
+//! last entry it delivered, and every entry it delivered but that was not
+//! acknowledged yet, along with the consumer it went to: the pending entries
+//! list, or PEL. Entries a consumer never acknowledged, say because it
+//! crashed, can be claimed by another one.
+//!
+//! Times in the PEL are Unix times in milliseconds rather than `Instant`s,
+//! so they survive a restart.
+
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// The field/value pairs of an entry.
+pub type Fields = Vec<(Bytes, Bytes)>;
+
+/// The ID of a stream entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+/// An append-only log of entries, and the consumer groups reading it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+
+    /// The greatest ID ever added. Trimming may remove the entry, but never
+    /// lets a smaller ID in after it.
+    last_id: StreamId,
+
+    groups: BTreeMap<Bytes, Group>,
+}
+
+/// A consumer group.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Group {
+    /// Entries after this one are new to the group.
+    pub last_delivered: StreamId,
+
+    /// Entries delivered but not acknowledged yet.
+    pub pending: BTreeMap<StreamId, Pending>,
+
+    /// The consumers known to the group, with when each was last seen.
+    pub consumers: BTreeMap<Bytes, u64>,
+}
+
+/// An entry of a group's PEL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    /// The consumer the entry was delivered to last.
+    pub consumer: Bytes,
+
+    /// When that was.
+    pub delivered_at: u64,
+
+    /// How many times the entry was delivered.
+    pub deliveries: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+
+    /// The greatest ID smaller than this one.
+    pub fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_sub(1)?, u64::MAX)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// Parses `ms-seq`, or `ms` alone, which means `ms-0`.
+impl FromStr for StreamId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<StreamId, ()> {
+        let (ms, seq) = s.split_once('-').unwrap_or((s, "0"));
+        let number = |s: &str| match s.bytes().all(|b| b.is_ascii_digit()) {
+            true => s.parse().map_err(|_| ()),
+            false => Err(()),
+        };
+        Ok(StreamId::new(number(ms)?, number(seq)?))
+    }
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The greatest ID ever added.
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Make `id` the greatest ID ever added. It can't be below the last
+    /// entry's.
+    pub fn set_last_id(&mut self, id: StreamId) -> Result<(), &'static str> {
+        match self.entries.last_key_value() {
+            Some((last, _)) if *last > id => {
+                Err("ERR The ID specified in XSETID is smaller than the target stream top item")
+            }
+            _ => {
+                self.last_id = id;
+                Ok(())
+            }
+        }
+    }
+
+    /// The ID `XADD` gives an entry with no explicit ID at Unix time `now`,
+    /// in milliseconds: the next sequence number within the same
+    /// millisecond, or of the last ID if the clock went back.
+    pub fn auto_id(&self, now: u64) -> Option<StreamId> {
+        match now > self.last_id.ms {
+            true => Some(StreamId::new(now, 0)),
+            false => self.last_id.next(),
+        }
+    }
+
+    /// The ID `XADD` gives an entry added as `ms-*`.
+    pub fn auto_seq(&self, ms: u64) -> Option<StreamId> {
+        match ms.cmp(&self.last_id.ms) {
+            std::cmp::Ordering::Greater => Some(StreamId::new(ms, 0)),
+            std::cmp::Ordering::Equal => self.last_id.next(),
+            std::cmp::Ordering::Less => None,
+        }
+    }
+
+    /// Append an entry. `id` must be greater than every ID added before.
+    pub fn add(&mut self, id: StreamId, fields: Fields) -> Result<(), &'static str> {
+        if id == StreamId::MIN {
+            return Err("ERR The ID specified in XADD must be greater than 0-0");
+        }
+        if id <= self.last_id {
+            return Err(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item",
+            );
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(())
+    }
+
+    /// Remove the oldest entries until at most `maxlen` are left. Returns how
+    /// many were removed.
+    pub fn trim(&mut self, maxlen: usize) -> usize {
+        let mut removed = 0;
+        while self.entries.len() > maxlen {
+            self.entries.pop_first();
+            removed += 1;
+        }
+        removed
+    }
+
+    /// The fields of the entry `id`, if it is still in the stream.
+    pub fn get(&self, id: &StreamId) -> Option<&Fields> {
+        self.entries.get(id)
+    }
+
+    /// The entries with an ID from `start` to `end`, both included, oldest
+    /// first.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        // `BTreeMap::range` panics on a reversed range.
+        let range = (start <= end).then(|| self.entries.range(start..=end));
+        range.into_iter().flatten()
+    }
+
+    /// Every entry, oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = (&StreamId, &Fields)> {
+        self.entries.iter()
+    }
+
+    pub fn groups(&self) -> &BTreeMap<Bytes, Group> {
+        &self.groups
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&Group> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<&mut Group> {
+        self.groups.get_mut(name)
+    }
+
+    pub fn groups_mut(&mut self) -> &mut BTreeMap<Bytes, Group> {
+        &mut self.groups
+    }
+
+    /// Add a group that has seen everything up to `last_delivered`. Returns
+    /// `false` if there is one with that name already.
+    pub fn create_group(&mut self, name: Bytes, last_delivered: StreamId) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        let group = Group {
+            last_delivered,
+            ..Group::default()
+        };
+        self.groups.insert(name, group);
+        true
+    }
+
+    /// Remove a group. Returns `false` if there was none with that name.
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Deliver to `consumer` of `group` up to `count` entries the group has
+    /// not seen yet, at Unix time `now`. They are added to the PEL unless
+    /// `noack` is set.
+    ///
+    /// Returns `None` if there is no such group.
+    pub fn deliver_new(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        count: usize,
+        now: u64,
+        noack: bool,
+    ) -> Option<Vec<(StreamId, Fields)>> {
+        let group = self.groups.get_mut(group)?;
+        group.seen(consumer, now);
+
+        let Some(start) = group.last_delivered.next() else {
+            return Some(vec![]);
+        };
+        let delivered: Vec<(StreamId, Fields)> = self
+            .entries
+            .range(start..)
+            .take(count)
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect();
+
+        for (id, _) in &delivered {
+            group.last_delivered = *id;
+            if !noack {
+                group.pending.insert(
+                    *id,
+                    Pending {
+                        consumer: consumer.clone(),
+                        delivered_at: now,
+                        deliveries: 1,
+                    },
+                );
+            }
+        }
+        Some(delivered)
+    }
+
+    /// Deliver again to `consumer` of `group` up to `count` of its pending
+    /// entries with an ID greater than `after`, at Unix time `now`. Entries
+    /// trimmed from the stream since come without fields.
+    ///
+    /// Returns `None` if there is no such group.
+    pub fn deliver_pending(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        after: StreamId,
+        count: usize,
+        now: u64,
+    ) -> Option<Vec<(StreamId, Option<Fields>)>> {
+        let group = self.groups.get_mut(group)?;
+        group.seen(consumer, now);
+
+        let Some(start) = after.next() else {
+            return Some(vec![]);
+        };
+        let mut delivered = Vec::new();
+        for (id, pending) in group.pending.range_mut(start..) {
+            if delivered.len() == count {
+                break;
+            }
+            if pending.consumer != consumer {
+                continue;
+            }
+            pending.delivered_at = now;
+            pending.deliveries += 1;
+            delivered.push((*id, self.entries.get(id).cloned()));
+        }
+        Some(delivered)
+    }
+
+    /// Hand to `consumer` of `group` those of the pending entries `ids`
+    /// that were idle for `claim.min_idle` milliseconds or more at Unix time
+    /// `now`. Returns the IDs claimed.
+    ///
+    /// Pending entries trimmed from the stream since are dropped from the
+    /// PEL instead. Returns `None` if there is no such group.
+    pub fn claim(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        ids: &[StreamId],
+        claim: &Claim,
+        now: u64,
+    ) -> Option<Vec<StreamId>> {
+        let group = self.groups.get_mut(group)?;
+        group.seen(consumer, now);
+
+        let mut claimed = Vec::new();
+        for id in ids {
+            if !self.entries.contains_key(id) {
+                group.pending.remove(id);
+                continue;
+            }
+
+            let pending = match group.pending.get_mut(id) {
+                Some(pending) if now.saturating_sub(pending.delivered_at) >= claim.min_idle => {
+                    pending
+                }
+                Some(_) => continue,
+                None if claim.force => group.pending.entry(*id).or_insert(Pending {
+                    consumer: consumer.clone(),
+                    delivered_at: now,
+                    deliveries: 0,
+                }),
+                None => continue,
+            };
+
+            pending.consumer = consumer.clone();
+            pending.delivered_at = claim.delivered_at.unwrap_or(now);
+            match claim.deliveries {
+                Some(deliveries) => pending.deliveries = deliveries,
+                None if !claim.justid => pending.deliveries += 1,
+                None => {}
+            }
+            claimed.push(*id);
+        }
+        Some(claimed)
+    }
+}
+
+/// How `Stream::claim` goes about it.
+#[derive(Debug, Clone, Default)]
+pub struct Claim {
+    /// Only claim entries idle for at least this many milliseconds.
+    pub min_idle: u64,
+
+    /// Record the entries as delivered at this Unix time, rather than now.
+    pub delivered_at: Option<u64>,
+
+    /// Set the delivery count to this, rather than count one more delivery.
+    pub deliveries: Option<u64>,
+
+    /// Claim entries that are not pending too, if they are in the stream.
+    pub force: bool,
+
+    /// Leave the delivery count alone.
+    pub justid: bool,
+}
+
+impl Group {
+    /// Record that `consumer` was seen at `now`, adding it if needed.
+    pub fn seen(&mut self, consumer: &Bytes, now: u64) {
+        self.consumers.insert(consumer.clone(), now);
+    }
+
+    /// How many entries are pending for each consumer with any.
+    pub fn pending_counts(&self) -> BTreeMap<&Bytes, usize> {
+        let mut counts = BTreeMap::new();
+        for pending in self.pending.values() {
+            *counts.entry(&pending.consumer).or_default() += 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(field: &str, value: &str) -> Fields {
+        vec![(
+            Bytes::copy_from_slice(field.as_bytes()),
+            Bytes::copy_from_slice(value.as_bytes()),
+        )]
+    }
+
+    #[test]
+    fn ids_parse_and_order() {
+        assert_eq!("5-3".parse(), Ok(StreamId::new(5, 3)));
+        assert_eq!("5".parse(), Ok(StreamId::new(5, 0)));
+        assert!("5-".parse::<StreamId>().is_err());
+        assert!("-1".parse::<StreamId>().is_err());
+        assert!("x".parse::<StreamId>().is_err());
+        assert!("18446744073709551616".parse::<StreamId>().is_err());
+
+        assert_eq!(StreamId::new(1, u64::MAX).next(), Some(StreamId::new(2, 0)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::new(2, 0).prev(), Some(StreamId::new(1, u64::MAX)));
+        assert_eq!(StreamId::MIN.prev(), None);
+        assert_eq!(StreamId::new(7, 1).to_string(), "7-1");
+    }
+
+    #[test]
+    fn ids_only_grow() {
+        let mut stream = Stream::new();
+        assert!(stream.add(StreamId::MIN, fields("f", "v")).is_err());
+
+        let first = stream.auto_id(1000).unwrap();
+        assert_eq!(first, StreamId::new(1000, 0));
+        stream.add(first, fields("f", "v")).unwrap();
+
+        // The clock went back: the sequence number goes on.
+        assert_eq!(stream.auto_id(900), Some(StreamId::new(1000, 1)));
+        assert_eq!(stream.auto_seq(1000), Some(StreamId::new(1000, 1)));
+        assert_eq!(stream.auto_seq(999), None);
+        assert!(stream.add(first, fields("f", "v")).is_err());
+
+        stream
+            .add(StreamId::new(2000, 5), fields("f", "v"))
+            .unwrap();
+        assert_eq!(stream.trim(1), 1);
+        assert_eq!(stream.len(), 1);
+        assert!(
+            stream
+                .add(StreamId::new(1500, 0), fields("f", "v"))
+                .is_err()
+        );
+        assert!(stream.set_last_id(StreamId::new(1999, 0)).is_err());
+    }
+
+    #[test]
+    fn ranges_are_inclusive() {
+        let mut stream = Stream::new();
+        for ms in 1..=5 {
+            stream.add(StreamId::new(ms, 0), fields("n", "x")).unwrap();
+        }
+
+        let ids =
+            |start, end| -> Vec<u64> { stream.range(start, end).map(|(id, _)| id.ms).collect() };
+        assert_eq!(ids(StreamId::new(2, 0), StreamId::new(4, 0)), [2, 3, 4]);
+        assert_eq!(ids(StreamId::MIN, StreamId::MAX), [1, 2, 3, 4, 5]);
+        assert_eq!(
+            ids(StreamId::new(4, 0), StreamId::new(2, 0)),
+            [] as [u64; 0]
+        );
+    }
+
+    #[test]
+    fn groups_track_pending_entries() {
+        let mut stream = Stream::new();
+        for ms in 1..=3 {
+            stream.add(StreamId::new(ms, 0), fields("n", "x")).unwrap();
+        }
+        assert!(stream.create_group("g".into(), StreamId::MIN));
+        assert!(!stream.create_group("g".into(), StreamId::MIN));
+
+        let ann = Bytes::from("ann");
+        let bob = Bytes::from("bob");
+        let first = stream.deliver_new(b"g", &ann, 2, 100, false).unwrap();
+        assert_eq!(first.len(), 2);
+        let rest = stream.deliver_new(b"g", &bob, 10, 100, false).unwrap();
+        assert_eq!(rest[0].0, StreamId::new(3, 0));
+        assert!(
+            stream
+                .deliver_new(b"g", &bob, 10, 100, false)
+                .unwrap()
+                .is_empty()
+        );
+
+        // History only holds the consumer's own entries.
+        let again = stream
+            .deliver_pending(b"g", &ann, StreamId::MIN, 10, 200)
+            .unwrap();
+        assert_eq!(again.len(), 2);
+
+        let group = stream.group(b"g").unwrap();
+        assert_eq!(group.pending[&StreamId::new(1, 0)].deliveries, 2);
+        assert_eq!(group.pending[&StreamId::new(1, 0)].delivered_at, 200);
+        assert_eq!(group.pending_counts()[&ann], 2);
+        assert_eq!(group.pending_counts()[&bob], 1);
+
+        assert!(stream.deliver_new(b"nope", &ann, 1, 0, false).is_none());
+        assert!(stream.destroy_group(b"g"));
+        assert!(stream.groups().is_empty());
+    }
+}
@@ -0,0 +1,89 @@
+//! TLS for clients connecting to `tls-port`.
+//!
+//! The server presents the certificate chain in `tls-cert-file`, signed with
+//! the private key in `tls-key-file`, both PEM. Clients are not asked for a
+//! certificate of their own; they log in with `AUTH` as on the plain port.
+//!
+//! Once the handshake is done, a connection is served exactly like a plain
+//! one: `Connection` runs over any `Stream`.
+
+use crate::Stream;
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::{TlsAcceptor, client, server};
+
+/// How long a client has to complete the handshake before it is dropped, so
+/// one that connects and goes quiet doesn't hold a task forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A TCP listener whose connections speak TLS.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsListener {
+    /// Serve TLS on `listener` with the certificate chain at `cert` and the
+    /// private key at `key`.
+    ///
+    /// Returns an error if either file can't be read or parsed, or the key
+    /// doesn't go with the certificate.
+    pub fn new(listener: TcpListener, cert: &Path, key: &Path) -> crate::Result<TlsListener> {
+        let chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| format!("can't load '{}': {}", cert.display(), err))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|err| format!("can't load '{}': {}", key.display(), err))?;
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|err| format!("invalid TLS certificate or key: {}", err))?;
+        Ok(TlsListener {
+            listener,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// The address connections are accepted on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept a connection. The handshake is left to the caller, as the
+    /// returned future, so a slow client doesn't hold up the next one.
+    pub(crate) async fn accept(
+        &self,
+    ) -> io::Result<impl Future<Output = io::Result<server::TlsStream<TcpStream>>> + Send + 'static>
+    {
+        let (socket, _) = self.listener.accept().await?;
+        let handshake = self.acceptor.accept(socket);
+        Ok(async move {
+            time::timeout(HANDSHAKE_TIMEOUT, handshake)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+        })
+    }
+}
+
+impl Stream for server::TlsStream<TcpStream> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+}
+
+/// Lets a client, such as a test, talk to a server over TLS.
+impl Stream for client::TlsStream<TcpStream> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+}
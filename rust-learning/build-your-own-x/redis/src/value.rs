@@ -0,0 +1,103 @@
+use crate::stream::Stream;
+use crate::zset::ZSet;
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// How many elements of a collection `Value::footprint` looks at.
+const SAMPLES: usize = 5;
+
+/// A value stored in the keyspace.
+///
+/// Each key holds exactly one type of value. Commands check the type and
+/// reply with a `WRONGTYPE` error when a key holds something else.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    ZSet(ZSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// The type name reported by `TYPE`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// Approximate number of bytes the value takes in memory, bookkeeping
+    /// included.
+    ///
+    /// Like `MEMORY USAGE` in Redis, collections are not walked in full:
+    /// the size of a few elements is extrapolated to the others, so this
+    /// stays cheap enough to run after every write.
+    pub fn footprint(&self) -> usize {
+        // Per-element costs cover the `Bytes` handles and the collection's
+        // own slots; skip lists and hash tables need more than a ring
+        // buffer.
+        match self {
+            Value::String(s) => 32 + s.len(),
+            Value::List(list) => 48 + sampled(list.len(), list.iter().map(|e| 32 + e.len())),
+            Value::Hash(hash) => {
+                64 + sampled(hash.len(), hash.iter().map(|(f, v)| 72 + f.len() + v.len()))
+            }
+            Value::Set(set) => 64 + sampled(set.len(), set.iter().map(|m| 40 + m.len())),
+            Value::ZSet(zset) => {
+                let members = zset.range_by_rank(0, SAMPLES, false);
+                96 + sampled(zset.len(), members.map(|(m, _)| 120 + m.len()))
+            }
+            Value::Stream(stream) => {
+                let entries = stream.entries().map(|(_, fields)| {
+                    let pairs = fields.iter().map(|(f, v)| 32 + f.len() + v.len());
+                    64 + pairs.sum::<usize>()
+                });
+                let pending = stream.groups().values().map(|g| g.pending.len());
+                128 + sampled(stream.len(), entries) + 64 * pending.sum::<usize>()
+            }
+        }
+    }
+}
+
+/// Estimate the total of `len` sizes from the first few in `sizes`.
+fn sampled(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let (count, total) = sizes
+        .take(SAMPLES)
+        .fold((0, 0), |(count, total), size| (count + 1, total + size));
+    if count == 0 {
+        return 0;
+    }
+    total * len / count
+}
+
+impl From<Bytes> for Value {
+    fn from(bytes: Bytes) -> Value {
+        Value::String(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn footprint_grows_with_content() {
+        let short = Value::String(Bytes::from("x"));
+        let long = Value::String(Bytes::from(vec![b'x'; 1000]));
+        assert!(long.footprint() > short.footprint() + 900);
+
+        let list = |n: usize| Value::List((0..n).map(|_| Bytes::from("value")).collect());
+        assert_eq!(
+            list(200).footprint() - list(100).footprint(),
+            list(100).footprint() - list(0).footprint()
+        );
+    }
+}
@@ -0,0 +1,591 @@
+//! Sorted sets.
+//!
+//! A sorted set keeps members ordered by score, ties broken by comparing the
+//! members themselves. Like in Redis it pairs two structures:
+//!
+//! * a hash map from member to score, for `O(1)` score lookups, and
+//! * a skiplist holding `(score, member)` in order.
+//!
+//! Every skiplist link records its *span*: how many elements it jumps over.
+//! Summing spans along a search path gives the rank of the node it ends on,
+//! so finding the rank of a member, the node at a given rank or the bounds of
+//! a score range all take `O(log n)`, and a range is then walked along the
+//! bottom level.
+//!
+//! Nodes live in an arena (`Vec<Node>`) and link to each other by index,
+//! which keeps the borrow checker happy without `unsafe` or `Rc<RefCell<_>>`.
+
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A node has at most this many levels, which is plenty for 2^64 elements
+/// with `P = 1/4`.
+const MAX_LEVEL: usize = 32;
+
+/// Index of the header node, which holds no element.
+const HEAD: usize = 0;
+
+/// A set of unique members ordered by score.
+#[derive(Clone, Default)]
+pub struct ZSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList,
+}
+
+/// One end of a score range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+impl ZSet {
+    pub fn new() -> ZSet {
+        ZSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// The score of `member`, if it is in the set.
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Add `member` with `score`, or move it to `score` if it is already in
+    /// the set. Returns `true` if the member is new.
+    ///
+    /// # Panics
+    ///
+    /// Scores must not be NaN; callers validate them.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        assert!(!score.is_nan(), "sorted set scores must not be NaN");
+        // Redis does not tell -0 from 0 either.
+        let score = if score == 0.0 { 0.0 } else { score };
+
+        match self.scores.insert(member.clone(), score) {
+            Some(old) if old == score => {}
+            Some(old) => {
+                self.list.remove(old, &member);
+                self.list.insert(score, member);
+            }
+            None => {
+                self.list.insert(score, member);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Remove `member`, returning whether it was in the set.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The zero-based position of `member` in score order.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        // Everything up to and including the member itself.
+        Some(
+            self.list
+                .count_while(|s, m| cmp(s, m, score, member).is_le())
+                - 1,
+        )
+    }
+
+    /// Iterate over the members ranked `start..end`, in ascending order, or
+    /// descending from `end - 1` if `rev` is set.
+    pub fn range_by_rank(&self, start: usize, end: usize, rev: bool) -> Iter<'_> {
+        let end = end.min(self.len());
+        if start >= end {
+            return Iter::empty(&self.list);
+        }
+
+        let (first, remaining) = if rev {
+            (self.list.node_at(end - 1), end - start)
+        } else {
+            (self.list.node_at(start), end - start)
+        };
+        Iter {
+            list: &self.list,
+            next: first,
+            remaining,
+            rev,
+        }
+    }
+
+    /// The ranks `start..end` of the members whose scores lie between `min`
+    /// and `max`.
+    pub fn score_range(&self, min: ScoreBound, max: ScoreBound) -> (usize, usize) {
+        let start = self.list.count_while(|score, _| !min.allows_above(score));
+        let end = self.list.count_while(|score, _| max.allows_below(score));
+        (start, end.max(start))
+    }
+}
+
+impl ScoreBound {
+    /// Whether `score` is on the allowed side of this bound used as a
+    /// minimum.
+    fn allows_above(self, score: f64) -> bool {
+        match self {
+            ScoreBound::Inclusive(min) => score >= min,
+            ScoreBound::Exclusive(min) => score > min,
+        }
+    }
+
+    /// Whether `score` is on the allowed side of this bound used as a
+    /// maximum.
+    fn allows_below(self, score: f64) -> bool {
+        match self {
+            ScoreBound::Inclusive(max) => score <= max,
+            ScoreBound::Exclusive(max) => score < max,
+        }
+    }
+}
+
+impl PartialEq for ZSet {
+    fn eq(&self, other: &ZSet) -> bool {
+        self.len() == other.len()
+            && self
+                .range_by_rank(0, self.len(), false)
+                .eq(other.range_by_rank(0, other.len(), false))
+    }
+}
+
+impl fmt::Debug for ZSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.range_by_rank(0, self.len(), false))
+            .finish()
+    }
+}
+
+/// Members and scores of a sorted set, in rank order.
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    next: Option<usize>,
+    remaining: usize,
+    rev: bool,
+}
+
+impl<'a> Iter<'a> {
+    fn empty(list: &'a SkipList) -> Iter<'a> {
+        Iter {
+            list,
+            next: None,
+            remaining: 0,
+            rev: false,
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Bytes, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.list.nodes[self.next?];
+
+        self.remaining -= 1;
+        self.next = if self.rev {
+            node.backward
+        } else {
+            node.levels[0].next
+        };
+        Some((&node.member, node.score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+/// Order by score, then by member.
+fn cmp(score: f64, member: &[u8], other_score: f64, other_member: &[u8]) -> Ordering {
+    score
+        .partial_cmp(&other_score)
+        .expect("scores are never NaN")
+        .then_with(|| member.cmp(other_member))
+}
+
+#[derive(Clone)]
+struct SkipList {
+    /// The arena. `nodes[HEAD]` is the header; freed slots are listed in
+    /// `free` and reused.
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+
+    /// The last node, for walking backwards.
+    tail: Option<usize>,
+
+    /// Number of levels in use, at least 1.
+    level: usize,
+
+    len: usize,
+
+    /// State of the generator picking node levels.
+    rng: u64,
+}
+
+#[derive(Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Level {
+    next: Option<usize>,
+
+    /// Number of bottom-level steps `next` is away; the distance to the end
+    /// of the list when there is no `next`.
+    span: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> SkipList {
+        let head = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            backward: None,
+            levels: vec![Level::default(); MAX_LEVEL],
+        };
+        SkipList {
+            nodes: vec![head],
+            free: Vec::new(),
+            tail: None,
+            level: 1,
+            len: 0,
+            rng: 0x2545_F491_4F6C_DD1D,
+        }
+    }
+}
+
+impl SkipList {
+    /// Pick a level for a new node: each extra level has a 1 in 4 chance.
+    fn random_level(&mut self) -> usize {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+
+        // Two bits per level.
+        let level = 1 + (self.rng.trailing_zeros() / 2) as usize;
+        level.min(MAX_LEVEL)
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Insert an element that is not in the list yet.
+    fn insert(&mut self, score: f64, member: Bytes) {
+        // For each level, the last node before the new element and its rank.
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].levels[i].next {
+                let node = &self.nodes[next];
+                if cmp(node.score, &node.member, score, &member).is_ge() {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let new = self.alloc(Node {
+            member,
+            score,
+            backward: (update[0] != HEAD).then_some(update[0]),
+            levels: vec![Level::default(); level],
+        });
+
+        for i in 0..level {
+            let prev = self.nodes[update[i]].levels[i];
+            // Steps from `update[i]` to the new node.
+            let before = rank[0] - rank[i] + 1;
+
+            self.nodes[new].levels[i] = Level {
+                next: prev.next,
+                span: prev.span + 1 - before,
+            };
+            self.nodes[update[i]].levels[i] = Level {
+                next: Some(new),
+                span: before,
+            };
+        }
+
+        // Higher links now jump over one more element.
+        for (i, &x) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[x].levels[i].span += 1;
+        }
+
+        match self.nodes[new].levels[0].next {
+            Some(next) => self.nodes[next].backward = Some(new),
+            None => self.tail = Some(new),
+        }
+        self.len += 1;
+    }
+
+    /// Remove an element that is in the list.
+    fn remove(&mut self, score: f64, member: &[u8]) {
+        let mut update = [HEAD; MAX_LEVEL];
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].next {
+                let node = &self.nodes[next];
+                if cmp(node.score, &node.member, score, member).is_ge() {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let target = self.nodes[x].levels[0]
+            .next
+            .expect("removed element is in the list");
+        debug_assert_eq!(&self.nodes[target].member[..], member);
+
+        for (i, &x) in update.iter().enumerate().take(self.level) {
+            let prev = self.nodes[x].levels[i];
+            if prev.next == Some(target) {
+                let removed = self.nodes[target].levels[i];
+                self.nodes[x].levels[i] = Level {
+                    next: removed.next,
+                    span: prev.span + removed.span - 1,
+                };
+            } else {
+                self.nodes[x].levels[i].span -= 1;
+            }
+        }
+
+        let backward = self.nodes[target].backward;
+        match self.nodes[target].levels[0].next {
+            Some(next) => self.nodes[next].backward = backward,
+            None => self.tail = backward,
+        }
+
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+
+        // Drop the member now rather than when the slot is reused.
+        self.nodes[target].member = Bytes::new();
+        self.nodes[target].levels = Vec::new();
+        self.free.push(target);
+        self.len -= 1;
+    }
+
+    /// Count the elements, from the start, for which `pred` holds. `pred`
+    /// must hold for a prefix of the list and not after.
+    fn count_while(&self, pred: impl Fn(f64, &[u8]) -> bool) -> usize {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].next {
+                let node = &self.nodes[next];
+                if !pred(node.score, &node.member) {
+                    break;
+                }
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+        }
+        rank
+    }
+
+    /// The node at zero-based `rank`.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].next {
+                let span = self.nodes[x].levels[i].span;
+                if traversed + span > target {
+                    break;
+                }
+                traversed += span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    fn members(set: &ZSet, start: usize, end: usize, rev: bool) -> Vec<(String, f64)> {
+        set.range_by_rank(start, end, rev)
+            .map(|(m, s)| (String::from_utf8_lossy(m).into_owned(), s))
+            .collect()
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let mut set = ZSet::new();
+        assert!(set.insert("c".into(), 2.0));
+        assert!(set.insert("b".into(), 1.0));
+        assert!(set.insert("a".into(), 2.0));
+        assert!(set.insert("z".into(), f64::NEG_INFINITY));
+
+        assert_eq!(
+            members(&set, 0, 10, false),
+            [
+                ("z".into(), f64::NEG_INFINITY),
+                ("b".into(), 1.0),
+                ("a".into(), 2.0),
+                ("c".into(), 2.0)
+            ]
+        );
+        assert_eq!(set.rank(b"a"), Some(2));
+        assert_eq!(set.rank(b"nope"), None);
+
+        // Updating a score moves the member.
+        assert!(!set.insert("z".into(), 5.0));
+        assert_eq!(set.rank(b"z"), Some(3));
+        assert_eq!(set.len(), 4);
+
+        assert_eq!(
+            members(&set, 1, 3, true),
+            [("c".into(), 2.0), ("a".into(), 2.0)]
+        );
+    }
+
+    #[test]
+    fn score_ranges() {
+        let mut set = ZSet::new();
+        for i in 0..10 {
+            set.insert(i.to_string().into(), i as f64);
+        }
+
+        let range = |min, max| set.score_range(min, max);
+        use ScoreBound::*;
+        assert_eq!(range(Inclusive(2.0), Inclusive(5.0)), (2, 6));
+        assert_eq!(range(Exclusive(2.0), Exclusive(5.0)), (3, 5));
+        assert_eq!(
+            range(Inclusive(f64::NEG_INFINITY), Inclusive(f64::INFINITY)),
+            (0, 10)
+        );
+        assert_eq!(range(Inclusive(7.5), Inclusive(7.6)), (8, 8));
+        assert_eq!(range(Inclusive(5.0), Inclusive(2.0)), (5, 5));
+    }
+
+    /// Small deterministic PRNG (xorshift64*), as in the frame tests.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) % n
+        }
+    }
+
+    /// Random inserts, updates and removals checked against a `BTreeMap`.
+    #[test]
+    fn matches_a_simple_model() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let mut set = ZSet::new();
+        let mut model: HashMap<String, i64> = HashMap::new();
+
+        for _ in 0..5000 {
+            let member = format!("m{}", rng.below(300));
+            let score = rng.below(50) as i64 - 25;
+            if rng.below(3) == 0 {
+                assert_eq!(
+                    set.remove(member.as_bytes()),
+                    model.remove(&member).is_some()
+                );
+            } else {
+                assert_eq!(
+                    set.insert(member.clone().into(), score as f64),
+                    model.insert(member, score).is_none()
+                );
+            }
+        }
+
+        let sorted: BTreeMap<(i64, String), ()> =
+            model.iter().map(|(m, s)| ((*s, m.clone()), ())).collect();
+        let expected: Vec<(String, f64)> =
+            sorted.keys().map(|(s, m)| (m.clone(), *s as f64)).collect();
+
+        assert_eq!(set.len(), expected.len());
+        assert_eq!(members(&set, 0, usize::MAX, false), expected);
+        let mut reversed = expected.clone();
+        reversed.reverse();
+        assert_eq!(members(&set, 0, usize::MAX, true), reversed);
+
+        for (rank, (member, _)) in expected.iter().enumerate() {
+            assert_eq!(set.rank(member.as_bytes()), Some(rank));
+            assert_eq!(
+                members(&set, rank, rank + 1, false),
+                [expected[rank].clone()]
+            );
+        }
+
+        for min in -26..26 {
+            let (start, end) = set.score_range(
+                ScoreBound::Inclusive(min as f64),
+                ScoreBound::Exclusive(min as f64 + 3.0),
+            );
+            let want: Vec<_> = expected
+                .iter()
+                .filter(|(_, s)| *s >= min as f64 && *s < min as f64 + 3.0)
+                .cloned()
+                .collect();
+            assert_eq!(members(&set, start, end, false), want);
+        }
+    }
+}
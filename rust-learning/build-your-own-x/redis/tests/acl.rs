@@ -0,0 +1,188 @@
+//! Logging in with `AUTH` and `HELLO`, and what `ACL` users may run.
+
+mod common;
+
+use common::{call, connect, start, strings};
+use redis::{Db, Frame};
+
+use bytes::Bytes;
+
+fn error(frame: Frame) -> String {
+    match frame {
+        Frame::Error(err) => err,
+        frame => panic!("not an error: {:?}", frame),
+    }
+}
+
+#[tokio::test]
+async fn requirepass_makes_connections_log_in() {
+    let addr = start(Db::new(4)).await;
+    let mut admin = connect(addr).await;
+
+    // Without a password, everyone is `default` already.
+    assert_eq!(
+        call(&mut admin, &["ACL", "WHOAMI"]).await,
+        Frame::Bulk("default".into())
+    );
+    assert!(error(call(&mut admin, &["AUTH", "secret"]).await).starts_with("ERR AUTH"));
+    assert_eq!(
+        call(&mut admin, &["CONFIG", "SET", "requirepass", "secret"]).await,
+        Frame::ok()
+    );
+
+    let mut conn = connect(addr).await;
+    assert!(error(call(&mut conn, &["PING"]).await).starts_with("NOAUTH"));
+    assert!(error(call(&mut conn, &["HELLO", "3"]).await).starts_with("NOAUTH"));
+    assert!(error(call(&mut conn, &["AUTH", "wrong"]).await).starts_with("WRONGPASS"));
+    assert_eq!(call(&mut conn, &["AUTH", "secret"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut conn, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+
+    let mut conn = connect(addr).await;
+    let Frame::Map(_) = call(&mut conn, &["HELLO", "3", "AUTH", "default", "secret"]).await else {
+        panic!("HELLO replies with a map");
+    };
+    assert_eq!(
+        call(&mut conn, &["ACL", "WHOAMI"]).await,
+        Frame::Bulk("default".into())
+    );
+}
+
+#[tokio::test]
+async fn users_only_run_their_commands_on_their_keys() {
+    let addr = start(Db::new(4)).await;
+    let mut admin = connect(addr).await;
+    assert_eq!(
+        call(
+            &mut admin,
+            &[
+                "ACL", "SETUSER", "alice", "on", ">pw", "~cache:*", "+@read", "+set", "+multi",
+                "+exec"
+            ]
+        )
+        .await,
+        Frame::ok()
+    );
+
+    let mut conn = connect(addr).await;
+    assert!(error(call(&mut conn, &["AUTH", "alice", "nope"]).await).starts_with("WRONGPASS"));
+    assert_eq!(call(&mut conn, &["AUTH", "alice", "pw"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut conn, &["ACL", "WHOAMI"]).await,
+        Frame::Bulk("alice".into())
+    );
+
+    assert_eq!(call(&mut conn, &["SET", "cache:a", "1"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut conn, &["GET", "cache:a"]).await,
+        Frame::Bulk("1".into())
+    );
+    assert_eq!(
+        error(call(&mut conn, &["SET", "other", "1"]).await),
+        "NOPERM No permissions to access a key"
+    );
+    assert_eq!(
+        error(call(&mut conn, &["DEL", "cache:a"]).await),
+        "NOPERM User alice has no permissions to run the 'del' command"
+    );
+    assert!(error(call(&mut conn, &["ACL", "LIST"]).await).starts_with("NOPERM"));
+
+    // A refused command discards the transaction it was queued in.
+    assert_eq!(call(&mut conn, &["MULTI"]).await, Frame::ok());
+    assert!(error(call(&mut conn, &["SET", "other", "1"]).await).starts_with("NOPERM"));
+    assert!(error(call(&mut conn, &["EXEC"]).await).starts_with("EXECABORT"));
+
+    // Changes apply to connections already logged in.
+    assert_eq!(
+        call(&mut admin, &["ACL", "SETUSER", "alice", "-get"]).await,
+        Frame::ok()
+    );
+    assert!(error(call(&mut conn, &["GET", "cache:a"]).await).starts_with("NOPERM"));
+    assert_eq!(
+        call(&mut admin, &["ACL", "SETUSER", "alice", "off"]).await,
+        Frame::ok()
+    );
+    let mut other = connect(addr).await;
+    assert!(error(call(&mut other, &["AUTH", "alice", "pw"]).await).starts_with("WRONGPASS"));
+}
+
+#[tokio::test]
+async fn scripts_run_their_commands_as_the_caller() {
+    let addr = start(Db::new(4)).await;
+    let mut admin = connect(addr).await;
+    call(
+        &mut admin,
+        &[
+            "ACL", "SETUSER", "bob", "on", "nopass", "~*", "+eval", "+get",
+        ],
+    )
+    .await;
+
+    let mut conn = connect(addr).await;
+    assert_eq!(call(&mut conn, &["AUTH", "bob", "x"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut conn, &["EVAL", r#"redis::cmd("GET", "k")"#, "0"]).await,
+        Frame::Null
+    );
+    let err = error(call(&mut conn, &["EVAL", r#"redis::cmd("SET", "k", "v")"#, "0"]).await);
+    assert!(err.contains("NOPERM"), "{}", err);
+    assert_eq!(call(&mut admin, &["GET", "k"]).await, Frame::Null);
+}
+
+#[tokio::test]
+async fn users_are_listed_described_and_deleted() {
+    let addr = start(Db::new(4)).await;
+    let mut conn = connect(addr).await;
+    call(
+        &mut conn,
+        &[
+            "ACL", "SETUSER", "carol", "on", ">pw", "~app:*", "+@string", "-set",
+        ],
+    )
+    .await;
+
+    // A map, which comes flattened over RESP2.
+    let Frame::Array(fields) = call(&mut conn, &["ACL", "GETUSER", "carol"]).await else {
+        panic!("GETUSER replies with a map");
+    };
+    assert_eq!(
+        fields[0..2],
+        [Frame::Bulk("flags".into()), strings(&["on"])]
+    );
+    assert_eq!(
+        fields[4..],
+        [
+            Frame::Bulk("commands".into()),
+            Frame::Bulk("-@all +@string -set".into()),
+            Frame::Bulk("keys".into()),
+            Frame::Bulk("~app:*".into()),
+        ]
+    );
+
+    let Frame::Array(users) = call(&mut conn, &["ACL", "LIST"]).await else {
+        panic!("LIST replies with an array");
+    };
+    assert_eq!(users.len(), 2);
+    let Frame::Bulk(carol) = &users[0] else {
+        panic!("not a bulk string: {:?}", users[0]);
+    };
+    assert!(carol.starts_with(b"user carol on #"), "{:?}", carol);
+
+    let Frame::Array(string) = call(&mut conn, &["ACL", "CAT", "string"]).await else {
+        panic!("CAT replies with an array");
+    };
+    assert!(string.contains(&Frame::Bulk(Bytes::from("get"))));
+    assert!(error(call(&mut conn, &["ACL", "CAT", "nope"]).await).starts_with("ERR"));
+
+    assert_eq!(
+        call(&mut conn, &["ACL", "DELUSER", "carol", "nobody"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&mut conn, &["ACL", "GETUSER", "carol"]).await,
+        Frame::Null
+    );
+    assert!(error(call(&mut conn, &["ACL", "DELUSER", "default"]).await).starts_with("ERR"));
+}
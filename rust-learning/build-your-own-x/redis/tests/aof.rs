@@ -0,0 +1,138 @@
+//! Append-only file persistence, end to end.
+
+mod common;
+
+use common::{call, connect, send, start, strings};
+use redis::aof::{self, Aof, Fsync};
+use redis::{Db, Frame, Value};
+
+use std::time::Duration;
+use tokio::time;
+
+#[tokio::test]
+async fn writes_survive_a_restart() {
+    let path = common::temp_dir("aof-restart").join("appendonly.aof");
+
+    let db = Db::new(4);
+    db.set_aof(Some(Aof::open(&path, Fsync::Always).unwrap()));
+    let addr = start(db).await;
+    let mut conn = connect(addr).await;
+    let mut blocked = connect(addr).await;
+
+    call(&mut conn, &["SET", "greeting", "hello"]).await;
+    call(&mut conn, &["SET", "session", "x", "EX", "100"]).await;
+    call(&mut conn, &["HSET", "user:1", "name", "ann"]).await;
+    call(&mut conn, &["ZADD", "board", "10", "ann", "20", "bob"]).await;
+    call(&mut conn, &["RPUSH", "jobs", "a", "b"]).await;
+    assert_eq!(
+        call(&mut blocked, &["BLPOP", "jobs", "0"]).await,
+        strings(&["jobs", "a"])
+    );
+
+    // A pop served to a blocked client is logged too.
+    send(&mut blocked, &["BLPOP", "queue", "0"]).await;
+    time::sleep(Duration::from_millis(50)).await;
+    call(&mut conn, &["RPUSH", "queue", "only"]).await;
+    assert_eq!(
+        common::reply(&mut blocked).await,
+        strings(&["queue", "only"])
+    );
+
+    // Failed and read-only commands are not logged.
+    call(&mut conn, &["LPUSH", "greeting", "x"]).await;
+    call(&mut conn, &["GET", "greeting"]).await;
+    call(&mut conn, &["DEL", "user:1"]).await;
+
+    // `always` means every acknowledged write is already in the file.
+    let restored = Db::new(4);
+    assert_eq!(aof::load(&path, &restored).unwrap(), 9);
+
+    assert_eq!(
+        restored.get("greeting"),
+        Some(Value::String("hello".into()))
+    );
+    assert_eq!(restored.get("user:1"), None);
+    assert_eq!(restored.get("queue"), None);
+    assert_eq!(restored.get("jobs"), Some(Value::List(["b".into()].into())));
+    assert!(matches!(restored.get("board"), Some(Value::ZSet(z)) if z.len() == 2));
+
+    // The relative TTL was logged as an absolute deadline.
+    let ttl = restored.ttl("session").unwrap().unwrap();
+    assert!(ttl > Duration::from_secs(95) && ttl <= Duration::from_secs(100));
+}
+
+#[tokio::test]
+async fn a_server_replays_its_file_and_keeps_appending() {
+    let path = common::temp_dir("aof-append").join("appendonly.aof");
+
+    for round in 0..3 {
+        let db = Db::new(4);
+        aof::load(&path, &db).unwrap();
+        db.set_aof(Some(Aof::open(&path, Fsync::Always).unwrap()));
+
+        let mut conn = connect(start(db).await).await;
+        assert_eq!(
+            call(&mut conn, &["RPUSH", "rounds", &round.to_string()]).await,
+            Frame::Integer(round + 1)
+        );
+    }
+
+    // Cut the last command short, as a crash in the middle of a write would.
+    let len = std::fs::metadata(&path).unwrap().len();
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(len - 3).unwrap();
+
+    let db = Db::new(4);
+    assert_eq!(aof::load(&path, &db).unwrap(), 2);
+    assert_eq!(
+        db.get("rounds"),
+        Some(Value::List(["0".into(), "1".into()].into()))
+    );
+}
+
+#[tokio::test]
+async fn bgrewriteaof_compacts_the_file() {
+    let path = common::temp_dir("aof-rewrite").join("appendonly.aof");
+
+    let db = Db::new(4);
+    db.set_aof(Some(Aof::open(&path, Fsync::Always).unwrap()));
+    let mut conn = connect(start(db).await).await;
+
+    for i in 0..50 {
+        call(&mut conn, &["SET", "counter", &i.to_string()]).await;
+        call(&mut conn, &["RPUSH", "list", &i.to_string()]).await;
+        call(&mut conn, &["LPOP", "list"]).await;
+    }
+    call(&mut conn, &["SADD", "tags", "a", "b"]).await;
+    assert_eq!(
+        call(&mut conn, &["EXPIRE", "tags", "100"]).await,
+        Frame::Integer(1)
+    );
+    let before = std::fs::metadata(&path).unwrap().len();
+
+    assert_eq!(
+        call(&mut conn, &["BGREWRITEAOF"]).await,
+        Frame::Simple("Background append only file rewriting started".into())
+    );
+    let mut polls = 0;
+    while std::fs::metadata(&path).unwrap().len() >= before {
+        polls += 1;
+        assert!(polls < 100, "the file was not rewritten");
+        time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let restored = Db::new(4);
+    assert_eq!(aof::load(&path, &restored).unwrap(), 3);
+    assert_eq!(restored.get("counter"), Some(Value::String("49".into())));
+    assert_eq!(restored.get("list"), None);
+    assert!(restored.ttl("tags").unwrap().unwrap() > Duration::from_secs(95));
+}
+
+#[tokio::test]
+async fn bgrewriteaof_needs_the_file() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+    assert_eq!(
+        call(&mut conn, &["BGREWRITEAOF"]).await,
+        Frame::Error("ERR Append only file is disabled".into())
+    );
+}
@@ -0,0 +1,125 @@
+//! Blocking list pops: `BLPOP` and `BRPOP`.
+
+mod common;
+
+use common::{connect, reply, send, strings};
+use redis::{Db, Frame};
+
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+async fn start() -> std::net::SocketAddr {
+    common::start(Db::new(4)).await
+}
+
+/// Give a request just sent time to reach the server and block.
+async fn settle() {
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn pops_right_away_when_data_is_there() {
+    let addr = start().await;
+    let mut conn = connect(addr).await;
+
+    send(&mut conn, &["RPUSH", "b", "1", "2"]).await;
+    assert_eq!(reply(&mut conn).await, Frame::Integer(2));
+
+    // The first non-empty key wins.
+    send(&mut conn, &["BLPOP", "a", "b", "0"]).await;
+    assert_eq!(reply(&mut conn).await, strings(&["b", "1"]));
+    send(&mut conn, &["BRPOP", "a", "b", "0"]).await;
+    assert_eq!(reply(&mut conn).await, strings(&["b", "2"]));
+
+    // The list was emptied and removed.
+    send(&mut conn, &["EXISTS", "b"]).await;
+    assert_eq!(reply(&mut conn).await, Frame::Integer(0));
+}
+
+#[tokio::test]
+async fn blocked_clients_are_served_in_order() {
+    let addr = start().await;
+    let mut first = connect(addr).await;
+    let mut second = connect(addr).await;
+    let mut pusher = connect(addr).await;
+
+    send(&mut first, &["BLPOP", "queue", "0"]).await;
+    settle().await;
+    send(&mut second, &["BLPOP", "other", "queue", "0"]).await;
+    settle().await;
+
+    send(&mut pusher, &["RPUSH", "queue", "a", "b", "c"]).await;
+    assert_eq!(reply(&mut pusher).await, Frame::Integer(3));
+
+    assert_eq!(reply(&mut first).await, strings(&["queue", "a"]));
+    assert_eq!(reply(&mut second).await, strings(&["queue", "b"]));
+
+    // The element nobody waited for stays in the list.
+    send(&mut pusher, &["LRANGE", "queue", "0", "-1"]).await;
+    assert_eq!(reply(&mut pusher).await, strings(&["c"]));
+}
+
+#[tokio::test]
+async fn times_out_with_null() {
+    let addr = start().await;
+    let mut conn = connect(addr).await;
+
+    let start = Instant::now();
+    send(&mut conn, &["BRPOP", "empty", "0.1"]).await;
+    assert_eq!(reply(&mut conn).await, Frame::Null);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // A push after the timeout is not swallowed by the gone waiter.
+    send(&mut conn, &["LPUSH", "empty", "x"]).await;
+    assert_eq!(reply(&mut conn).await, Frame::Integer(1));
+    send(&mut conn, &["LLEN", "empty"]).await;
+    assert_eq!(reply(&mut conn).await, Frame::Integer(1));
+}
+
+#[tokio::test]
+async fn disconnected_waiter_does_not_lose_elements() {
+    let addr = start().await;
+    let mut gone = connect(addr).await;
+    let mut waiting = connect(addr).await;
+    let mut pusher = connect(addr).await;
+
+    send(&mut gone, &["BLPOP", "q", "0"]).await;
+    settle().await;
+    send(&mut waiting, &["BLPOP", "q", "0"]).await;
+    settle().await;
+    drop(gone);
+    settle().await;
+
+    send(&mut pusher, &["RPUSH", "q", "only"]).await;
+    assert_eq!(reply(&mut pusher).await, Frame::Integer(1));
+    assert_eq!(reply(&mut waiting).await, strings(&["q", "only"]));
+}
+
+#[tokio::test]
+async fn rejects_bad_timeouts_and_wrong_types() {
+    let addr = start().await;
+    let mut conn = connect(addr).await;
+
+    send(&mut conn, &["BLPOP", "q", "-1"]).await;
+    assert_eq!(
+        reply(&mut conn).await,
+        Frame::Error("ERR timeout is negative".into())
+    );
+    send(&mut conn, &["BLPOP", "q", "soon"]).await;
+    assert_eq!(
+        reply(&mut conn).await,
+        Frame::Error("ERR timeout is not a float or out of range".into())
+    );
+    send(&mut conn, &["BLPOP", "q", "1e300"]).await;
+    assert_eq!(
+        reply(&mut conn).await,
+        Frame::Error("ERR timeout is out of range".into())
+    );
+    send(&mut conn, &["BLPOP", "q"]).await;
+    assert!(matches!(reply(&mut conn).await, Frame::Error(_)));
+
+    send(&mut conn, &["SET", "s", "v"]).await;
+    reply(&mut conn).await;
+    send(&mut conn, &["BLPOP", "s", "0"]).await;
+    assert!(matches!(reply(&mut conn).await, Frame::Error(e) if e.starts_with("WRONGTYPE")));
+}
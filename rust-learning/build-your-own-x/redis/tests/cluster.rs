@@ -0,0 +1,257 @@
+//! Three server processes sharing the keyspace as a cluster.
+
+mod common;
+
+use common::{Server, call, eventually};
+use redis::{Connection, Frame};
+
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+/// Three nodes meeting each other, with a third of the slots each.
+async fn start() -> [(Server, Connection); 3] {
+    let mut nodes = Vec::new();
+    for name in ["cluster-a", "cluster-b", "cluster-c"] {
+        let server = Server::start(name, &["--cluster-enabled", "yes"]).await;
+        let conn = server.connect().await;
+        nodes.push((server, conn));
+    }
+    let [mut a, mut b, mut c]: [(Server, Connection); 3] = nodes.try_into().ok().unwrap();
+
+    for port in [b.0.port, c.0.port] {
+        let port = port.to_string();
+        let reply = call(&mut a.1, &["CLUSTER", "MEET", "127.0.0.1", &port]).await;
+        assert_eq!(reply, Frame::ok());
+    }
+    for ((_, conn), (start, end)) in [
+        (&mut a, ("0", "5460")),
+        (&mut b, ("5461", "10922")),
+        (&mut c, ("10923", "16383")),
+    ] {
+        let reply = call(conn, &["CLUSTER", "ADDSLOTSRANGE", start, end]).await;
+        assert_eq!(reply, Frame::ok());
+    }
+
+    // Gossip spreads who owns what until everyone agrees.
+    for (_, conn) in [&mut a, &mut b, &mut c] {
+        info_eventually(conn, "cluster_state:ok").await;
+        info_eventually(conn, "cluster_known_nodes:3").await;
+    }
+    [a, b, c]
+}
+
+/// Wait until `CLUSTER INFO` has `field`, for up to ten seconds.
+async fn info_eventually(conn: &mut Connection, field: &str) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let info = call(conn, &["CLUSTER", "INFO"]).await;
+        let Frame::Bulk(info) = &info else {
+            panic!("CLUSTER INFO replied {:?}", info);
+        };
+        if std::str::from_utf8(info).unwrap().contains(field) {
+            return;
+        }
+        assert!(Instant::now() < deadline, "no {} in {:?}", field, info);
+        time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+fn moved(kind: &str, slot: u16, port: u16) -> Frame {
+    Frame::Error(format!("{} {} 127.0.0.1:{}", kind, slot, port))
+}
+
+#[tokio::test]
+async fn keys_are_served_by_the_owner_of_their_slot() {
+    let [mut a, mut b, mut c] = start().await;
+
+    // "foo" hashes to slot 12182, which is C's.
+    assert_eq!(
+        call(&mut a.1, &["CLUSTER", "KEYSLOT", "foo"]).await,
+        Frame::Integer(12182)
+    );
+    assert_eq!(
+        call(&mut a.1, &["SET", "foo", "bar"]).await,
+        moved("MOVED", 12182, c.0.port)
+    );
+    assert_eq!(call(&mut c.1, &["SET", "foo", "bar"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut b.1, &["GET", "foo"]).await,
+        moved("MOVED", 12182, c.0.port)
+    );
+
+    // Keys of one command share a slot, which hash tags make happen.
+    assert_eq!(
+        call(&mut a.1, &["DEL", "foo", "bar"]).await,
+        Frame::Error("CROSSSLOT Keys in request don't hash to the same slot".into())
+    );
+    let Frame::Error(err) = call(&mut a.1, &["SADD", "{user1}.a", "x"]).await else {
+        panic!("slot 8106 is B's");
+    };
+    assert_eq!(err, format!("MOVED 8106 127.0.0.1:{}", b.0.port));
+    for key in ["{user1}.a", "{user1}.b"] {
+        assert_eq!(call(&mut b.1, &["SADD", key, "x"]).await, Frame::Integer(1));
+    }
+    assert_eq!(
+        call(&mut b.1, &["SINTER", "{user1}.a", "{user1}.b"]).await,
+        Frame::Array(vec![Frame::Bulk("x".into())])
+    );
+
+    // Keyless commands run anywhere.
+    assert_eq!(
+        call(&mut a.1, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+
+    let Frame::Array(slots) = call(&mut b.1, &["CLUSTER", "SLOTS"]).await else {
+        panic!("CLUSTER SLOTS replies with an array");
+    };
+    let ranges: Vec<_> = slots
+        .iter()
+        .map(|range| match range {
+            Frame::Array(range) => match &range[..] {
+                [start, end, Frame::Array(node)] => (start.clone(), end.clone(), node[1].clone()),
+                _ => panic!("bad range {:?}", range),
+            },
+            _ => panic!("bad range {:?}", range),
+        })
+        .collect();
+    assert_eq!(
+        ranges,
+        [
+            (
+                Frame::Integer(0),
+                Frame::Integer(5460),
+                Frame::Integer(a.0.port as i64)
+            ),
+            (
+                Frame::Integer(5461),
+                Frame::Integer(10922),
+                Frame::Integer(b.0.port as i64)
+            ),
+            (
+                Frame::Integer(10923),
+                Frame::Integer(16383),
+                Frame::Integer(c.0.port as i64)
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn a_slot_migrates_between_nodes() {
+    let [mut a, mut b, mut c] = start().await;
+    assert_eq!(call(&mut c.1, &["SET", "foo", "bar"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut c.1, &["PEXPIRE", "foo", "100000"]).await,
+        Frame::Integer(1)
+    );
+
+    let Frame::Bulk(id_a) = call(&mut a.1, &["CLUSTER", "MYID"]).await else {
+        panic!("CLUSTER MYID replies with a bulk string");
+    };
+    let Frame::Bulk(id_c) = call(&mut c.1, &["CLUSTER", "MYID"]).await else {
+        panic!("CLUSTER MYID replies with a bulk string");
+    };
+    let (id_a, id_c) = (
+        std::str::from_utf8(&id_a).unwrap(),
+        std::str::from_utf8(&id_c).unwrap(),
+    );
+
+    // Moving slot 12182 from C to A.
+    let importing = ["CLUSTER", "SETSLOT", "12182", "IMPORTING", id_c];
+    assert_eq!(call(&mut a.1, &importing).await, Frame::ok());
+    let migrating = ["CLUSTER", "SETSLOT", "12182", "MIGRATING", id_a];
+    assert_eq!(call(&mut c.1, &migrating).await, Frame::ok());
+
+    // Keys still on C are served there; new ones are asked of A, which
+    // only takes them after `ASKING`.
+    assert_eq!(
+        call(&mut c.1, &["GET", "foo"]).await,
+        Frame::Bulk("bar".into())
+    );
+    assert_eq!(
+        call(&mut c.1, &["SET", "{foo}.new", "1"]).await,
+        moved("ASK", 12182, a.0.port)
+    );
+    assert_eq!(
+        call(&mut a.1, &["SET", "{foo}.new", "1"]).await,
+        moved("MOVED", 12182, c.0.port)
+    );
+    assert_eq!(call(&mut a.1, &["ASKING"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut a.1, &["SET", "{foo}.new", "1"]).await,
+        Frame::ok()
+    );
+    assert_eq!(
+        call(&mut c.1, &["EXISTS", "foo", "{foo}.new"]).await,
+        Frame::Error("TRYAGAIN Multiple keys request during rehashing of slot".into())
+    );
+
+    // A slot can't be given away while it holds keys.
+    let Frame::Error(err) = call(&mut c.1, &["CLUSTER", "SETSLOT", "12182", "NODE", id_a]).await
+    else {
+        panic!("C still holds foo");
+    };
+    assert!(err.contains("still hold keys"), "{}", err);
+
+    let port = a.0.port.to_string();
+    let migrate = [
+        "MIGRATE",
+        "127.0.0.1",
+        &port,
+        "",
+        "0",
+        "5000",
+        "KEYS",
+        "foo",
+    ];
+    assert_eq!(call(&mut c.1, &migrate).await, Frame::ok());
+    assert_eq!(
+        call(&mut c.1, &["CLUSTER", "COUNTKEYSINSLOT", "12182"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        call(&mut c.1, &["GET", "foo"]).await,
+        moved("ASK", 12182, a.0.port)
+    );
+    assert_eq!(
+        call(&mut c.1, &migrate).await,
+        Frame::Simple("NOKEY".into())
+    );
+
+    for conn in [&mut a.1, &mut c.1] {
+        let reply = call(conn, &["CLUSTER", "SETSLOT", "12182", "NODE", id_a]).await;
+        assert_eq!(reply, Frame::ok());
+    }
+    assert_eq!(
+        call(&mut a.1, &["GET", "foo"]).await,
+        Frame::Bulk("bar".into())
+    );
+    let Frame::Integer(ttl) = call(&mut a.1, &["PTTL", "foo"]).await else {
+        panic!("PTTL replies with an integer");
+    };
+    assert!(ttl > 90_000 && ttl <= 100_000, "{}", ttl);
+    assert_eq!(
+        call(&mut c.1, &["GET", "foo"]).await,
+        moved("MOVED", 12182, a.0.port)
+    );
+
+    // B hears about it through gossip.
+    eventually(&mut b.1, &["GET", "foo"], moved("MOVED", 12182, a.0.port)).await;
+}
+
+#[tokio::test]
+async fn cluster_commands_need_cluster_mode() {
+    let server = Server::start("cluster-disabled", &[]).await;
+    let mut conn = server.connect().await;
+    let disabled = Frame::Error("ERR This instance has cluster support disabled".into());
+    assert_eq!(call(&mut conn, &["CLUSTER", "INFO"]).await, disabled);
+    assert_eq!(call(&mut conn, &["ASKING"]).await, disabled);
+
+    // Without a cluster, keys in any slot are served here.
+    assert_eq!(call(&mut conn, &["SET", "foo", "bar"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut conn, &["DEL", "foo", "baz"]).await,
+        Frame::Integer(1)
+    );
+}
@@ -0,0 +1,118 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use redis::{Connection, Db, Frame, server};
+
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Instant};
+
+/// Serve `db` on an ephemeral port.
+pub async fn start(db: Db) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server::run(listener, db));
+    addr
+}
+
+pub async fn connect(addr: SocketAddr) -> Connection {
+    Connection::new(TcpStream::connect(addr).await.unwrap())
+}
+
+/// Send a command made of `parts`.
+pub async fn send(conn: &mut Connection, parts: &[&str]) {
+    conn.write_frame(&strings(parts)).await.unwrap();
+    conn.flush().await.unwrap();
+}
+
+pub async fn reply(conn: &mut Connection) -> Frame {
+    conn.read_frame().await.unwrap().unwrap()
+}
+
+/// Send a command and read its reply.
+pub async fn call(conn: &mut Connection, parts: &[&str]) -> Frame {
+    send(conn, parts).await;
+    reply(conn).await
+}
+
+/// An array of bulk strings.
+pub fn strings(parts: &[&str]) -> Frame {
+    Frame::Array(
+        parts
+            .iter()
+            .map(|p| Frame::Bulk(Bytes::copy_from_slice(p.as_bytes())))
+            .collect(),
+    )
+}
+
+/// A fresh, empty directory under the system temp directory.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("redis-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A server process, killed when dropped.
+pub struct Server {
+    child: Child,
+    pub port: u16,
+}
+
+impl Server {
+    pub async fn start(name: &str, extra: &[&str]) -> Server {
+        let port = free_port();
+        let dir = temp_dir(name);
+        let child = Command::new(env!("CARGO_BIN_EXE_redis"))
+            .args(["--port", &port.to_string(), "--dir", dir.to_str().unwrap()])
+            .args(extra)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { child, port };
+
+        // Wait for it to listen.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            assert!(Instant::now() < deadline, "server did not start");
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        server
+    }
+
+    pub async fn connect(&self) -> Connection {
+        Connection::new(TcpStream::connect(("127.0.0.1", self.port)).await.unwrap())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A port nothing listens on, most likely still free when used.
+pub fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// Send a command until it gets the `expected` reply, for up to ten
+/// seconds.
+pub async fn eventually(conn: &mut Connection, parts: &[&str], expected: Frame) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let got = call(conn, parts).await;
+        if got == expected {
+            return;
+        }
+        assert!(Instant::now() < deadline, "{:?}: got {:?}", parts, got);
+        time::sleep(Duration::from_millis(20)).await;
+    }
+}
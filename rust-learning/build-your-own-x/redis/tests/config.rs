@@ -0,0 +1,231 @@
+//! Settings: `CONFIG GET`/`SET`, the limits they control, and starting the
+//! server from a configuration file.
+
+mod common;
+
+use common::{call, connect, start, strings};
+use redis::{Config, Db, Frame, Value, aof};
+
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+
+/// A map reply, which RESP2 flattens into an array.
+fn map(pairs: &[(&str, &str)]) -> Frame {
+    let flat: Vec<&str> = pairs.iter().flat_map(|(k, v)| [*k, *v]).collect();
+    strings(&flat)
+}
+
+#[tokio::test]
+async fn config_get_and_set() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "GET", "port"]).await,
+        map(&[("port", "6379")])
+    );
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "GET", "append*", "MAXCLIENTS"]).await,
+        map(&[
+            ("maxclients", "10000"),
+            ("appendonly", "no"),
+            ("appendfilename", "appendonly.aof"),
+            ("appendfsync", "everysec"),
+        ])
+    );
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "GET", "nothing*"]).await,
+        Frame::Array(vec![])
+    );
+
+    assert_eq!(
+        call(
+            &mut conn,
+            &["CONFIG", "SET", "timeout", "300", "maxclients", "50"]
+        )
+        .await,
+        Frame::ok()
+    );
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "GET", "timeout", "maxclients"]).await,
+        map(&[("maxclients", "50"), ("timeout", "300")])
+    );
+
+    // All or nothing.
+    assert_eq!(
+        call(
+            &mut conn,
+            &["CONFIG", "SET", "timeout", "5", "maxclients", "0"]
+        )
+        .await,
+        Frame::Error(
+            "ERR CONFIG SET failed (possibly related to argument 'maxclients') - \
+             invalid maxclients '0'"
+                .into()
+        )
+    );
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "GET", "timeout"]).await,
+        map(&[("timeout", "300")])
+    );
+
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "SET", "port", "7000"]).await,
+        Frame::Error(
+            "ERR CONFIG SET failed (possibly related to argument 'port') - \
+             can't set immutable config"
+                .into()
+        )
+    );
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "SET", "nope", "1"]).await,
+        Frame::Error("ERR Unknown option or number of arguments for CONFIG SET - 'nope'".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "SET", "dir", "/no/such/dir"]).await,
+        Frame::Error(
+            "ERR CONFIG SET failed (possibly related to argument 'dir') - No such directory".into()
+        )
+    );
+}
+
+#[tokio::test]
+async fn clients_past_maxclients_are_refused() {
+    let addr = start(Db::new(4)).await;
+    let mut first = connect(addr).await;
+    call(&mut first, &["CONFIG", "SET", "maxclients", "2"]).await;
+
+    let mut second = connect(addr).await;
+    assert_eq!(
+        call(&mut second, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+
+    let mut third = connect(addr).await;
+    assert_eq!(
+        third.read_frame().await.unwrap(),
+        Some(Frame::Error("ERR max number of clients reached".into()))
+    );
+    assert_eq!(third.read_frame().await.unwrap(), None);
+
+    // A place frees up when someone leaves.
+    drop(second);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let mut conn = connect(addr).await;
+        if call(&mut conn, &["PING"]).await == Frame::Simple("PONG".into()) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "the slot was never given back");
+        time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn idle_clients_time_out() {
+    let addr = start(Db::new(4)).await;
+    let mut conn = connect(addr).await;
+    call(&mut conn, &["CONFIG", "SET", "timeout", "1"]).await;
+
+    let started = Instant::now();
+    assert_eq!(conn.read_frame().await.unwrap(), None);
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn appendonly_can_be_turned_on_live() {
+    let dir = common::temp_dir("config-aof");
+    let db = Db::new(4);
+    db.set_config(Config {
+        dir: dir.clone(),
+        ..Config::default()
+    });
+    let mut conn = connect(start(db).await).await;
+
+    call(&mut conn, &["SET", "before", "1"]).await;
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "SET", "appendonly", "yes"]).await,
+        Frame::ok()
+    );
+    call(&mut conn, &["RPUSH", "after", "a", "b"]).await;
+
+    // The rewrite brings in what was there before the file was opened.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let restored = Db::new(4);
+        aof::load(&dir.join("appendonly.aof"), &restored).unwrap();
+        if restored.get("before").is_some() && restored.get("after").is_some() {
+            assert_eq!(
+                restored.get("after"),
+                Some(Value::List(["a".into(), "b".into()].into()))
+            );
+            break;
+        }
+        assert!(Instant::now() < deadline, "the file was never rewritten");
+        time::sleep(Duration::from_millis(20)).await;
+    }
+
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "SET", "appendonly", "no"]).await,
+        Frame::ok()
+    );
+    assert_eq!(
+        call(&mut conn, &["BGREWRITEAOF"]).await,
+        Frame::Error("ERR Append only file is disabled".into())
+    );
+}
+
+#[tokio::test]
+async fn binary_reads_a_config_file() {
+    let dir = common::temp_dir("config-file");
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let path = dir.join("redis.conf");
+    std::fs::write(
+        &path,
+        format!(
+            "# test server\nbind 127.0.0.1\nport {}\ndir {}\nmaxclients 3\n",
+            port,
+            dir.display()
+        ),
+    )
+    .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_redis"))
+        .arg(&path)
+        .args(["--timeout", "60"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let socket = loop {
+        if let Ok(socket) = TcpStream::connect(("127.0.0.1", port)).await {
+            break socket;
+        }
+        assert!(Instant::now() < deadline, "server did not start");
+        time::sleep(Duration::from_millis(20)).await;
+    };
+    let mut conn = redis::Connection::new(socket);
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "GET", "maxclients", "timeout"]).await,
+        map(&[("maxclients", "3"), ("timeout", "60")])
+    );
+
+    // A second server can't have the port: it says so and exits, rather
+    // than panicking.
+    let output = Command::new(env!("CARGO_BIN_EXE_redis"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("redis: can't listen on"), "{}", stderr);
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
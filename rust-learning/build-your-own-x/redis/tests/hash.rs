@@ -0,0 +1,144 @@
+//! Hashes: `HSET`, `HGET`, `HDEL`, `HGETALL` and `HINCRBY` over the wire.
+
+mod common;
+
+use common::{call, connect, start, strings};
+use redis::{Db, Frame};
+
+/// The field/value pairs of an `HGETALL` reply, sorted by field. RESP2
+/// sends them flattened, RESP3 as a map.
+fn pairs(frame: Frame) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = match frame {
+        Frame::Array(items) => items
+            .chunks(2)
+            .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+            .collect(),
+        Frame::Map(pairs) => pairs
+            .into_iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect(),
+        frame => panic!("HGETALL replied {:?}", frame),
+    };
+    pairs.sort();
+    pairs
+}
+
+fn owned(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(field, value)| (field.to_string(), value.to_string()))
+        .collect()
+}
+
+#[tokio::test]
+async fn fields_are_set_read_and_deleted() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(
+        call(&mut conn, &["HSET", "user", "name", "ann", "age", "41"]).await,
+        Frame::Integer(2)
+    );
+    assert_eq!(
+        call(&mut conn, &["HSET", "user", "age", "42", "city", "oslo"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&mut conn, &["HGET", "user", "age"]).await,
+        Frame::Bulk("42".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["HGET", "user", "nope"]).await,
+        Frame::Null
+    );
+    assert_eq!(
+        pairs(call(&mut conn, &["HGETALL", "user"]).await),
+        owned(&[("age", "42"), ("city", "oslo"), ("name", "ann")])
+    );
+
+    // The same over RESP3, where the reply is a map.
+    let Frame::Map(_) = call(&mut conn, &["HELLO", "3"]).await else {
+        panic!("HELLO replies with a map");
+    };
+    let Frame::Map(_) = call(&mut conn, &["HGETALL", "user"]).await else {
+        panic!("HGETALL replies with a map over RESP3");
+    };
+    assert_eq!(
+        call(&mut conn, &["HGETALL", "nope"]).await,
+        Frame::Map(vec![])
+    );
+
+    // Deleting the last field deletes the key.
+    assert_eq!(
+        call(&mut conn, &["HDEL", "user", "age", "nope", "city"]).await,
+        Frame::Integer(2)
+    );
+    assert_eq!(
+        call(&mut conn, &["EXISTS", "user"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&mut conn, &["HDEL", "user", "name"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&mut conn, &["EXISTS", "user"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        call(&mut conn, &["TYPE", "user"]).await,
+        Frame::Simple("none".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["HDEL", "user", "name"]).await,
+        Frame::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn counters_and_wrong_types() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(
+        call(&mut conn, &["HINCRBY", "stats", "hits", "5"]).await,
+        Frame::Integer(5)
+    );
+    assert_eq!(
+        call(&mut conn, &["HINCRBY", "stats", "hits", "-7"]).await,
+        Frame::Integer(-2)
+    );
+    let max = i64::MAX.to_string();
+    call(&mut conn, &["HSET", "stats", "name", "x", "max", &max]).await;
+    assert_eq!(
+        call(&mut conn, &["HINCRBY", "stats", "name", "1"]).await,
+        Frame::Error("ERR hash value is not an integer".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["HINCRBY", "stats", "max", "1"]).await,
+        Frame::Error("ERR increment or decrement would overflow".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["HGET", "stats", "max"]).await,
+        Frame::Bulk(max.into())
+    );
+
+    let wrongtype =
+        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into());
+    assert_eq!(
+        call(&mut conn, &["RPUSH", "list", "a"]).await,
+        Frame::Integer(1)
+    );
+    for parts in [
+        &["HSET", "list", "f", "v"][..],
+        &["HGET", "list", "f"],
+        &["HDEL", "list", "f"],
+        &["HGETALL", "list"],
+        &["HINCRBY", "list", "f", "1"],
+    ] {
+        assert_eq!(call(&mut conn, parts).await, wrongtype, "{:?}", parts);
+    }
+    assert_eq!(call(&mut conn, &["GET", "stats"]).await, wrongtype);
+    assert_eq!(
+        call(&mut conn, &["LRANGE", "list", "0", "-1"]).await,
+        strings(&["a"])
+    );
+}
@@ -0,0 +1,218 @@
+//! `maxmemory` and the eviction policies, filling the keyspace past the
+//! limit.
+
+mod common;
+
+use common::{call, connect, start};
+use redis::aof::{self, Aof, Fsync};
+use redis::db::Eviction;
+use redis::{Config, Connection, Db, Frame};
+
+/// Values are this long, so a few hundred keys fill the limit.
+const VALUE: &str = "0123456789abcdefghijklmnopqrstuvwxyz0123456789abcdefghijklmnopqrstuvwxyz";
+
+const MAXMEMORY: usize = 32 * 1024;
+
+/// A keyspace limited to `MAXMEMORY`, evicting as `policy` says.
+fn limited(shards: usize, policy: Eviction) -> Db {
+    let db = Db::new(shards);
+    db.set_config(Config {
+        maxmemory: MAXMEMORY,
+        maxmemory_policy: policy,
+        ..Config::default()
+    });
+    db
+}
+
+/// Write `n` keys named `prefix:<i>`, returning the replies.
+async fn fill(conn: &mut Connection, prefix: &str, n: usize) -> Vec<Frame> {
+    let mut replies = Vec::new();
+    for i in 0..n {
+        let key = format!("{}:{}", prefix, i);
+        replies.push(call(conn, &["SET", &key, VALUE]).await);
+    }
+    replies
+}
+
+fn key_count(db: &Db) -> usize {
+    db.snapshot().len()
+}
+
+/// Whether the keyspace is within the limit, give or take the last write,
+/// which may take it over until the next one makes room.
+fn within_limit(db: &Db) -> bool {
+    db.used_memory() <= MAXMEMORY + 512
+}
+
+#[tokio::test]
+async fn noeviction_refuses_writes_once_full() {
+    let db = limited(4, Eviction::NoEviction);
+    let mut conn = connect(start(db.clone()).await).await;
+
+    let replies = fill(&mut conn, "k", 1000).await;
+    let oom = Frame::Error("OOM command not allowed when used memory > 'maxmemory'.".into());
+    let refused = replies.iter().position(|r| *r == oom).expect("never full");
+    assert!(refused > 100, "full after {} keys", refused);
+    assert!(replies[..refused].iter().all(|r| *r == Frame::ok()));
+    assert!(replies[refused..].iter().all(|r| *r == oom));
+    assert_eq!(key_count(&db), refused);
+
+    // Reads, and writes freeing memory, still work.
+    assert_eq!(
+        call(&mut conn, &["GET", "k:0"]).await,
+        Frame::Bulk(VALUE.into())
+    );
+    assert_eq!(call(&mut conn, &["RPUSH", "list", "x"]).await, oom);
+    assert_eq!(call(&mut conn, &["MULTI"]).await, Frame::ok());
+    assert_eq!(call(&mut conn, &["SADD", "s", "x"]).await, oom);
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Error("EXECABORT Transaction discarded because of previous errors.".into())
+    );
+
+    for i in 0..10 {
+        call(&mut conn, &["DEL", &format!("k:{}", i)]).await;
+    }
+    assert_eq!(call(&mut conn, &["SET", "again", "1"]).await, Frame::ok());
+}
+
+#[tokio::test]
+async fn allkeys_lru_keeps_recently_used_keys() {
+    // A single shard, so every key competes with every other.
+    let db = limited(1, Eviction::AllKeysLru);
+    let mut conn = connect(start(db.clone()).await).await;
+    call(&mut conn, &["SET", "hot", "1"]).await;
+
+    for i in 0..2000 {
+        call(&mut conn, &["GET", "hot"]).await;
+        assert_eq!(
+            call(&mut conn, &["SET", &format!("cold:{}", i), VALUE]).await,
+            Frame::ok()
+        );
+        assert!(within_limit(&db));
+    }
+
+    assert!(db.get("hot").is_some());
+    // The most recent keys are the ones left.
+    let survivors = |range: std::ops::Range<usize>| {
+        range
+            .filter(|i| db.get(&format!("cold:{}", i)).is_some())
+            .count()
+    };
+    assert!(key_count(&db) < 1000);
+    assert!(survivors(0..1000) < survivors(1000..2000) / 4);
+    assert!(survivors(1900..2000) > 80);
+}
+
+#[tokio::test]
+async fn allkeys_lfu_keeps_frequently_used_keys() {
+    let db = limited(1, Eviction::AllKeysLfu);
+    let mut conn = connect(start(db.clone()).await).await;
+    call(&mut conn, &["SET", "popular", "1"]).await;
+    for _ in 0..200 {
+        call(&mut conn, &["GET", "popular"]).await;
+    }
+
+    let replies = fill(&mut conn, "k", 2000).await;
+    assert!(replies.iter().all(|r| *r == Frame::ok()));
+    assert!(within_limit(&db));
+    assert!(key_count(&db) < 1000);
+    assert!(db.get("popular").is_some());
+}
+
+#[tokio::test]
+async fn volatile_ttl_evicts_keys_closest_to_expiring() {
+    let db = limited(4, Eviction::VolatileTtl);
+    let mut conn = connect(start(db.clone()).await).await;
+
+    // Keys without a TTL are never evicted...
+    call(&mut conn, &["SET", "forever", "1"]).await;
+    // ...and those with the shortest go first.
+    for i in 0..2000 {
+        let key = format!("k:{}", i);
+        let ttl = (10_000 + i).to_string();
+        let reply = call(&mut conn, &["SET", &key, VALUE, "EX", &ttl]).await;
+        assert_eq!(reply, Frame::ok());
+    }
+    assert!(within_limit(&db));
+    assert!(db.get("forever").is_some());
+    assert!(db.get("k:0").is_none());
+    assert!(db.get("k:1999").is_some());
+
+    // Once only keys without a TTL are left, writes are refused.
+    let replies = fill(&mut conn, "persistent", 2000).await;
+    assert!(
+        replies
+            .iter()
+            .any(|r| matches!(r, Frame::Error(e) if e.starts_with("OOM")))
+    );
+    assert!(db.get("forever").is_some());
+}
+
+#[tokio::test]
+async fn allkeys_random_stays_under_the_limit() {
+    let db = limited(4, Eviction::AllKeysRandom);
+    let mut conn = connect(start(db.clone()).await).await;
+
+    let replies = fill(&mut conn, "k", 2000).await;
+    assert!(replies.iter().all(|r| *r == Frame::ok()));
+    assert!(within_limit(&db));
+    assert!(key_count(&db) < 1000);
+}
+
+#[tokio::test]
+async fn evictions_are_logged() {
+    let path = common::temp_dir("aof-evict").join("appendonly.aof");
+    let db = limited(4, Eviction::AllKeysRandom);
+    db.set_aof(Some(Aof::open(&path, Fsync::Always).unwrap()));
+    let mut conn = connect(start(db.clone()).await).await;
+
+    fill(&mut conn, "k", 1000).await;
+
+    let restored = Db::new(4);
+    aof::load(&path, &restored).unwrap();
+    let mut expected = db.snapshot();
+    let mut got = restored.snapshot();
+    expected.sort_by(|a, b| a.0.cmp(&b.0));
+    got.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        got.iter().map(|e| &e.0).collect::<Vec<_>>(),
+        expected.iter().map(|e| &e.0).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn limit_and_policy_change_live() {
+    let db = Db::new(4);
+    let mut conn = connect(start(db.clone()).await).await;
+    fill(&mut conn, "k", 1000).await;
+    assert_eq!(key_count(&db), 1000);
+
+    call(&mut conn, &["CONFIG", "SET", "maxmemory", "32kb"]).await;
+    assert_eq!(
+        call(&mut conn, &["SET", "one", "more"]).await,
+        Frame::Error("OOM command not allowed when used memory > 'maxmemory'.".into())
+    );
+
+    assert_eq!(
+        call(
+            &mut conn,
+            &["CONFIG", "SET", "maxmemory-policy", "allkeys-lru"]
+        )
+        .await,
+        Frame::ok()
+    );
+    assert_eq!(call(&mut conn, &["SET", "one", "more"]).await, Frame::ok());
+    assert!(within_limit(&db));
+    assert_eq!(
+        call(&mut conn, &["CONFIG", "GET", "maxmemory*"]).await,
+        common::strings(&[
+            "maxmemory",
+            "32768",
+            "maxmemory-policy",
+            "allkeys-lru",
+            "maxmemory-samples",
+            "5",
+        ])
+    );
+}
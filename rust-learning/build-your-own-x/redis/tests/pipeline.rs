@@ -0,0 +1,81 @@
+//! Pipelining: many requests written back to back before reading any reply.
+
+use redis::{Db, server};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+async fn start() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server::run(listener, Db::new(4)));
+
+    TcpStream::connect(addr).await.unwrap()
+}
+
+fn command(parts: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", parts.len()).into_bytes();
+    for part in parts {
+        out.extend_from_slice(format!("${}\r\n{}\r\n", part.len(), part).as_bytes());
+    }
+    out
+}
+
+/// Read from `stream` until exactly `expected.len()` bytes arrived and
+/// compare them.
+async fn expect(stream: &mut TcpStream, expected: &[u8]) {
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&buf),
+        String::from_utf8_lossy(expected)
+    );
+}
+
+#[tokio::test]
+async fn thousand_pipelined_sets() {
+    let mut stream = start().await;
+
+    // Write every request with a single call, without waiting for replies.
+    let mut batch = Vec::new();
+    for i in 0..1000 {
+        batch.extend(command(&[
+            "SET",
+            &format!("key:{}", i),
+            &format!("value:{}", i),
+        ]));
+    }
+    stream.write_all(&batch).await.unwrap();
+
+    expect(&mut stream, &b"+OK\r\n".repeat(1000)).await;
+
+    // The writes are all visible, in order, to a pipelined read-back.
+    let mut batch = Vec::new();
+    let mut replies = Vec::new();
+    for i in 0..1000 {
+        batch.extend(command(&["GET", &format!("key:{}", i)]));
+        let value = format!("value:{}", i);
+        replies.extend(format!("${}\r\n{}\r\n", value.len(), value).into_bytes());
+    }
+    stream.write_all(&batch).await.unwrap();
+
+    expect(&mut stream, &replies).await;
+}
+
+#[tokio::test]
+async fn pipeline_split_across_reads() {
+    let mut stream = start().await;
+
+    let mut batch = command(&["SET", "a", "1"]);
+    let set_len = batch.len();
+    batch.extend(command(&["GET", "a"]));
+    batch.extend(command(&["PING"]));
+
+    // Cut the batch in the middle of the second request.
+    let (first, second) = batch.split_at(set_len + 10);
+    stream.write_all(first).await.unwrap();
+    expect(&mut stream, b"+OK\r\n").await;
+    stream.write_all(second).await.unwrap();
+
+    expect(&mut stream, b"$1\r\n1\r\n+PONG\r\n").await;
+}
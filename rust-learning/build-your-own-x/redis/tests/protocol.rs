@@ -0,0 +1,60 @@
+//! Malformed requests: the connection sending them is dropped, not the
+//! server.
+
+mod common;
+
+use common::{call, connect, start};
+use redis::{Db, Frame};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn deeply_nested_frames_are_refused() {
+    let addr = start(Db::new(4)).await;
+
+    // Far deeper than any command. Small enough to arrive with one read,
+    // so the server hangs up with nothing left unread and the client sees
+    // the error rather than a reset.
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let mut nested = b"*1\r\n".repeat(500);
+    nested.extend_from_slice(b":1\r\n");
+    socket.write_all(&nested).await.unwrap();
+
+    let mut conn = redis::Connection::new(socket);
+    let Ok(Some(Frame::Error(err))) = conn.read_frame().await else {
+        panic!("no error before hanging up");
+    };
+    assert!(err.starts_with("ERR protocol error"), "{}", err);
+    assert!(matches!(conn.read_frame().await, Ok(None) | Err(_)));
+
+    let mut conn = connect(addr).await;
+    assert_eq!(
+        call(&mut conn, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+}
+
+#[tokio::test]
+async fn endless_lines_are_refused() {
+    let addr = start(Db::new(4)).await;
+
+    // A header that never ends. Exactly as long as it takes to be refused,
+    // for the same reason as above.
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let mut line = b"*".to_vec();
+    line.resize(64 * 1024 + 3, b'1');
+    socket.write_all(&line).await.unwrap();
+
+    let mut conn = redis::Connection::new(socket);
+    let Ok(Some(Frame::Error(err))) = conn.read_frame().await else {
+        panic!("no error before hanging up");
+    };
+    assert_eq!(err, "ERR protocol error; line too long");
+
+    let mut conn = connect(addr).await;
+    assert_eq!(
+        call(&mut conn, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+}
@@ -0,0 +1,86 @@
+//! Snapshots with `SAVE` and `BGSAVE`, end to end.
+
+mod common;
+
+use common::{call, connect, start};
+use redis::{Db, Frame, Value, rdb};
+
+use std::time::Duration;
+use tokio::time;
+
+/// Fill `db` through a client with one key of every type.
+async fn fill(conn: &mut redis::Connection) {
+    call(conn, &["SET", "s", "v", "EX", "100"]).await;
+    call(conn, &["RPUSH", "l", "a", "b"]).await;
+    call(conn, &["HSET", "h", "f", "v"]).await;
+    call(conn, &["SADD", "set", "x"]).await;
+    call(conn, &["ZADD", "z", "2", "b", "1", "a"]).await;
+}
+
+#[tokio::test]
+async fn save_then_load() {
+    let path = common::temp_dir("rdb-save").join("dump.rdb");
+    let db = Db::new(4);
+    db.set_snapshot_path(path.clone());
+    let mut conn = connect(start(db.clone()).await).await;
+
+    assert_eq!(call(&mut conn, &["LASTSAVE"]).await, Frame::Integer(0));
+    fill(&mut conn).await;
+    assert_eq!(call(&mut conn, &["SAVE"]).await, Frame::ok());
+    assert!(matches!(call(&mut conn, &["LASTSAVE"]).await, Frame::Integer(t) if t > 0));
+
+    let restored = Db::new(2);
+    assert_eq!(rdb::load(&path, &restored).unwrap(), 5);
+    for key in ["l", "h", "set", "z", "s"] {
+        assert_eq!(restored.get(key), db.get(key), "{}", key);
+    }
+    let ttl = restored.ttl("s").unwrap().unwrap();
+    assert!(ttl > Duration::from_secs(95));
+}
+
+#[tokio::test]
+async fn bgsave_captures_the_moment_it_started() {
+    let path = common::temp_dir("rdb-bgsave").join("dump.rdb");
+    let db = Db::new(4);
+    db.set_snapshot_path(path.clone());
+    let mut conn = connect(start(db).await).await;
+
+    fill(&mut conn).await;
+    assert_eq!(
+        call(&mut conn, &["BGSAVE"]).await,
+        Frame::Simple("Background saving started".into())
+    );
+    // Written after the copy was taken.
+    call(&mut conn, &["SET", "late", "1"]).await;
+
+    // The file only appears once complete.
+    while call(&mut conn, &["LASTSAVE"]).await == Frame::Integer(0) {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let restored = Db::new(4);
+    assert_eq!(rdb::load(&path, &restored).unwrap(), 5);
+    assert_eq!(restored.get("late"), None);
+    assert_eq!(
+        restored.get("l"),
+        Some(Value::List(["a".into(), "b".into()].into()))
+    );
+}
+
+#[tokio::test]
+async fn damaged_snapshots_are_refused() {
+    let path = common::temp_dir("rdb-damaged").join("dump.rdb");
+    let db = Db::new(4);
+    db.set_snapshot_path(path.clone());
+    let mut conn = connect(start(db).await).await;
+
+    fill(&mut conn).await;
+    call(&mut conn, &["SAVE"]).await;
+
+    let mut data = std::fs::read(&path).unwrap();
+    let last = data.len() - 10;
+    data[last] ^= 0xFF;
+    std::fs::write(&path, &data).unwrap();
+
+    assert!(rdb::load(&path, &Db::new(4)).is_err());
+}
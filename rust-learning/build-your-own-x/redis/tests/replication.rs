@@ -0,0 +1,170 @@
+//! Primary/replica replication, between two server processes and with a
+//! hand-driven replica.
+
+mod common;
+
+use common::{Server, call, connect, eventually, reply, send, start, strings};
+use redis::{Db, Frame, Value, rdb};
+
+#[tokio::test]
+async fn replica_process_follows_its_primary() {
+    let primary = Server::start("repl-primary", &[]).await;
+    let mut conn = primary.connect().await;
+
+    // Written before the replica exists: comes with the full sync.
+    call(&mut conn, &["SET", "greeting", "hello"]).await;
+    call(&mut conn, &["RPUSH", "jobs", "a", "b", "c"]).await;
+    call(&mut conn, &["SET", "session", "x", "EX", "100"]).await;
+
+    let port = primary.port.to_string();
+    let replica = Server::start("repl-replica", &["--replicaof", "127.0.0.1", &port]).await;
+    let mut replica_conn = replica.connect().await;
+    eventually(
+        &mut replica_conn,
+        &["GET", "greeting"],
+        Frame::Bulk("hello".into()),
+    )
+    .await;
+    assert_eq!(
+        call(&mut replica_conn, &["LRANGE", "jobs", "0", "-1"]).await,
+        strings(&["a", "b", "c"])
+    );
+    assert!(matches!(
+        call(&mut replica_conn, &["TTL", "session"]).await,
+        Frame::Integer(90..=100)
+    ));
+
+    // Written afterwards: streamed.
+    call(&mut conn, &["LPOP", "jobs"]).await;
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["HSET", "user", "name", "ann"]).await;
+    call(&mut conn, &["DEL", "greeting"]).await;
+    call(&mut conn, &["EXEC"]).await;
+    call(
+        &mut conn,
+        &["EVAL", r#"redis::cmd("SADD", KEYS[0], "x")"#, "1", "tags"],
+    )
+    .await;
+
+    eventually(&mut replica_conn, &["EXISTS", "tags"], Frame::Integer(1)).await;
+    assert_eq!(
+        call(&mut replica_conn, &["LRANGE", "jobs", "0", "-1"]).await,
+        strings(&["b", "c"])
+    );
+    assert_eq!(
+        call(&mut replica_conn, &["HGET", "user", "name"]).await,
+        Frame::Bulk("ann".into())
+    );
+    assert_eq!(
+        call(&mut replica_conn, &["EXISTS", "greeting"]).await,
+        Frame::Integer(0)
+    );
+
+    // The replica only takes writes from its primary.
+    assert_eq!(
+        call(&mut replica_conn, &["SET", "k", "v"]).await,
+        Frame::Error("READONLY You can't write against a read only replica.".into())
+    );
+
+    // Both ends agree on the offset once the replica acknowledged it.
+    let Frame::Array(role) = call(&mut conn, &["ROLE"]).await else {
+        panic!("ROLE replied with something else than an array");
+    };
+    assert_eq!(role[0], Frame::Bulk("master".into()));
+    let Frame::Integer(offset) = role[1] else {
+        panic!("no offset in {:?}", role);
+    };
+    assert!(offset > 0);
+    eventually(
+        &mut conn,
+        &["ROLE"],
+        Frame::Array(vec![
+            Frame::Bulk("master".into()),
+            Frame::Integer(offset),
+            Frame::Array(vec![strings(&[
+                "127.0.0.1",
+                &replica.port.to_string(),
+                &offset.to_string(),
+            ])]),
+        ]),
+    )
+    .await;
+    assert_eq!(
+        call(&mut replica_conn, &["ROLE"]).await,
+        Frame::Array(vec![
+            Frame::Bulk("slave".into()),
+            Frame::Bulk("127.0.0.1".into()),
+            Frame::Integer(primary.port as i64),
+            Frame::Bulk("connected".into()),
+            Frame::Integer(offset),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn replica_logs_in_to_a_primary_with_a_password() {
+    let primary = Server::start("repl-auth-primary", &["--requirepass", "secret"]).await;
+    let mut conn = primary.connect().await;
+    call(&mut conn, &["AUTH", "secret"]).await;
+    call(&mut conn, &["SET", "greeting", "hello"]).await;
+
+    let port = primary.port.to_string();
+    let replica = Server::start(
+        "repl-auth-replica",
+        &["--replicaof", "127.0.0.1", &port, "--masterauth", "secret"],
+    )
+    .await;
+    let mut replica_conn = replica.connect().await;
+    eventually(
+        &mut replica_conn,
+        &["GET", "greeting"],
+        Frame::Bulk("hello".into()),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn replica_resumes_from_the_backlog() {
+    let db = Db::new(4);
+    db.set("before".into(), "1".into(), None);
+    let addr = start(db).await;
+    let mut client = connect(addr).await;
+
+    // Act as a replica by hand.
+    let mut replica = connect(addr).await;
+    send(&mut replica, &["PSYNC", "?", "-1"]).await;
+    let Frame::Simple(full) = reply(&mut replica).await else {
+        panic!("expected FULLRESYNC");
+    };
+    let parts: Vec<&str> = full.split(' ').collect();
+    assert_eq!(parts[0], "FULLRESYNC");
+    let (replid, offset) = (parts[1].to_string(), parts[2].to_string());
+
+    let Frame::Bulk(snapshot) = reply(&mut replica).await else {
+        panic!("expected a snapshot");
+    };
+    let restored = Db::new(1);
+    rdb::restore(rdb::decode(&snapshot).unwrap(), &restored);
+    assert_eq!(restored.get("before"), Some(Value::String("1".into())));
+
+    call(&mut client, &["SET", "after", "2"]).await;
+    assert_eq!(reply(&mut replica).await, strings(&["set", "after", "2"]));
+    drop(replica);
+
+    // Missed while disconnected.
+    call(&mut client, &["DEL", "before"]).await;
+
+    let mut replica = connect(addr).await;
+    send(&mut replica, &["PSYNC", &replid, &offset]).await;
+    assert_eq!(reply(&mut replica).await, Frame::Simple("CONTINUE".into()));
+    assert_eq!(reply(&mut replica).await, strings(&["set", "after", "2"]));
+    assert_eq!(reply(&mut replica).await, strings(&["del", "before"]));
+
+    // An unknown stream means starting over.
+    let mut replica = connect(addr).await;
+    send(&mut replica, &["PSYNC", "0000", "0"]).await;
+    assert!(matches!(
+        reply(&mut replica).await,
+        Frame::Simple(s) if s.starts_with("FULLRESYNC")
+    ));
+}
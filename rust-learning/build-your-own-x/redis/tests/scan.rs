@@ -0,0 +1,160 @@
+//! Iterating with `SCAN`, `HSCAN`, `SSCAN`, `ZSCAN`, and `KEYS`.
+
+mod common;
+
+use common::{call, connect, start};
+use redis::{Connection, Db, Frame};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// Run a scan to completion, `args` following the cursor, and count how
+/// often each element came back. `between` runs after every call.
+async fn scan_all(
+    conn: &mut Connection,
+    command: &[&str],
+    args: &[&str],
+    mut between: impl AsyncFnMut(&mut Connection),
+) -> HashMap<Bytes, usize> {
+    let mut seen = HashMap::new();
+    let mut cursor = "0".to_string();
+    loop {
+        let mut parts = command.to_vec();
+        parts.push(&cursor);
+        parts.extend(args);
+
+        let Frame::Array(reply) = call(conn, &parts).await else {
+            panic!("not a scan reply");
+        };
+        let [Frame::Bulk(next), Frame::Array(items)] = &reply[..] else {
+            panic!("not a scan reply: {:?}", reply);
+        };
+        for item in items {
+            let Frame::Bulk(item) = item else {
+                panic!("not a bulk string: {:?}", item);
+            };
+            *seen.entry(item.clone()).or_default() += 1;
+        }
+
+        between(conn).await;
+        cursor = String::from_utf8(next.to_vec()).unwrap();
+        if cursor == "0" {
+            return seen;
+        }
+    }
+}
+
+#[tokio::test]
+async fn scan_returns_each_key_once_while_the_keyspace_changes() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+    for i in 0..200 {
+        call(&mut conn, &["SET", &format!("key:{}", i), "v"]).await;
+    }
+
+    // Keys added along the way make the shards' tables grow.
+    let mut added = 0;
+    let seen = scan_all(&mut conn, &["SCAN"], &["COUNT", "7"], async |conn| {
+        for _ in 0..10 {
+            call(conn, &["SET", &format!("new:{}", added), "v"]).await;
+            added += 1;
+        }
+    })
+    .await;
+
+    for i in 0..200 {
+        assert_eq!(seen.get(format!("key:{}", i).as_bytes()), Some(&1));
+    }
+    assert!(seen.values().all(|&n| n == 1));
+}
+
+#[tokio::test]
+async fn scan_filters_by_pattern_and_type() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+    for i in 0..30 {
+        call(&mut conn, &["SET", &format!("user:{}", i), "v"]).await;
+        call(&mut conn, &["SET", &format!("session:{}", i), "v"]).await;
+    }
+    call(&mut conn, &["RPUSH", "user:list", "x"]).await;
+
+    let users = scan_all(&mut conn, &["SCAN"], &["MATCH", "user:*"], async |_| {}).await;
+    assert_eq!(users.len(), 31);
+
+    let lists = scan_all(&mut conn, &["SCAN"], &["TYPE", "list"], async |_| {}).await;
+    assert_eq!(lists.into_keys().collect::<Vec<_>>(), ["user:list"]);
+
+    let Frame::Array(mut keys) = call(&mut conn, &["KEYS", "session:?"]).await else {
+        panic!("KEYS replies with an array");
+    };
+    keys.sort_by_key(|key| key.to_string());
+    let expected: Vec<_> = (0..10)
+        .map(|i| Frame::Bulk(Bytes::from(format!("session:{}", i))))
+        .collect();
+    assert_eq!(keys, expected);
+
+    assert_eq!(
+        call(&mut conn, &["SCAN", "nope"]).await,
+        Frame::Error("ERR invalid cursor".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["SCAN", "0", "COUNT", "0"]).await,
+        Frame::Error("ERR syntax error".into())
+    );
+}
+
+#[tokio::test]
+async fn values_are_scanned_a_page_at_a_time() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+    for i in 0..50 {
+        let i = i.to_string();
+        call(&mut conn, &["HSET", "h", &format!("f{}", i), &i]).await;
+        call(&mut conn, &["SADD", "s", &format!("m{}", i)]).await;
+        call(&mut conn, &["ZADD", "z", &i, &format!("m{}", i)]).await;
+    }
+
+    let fields = scan_all(&mut conn, &["HSCAN", "h"], &["NOVALUES"], async |_| {}).await;
+    assert_eq!(fields.len(), 50);
+    assert!(fields.values().all(|&n| n == 1));
+
+    // Without `NOVALUES`, values follow their field. `MATCH` only filters
+    // what `COUNT` let through, hence the large one.
+    let Frame::Array(reply) = call(
+        &mut conn,
+        &["HSCAN", "h", "0", "MATCH", "f7", "COUNT", "100"],
+    )
+    .await
+    else {
+        panic!("not a scan reply");
+    };
+    assert_eq!(reply[1], common::strings(&["f7", "7"]));
+
+    // Members added mid-scan don't make the others come back twice.
+    let mut added = 0;
+    let members = scan_all(&mut conn, &["SSCAN", "s"], &["COUNT", "5"], async |conn| {
+        call(conn, &["SADD", "s", &format!("extra{}", added)]).await;
+        added += 1;
+    })
+    .await;
+    for i in 0..50 {
+        assert_eq!(members.get(format!("m{}", i).as_bytes()), Some(&1));
+    }
+
+    let Frame::Array(reply) = call(
+        &mut conn,
+        &["ZSCAN", "z", "0", "MATCH", "m3", "COUNT", "100"],
+    )
+    .await
+    else {
+        panic!("not a scan reply");
+    };
+    assert_eq!(reply[1], common::strings(&["m3", "3"]));
+
+    call(&mut conn, &["SET", "str", "v"]).await;
+    assert!(matches!(
+        call(&mut conn, &["SSCAN", "str", "0"]).await,
+        Frame::Error(e) if e.starts_with("WRONGTYPE")
+    ));
+    assert_eq!(
+        call(&mut conn, &["HSCAN", "missing", "0"]).await,
+        Frame::Array(vec![Frame::Bulk("0".into()), Frame::Array(vec![])])
+    );
+}
@@ -0,0 +1,159 @@
+//! Server-side scripts: `EVAL`, `EVALSHA` and `SCRIPT`.
+
+mod common;
+
+use common::{call, connect, start, strings};
+use redis::aof::{self, Aof, Fsync};
+use redis::{Db, Frame, Value};
+
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn scripts_run_commands() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(
+        call(&mut conn, &["EVAL", "40 + 2", "0"]).await,
+        Frame::Integer(42)
+    );
+
+    let script = r#"
+        redis::cmd("SET", KEYS[0], ARGV[0]);
+        redis::cmd("RPUSH", KEYS[1], "a", "b", 3);
+        [redis::cmd("GET", KEYS[0]), redis::cmd("LRANGE", KEYS[1], 0, -1)]
+    "#;
+    assert_eq!(
+        call(&mut conn, &["EVAL", script, "2", "k", "l", "v"]).await,
+        Frame::Array(vec![Frame::Bulk("v".into()), strings(&["a", "b", "3"])])
+    );
+
+    // Nulls become `()` and back.
+    assert_eq!(
+        call(
+            &mut conn,
+            &["EVAL", r#"redis::cmd("GET", "missing")"#, "0"]
+        )
+        .await,
+        Frame::Null
+    );
+}
+
+#[tokio::test]
+async fn errors_raise_or_are_returned() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+    call(&mut conn, &["RPUSH", "l", "x"]).await;
+
+    // `call` raises the error, which ends the script.
+    assert_eq!(
+        call(&mut conn, &["EVAL", r#"redis::cmd("GET", "l"); 1"#, "0"]).await,
+        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+    );
+    // `pcall` hands it to the script.
+    let script =
+        r#"let r = redis::pcmd("GET", "l"); if "err" in r { "caught" } else { "missed" }"#;
+    assert_eq!(
+        call(&mut conn, &["EVAL", script, "0"]).await,
+        Frame::Bulk("caught".into())
+    );
+
+    assert_eq!(
+        call(&mut conn, &["EVAL", r#"throw "no way""#, "0"]).await,
+        Frame::Error("ERR no way".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["EVAL", r#"redis::cmd("MULTI")"#, "0"]).await,
+        Frame::Error("ERR This Redis command is not allowed from script".into())
+    );
+    assert!(matches!(
+        call(&mut conn, &["EVAL", "let = ;", "0"]).await,
+        Frame::Error(e) if e.starts_with("ERR Error compiling script")
+    ));
+    assert_eq!(
+        call(&mut conn, &["EVAL", "1", "2", "k"]).await,
+        Frame::Error("ERR Number of keys can't be greater than number of args".into())
+    );
+}
+
+#[tokio::test]
+async fn scripts_are_cached_by_sha() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+    let sha = "e0e1f9fabfc9d4800c877a703b823ac0578ff8db";
+
+    assert_eq!(
+        call(&mut conn, &["EVALSHA", sha, "0"]).await,
+        Frame::Error("NOSCRIPT No matching script. Please use EVAL.".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["SCRIPT", "LOAD", "return 1"]).await,
+        Frame::Bulk(sha.into())
+    );
+    assert_eq!(
+        call(&mut conn, &["EVALSHA", sha, "0"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&mut conn, &["SCRIPT", "EXISTS", sha, "ffff"]).await,
+        Frame::Array(vec![Frame::Integer(1), Frame::Integer(0)])
+    );
+
+    assert_eq!(call(&mut conn, &["SCRIPT", "FLUSH"]).await, Frame::ok());
+    assert_eq!(
+        call(&mut conn, &["SCRIPT", "EXISTS", sha]).await,
+        Frame::Array(vec![Frame::Integer(0)])
+    );
+
+    // `EVAL` caches what it runs.
+    call(&mut conn, &["EVAL", "return 1", "0"]).await;
+    assert_eq!(
+        call(&mut conn, &["EVALSHA", sha, "0"]).await,
+        Frame::Integer(1)
+    );
+}
+
+#[tokio::test]
+async fn runaway_scripts_are_killed() {
+    let addr = start(Db::new(4)).await;
+    let mut conn = connect(addr).await;
+
+    let start = Instant::now();
+    assert!(matches!(
+        call(&mut conn, &["EVAL", "loop {}", "0"]).await,
+        Frame::Error(e) if e.contains("budget")
+    ));
+    assert!(start.elapsed() < Duration::from_secs(30));
+
+    // The server carries on.
+    let mut other = connect(addr).await;
+    assert_eq!(
+        call(&mut other, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+}
+
+#[tokio::test]
+async fn scripts_are_logged_as_their_commands() {
+    let path = common::temp_dir("aof-eval").join("appendonly.aof");
+
+    let db = Db::new(4);
+    db.set_aof(Some(Aof::open(&path, Fsync::Always).unwrap()));
+    let mut conn = connect(start(db).await).await;
+
+    let script = r#"
+        let n = redis::cmd("RPUSH", KEYS[0], "a", "b", "c");
+        redis::cmd("LPOP", KEYS[0]);
+        redis::cmd("EXPIRE", KEYS[0], 100);
+        n
+    "#;
+    assert_eq!(
+        call(&mut conn, &["EVAL", script, "1", "l"]).await,
+        Frame::Integer(3)
+    );
+
+    let restored = Db::new(4);
+    assert_eq!(aof::load(&path, &restored).unwrap(), 3);
+    assert_eq!(
+        restored.get("l"),
+        Some(Value::List(["b".into(), "c".into()].into()))
+    );
+    assert!(restored.ttl("l").unwrap().is_some());
+}
@@ -0,0 +1,176 @@
+//! Sets and sorted sets: `SADD`, `SMEMBERS`, `SINTER`, `ZADD`, `ZRANGE`
+//! and `ZRANGEBYSCORE` over the wire.
+
+mod common;
+
+use common::{call, connect, start, strings};
+use redis::{Db, Frame};
+
+/// The members of a set reply, sorted. RESP2 sends them as an array, RESP3
+/// as a set.
+fn members(frame: Frame) -> Vec<String> {
+    let (Frame::Array(items) | Frame::Set(items)) = frame else {
+        panic!("expected members, got {:?}", frame);
+    };
+    let mut members: Vec<String> = items.iter().map(Frame::to_string).collect();
+    members.sort();
+    members
+}
+
+fn wrongtype() -> Frame {
+    Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+}
+
+#[tokio::test]
+async fn set_members_and_intersections() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(
+        call(&mut conn, &["SADD", "a", "x", "y", "z", "x"]).await,
+        Frame::Integer(3)
+    );
+    assert_eq!(
+        call(&mut conn, &["SADD", "a", "y", "w"]).await,
+        Frame::Integer(1)
+    );
+    call(&mut conn, &["SADD", "b", "y", "z", "q"]).await;
+    assert_eq!(
+        members(call(&mut conn, &["SMEMBERS", "a"]).await),
+        ["w", "x", "y", "z"]
+    );
+    assert_eq!(
+        members(call(&mut conn, &["SINTER", "a", "b"]).await),
+        ["y", "z"]
+    );
+    assert_eq!(
+        call(&mut conn, &["SINTER", "a", "nope"]).await,
+        Frame::Array(vec![])
+    );
+    assert_eq!(
+        call(&mut conn, &["TYPE", "a"]).await,
+        Frame::Simple("set".into())
+    );
+
+    // RESP3 clients get a set.
+    let Frame::Map(_) = call(&mut conn, &["HELLO", "3"]).await else {
+        panic!("HELLO replies with a map");
+    };
+    let Frame::Set(_) = call(&mut conn, &["SMEMBERS", "a"]).await else {
+        panic!("SMEMBERS replies with a set over RESP3");
+    };
+
+    // Nothing is left behind by a command that failed or found nothing.
+    assert!(matches!(
+        call(&mut conn, &["SADD", "empty"]).await,
+        Frame::Error(_)
+    ));
+    assert_eq!(
+        call(&mut conn, &["SMEMBERS", "empty"]).await,
+        Frame::Set(vec![])
+    );
+    assert_eq!(
+        call(&mut conn, &["EXISTS", "empty"]).await,
+        Frame::Integer(0)
+    );
+
+    call(&mut conn, &["SET", "s", "v"]).await;
+    for parts in [
+        &["SADD", "s", "x"][..],
+        &["SMEMBERS", "s"],
+        &["SINTER", "a", "s"],
+        &["ZADD", "a", "1", "x"],
+    ] {
+        assert_eq!(call(&mut conn, parts).await, wrongtype(), "{:?}", parts);
+    }
+    assert_eq!(call(&mut conn, &["DEL", "a", "b"]).await, Frame::Integer(2));
+    assert_eq!(
+        call(&mut conn, &["EXISTS", "a", "b"]).await,
+        Frame::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn sorted_set_ranges() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(
+        call(
+            &mut conn,
+            &["ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d"]
+        )
+        .await,
+        Frame::Integer(4)
+    );
+    assert_eq!(
+        call(&mut conn, &["ZADD", "z", "CH", "0.5", "a", "9", "b"]).await,
+        Frame::Integer(2)
+    );
+    assert_eq!(
+        call(&mut conn, &["TYPE", "z"]).await,
+        Frame::Simple("zset".into())
+    );
+
+    // Scores come as bulk strings over RESP2...
+    assert_eq!(
+        call(&mut conn, &["ZRANGE", "z", "0", "-1", "WITHSCORES"]).await,
+        strings(&["a", "0.5", "c", "3", "d", "4", "b", "9"])
+    );
+    assert_eq!(
+        call(&mut conn, &["ZRANGE", "z", "0", "1", "REV"]).await,
+        strings(&["b", "d"])
+    );
+    assert_eq!(
+        call(
+            &mut conn,
+            &["ZRANGEBYSCORE", "z", "(1", "+inf", "LIMIT", "1", "2"]
+        )
+        .await,
+        strings(&["d", "b"])
+    );
+    assert_eq!(
+        call(&mut conn, &["ZRANGEBYSCORE", "z", "5", "8"]).await,
+        Frame::Array(vec![])
+    );
+
+    // ...and as doubles over RESP3.
+    let Frame::Map(_) = call(&mut conn, &["HELLO", "3"]).await else {
+        panic!("HELLO replies with a map");
+    };
+    assert_eq!(
+        call(&mut conn, &["ZRANGEBYSCORE", "z", "3", "4", "WITHSCORES"]).await,
+        Frame::Array(vec![
+            Frame::Bulk("c".into()),
+            Frame::Double(3.0),
+            Frame::Bulk("d".into()),
+            Frame::Double(4.0),
+        ])
+    );
+
+    // A ZADD that adds nothing doesn't leave an empty key behind.
+    assert_eq!(
+        call(&mut conn, &["ZADD", "empty", "XX", "1", "a"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        call(&mut conn, &["EXISTS", "empty"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        call(&mut conn, &["ZRANGE", "empty", "0", "-1"]).await,
+        Frame::Array(vec![])
+    );
+
+    assert_eq!(
+        call(&mut conn, &["ZADD", "z", "nan", "a"]).await,
+        Frame::Error("ERR value is not a valid float".into())
+    );
+    call(&mut conn, &["SADD", "s", "x"]).await;
+    for parts in [
+        &["ZADD", "s", "1", "x"][..],
+        &["ZRANGE", "s", "0", "-1"],
+        &["ZRANGEBYSCORE", "s", "0", "1"],
+        &["SMEMBERS", "z"],
+    ] {
+        assert_eq!(call(&mut conn, parts).await, wrongtype(), "{:?}", parts);
+    }
+}
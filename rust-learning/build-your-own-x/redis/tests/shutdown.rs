@@ -0,0 +1,196 @@
+//! Graceful shutdown: `SHUTDOWN`, and `SIGTERM` to the binary.
+
+mod common;
+
+use common::{call, connect, reply, send, strings};
+use redis::aof::{self, Aof, Fsync};
+use redis::shutdown::SaveMode;
+use redis::{Config, Connection, Db, Frame, Value, rdb, server};
+
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// Serve `db` with its files in `dir`, keeping hold of the server task.
+async fn serve(db: Db, dir: &Path) -> (SocketAddr, JoinHandle<redis::Result<()>>) {
+    db.set_config(Config {
+        dir: dir.to_path_buf(),
+        ..Config::default()
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (addr, tokio::spawn(server::run(listener, db)))
+}
+
+async fn stopped(server: JoinHandle<redis::Result<()>>) {
+    time::timeout(Duration::from_secs(10), server)
+        .await
+        .expect("the server did not stop")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_saves_a_snapshot() {
+    let dir = common::temp_dir("shutdown-save");
+    let (addr, server) = serve(Db::new(4), &dir).await;
+    let mut conn = connect(addr).await;
+    let mut blocked = connect(addr).await;
+    let mut subscriber = connect(addr).await;
+
+    call(&mut conn, &["SET", "greeting", "hello"]).await;
+    send(&mut blocked, &["BLPOP", "jobs", "0"]).await;
+    assert_eq!(
+        call(&mut subscriber, &["SUBSCRIBE", "news"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("subscribe")),
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Integer(1),
+        ])
+    );
+    time::sleep(Duration::from_millis(50)).await;
+
+    // A request pipelined ahead of `SHUTDOWN` still gets its reply.
+    let mut last = connect(addr).await;
+    send(&mut last, &["RPUSH", "jobs", "a"]).await;
+    send(&mut last, &["SHUTDOWN"]).await;
+    assert_eq!(reply(&mut last).await, Frame::Integer(1));
+    assert_eq!(last.read_frame().await.unwrap(), None);
+
+    // The blocked client got the element pushed before the shutdown; the
+    // others are hung up on.
+    assert_eq!(reply(&mut blocked).await, strings(&["jobs", "a"]));
+    assert_eq!(blocked.read_frame().await.unwrap(), None);
+    assert_eq!(subscriber.read_frame().await.unwrap(), None);
+    assert_eq!(conn.read_frame().await.unwrap(), None);
+
+    stopped(server).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+
+    let restored = Db::new(4);
+    rdb::load(&dir.join("dump.rdb"), &restored).unwrap();
+    assert_eq!(
+        restored.get("greeting"),
+        Some(Value::String("hello".into()))
+    );
+    assert_eq!(restored.get("jobs"), None);
+}
+
+#[tokio::test]
+async fn blocked_clients_get_nil() {
+    let dir = common::temp_dir("shutdown-blocked");
+    let db = Db::new(4);
+    let (addr, server) = serve(db.clone(), &dir).await;
+    let mut blocked = connect(addr).await;
+
+    send(&mut blocked, &["BLPOP", "jobs", "0"]).await;
+    time::sleep(Duration::from_millis(50)).await;
+    db.shutdown(SaveMode::NoSave);
+
+    assert_eq!(reply(&mut blocked).await, Frame::Null);
+    assert_eq!(blocked.read_frame().await.unwrap(), None);
+    stopped(server).await;
+
+    // `NOSAVE` leaves no snapshot behind.
+    assert!(!dir.join("dump.rdb").exists());
+}
+
+#[tokio::test]
+async fn shutdown_syncs_the_append_only_file() {
+    let dir = common::temp_dir("shutdown-aof");
+    let path = dir.join("appendonly.aof");
+    let db = Db::new(4);
+    db.set_aof(Some(Aof::open(&path, Fsync::No).unwrap()));
+    let (addr, server) = serve(db, &dir).await;
+    let mut conn = connect(addr).await;
+
+    call(&mut conn, &["HSET", "user:1", "name", "ann"]).await;
+    send(&mut conn, &["SHUTDOWN"]).await;
+    assert_eq!(conn.read_frame().await.unwrap(), None);
+    stopped(server).await;
+
+    let restored = Db::new(4);
+    assert_eq!(aof::load(&path, &restored).unwrap(), 1);
+    assert!(restored.get("user:1").is_some());
+
+    // With the append-only file on, a snapshot is only taken when asked.
+    assert!(!dir.join("dump.rdb").exists());
+}
+
+#[tokio::test]
+async fn shutdown_is_refused_where_it_cant_run() {
+    let dir = common::temp_dir("shutdown-refused");
+    let (addr, _server) = serve(Db::new(4), &dir).await;
+    let mut conn = connect(addr).await;
+
+    assert_eq!(
+        call(&mut conn, &["SHUTDOWN", "MAYBE"]).await,
+        Frame::Error("ERR syntax error".into())
+    );
+
+    call(&mut conn, &["MULTI"]).await;
+    assert!(matches!(
+        call(&mut conn, &["SHUTDOWN"]).await,
+        Frame::Error(_)
+    ));
+    call(&mut conn, &["DISCARD"]).await;
+
+    assert!(matches!(
+        call(&mut conn, &["EVAL", "redis.call('SHUTDOWN')", "0"]).await,
+        Frame::Error(_)
+    ));
+
+    // Still up.
+    assert_eq!(
+        call(&mut conn, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+}
+
+#[tokio::test]
+async fn sigterm_stops_the_binary_cleanly() {
+    let dir = common::temp_dir("shutdown-sigterm");
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_redis"))
+        .args(["--port", &port.to_string()])
+        .arg("--dir")
+        .arg(&dir)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let socket = loop {
+        if let Ok(socket) = TcpStream::connect(("127.0.0.1", port)).await {
+            break socket;
+        }
+        assert!(Instant::now() < deadline, "server did not start");
+        time::sleep(Duration::from_millis(20)).await;
+    };
+    let mut conn = Connection::new(socket);
+    call(&mut conn, &["SET", "greeting", "hello"]).await;
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+
+    let restored = Db::new(4);
+    rdb::load(&dir.join("dump.rdb"), &restored).unwrap();
+    assert_eq!(
+        restored.get("greeting"),
+        Some(Value::String("hello".into()))
+    );
+}
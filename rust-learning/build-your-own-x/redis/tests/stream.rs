@@ -0,0 +1,523 @@
+//! Streams: appending and range reads, blocking `XREAD`, and consumer
+//! groups with their pending entries.
+
+mod common;
+
+use common::{call, connect, reply, send, start, strings};
+use redis::aof::{self, Aof, Fsync};
+use redis::{Db, Frame, Value};
+
+use bytes::Bytes;
+use std::time::{Duration, SystemTime};
+use tokio::time::{self, Instant};
+
+/// Give a request just sent time to reach the server and block.
+async fn settle() {
+    time::sleep(Duration::from_millis(50)).await;
+}
+
+/// An entry as replied: its ID and its fields.
+fn entry(id: &str, fields: &[&str]) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from(id.to_string())),
+        strings(fields),
+    ])
+}
+
+/// The reply of `XREAD`: each key with its entries.
+fn streams(streams: Vec<(&str, Vec<Frame>)>) -> Frame {
+    Frame::Array(
+        streams
+            .into_iter()
+            .map(|(key, entries)| {
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(key.to_string())),
+                    Frame::Array(entries),
+                ])
+            })
+            .collect(),
+    )
+}
+
+#[tokio::test]
+async fn xadd_and_ranges() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(
+        call(&mut conn, &["XADD", "s", "1-1", "temp", "20"]).await,
+        Frame::Bulk("1-1".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["XADD", "s", "1-*", "temp", "21"]).await,
+        Frame::Bulk("1-2".into())
+    );
+    call(&mut conn, &["XADD", "s", "2-0", "temp", "22", "unit", "c"]).await;
+    call(&mut conn, &["XADD", "s", "3", "temp", "23"]).await;
+    assert_eq!(
+        call(&mut conn, &["TYPE", "s"]).await,
+        Frame::Simple("stream".into())
+    );
+    assert_eq!(call(&mut conn, &["XLEN", "s"]).await, Frame::Integer(4));
+
+    // IDs only grow.
+    assert_eq!(
+        call(&mut conn, &["XADD", "s", "2-5", "temp", "0"]).await,
+        Frame::Error(
+            "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                .into()
+        )
+    );
+    assert_eq!(
+        call(&mut conn, &["XADD", "new", "0-0", "f", "v"]).await,
+        Frame::Error("ERR The ID specified in XADD must be greater than 0-0".into())
+    );
+    assert_eq!(call(&mut conn, &["EXISTS", "new"]).await, Frame::Integer(0));
+    assert_eq!(
+        call(&mut conn, &["XADD", "new", "NOMKSTREAM", "*", "f", "v"]).await,
+        Frame::Null
+    );
+
+    // `*` follows the clock.
+    let Frame::Bulk(id) = call(&mut conn, &["XADD", "clock", "*", "f", "v"]).await else {
+        panic!("XADD replies with the ID");
+    };
+    let ms: u128 = std::str::from_utf8(&id)
+        .unwrap()
+        .split('-')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    assert!(ms <= now && ms + 5000 > now);
+
+    assert_eq!(
+        call(&mut conn, &["XRANGE", "s", "-", "+", "COUNT", "2"]).await,
+        Frame::Array(vec![
+            entry("1-1", &["temp", "20"]),
+            entry("1-2", &["temp", "21"]),
+        ])
+    );
+    // A bare time covers all of its sequence numbers; `(` excludes.
+    assert_eq!(
+        call(&mut conn, &["XRANGE", "s", "(1-1", "2"]).await,
+        Frame::Array(vec![
+            entry("1-2", &["temp", "21"]),
+            entry("2-0", &["temp", "22", "unit", "c"]),
+        ])
+    );
+    assert_eq!(
+        call(&mut conn, &["XREVRANGE", "s", "+", "-", "COUNT", "1"]).await,
+        Frame::Array(vec![entry("3-0", &["temp", "23"])])
+    );
+    assert_eq!(
+        call(&mut conn, &["XRANGE", "s", "3", "1"]).await,
+        Frame::Array(vec![])
+    );
+
+    // Trimming keeps the newest entries.
+    call(
+        &mut conn,
+        &["XADD", "s", "MAXLEN", "~", "2", "4-0", "temp", "24"],
+    )
+    .await;
+    assert_eq!(
+        call(&mut conn, &["XRANGE", "s", "-", "+"]).await,
+        Frame::Array(vec![
+            entry("3-0", &["temp", "23"]),
+            entry("4-0", &["temp", "24"]),
+        ])
+    );
+
+    call(&mut conn, &["SET", "str", "x"]).await;
+    assert_eq!(
+        call(&mut conn, &["XADD", "str", "*", "f", "v"]).await,
+        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+    );
+}
+
+#[tokio::test]
+async fn xread_returns_entries_after_the_ids() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+    call(&mut conn, &["XADD", "a", "1-0", "n", "1"]).await;
+    call(&mut conn, &["XADD", "a", "2-0", "n", "2"]).await;
+    call(&mut conn, &["XADD", "b", "5-0", "n", "5"]).await;
+
+    assert_eq!(
+        call(&mut conn, &["XREAD", "STREAMS", "a", "b", "1-0", "0"]).await,
+        streams(vec![
+            ("a", vec![entry("2-0", &["n", "2"])]),
+            ("b", vec![entry("5-0", &["n", "5"])]),
+        ])
+    );
+    assert_eq!(
+        call(&mut conn, &["XREAD", "COUNT", "1", "STREAMS", "a", "0"]).await,
+        streams(vec![("a", vec![entry("1-0", &["n", "1"])])])
+    );
+    assert_eq!(
+        call(&mut conn, &["XREAD", "STREAMS", "a", "b", "$", "5"]).await,
+        Frame::Null
+    );
+    assert!(matches!(
+        call(&mut conn, &["XREAD", "STREAMS", "a", "b", "0"]).await,
+        Frame::Error(err) if err.starts_with("ERR Unbalanced 'xread' list of streams")
+    ));
+}
+
+#[tokio::test]
+async fn blocked_readers_all_get_the_new_entry() {
+    let addr = start(Db::new(4)).await;
+    let mut first = connect(addr).await;
+    let mut second = connect(addr).await;
+    let mut writer = connect(addr).await;
+    call(&mut writer, &["XADD", "events", "1-0", "old", "x"]).await;
+
+    // `$` is the last ID when the read started.
+    send(
+        &mut first,
+        &["XREAD", "BLOCK", "0", "STREAMS", "events", "$"],
+    )
+    .await;
+    send(
+        &mut second,
+        &[
+            "XREAD", "BLOCK", "5000", "STREAMS", "other", "events", "0", "1-0",
+        ],
+    )
+    .await;
+    settle().await;
+
+    call(&mut writer, &["XADD", "events", "2-0", "new", "y"]).await;
+    let expected = streams(vec![("events", vec![entry("2-0", &["new", "y"])])]);
+    assert_eq!(reply(&mut first).await, expected);
+    assert_eq!(reply(&mut second).await, expected);
+
+    // Entries already there are served without blocking.
+    assert_eq!(
+        call(
+            &mut first,
+            &["XREAD", "BLOCK", "0", "STREAMS", "events", "1"]
+        )
+        .await,
+        expected
+    );
+
+    // A timeout replies with a null.
+    let started = Instant::now();
+    assert_eq!(
+        call(
+            &mut first,
+            &["XREAD", "BLOCK", "100", "STREAMS", "events", "$"]
+        )
+        .await,
+        Frame::Null
+    );
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    // Inside a transaction, nothing blocks.
+    call(&mut first, &["MULTI"]).await;
+    call(
+        &mut first,
+        &["XREAD", "BLOCK", "0", "STREAMS", "events", "$"],
+    )
+    .await;
+    assert_eq!(
+        call(&mut first, &["EXEC"]).await,
+        Frame::Array(vec![Frame::Null])
+    );
+}
+
+#[tokio::test]
+async fn consumer_groups_share_the_entries() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(
+        call(&mut conn, &["XGROUP", "CREATE", "jobs", "workers", "$"]).await,
+        Frame::Error(
+            "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may \
+             want to use the MKSTREAM option to create an empty stream automatically."
+                .into()
+        )
+    );
+    assert_eq!(
+        call(
+            &mut conn,
+            &["XGROUP", "CREATE", "jobs", "workers", "$", "MKSTREAM"]
+        )
+        .await,
+        Frame::ok()
+    );
+    assert_eq!(
+        call(&mut conn, &["XGROUP", "CREATE", "jobs", "workers", "0"]).await,
+        Frame::Error("BUSYGROUP Consumer Group name already exists".into())
+    );
+    for (id, job) in [("1-0", "a"), ("2-0", "b"), ("3-0", "c")] {
+        call(&mut conn, &["XADD", "jobs", id, "job", job]).await;
+    }
+
+    let read = |consumer: &'static str, count: &'static str, id: &'static str| {
+        [
+            "XREADGROUP",
+            "GROUP",
+            "workers",
+            consumer,
+            "COUNT",
+            count,
+            "STREAMS",
+            "jobs",
+            id,
+        ]
+    };
+    assert_eq!(
+        call(&mut conn, &read("ann", "2", ">")).await,
+        streams(vec![(
+            "jobs",
+            vec![entry("1-0", &["job", "a"]), entry("2-0", &["job", "b"])]
+        )])
+    );
+    assert_eq!(
+        call(&mut conn, &read("bob", "10", ">")).await,
+        streams(vec![("jobs", vec![entry("3-0", &["job", "c"])])])
+    );
+    assert_eq!(call(&mut conn, &read("bob", "10", ">")).await, Frame::Null);
+
+    // Each consumer's history is its own.
+    assert_eq!(
+        call(&mut conn, &read("ann", "10", "0")).await,
+        streams(vec![(
+            "jobs",
+            vec![entry("1-0", &["job", "a"]), entry("2-0", &["job", "b"])]
+        )])
+    );
+    assert_eq!(
+        call(&mut conn, &read("carl", "10", "0")).await,
+        streams(vec![("jobs", vec![])])
+    );
+
+    assert_eq!(
+        call(&mut conn, &["XPENDING", "jobs", "workers"]).await,
+        Frame::Array(vec![
+            Frame::Integer(3),
+            Frame::Bulk("1-0".into()),
+            Frame::Bulk("3-0".into()),
+            Frame::Array(vec![strings(&["ann", "2"]), strings(&["bob", "1"])]),
+        ])
+    );
+
+    assert_eq!(
+        call(&mut conn, &["XACK", "jobs", "workers", "1-0", "9-0"]).await,
+        Frame::Integer(1)
+    );
+    let Frame::Array(pending) = call(
+        &mut conn,
+        &["XPENDING", "jobs", "workers", "-", "+", "10", "ann"],
+    )
+    .await
+    else {
+        panic!("XPENDING replies with an array");
+    };
+    let [Frame::Array(fields)] = pending.as_slice() else {
+        panic!("one entry is pending for ann: {:?}", pending);
+    };
+    assert_eq!(fields[0], Frame::Bulk("2-0".into()));
+    assert_eq!(fields[1], Frame::Bulk("ann".into()));
+    // Delivered once by `>` and once more by reading the history.
+    assert_eq!(fields[3], Frame::Integer(2));
+
+    assert_eq!(
+        call(&mut conn, &read("ann", "1", "$")).await,
+        Frame::Error(
+            "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the \
+             history of this consumer by specifying a proper ID, or use the > ID to get new \
+             messages. The $ ID would just return an empty result set."
+                .into()
+        )
+    );
+    assert_eq!(
+        call(
+            &mut conn,
+            &["XREADGROUP", "GROUP", "nope", "ann", "STREAMS", "jobs", ">"]
+        )
+        .await,
+        Frame::Error(
+            "NOGROUP No such key 'jobs' or consumer group 'nope' in XREADGROUP with GROUP option"
+                .into()
+        )
+    );
+}
+
+#[tokio::test]
+async fn idle_entries_can_be_claimed() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+    call(
+        &mut conn,
+        &["XGROUP", "CREATE", "jobs", "g", "0", "MKSTREAM"],
+    )
+    .await;
+    call(&mut conn, &["XADD", "jobs", "1-0", "job", "a"]).await;
+    call(&mut conn, &["XADD", "jobs", "2-0", "job", "b"]).await;
+    call(
+        &mut conn,
+        &["XREADGROUP", "GROUP", "g", "ann", "STREAMS", "jobs", ">"],
+    )
+    .await;
+
+    // Not idle long enough yet.
+    assert_eq!(
+        call(&mut conn, &["XCLAIM", "jobs", "g", "bob", "60000", "1-0"]).await,
+        Frame::Array(vec![])
+    );
+    time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(
+        call(
+            &mut conn,
+            &["XCLAIM", "jobs", "g", "bob", "20", "1-0", "2-0"]
+        )
+        .await,
+        Frame::Array(vec![
+            entry("1-0", &["job", "a"]),
+            entry("2-0", &["job", "b"])
+        ])
+    );
+    assert_eq!(
+        call(
+            &mut conn,
+            &[
+                "XCLAIM",
+                "jobs",
+                "g",
+                "carl",
+                "0",
+                "1-0",
+                "JUSTID",
+                "RETRYCOUNT",
+                "7"
+            ]
+        )
+        .await,
+        strings(&["1-0"])
+    );
+
+    let Frame::Array(pending) = call(&mut conn, &["XPENDING", "jobs", "g", "-", "+", "10"]).await
+    else {
+        panic!("XPENDING replies with an array");
+    };
+    let owners: Vec<(Frame, Frame)> = pending
+        .into_iter()
+        .map(|p| match p {
+            Frame::Array(p) => (p[1].clone(), p[3].clone()),
+            p => panic!("unexpected {:?}", p),
+        })
+        .collect();
+    assert_eq!(
+        owners,
+        [
+            (Frame::Bulk("carl".into()), Frame::Integer(7)),
+            (Frame::Bulk("bob".into()), Frame::Integer(2)),
+        ]
+    );
+
+    // Deleting a consumer drops what was pending for it.
+    assert_eq!(
+        call(&mut conn, &["XGROUP", "DELCONSUMER", "jobs", "g", "bob"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        call(&mut conn, &["XGROUP", "DESTROY", "jobs", "g"]).await,
+        Frame::Integer(1)
+    );
+}
+
+#[tokio::test]
+async fn blocked_group_reads_are_served_and_logged() {
+    let path = common::temp_dir("stream-aof").join("appendonly.aof");
+    let db = Db::new(4);
+    db.set_aof(Some(Aof::open(&path, Fsync::Always).unwrap()));
+    let addr = start(db.clone()).await;
+    let mut conn = connect(addr).await;
+    let mut worker = connect(addr).await;
+
+    call(
+        &mut conn,
+        &["XGROUP", "CREATE", "jobs", "g", "$", "MKSTREAM"],
+    )
+    .await;
+    send(
+        &mut worker,
+        &[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "ann",
+            "BLOCK",
+            "0",
+            "STREAMS",
+            "jobs",
+            ">",
+        ],
+    )
+    .await;
+    settle().await;
+    call(&mut conn, &["XADD", "jobs", "*", "job", "a"]).await;
+    let Frame::Array(served) = reply(&mut worker).await else {
+        panic!("the blocked worker is served");
+    };
+    assert_eq!(served.len(), 1);
+
+    call(&mut conn, &["XADD", "jobs", "MAXLEN", "5", "*", "job", "b"]).await;
+    call(
+        &mut conn,
+        &[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "bob",
+            "NOACK",
+            "STREAMS",
+            "jobs",
+            ">",
+        ],
+    )
+    .await;
+
+    // Replaying the file rebuilds the stream, PEL included, down to the
+    // delivery times.
+    let restored = Db::new(4);
+    aof::load(&path, &restored).unwrap();
+    let (Some(Value::Stream(expected)), Some(Value::Stream(replayed))) =
+        (db.get("jobs"), restored.get("jobs"))
+    else {
+        panic!("both hold the stream");
+    };
+    assert_eq!(
+        replayed.entries().collect::<Vec<_>>(),
+        expected.entries().collect::<Vec<_>>()
+    );
+    let (expected, replayed) = (expected.group(b"g").unwrap(), replayed.group(b"g").unwrap());
+    assert_eq!(replayed.last_delivered, expected.last_delivered);
+    assert_eq!(replayed.pending, expected.pending);
+    assert_eq!(
+        replayed.consumers.keys().collect::<Vec<_>>(),
+        expected.consumers.keys().collect::<Vec<_>>()
+    );
+
+    // So does a rewrite.
+    let now = SystemTime::now();
+    let rewritten = Db::new(4);
+    for cmd in aof::rewrite_commands(&db.snapshot(), now) {
+        redis::cmd::apply(&cmd, &rewritten);
+    }
+    let (Some(Value::Stream(original)), Some(Value::Stream(copy))) =
+        (db.get("jobs"), rewritten.get("jobs"))
+    else {
+        panic!("both hold the stream");
+    };
+    assert_eq!(copy.last_id(), original.last_id());
+    assert_eq!(
+        copy.group(b"g").unwrap().pending,
+        original.group(b"g").unwrap().pending
+    );
+}
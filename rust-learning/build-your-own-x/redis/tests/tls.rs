@@ -0,0 +1,136 @@
+//! Clients connecting over TLS, next to plain ones.
+
+mod common;
+
+use common::{call, connect, temp_dir};
+use redis::tls::TlsListener;
+use redis::{Connection, Db, Frame, server};
+
+use rcgen::CertifiedKey;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+/// A self-signed certificate for `localhost`, and its key, written to
+/// `dir` as PEM files.
+fn certificate(dir: &Path) -> CertificateDer<'static> {
+    let CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(dir.join("redis.crt"), cert.pem()).unwrap();
+    std::fs::write(dir.join("redis.key"), key_pair.serialize_pem()).unwrap();
+    cert.der().clone()
+}
+
+/// Serve `db` on a plain and a TLS port, with the certificate in `dir`.
+async fn start(db: Db, dir: &Path) -> (SocketAddr, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tls = TlsListener::new(
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        &dir.join("redis.crt"),
+        &dir.join("redis.key"),
+    )
+    .unwrap();
+    let addrs = (listener.local_addr().unwrap(), tls.local_addr().unwrap());
+    tokio::spawn(server::serve(listener, Some(tls), db));
+    addrs
+}
+
+/// Connect over TLS, trusting only `cert`.
+async fn connect_tls(addr: SocketAddr, cert: CertificateDer<'static>) -> Connection {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), socket)
+        .await
+        .unwrap();
+    Connection::new(stream)
+}
+
+#[tokio::test]
+async fn clients_share_the_keyspace_over_either_port() {
+    let dir = temp_dir("tls");
+    let cert = certificate(&dir);
+    let (plain, tls) = start(Db::new(4), &dir).await;
+
+    let mut secure = connect_tls(tls, cert).await;
+    assert_eq!(
+        call(&mut secure, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+    assert_eq!(
+        call(&mut secure, &["SET", "greeting", "hello"]).await,
+        Frame::ok()
+    );
+
+    let mut conn = connect(plain).await;
+    assert_eq!(
+        call(&mut conn, &["GET", "greeting"]).await,
+        Frame::Bulk("hello".into())
+    );
+
+    // Pipelined requests come back in order through TLS too.
+    let Frame::Map(_) = call(&mut secure, &["HELLO", "3"]).await else {
+        panic!("HELLO replies with a map");
+    };
+    for i in 0..50 {
+        common::send(&mut secure, &["RPUSH", "list", &i.to_string()]).await;
+    }
+    for i in 0..50 {
+        assert_eq!(common::reply(&mut secure).await, Frame::Integer(i + 1));
+    }
+}
+
+#[tokio::test]
+async fn a_client_without_tls_is_dropped() {
+    let dir = temp_dir("tls-plaintext");
+    let cert = certificate(&dir);
+    let (_, tls) = start(Db::new(4), &dir).await;
+
+    // Plain RESP where a handshake should be ends that connection only.
+    let mut socket = TcpStream::connect(tls).await.unwrap();
+    socket.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    let mut conn = Connection::new(socket);
+    assert!(!matches!(conn.read_frame().await, Ok(Some(_))));
+
+    let mut secure = connect_tls(tls, cert).await;
+    assert_eq!(
+        call(&mut secure, &["PING"]).await,
+        Frame::Simple("PONG".into())
+    );
+}
+
+#[tokio::test]
+async fn missing_or_mismatched_files_are_refused() {
+    let dir = temp_dir("tls-files");
+    certificate(&dir);
+    let err = TlsListener::new(
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        &dir.join("missing.crt"),
+        &dir.join("redis.key"),
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("missing.crt"), "{}", err);
+
+    // A key that goes with another certificate.
+    let other = temp_dir("tls-files-other");
+    certificate(&other);
+    assert!(
+        TlsListener::new(
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            &dir.join("redis.crt"),
+            &other.join("redis.key"),
+        )
+        .is_err()
+    );
+}
@@ -0,0 +1,272 @@
+//! Transactions: `MULTI`, `EXEC`, `DISCARD` and `WATCH`.
+
+mod common;
+
+use common::{call, connect, start};
+use redis::aof::{self, Aof, Fsync};
+use redis::{Db, Frame, Value};
+
+use std::time::Duration;
+use tokio::time;
+
+fn queued() -> Frame {
+    Frame::Simple("QUEUED".into())
+}
+
+#[tokio::test]
+async fn exec_runs_the_queued_commands() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    assert_eq!(call(&mut conn, &["MULTI"]).await, Frame::ok());
+    assert_eq!(call(&mut conn, &["SET", "a", "1"]).await, queued());
+    assert_eq!(call(&mut conn, &["RPUSH", "a", "x"]).await, queued());
+    assert_eq!(call(&mut conn, &["GET", "a"]).await, queued());
+    assert_eq!(call(&mut conn, &["BLPOP", "empty", "0"]).await, queued());
+
+    // A failing command does not stop the others.
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Array(vec![
+            Frame::ok(),
+            Frame::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".into()
+            ),
+            Frame::Bulk("1".into()),
+            // Blocking pops do not block inside a transaction.
+            Frame::Null,
+        ])
+    );
+
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Error("ERR EXEC without MULTI".into())
+    );
+}
+
+#[tokio::test]
+async fn bad_arguments_are_found_before_running() {
+    let addr = start(Db::new(4)).await;
+    let mut conn = connect(addr).await;
+
+    assert_eq!(call(&mut conn, &["MULTI"]).await, Frame::ok());
+    let max = i64::MAX.to_string();
+    for parts in [
+        &["BLPOP", "k", "1e300"][..],
+        &["SET", "a", "1", "EX", &max],
+        &["PEXPIRE", "a", &max],
+        &["SET", "b", "2"],
+    ] {
+        assert_eq!(call(&mut conn, parts).await, queued());
+    }
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Array(vec![
+            Frame::Error("ERR timeout is out of range".into()),
+            Frame::Error("ERR invalid expire time in 'set' command".into()),
+            Frame::Error("ERR invalid expire time in 'pexpire' command".into()),
+            Frame::ok(),
+        ])
+    );
+
+    // Every client carries on as before.
+    let mut other = connect(addr).await;
+    assert_eq!(
+        call(&mut other, &["GET", "b"]).await,
+        Frame::Bulk("2".into())
+    );
+    assert_eq!(call(&mut conn, &["EXISTS", "a"]).await, Frame::Integer(0));
+}
+
+#[tokio::test]
+async fn discard_and_refused_commands() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    call(&mut conn, &["MULTI"]).await;
+    assert_eq!(
+        call(&mut conn, &["MULTI"]).await,
+        Frame::Error("ERR MULTI calls can not be nested".into())
+    );
+    call(&mut conn, &["SET", "a", "1"]).await;
+    assert_eq!(call(&mut conn, &["DISCARD"]).await, Frame::ok());
+    assert_eq!(call(&mut conn, &["EXISTS", "a"]).await, Frame::Integer(0));
+    assert_eq!(
+        call(&mut conn, &["DISCARD"]).await,
+        Frame::Error("ERR DISCARD without MULTI".into())
+    );
+
+    // A refused command dooms the whole transaction.
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["SET", "a", "1"]).await;
+    assert_eq!(
+        call(&mut conn, &["WATCH", "a"]).await,
+        Frame::Error("ERR WATCH inside MULTI is not allowed".into())
+    );
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Error("EXECABORT Transaction discarded because of previous errors.".into())
+    );
+    assert_eq!(call(&mut conn, &["EXISTS", "a"]).await, Frame::Integer(0));
+}
+
+#[tokio::test]
+async fn watched_key_modified_by_another_client_aborts() {
+    let addr = start(Db::new(4)).await;
+    let mut conn = connect(addr).await;
+    let mut other = connect(addr).await;
+
+    call(&mut conn, &["SET", "balance", "10"]).await;
+    assert_eq!(call(&mut conn, &["WATCH", "balance"]).await, Frame::ok());
+    call(&mut other, &["SET", "balance", "20"]).await;
+
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["SET", "balance", "11"]).await;
+    assert_eq!(call(&mut conn, &["EXEC"]).await, Frame::Null);
+    assert_eq!(
+        call(&mut conn, &["GET", "balance"]).await,
+        Frame::Bulk("20".into())
+    );
+
+    // The watch ended with `EXEC`: the next transaction goes through.
+    call(&mut other, &["SET", "balance", "30"]).await;
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["SET", "balance", "31"]).await;
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Array(vec![Frame::ok()])
+    );
+}
+
+#[tokio::test]
+async fn untouched_watched_keys_let_exec_through() {
+    let addr = start(Db::new(4)).await;
+    let mut conn = connect(addr).await;
+    let mut other = connect(addr).await;
+
+    call(&mut conn, &["WATCH", "a", "b"]).await;
+    // Reads and writes to other keys do not count.
+    call(&mut other, &["GET", "a"]).await;
+    call(&mut other, &["SET", "c", "1"]).await;
+
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["SET", "a", "1"]).await;
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Array(vec![Frame::ok()])
+    );
+
+    // Neither does a change made before `UNWATCH`.
+    call(&mut conn, &["WATCH", "a"]).await;
+    call(&mut other, &["DEL", "a"]).await;
+    call(&mut conn, &["UNWATCH"]).await;
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["SET", "a", "2"]).await;
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Array(vec![Frame::ok()])
+    );
+}
+
+#[tokio::test]
+async fn reading_a_watched_key_lets_exec_through() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    call(&mut conn, &["RPUSH", "l", "a"]).await;
+    call(&mut conn, &["HSET", "h", "f", "v"]).await;
+    call(&mut conn, &["XADD", "s", "1-1", "f", "v"]).await;
+    call(&mut conn, &["WATCH", "l", "h", "s"]).await;
+    for parts in [
+        &["LLEN", "l"][..],
+        &["LRANGE", "l", "0", "-1"],
+        &["HGET", "h", "f"],
+        &["HGETALL", "h"],
+        &["XLEN", "s"],
+        &["XRANGE", "s", "-", "+"],
+    ] {
+        call(&mut conn, parts).await;
+    }
+
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["RPUSH", "l", "b"]).await;
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Array(vec![Frame::Integer(2)])
+    );
+}
+
+#[tokio::test]
+async fn expiring_watched_key_aborts() {
+    let mut conn = connect(start(Db::new(4)).await).await;
+
+    call(&mut conn, &["SET", "lock", "me", "PX", "20"]).await;
+    call(&mut conn, &["WATCH", "lock"]).await;
+    time::sleep(Duration::from_millis(50)).await;
+
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["SET", "lock", "me"]).await;
+    assert_eq!(call(&mut conn, &["EXEC"]).await, Frame::Null);
+}
+
+#[tokio::test]
+async fn transactions_do_not_interleave() {
+    let addr = start(Db::new(4)).await;
+
+    let mut clients = Vec::new();
+    for client in ["x", "y"] {
+        let mut conn = connect(addr).await;
+        clients.push(tokio::spawn(async move {
+            for _ in 0..50 {
+                call(&mut conn, &["MULTI"]).await;
+                call(&mut conn, &["RPUSH", "log", client]).await;
+                call(&mut conn, &["RPUSH", "log", client]).await;
+                call(&mut conn, &["EXEC"]).await;
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+
+    let mut conn = connect(addr).await;
+    let Frame::Array(log) = call(&mut conn, &["LRANGE", "log", "0", "-1"]).await else {
+        panic!("not a list");
+    };
+    assert_eq!(log.len(), 200);
+    for pair in log.chunks(2) {
+        assert_eq!(pair[0], pair[1]);
+    }
+}
+
+#[tokio::test]
+async fn transactions_are_logged_whole() {
+    let path = common::temp_dir("aof-multi").join("appendonly.aof");
+
+    let db = Db::new(4);
+    db.set_aof(Some(Aof::open(&path, Fsync::Always).unwrap()));
+    let mut conn = connect(start(db).await).await;
+
+    call(&mut conn, &["SET", "before", "1"]).await;
+    call(&mut conn, &["MULTI"]).await;
+    call(&mut conn, &["RPUSH", "l", "a", "b"]).await;
+    call(&mut conn, &["LPOP", "l"]).await;
+    assert_eq!(
+        call(&mut conn, &["EXEC"]).await,
+        Frame::Array(vec![Frame::Integer(2), Frame::Bulk("a".into())])
+    );
+
+    let restored = Db::new(4);
+    assert_eq!(aof::load(&path, &restored).unwrap(), 3);
+    assert_eq!(restored.get("l"), Some(Value::List(["b".into()].into())));
+
+    // Without its `EXEC`, the transaction is dropped as a whole.
+    let len = std::fs::metadata(&path).unwrap().len();
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(len - 2).unwrap();
+
+    let restored = Db::new(4);
+    assert_eq!(aof::load(&path, &restored).unwrap(), 1);
+    assert_eq!(restored.get("l"), None);
+    assert_eq!(
+        std::fs::read(&path).unwrap(),
+        b"*3\r\n$3\r\nset\r\n$6\r\nbefore\r\n$1\r\n1\r\n"
+    );
+}